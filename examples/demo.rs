@@ -0,0 +1,99 @@
+//! Demo server for the streaming, WebSocket, SSE and trailer support in the
+//! hyperport library, kept out of the `hyperport` binary so production
+//! configs never expose it.
+//!
+//! ```bash
+//! cargo run --example demo
+//! curl -N http://localhost:8081/stream
+//! curl -N http://localhost:8081/events
+//! curl --data-binary @Cargo.toml http://localhost:8081/echo
+//! ```
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+use hyperport::http::handler;
+use hyperport::http::sse::{self, Event};
+use hyperport::http::websocket::{self, Message};
+use hyperport::http::{Response, Router, StatusCode};
+use hyperport::server::Server;
+
+const LISTEN_ADDR: &str = "[::]:8081";
+
+fn main() -> Result<(), hyperport::HyperportError> {
+    let mut router = Router::new();
+    router
+        .get("/stream", |_, writer| {
+            writer.start(StatusCode::Ok, &[("Content-Type", "text/plain; charset=utf-8")], None)?;
+            for i in 1..=10 {
+                writeln!(writer, "line {}", i)?;
+                writer.flush()?;
+                thread::sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        })
+        .get("/users/{id}/posts/{post_id}", |request, writer| {
+            let body = format!(
+                "user {} post {}\n",
+                request.param("id").unwrap_or(""),
+                request.param("post_id").unwrap_or("")
+            );
+            writer.send(StatusCode::Ok, &[("Content-Type", "text/plain; charset=utf-8")], body.as_bytes())
+        })
+        .get("/ws", |request, writer| {
+            let Some(mut socket) = websocket::upgrade(request, writer)? else {
+                return Ok(());
+            };
+            loop {
+                match socket.recv()? {
+                    Message::Close(_) => return Ok(()),
+                    message @ (Message::Text(_) | Message::Binary(_)) => socket.send(&message)?,
+                    _ => {}
+                }
+            }
+        })
+        .get("/events", |_, writer| {
+            sse::stream(writer, Duration::from_secs(15), |events| {
+                thread::spawn(move || {
+                    let hello = Event::new("connected").retry(Duration::from_secs(3));
+                    if events.send(hello).is_err() {
+                        return;
+                    }
+                    for i in 1..=5 {
+                        let event = Event::new(&format!("tick {}", i)).event("tick").id(&i.to_string());
+                        if events.send(event).is_err() {
+                            return;
+                        }
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                });
+            })
+        })
+        .post("/echo", |request, writer| {
+            let content_type = request.headers.get("content-type").unwrap_or("application/octet-stream");
+            // Trailers sent with the body come back as trailers too.
+            let names: Vec<&str> = request.trailers.iter().map(|(name, _)| name).collect();
+            let names = names.join(", ");
+            let mut headers = vec![("Content-Type", content_type)];
+            if !names.is_empty() {
+                headers.push(("Trailer", &names));
+            }
+            for (name, value) in request.trailers.iter() {
+                writer.add_trailer(name, value);
+            }
+            writer.start(StatusCode::Ok, &headers, Some(request.body_len()?))?;
+            io::copy(&mut request.body_reader(), writer)?;
+            writer.finish()
+        })
+        // Echoed bodies go back byte for byte.
+        .disable_compression("POST", "/echo")
+        .handle(
+            "GET",
+            "/health",
+            handler::respond(|_| Response::builder().header("Content-Type", "text/plain").body("ok\n")),
+        );
+
+    println!("demo listening on http://{}", LISTEN_ADDR);
+    Server::builder().bind(LISTEN_ADDR).router(router).run()
+}
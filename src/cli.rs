@@ -0,0 +1,102 @@
+//! Command-line options for the example server.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use hyperport::log::Filter;
+
+pub const USAGE: &str = "\
+Usage: hyperport [OPTIONS] [CONFIG.toml]
+
+Options:
+  -c, --config <PATH>     Read settings from a TOML file
+  -b, --bind <ADDR>       Listen on ip:port; may be repeated
+  -p, --port <PORT>       Listen on [::]:PORT
+  -r, --root <DIR>        Serve files from DIR under /static
+  -w, --workers <N>       Number of reactor threads
+  -u, --user <USER>       Switch to USER after binding
+  -g, --group <GROUP>     Switch to GROUP after binding
+  -l, --log-level <LEVEL> off, error, warn, info, debug or trace, optionally
+                          per module, e.g. warn,hyperport::http::proxy=debug
+                          [default: info]
+  -h, --help              Print this help
+
+Options given on the command line override the config file.";
+
+#[derive(Debug)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub bind: Vec<String>,
+    pub port: Option<u16>,
+    pub root: Option<PathBuf>,
+    pub workers: Option<usize>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Overrides `level` under `[log]`.
+    pub log_level: Option<Filter>,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parses the arguments following the program name. Both `--flag value`
+    /// and `--flag=value` are accepted.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut parsed = Args {
+            config: None,
+            bind: Vec::new(),
+            port: None,
+            root: None,
+            workers: None,
+            user: None,
+            group: None,
+            log_level: None,
+            help: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                if parsed.config.is_some() {
+                    return Err(format!("unexpected argument `{}`", arg));
+                }
+                parsed.config = Some(arg.into());
+                continue;
+            }
+
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("`{}` needs a value", flag))
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => parsed.config = Some(value()?.into()),
+                "-b" | "--bind" => parsed.bind.push(value()?),
+                "-p" | "--port" => parsed.port = Some(number(&flag, &value()?)?),
+                "-r" | "--root" => parsed.root = Some(value()?.into()),
+                "-w" | "--workers" => parsed.workers = Some(number(&flag, &value()?)?),
+                "-u" | "--user" => parsed.user = Some(value()?),
+                "-g" | "--group" => parsed.group = Some(value()?),
+                "-l" | "--log-level" => parsed.log_level = Some(value()?.parse()?),
+                "-h" | "--help" => parsed.help = true,
+                _ => return Err(format!("unknown option `{}`", flag)),
+            }
+        }
+
+        if parsed.port.is_some() && !parsed.bind.is_empty() {
+            return Err("`--port` cannot be combined with `--bind`".to_string());
+        }
+        Ok(parsed)
+    }
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` expects a number, got `{}`", flag, value))
+}
@@ -0,0 +1,1409 @@
+//! Server settings loaded from a TOML file.
+//!
+//! ```toml
+//! [server]
+//! listen = ["0.0.0.0:8080", "[::1]:8080"]   # or a single string
+//! workers = 4
+//! pool_size = 64
+//! queue_depth = 1024
+//! max_connections = 10000
+//! read_timeout = 30                         # seconds
+//! header_timeout = 10                       # first byte to end of head
+//! keep_alive_timeout = 5                    # idle between requests
+//! write_timeout = 30
+//! request_timeout = 60                      # whole request and response
+//! slow_request_ms = 1000                    # log requests slower than this
+//! max_request_line = 8192                   # bytes; longer gets 414
+//! max_header_size = 8192                    # bytes per header line; 431
+//! max_head_size = 65536                     # bytes in the whole head; 431
+//! max_headers = 100                         # header fields; 431
+//! max_body_size = 67108864                  # bytes; larger gets 413
+//! spool_threshold = 1048576                 # larger bodies go to disk
+//! spool_dir = "/var/tmp"                    # default: the system temp dir
+//! user = "www-data"                         # switch to after binding
+//! group = "www-data"
+//! trusted_proxies = ["127.0.0.1", "10.0.0.0/8"]  # believe their X-Forwarded-For
+//! proxy_protocol = ["0.0.0.0:8080"]         # listeners behind a PROXY protocol LB
+//! http2 = true                              # h2 with prior knowledge; default on
+//! server_header = "hyperport"               # false leaves it out
+//! request_id = true                         # or the header to use instead of
+//!                                           # X-Request-Id
+//!
+//! [[static]]
+//! mount = "/static"
+//! root = "./public"
+//! host = "www.example.com"                  # only for this Host; default: any
+//! cache_control = [                         # first matching pattern wins
+//!     ["/assets/*", "public, max-age=31536000, immutable"],
+//!     ["/index.html", "no-cache"],
+//! ]
+//! precompressed = true                      # serve foo.js.br / foo.js.gz
+//! directory_listing = false                 # list directories without an index
+//! spa_fallback = false                      # unknown pages get /index.html
+//! allow = ["10.0.0.0/8"]                    # clients let in; others get 403
+//! deny = ["10.0.0.66"]                      # clients refused with 403
+//!
+//! [[proxy]]
+//! mount = "/api"
+//! upstream = "127.0.0.1:9000"              # or several, as an array; an entry
+//!                                           # may be [address, weight]
+//! balancer = "round_robin"                  # "weighted", "least_connections"
+//!                                           # or "power_of_two_choices"
+//! health_check = "/healthz"                 # GET path, or "tcp"; default: none
+//! health_interval = 5                       # seconds between probes
+//! health_timeout = 2                        # seconds per probe
+//! healthy_threshold = 2                     # passes in a row to come back up
+//! unhealthy_threshold = 3                   # failures in a row to go down
+//! retries = 2                               # idempotent requests, on errors/5xx
+//! retry_backoff_ms = 50                     # doubling per retry
+//! max_retry_backoff_ms = 1000
+//! circuit_breaker = true                    # fail fast with 503 on failing upstreams
+//! circuit_failure_percent = 50              # of at least circuit_min_requests
+//! circuit_min_requests = 10                 # within circuit_window seconds
+//! circuit_window = 10
+//! circuit_open_for = 30                     # seconds before a trial request
+//! host = "api.example.com"                  # only for this Host; default: any
+//! max_idle = 32                             # idle upstream connections kept
+//! idle_timeout = 60                         # seconds before an idle one closes
+//! max_lifetime = 600                        # seconds; default: no limit
+//! dns_ttl = 30                              # seconds before host names are looked up
+//!                                           # again; 0 resolves them once
+//! proxy_protocol = "v1"                     # or "v2": send upstreams a PROXY header
+//! rate_limit = 50                           # requests a second per client, in place
+//!                                           # of [rate_limit]'s
+//! allow = ["192.168.0.0/16"]                # as for [[static]]
+//!
+//! [[ip_filter]]                             # close connections from unwanted peers
+//! listen = "0.0.0.0:8080"                   # only this listener; default: all
+//!                                           # without an [[ip_filter]] of their own
+//! allow = ["10.0.0.0/8", "fd00::/8"]        # default: any
+//! deny = ["10.0.0.66"]
+//!
+//! [[forward]]                               # plain TCP, no HTTP
+//! listen = "0.0.0.0:2222"
+//! target = "10.0.0.5:22"
+//! connect_timeout = 5                       # seconds
+//! idle_timeout = 300                        # seconds before a quiet connection closes
+//! proxy_protocol = "v2"                     # send the target a PROXY header
+//!
+//! [[forward]]
+//! protocol = "udp"                          # default: "tcp"
+//! listen = "0.0.0.0:5353"
+//! target = "10.0.0.2:53"
+//! idle_timeout = 60                         # seconds before a quiet session ends
+//! max_sessions = 4096                       # client addresses mapped at once
+//!
+//! [compression]                             # compress responses when present
+//! level = 6                                 # 1 (fastest) to 9 (smallest)
+//! gzip_level = 6                            # per encoding, overriding level
+//! deflate_level = 6
+//! min_size = 256                            # bytes; smaller stay as they are
+//! types = ["text/*", "application/json"]    # media types to compress
+//!
+//! [security_headers]                        # add security headers when present
+//! hsts = 31536000                           # Strict-Transport-Security max-age, seconds
+//! hsts_subdomains = true                    # with includeSubDomains
+//! frame_options = "DENY"                    # default: "SAMEORIGIN"
+//! referrer_policy = "no-referrer"           # default: "strict-origin-when-cross-origin"
+//! content_type_options = false              # X-Content-Type-Options: nosniff; default true
+//! content_security_policy = "default-src 'self'"
+//!                                           # "" leaves a header out
+//!
+//! [cors]                                    # answer cross-origin requests when present
+//! origins = ["https://app.example.com", "https://*.example.com"]  # default: any
+//! methods = ["GET", "POST", "DELETE"]       # default: GET, HEAD and POST
+//! headers = ["Authorization"]               # request headers; default: any asked for
+//! expose_headers = ["X-Request-Id"]         # response headers pages may read
+//! credentials = true                        # cookies and HTTP auth
+//! max_age = 600                             # seconds browsers cache preflights
+//!
+//! [rate_limit]                              # limit requests per client when present
+//! requests = 600                            # per period, refilled steadily
+//! period = 60                               # seconds; default 1
+//! burst = 100                               # most at once; default: requests
+//! key = "X-Api-Key"                         # header telling clients apart;
+//!                                           # default: their address
+//!
+//! [basic_auth]                              # require a login when present
+//! realm = "Admin"                           # shown by browsers; default: "hyperport"
+//! users = [["alice", "secret"]]             # [name, plain-text password]
+//! htpasswd = "/etc/hyperport/htpasswd"      # bcrypt hashes, from `htpasswd -B`
+//! paths = ["/admin"]                        # default: every path
+//!
+//! [jwt]                                     # require a bearer token when present
+//! secret = "change me"                      # HS256 key
+//! jwks_url = "http://127.0.0.1:8000/jwks.json" # RS256 keys, fetched and refetched;
+//!                                           # loopback only, as there is no TLS
+//! jwks_file = "/etc/hyperport/jwks.json"    # RS256 keys
+//! jwks_refresh = 3600                       # seconds between fetches; default 3600
+//! issuer = "https://auth.example.com"       # required `iss`
+//! audience = ["api"]                        # `aud` values accepted
+//! leeway = 60                               # seconds of clock skew; default 60
+//! realm = "api"                             # named in challenges
+//! paths = ["/api"]                          # default: every path
+//!
+//! [connect]                                 # tunnel CONNECT requests when present
+//! ports = [443]                             # destination ports allowed
+//! hosts = ["*.example.com"]                 # destination hosts; default: any
+//! connect_timeout = 10                      # seconds
+//! idle_timeout = 300                        # seconds before a quiet tunnel closes
+//!
+//! [socks]                                   # SOCKS5 proxy when present
+//! listen = "127.0.0.1:1080"
+//! users = [["alice", "secret"]]             # [username, password]; default: no login
+//! handshake_timeout = 10                    # seconds
+//! connect_timeout = 10
+//! idle_timeout = 300                        # seconds before a quiet connection closes
+//!
+//! [tracing]                                 # export spans when present
+//! endpoint = "http://127.0.0.1:4318/v1/traces"  # OTLP/HTTP collector
+//! service_name = "hyperport"
+//!
+//! [metrics]                                 # serve Prometheus metrics when present
+//! path = "/metrics"                         # default: "/metrics"
+//! allow = ["127.0.0.1", "10.0.0.0/8"]       # scrapers let in; others get 403
+//! deny = ["10.0.0.66"]
+//!
+//! [error_pages]                             # replace the built-in pages
+//! 404 = "errors/404.html"                   # {{status}} and {{reason}} are
+//! 503 = "errors/busy.html"                  # filled in
+//!
+//! [log]
+//! access_log = "stdout"                     # "off", or a file path
+//! format = "common"                         # or "json"
+//! level = "warn,hyperport::http::proxy=debug"  # off, error, warn, info, debug
+//!                                           # or trace, per module if need be
+//! output = "stderr"                         # "stdout", or a file path
+//! ```
+//!
+//! Every section and key is optional; anything left out keeps the
+//! [`ServerBuilder`] default. Unknown keys are rejected so that typos do not
+//! silently fall back to defaults.
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::HyperportError;
+use crate::http::jwt_auth::is_loopback_http;
+use crate::http::proxy::{CircuitBreaker, HealthCheck};
+use crate::http::{HeadLimits, LogFormat, StatusCode, TrustedProxies};
+use crate::log::Filter;
+use crate::net::forward::Protocol;
+use crate::net::ip_filter::IpFilter;
+use crate::net::proxy_protocol;
+use crate::server::ServerBuilder;
+use crate::util::toml::{self, Table, Value};
+
+/// A directory served under a URL prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticMount {
+    /// URL path prefix, e.g. `/static`.
+    pub mount: String,
+    pub root: PathBuf,
+    /// Cache-Control values by file pattern, as for
+    /// [`StaticFiles::cache_control`](crate::http::StaticFiles::cache_control).
+    pub cache_control: Vec<(String, String)>,
+    /// Whether to serve `.br` and `.gz` siblings, as for
+    /// [`StaticFiles::precompressed`](crate::http::StaticFiles::precompressed).
+    pub precompressed: bool,
+    /// Whether to list directories that have no index file, as for
+    /// [`StaticFiles::directory_listing`](crate::http::StaticFiles::directory_listing).
+    pub directory_listing: bool,
+    /// Whether unknown pages get the root index file, as for
+    /// [`StaticFiles::spa_fallback`](crate::http::StaticFiles::spa_fallback).
+    pub spa_fallback: bool,
+    /// The virtual host serving this mount, as for
+    /// [`Router::host`](crate::http::Router::host); `None` serves it for
+    /// every host without mounts of its own.
+    pub host: Option<String>,
+    /// Clients refused with 403, as for
+    /// [`Router::ip_filter`](crate::http::Router::ip_filter).
+    pub ip_filter: Option<IpFilter>,
+}
+
+/// Requests under a URL prefix forwarded to an upstream server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyRoute {
+    /// URL path prefix, e.g. `/api`.
+    pub mount: String,
+    /// `host:port` of each upstream with its weight, as for
+    /// [`Proxy::balanced`](crate::http::Proxy::balanced).
+    pub upstreams: Vec<(String, u32)>,
+    pub balancer: BalancerKind,
+    /// Set when `health_check` is given.
+    pub health_check: Option<HealthCheck>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<Duration>,
+    pub max_retry_backoff: Option<Duration>,
+    /// Set when `circuit_breaker` is true.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// The virtual host serving this mount, as for [`StaticMount::host`].
+    pub host: Option<String>,
+    /// Pool settings; anything left unset keeps the [`Proxy`](crate::http::Proxy) default.
+    pub max_idle: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// How often upstream host names are resolved again, as for
+    /// [`Proxy::dns_ttl`](crate::http::Proxy::dns_ttl).
+    pub dns_ttl: Option<Duration>,
+    /// Set when upstream connections open with a PROXY protocol header.
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Requests a second each client may send, as for
+    /// [`Router::rate_limit`](crate::http::Router::rate_limit).
+    pub rate_limit: Option<u32>,
+    /// Clients refused with 403, as for [`StaticMount::ip_filter`].
+    pub ip_filter: Option<IpFilter>,
+}
+
+/// A `[[forward]]` port forwarder. Timeouts left unset keep the
+/// [`Forwarder`](crate::net::forward::Forwarder) defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardConfig {
+    /// Whether connections or datagrams are forwarded.
+    pub protocol: Protocol,
+    /// Literal socket address to listen on.
+    pub listen: String,
+    /// `host:port` to forward connections to.
+    pub target: String,
+    pub connect_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    /// Set when target connections open with a PROXY protocol header.
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Most UDP sessions at once.
+    pub max_sessions: Option<usize>,
+}
+
+/// The built-in [`Balancer`](crate::http::proxy::Balancer) a proxy uses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BalancerKind {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConnections,
+    PowerOfTwoChoices,
+}
+
+/// Where access log lines go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+    Off,
+}
+
+/// Where the server's own log lines go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stderr,
+    Stdout,
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    pub access_log: AccessLogTarget,
+    pub format: LogFormat,
+    /// Which messages are logged; `None` keeps the [`log`](crate::log)
+    /// default of `info`.
+    pub level: Option<Filter>,
+    pub output: LogOutput,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            access_log: AccessLogTarget::Stdout,
+            format: LogFormat::Common,
+            level: None,
+            output: LogOutput::Stderr,
+        }
+    }
+}
+
+/// Response compression settings. Anything left unset keeps the
+/// [`Compression`](crate::http::Compression) default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub gzip_level: Option<u32>,
+    pub deflate_level: Option<u32>,
+    pub min_size: Option<u64>,
+    pub types: Option<Vec<String>>,
+}
+
+/// Security header settings. Anything left unset keeps the
+/// [`SecurityHeaders`](crate::http::SecurityHeaders) default; an empty
+/// string leaves the header out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecurityHeadersConfig {
+    pub hsts: Option<Duration>,
+    pub hsts_subdomains: bool,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub content_type_options: bool,
+    pub content_security_policy: Option<String>,
+}
+
+/// Cross-origin settings. Anything left unset keeps the
+/// [`Cors`](crate::http::Cors) default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CorsConfig {
+    pub origins: Option<Vec<String>>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub expose_headers: Option<Vec<String>>,
+    pub credentials: Option<bool>,
+    pub max_age: Option<Duration>,
+}
+
+/// The default rate limit, as for [`Limit`](crate::http::Limit).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: Option<u32>,
+    /// The header keying buckets, in place of the client address.
+    pub key: Option<String>,
+}
+
+/// Basic authentication settings, as for [`BasicAuth`](crate::http::BasicAuth).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicAuthConfig {
+    pub realm: String,
+    /// Names and plain-text passwords.
+    pub users: Vec<(String, String)>,
+    pub htpasswd: Option<PathBuf>,
+    /// Path prefixes requiring a login; `None` for every path.
+    pub paths: Option<Vec<String>>,
+}
+
+/// Bearer token settings, as for [`JwtAuth`](crate::http::JwtAuth).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwks_file: Option<PathBuf>,
+    pub jwks_refresh: Option<Duration>,
+    pub issuer: Option<String>,
+    pub audience: Option<Vec<String>>,
+    pub leeway: Option<Duration>,
+    pub realm: Option<String>,
+    /// Path prefixes requiring a token; `None` for every path.
+    pub paths: Option<Vec<String>>,
+}
+
+/// CONNECT tunnelling settings. Anything left unset keeps the
+/// [`ConnectProxy`](crate::http::proxy::ConnectProxy) default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectConfig {
+    pub ports: Option<Vec<u16>>,
+    pub hosts: Option<Vec<String>>,
+    pub connect_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// SOCKS5 proxy settings. Timeouts left unset keep the
+/// [`Socks5`](crate::net::socks::Socks5) defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocksConfig {
+    /// Literal socket address to listen on.
+    pub listen: String,
+    /// Username and password pairs clients log in with; empty for no login.
+    pub users: Vec<(String, String)>,
+    pub handshake_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Where spans are exported, as for
+/// [`ServerOptions::otlp_endpoint`](crate::server::ServerOptions::otlp_endpoint).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracingConfig {
+    /// An `http://` URL.
+    pub endpoint: String,
+    pub service_name: Option<String>,
+}
+
+/// Where Prometheus metrics are served, as for
+/// [`Metrics`](crate::server::stats::Metrics).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+    pub path: String,
+    /// Clients refused with 403, as for [`StaticMount::ip_filter`].
+    pub ip_filter: Option<IpFilter>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub workers: Option<usize>,
+    pub pool_size: Option<usize>,
+    pub queue_depth: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub read_timeout: Option<Duration>,
+    pub header_timeout: Option<Duration>,
+    pub keep_alive_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub slow_request_threshold: Option<Duration>,
+    /// Set when any of the limits is given; the others keep their defaults.
+    pub head_limits: Option<HeadLimits>,
+    pub max_body_size: Option<usize>,
+    pub spool_threshold: Option<usize>,
+    pub spool_dir: Option<PathBuf>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Addresses and networks whose forwarding headers name the client, as
+    /// for [`TrustedProxies`](crate::http::TrustedProxies).
+    pub trusted_proxies: Vec<String>,
+    /// Listen addresses expecting a PROXY protocol header, as for
+    /// [`ServerOptions::proxy_protocol`](crate::server::ServerOptions::proxy_protocol).
+    pub proxy_protocol: Vec<String>,
+    /// The `[[ip_filter]]` without a `listen` address, as for
+    /// [`ServerOptions::ip_filter`](crate::server::ServerOptions::ip_filter).
+    pub ip_filter: Option<IpFilter>,
+    /// The `[[ip_filter]]`s naming a listen address.
+    pub listener_ip_filters: Vec<(String, IpFilter)>,
+    /// Whether to serve HTTP/2; `None` keeps the default.
+    pub http2: Option<bool>,
+    /// The `Server` header: a replacement value, or `Some(None)` for
+    /// `false`, which leaves it out. `None` keeps the default.
+    pub server_header: Option<Option<String>>,
+    /// The header carrying request IDs, when they are on. See
+    /// [`RequestId`](crate::http::RequestId).
+    pub request_id: Option<String>,
+    pub static_mounts: Vec<StaticMount>,
+    pub proxies: Vec<ProxyRoute>,
+    pub forwards: Vec<ForwardConfig>,
+    /// Set when the `[compression]` section is present.
+    pub compression: Option<CompressionConfig>,
+    /// Set when the `[security_headers]` section is present.
+    pub security_headers: Option<SecurityHeadersConfig>,
+    /// Set when the `[cors]` section is present.
+    pub cors: Option<CorsConfig>,
+    /// Set when the `[rate_limit]` section is present.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Set when the `[basic_auth]` section is present.
+    pub basic_auth: Option<BasicAuthConfig>,
+    /// Set when the `[jwt]` section is present.
+    pub jwt: Option<JwtConfig>,
+    /// Set when the `[connect]` section is present.
+    pub connect: Option<ConnectConfig>,
+    /// Set when the `[socks]` section is present.
+    pub socks: Option<SocksConfig>,
+    /// Set when the `[tracing]` section is present.
+    pub tracing: Option<TracingConfig>,
+    /// Set when the `[metrics]` section is present.
+    pub metrics: Option<MetricsConfig>,
+    /// Template files for error statuses, as for
+    /// [`Router::error_page`](crate::http::Router::error_page).
+    pub error_pages: Vec<(StatusCode, PathBuf)>,
+    pub log: LogConfig,
+}
+
+impl Config {
+    /// Reads and parses the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, HyperportError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Config::parse(&text).map_err(|e| match e {
+            HyperportError::Config(msg) => HyperportError::Config(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// Parses configuration from TOML text.
+    pub fn parse(text: &str) -> Result<Config, HyperportError> {
+        let mut root = toml::parse(text).map_err(|e| HyperportError::Config(e.to_string()))?;
+        let mut config = Config::default();
+
+        if let Some(server) = take_table(&mut root, "server")? {
+            let mut server = Section::new("server", server);
+            config.listen = match server.take("listen") {
+                None => Vec::new(),
+                Some(Value::String(addr)) => vec![addr],
+                Some(Value::Array(items)) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(addr) => Ok(addr),
+                        other => Err(invalid("server.listen", "a string", &other)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(other) => return Err(invalid("server.listen", "a string or array of strings", &other)),
+            };
+            config.workers = server.count("workers")?;
+            config.pool_size = server.count("pool_size")?;
+            config.queue_depth = server.count("queue_depth")?;
+            config.max_connections = server.count("max_connections")?;
+            config.read_timeout = server.count("read_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+            config.header_timeout = server.count("header_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+            config.keep_alive_timeout = server.count("keep_alive_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+            config.write_timeout = server.count("write_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+            config.request_timeout = server.count("request_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+            config.slow_request_threshold =
+                server.count("slow_request_ms")?.map(|ms| Duration::from_millis(ms as u64));
+            let limits = [
+                server.count("max_request_line")?,
+                server.count("max_header_size")?,
+                server.count("max_head_size")?,
+                server.count("max_headers")?,
+            ];
+            if limits.iter().any(Option::is_some) {
+                let defaults = HeadLimits::default();
+                config.head_limits = Some(HeadLimits {
+                    max_request_line: limits[0].unwrap_or(defaults.max_request_line),
+                    max_header_size: limits[1].unwrap_or(defaults.max_header_size),
+                    max_head_size: limits[2].unwrap_or(defaults.max_head_size),
+                    max_headers: limits[3].unwrap_or(defaults.max_headers),
+                });
+            }
+            config.max_body_size = server.count("max_body_size")?;
+            config.spool_threshold = server.count("spool_threshold")?;
+            config.spool_dir = server.string("spool_dir")?.map(PathBuf::from);
+            config.user = server.string("user")?;
+            config.group = server.string("group")?;
+            config.trusted_proxies = match server.take("trusted_proxies") {
+                None => Vec::new(),
+                Some(Value::Array(items)) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(network) => Ok(network),
+                        other => Err(invalid("server.trusted_proxies", "a string", &other)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(other) => return Err(invalid("server.trusted_proxies", "an array of strings", &other)),
+            };
+            TrustedProxies::new(&config.trusted_proxies).map_err(|e| match e {
+                HyperportError::InvalidAddress(network) => HyperportError::Config(format!(
+                    "`server.trusted_proxies` entries must be addresses or networks, found \"{}\"",
+                    network
+                )),
+                e => e,
+            })?;
+            config.proxy_protocol = match server.take("proxy_protocol") {
+                None => Vec::new(),
+                Some(Value::Array(items)) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(addr) if addr.parse::<SocketAddr>().is_ok() => Ok(addr),
+                        other => Err(invalid("server.proxy_protocol", "a listen address", &other)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(other) => return Err(invalid("server.proxy_protocol", "an array of strings", &other)),
+            };
+            config.http2 = server.boolean("http2")?;
+            config.server_header = match server.take("server_header") {
+                None => None,
+                Some(Value::String(value)) => Some(Some(value)),
+                Some(Value::Boolean(false)) => Some(None),
+                Some(other) => return Err(invalid("server.server_header", "a string or false", &other)),
+            };
+            config.request_id = match server.take("request_id") {
+                None | Some(Value::Boolean(false)) => None,
+                Some(Value::Boolean(true)) => Some("X-Request-Id".to_string()),
+                Some(Value::String(header)) => Some(header),
+                Some(other) => return Err(invalid("server.request_id", "a boolean or a header name", &other)),
+            };
+            server.finish()?;
+        }
+
+        match root.remove("static") {
+            None => {}
+            Some(Value::Array(tables)) => {
+                for table in tables {
+                    let Value::Table(table) = table else {
+                        return Err(invalid("static", "an array of tables", &table));
+                    };
+                    let mut section = Section::new("static", table);
+                    let mount = section.required_string("mount")?;
+                    let root = PathBuf::from(section.required_string("root")?);
+                    let cache_control = match section.take("cache_control") {
+                        None => Vec::new(),
+                        Some(Value::Array(rules)) => rules
+                            .into_iter()
+                            .map(|rule| match rule {
+                                Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+                                    Ok([Value::String(pattern), Value::String(value)]) => Ok((pattern, value)),
+                                    Ok([Value::String(_), other]) | Ok([other, _]) => {
+                                        Err(invalid("static.cache_control", "pairs of strings", &other))
+                                    }
+                                    Err(pair) => Err(HyperportError::Config(format!(
+                                        "`static.cache_control` entries must be [pattern, value] pairs, found {} items",
+                                        pair.len()
+                                    ))),
+                                },
+                                other => Err(invalid("static.cache_control", "an array of [pattern, value] pairs", &other)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                        Some(other) => {
+                            return Err(invalid("static.cache_control", "an array of [pattern, value] pairs", &other))
+                        }
+                    };
+                    let precompressed = section.boolean("precompressed")?.unwrap_or(false);
+                    let directory_listing = section.boolean("directory_listing")?.unwrap_or(false);
+                    let spa_fallback = section.boolean("spa_fallback")?.unwrap_or(false);
+                    let host = section.string("host")?;
+                    let ip_filter = section.ip_filter()?;
+                    section.finish()?;
+                    config.static_mounts.push(StaticMount {
+                        mount,
+                        root,
+                        cache_control,
+                        precompressed,
+                        directory_listing,
+                        spa_fallback,
+                        host,
+                        ip_filter,
+                    });
+                }
+            }
+            Some(other) => return Err(invalid("static", "an array of tables ([[static]])", &other)),
+        }
+
+        match root.remove("proxy") {
+            None => {}
+            Some(Value::Array(tables)) => {
+                for table in tables {
+                    let Value::Table(table) = table else {
+                        return Err(invalid("proxy", "an array of tables", &table));
+                    };
+                    let mut section = Section::new("proxy", table);
+                    let mount = section.required_string("mount")?;
+                    let upstreams = match section.take("upstream") {
+                        None => return Err(HyperportError::Config("`proxy.upstream` is required".to_string())),
+                        Some(Value::String(addr)) => vec![(addr, 1)],
+                        Some(Value::Array(items)) if !items.is_empty() => items
+                            .into_iter()
+                            .map(|item| match item {
+                                Value::String(addr) => Ok((addr, 1)),
+                                Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+                                    Ok([Value::String(addr), Value::Integer(weight @ 1..=1000)]) => {
+                                        Ok((addr, weight as u32))
+                                    }
+                                    Ok([Value::String(_), other]) => {
+                                        Err(invalid("proxy.upstream", "weights from 1 to 1000", &other))
+                                    }
+                                    Ok([other, _]) => Err(invalid("proxy.upstream", "address strings", &other)),
+                                    Err(pair) => Err(HyperportError::Config(format!(
+                                        "`proxy.upstream` entries must be [address, weight], found {} items",
+                                        pair.len()
+                                    ))),
+                                },
+                                other => Err(invalid("proxy.upstream", "an address or [address, weight]", &other)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                        Some(other) => return Err(invalid("proxy.upstream", "a string or non-empty array", &other)),
+                    };
+                    let balancer = match section.string("balancer")?.as_deref() {
+                        None | Some("round_robin") => BalancerKind::RoundRobin,
+                        Some("weighted") => BalancerKind::Weighted,
+                        Some("least_connections") => BalancerKind::LeastConnections,
+                        Some("power_of_two_choices") => BalancerKind::PowerOfTwoChoices,
+                        Some(other) => {
+                            return Err(HyperportError::Config(format!(
+                                "`proxy.balancer` must be \"round_robin\", \"weighted\", \"least_connections\" \
+                                 or \"power_of_two_choices\", found \"{}\"",
+                                other
+                            )))
+                        }
+                    };
+                    let health_check = match section.string("health_check")? {
+                        None => None,
+                        Some(probe) if probe == "tcp" => Some(HealthCheck::tcp()),
+                        Some(path) if path.starts_with('/') => Some(HealthCheck::http(&path)),
+                        Some(other) => {
+                            return Err(HyperportError::Config(format!(
+                                "`proxy.health_check` must be \"tcp\" or a path, found \"{}\"",
+                                other
+                            )))
+                        }
+                    };
+                    let interval = section.count("health_interval")?.map(|secs| Duration::from_secs(secs as u64));
+                    let timeout = section.count("health_timeout")?.map(|secs| Duration::from_secs(secs as u64));
+                    let healthy_threshold = section.count("healthy_threshold")?;
+                    let unhealthy_threshold = section.count("unhealthy_threshold")?;
+                    let health_check = health_check.map(|mut check| {
+                        if let Some(interval) = interval {
+                            check = check.interval(interval.max(Duration::from_secs(1)));
+                        }
+                        if let Some(timeout) = timeout {
+                            check = check.timeout(timeout.max(Duration::from_secs(1)));
+                        }
+                        if let Some(count) = healthy_threshold {
+                            check = check.healthy_threshold(count as u32);
+                        }
+                        if let Some(count) = unhealthy_threshold {
+                            check = check.unhealthy_threshold(count as u32);
+                        }
+                        check
+                    });
+                    let enabled = section.boolean("circuit_breaker")?.unwrap_or(false);
+                    let mut breaker = CircuitBreaker::new();
+                    if let Some(percent) = section.count("circuit_failure_percent")? {
+                        match percent {
+                            1..=100 => breaker = breaker.failure_percent(percent as u32),
+                            _ => {
+                                return Err(HyperportError::Config(format!(
+                                    "`proxy.circuit_failure_percent` must be from 1 to 100, found {}",
+                                    percent
+                                )))
+                            }
+                        }
+                    }
+                    if let Some(count) = section.count("circuit_min_requests")? {
+                        breaker = breaker.min_requests(count.min(u32::MAX as usize) as u32);
+                    }
+                    if let Some(secs) = section.count("circuit_window")? {
+                        breaker = breaker.window(Duration::from_secs(secs as u64));
+                    }
+                    if let Some(secs) = section.count("circuit_open_for")? {
+                        breaker = breaker.open_for(Duration::from_secs(secs as u64));
+                    }
+                    let proxy_protocol = proxy_protocol_version("proxy", section.string("proxy_protocol")?)?;
+                    let route = ProxyRoute {
+                        mount,
+                        upstreams,
+                        balancer,
+                        health_check,
+                        retries: section.count("retries")?.map(|count| count.min(u32::MAX as usize) as u32),
+                        retry_backoff: section.count("retry_backoff_ms")?.map(|ms| Duration::from_millis(ms as u64)),
+                        max_retry_backoff: section
+                            .count("max_retry_backoff_ms")?
+                            .map(|ms| Duration::from_millis(ms as u64)),
+                        circuit_breaker: enabled.then_some(breaker),
+                        host: section.string("host")?,
+                        max_idle: section.count("max_idle")?,
+                        idle_timeout: section.count("idle_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                        max_lifetime: section.count("max_lifetime")?.map(|secs| Duration::from_secs(secs as u64)),
+                        dns_ttl: section.count("dns_ttl")?.map(|secs| Duration::from_secs(secs as u64)),
+                        proxy_protocol,
+                        rate_limit: section.positive("rate_limit")?,
+                        ip_filter: section.ip_filter()?,
+                    };
+                    section.finish()?;
+                    config.proxies.push(route);
+                }
+            }
+            Some(other) => return Err(invalid("proxy", "an array of tables ([[proxy]])", &other)),
+        }
+
+        match root.remove("ip_filter") {
+            None => {}
+            Some(Value::Array(tables)) => {
+                for table in tables {
+                    let Value::Table(table) = table else {
+                        return Err(invalid("ip_filter", "an array of tables", &table));
+                    };
+                    let mut section = Section::new("ip_filter", table);
+                    let listen = section.string("listen")?;
+                    let filter = section.ip_filter()?.unwrap_or_default();
+                    section.finish()?;
+                    match listen {
+                        Some(listen) if listen.parse::<SocketAddr>().is_err() => {
+                            return Err(HyperportError::Config(format!(
+                                "`ip_filter.listen` must be a socket address such as \"0.0.0.0:8080\", found \"{}\"",
+                                listen
+                            )));
+                        }
+                        Some(listen) => config.listener_ip_filters.push((listen, filter)),
+                        None if config.ip_filter.is_some() => {
+                            return Err(HyperportError::Config(
+                                "only one `[[ip_filter]]` may leave out `listen`".to_string(),
+                            ));
+                        }
+                        None => config.ip_filter = Some(filter),
+                    }
+                }
+            }
+            Some(other) => return Err(invalid("ip_filter", "an array of tables ([[ip_filter]])", &other)),
+        }
+
+        match root.remove("forward") {
+            None => {}
+            Some(Value::Array(tables)) => {
+                for table in tables {
+                    let Value::Table(table) = table else {
+                        return Err(invalid("forward", "an array of tables", &table));
+                    };
+                    let mut section = Section::new("forward", table);
+                    let listen = section.required_string("listen")?;
+                    if listen.parse::<SocketAddr>().is_err() {
+                        return Err(HyperportError::Config(format!(
+                            "`forward.listen` must be a socket address such as \"0.0.0.0:2222\", found \"{}\"",
+                            listen
+                        )));
+                    }
+                    let protocol = match section.string("protocol")?.as_deref() {
+                        None | Some("tcp") => Protocol::Tcp,
+                        Some("udp") => Protocol::Udp,
+                        Some(other) => {
+                            return Err(HyperportError::Config(format!(
+                                "`forward.protocol` must be \"tcp\" or \"udp\", found \"{}\"",
+                                other
+                            )))
+                        }
+                    };
+                    let forward = ForwardConfig {
+                        protocol,
+                        listen,
+                        target: section.required_string("target")?,
+                        connect_timeout: section
+                            .count("connect_timeout")?
+                            .map(|secs| Duration::from_secs(secs as u64)),
+                        idle_timeout: section.count("idle_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                        proxy_protocol: proxy_protocol_version("forward", section.string("proxy_protocol")?)?,
+                        max_sessions: section.count("max_sessions")?,
+                    };
+                    if forward.protocol == Protocol::Udp && forward.proxy_protocol.is_some() {
+                        return Err(HyperportError::Config(
+                            "`forward.proxy_protocol` only applies to TCP forwarding".to_string(),
+                        ));
+                    }
+                    section.finish()?;
+                    config.forwards.push(forward);
+                }
+            }
+            Some(other) => return Err(invalid("forward", "an array of tables ([[forward]])", &other)),
+        }
+
+        if let Some(compression) = take_table(&mut root, "compression")? {
+            let mut section = Section::new("compression", compression);
+            let level = section.level("level")?;
+            let mut settings = CompressionConfig {
+                gzip_level: section.level("gzip_level")?.or(level),
+                deflate_level: section.level("deflate_level")?.or(level),
+                min_size: section.count("min_size")?.map(|bytes| bytes as u64),
+                types: None,
+            };
+            match section.take("types") {
+                None => {}
+                Some(Value::Array(items)) => {
+                    settings.types = Some(
+                        items
+                            .into_iter()
+                            .map(|item| match item {
+                                Value::String(media_type) => Ok(media_type),
+                                other => Err(invalid("compression.types", "a string", &other)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                Some(other) => return Err(invalid("compression.types", "an array of strings", &other)),
+            }
+            section.finish()?;
+            config.compression = Some(settings);
+        }
+
+        if let Some(security_headers) = take_table(&mut root, "security_headers")? {
+            let mut section = Section::new("security_headers", security_headers);
+            config.security_headers = Some(SecurityHeadersConfig {
+                hsts: section.positive("hsts")?.map(|secs| Duration::from_secs(secs as u64)),
+                hsts_subdomains: section.boolean("hsts_subdomains")?.unwrap_or(false),
+                frame_options: section.string("frame_options")?,
+                referrer_policy: section.string("referrer_policy")?,
+                content_type_options: section.boolean("content_type_options")?.unwrap_or(true),
+                content_security_policy: section.string("content_security_policy")?,
+            });
+            section.finish()?;
+        }
+
+        if let Some(cors) = take_table(&mut root, "cors")? {
+            let mut section = Section::new("cors", cors);
+            config.cors = Some(CorsConfig {
+                origins: section.strings("origins")?,
+                methods: section.strings("methods")?,
+                headers: section.strings("headers")?,
+                expose_headers: section.strings("expose_headers")?,
+                credentials: section.boolean("credentials")?,
+                max_age: section.count("max_age")?.map(|secs| Duration::from_secs(secs as u64)),
+            });
+            section.finish()?;
+        }
+
+        if let Some(rate_limit) = take_table(&mut root, "rate_limit")? {
+            let mut section = Section::new("rate_limit", rate_limit);
+            let Some(requests) = section.positive("requests")? else {
+                return Err(HyperportError::Config("`rate_limit.requests` is required".to_string()));
+            };
+            config.rate_limit = Some(RateLimitConfig {
+                requests,
+                period: Duration::from_secs(section.positive("period")?.unwrap_or(1) as u64),
+                burst: section.positive("burst")?,
+                key: section.string("key")?,
+            });
+            section.finish()?;
+        }
+
+        if let Some(basic_auth) = take_table(&mut root, "basic_auth")? {
+            let mut section = Section::new("basic_auth", basic_auth);
+            let users = match section.take("users") {
+                None => Vec::new(),
+                Some(Value::Array(entries)) => entries
+                    .into_iter()
+                    .map(|entry| match entry {
+                        Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+                            Ok([Value::String(name), Value::String(password)]) if !name.contains(':') => {
+                                Ok((name, password))
+                            }
+                            _ => Err(HyperportError::Config(
+                                "`basic_auth.users` entries must be [name, password] strings, with no `:` in the name"
+                                    .to_string(),
+                            )),
+                        },
+                        other => Err(invalid("basic_auth.users", "an array of [name, password] pairs", &other)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(other) => return Err(invalid("basic_auth.users", "an array of [name, password] pairs", &other)),
+            };
+            let settings = BasicAuthConfig {
+                realm: section.string("realm")?.unwrap_or_else(|| "hyperport".to_string()),
+                users,
+                htpasswd: section.string("htpasswd")?.map(PathBuf::from),
+                paths: section.strings("paths")?,
+            };
+            section.finish()?;
+            if settings.users.is_empty() && settings.htpasswd.is_none() {
+                return Err(HyperportError::Config("`basic_auth` needs `users` or `htpasswd`".to_string()));
+            }
+            config.basic_auth = Some(settings);
+        }
+
+        if let Some(jwt) = take_table(&mut root, "jwt")? {
+            let mut section = Section::new("jwt", jwt);
+            let settings = JwtConfig {
+                secret: section.string("secret")?,
+                jwks_url: section.string("jwks_url")?,
+                jwks_file: section.string("jwks_file")?.map(PathBuf::from),
+                jwks_refresh: section.positive("jwks_refresh")?.map(|secs| Duration::from_secs(secs as u64)),
+                issuer: section.string("issuer")?,
+                audience: section.strings("audience")?,
+                leeway: section.count("leeway")?.map(|secs| Duration::from_secs(secs as u64)),
+                realm: section.string("realm")?,
+                paths: section.strings("paths")?,
+            };
+            section.finish()?;
+            if settings.secret.is_none() && settings.jwks_url.is_none() && settings.jwks_file.is_none() {
+                return Err(HyperportError::Config("`jwt` needs `secret`, `jwks_url` or `jwks_file`".to_string()));
+            }
+            if let Some(url) = settings.jwks_url.as_deref().filter(|url| !is_loopback_http(url)) {
+                return Err(HyperportError::Config(format!(
+                    "`jwt.jwks_url` must be an http:// URL on a loopback address, found {}; \
+                     use `jwt.jwks_file` for key sets from elsewhere",
+                    url
+                )));
+            }
+            config.jwt = Some(settings);
+        }
+
+        if let Some(connect) = take_table(&mut root, "connect")? {
+            let mut section = Section::new("connect", connect);
+            let mut settings = ConnectConfig {
+                connect_timeout: section.count("connect_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                idle_timeout: section.count("idle_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                ..ConnectConfig::default()
+            };
+            match section.take("ports") {
+                None => {}
+                Some(Value::Array(items)) => {
+                    settings.ports = Some(
+                        items
+                            .into_iter()
+                            .map(|item| match item {
+                                Value::Integer(port @ 1..=65535) => Ok(port as u16),
+                                other => Err(invalid("connect.ports", "a port number", &other)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                Some(other) => return Err(invalid("connect.ports", "an array of port numbers", &other)),
+            }
+            match section.take("hosts") {
+                None => {}
+                Some(Value::Array(items)) => {
+                    settings.hosts = Some(
+                        items
+                            .into_iter()
+                            .map(|item| match item {
+                                Value::String(host) => Ok(host),
+                                other => Err(invalid("connect.hosts", "a string", &other)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                Some(other) => return Err(invalid("connect.hosts", "an array of strings", &other)),
+            }
+            section.finish()?;
+            config.connect = Some(settings);
+        }
+
+        if let Some(socks) = take_table(&mut root, "socks")? {
+            let mut section = Section::new("socks", socks);
+            let listen = section.required_string("listen")?;
+            if listen.parse::<SocketAddr>().is_err() {
+                return Err(HyperportError::Config(format!(
+                    "`socks.listen` must be a socket address such as \"127.0.0.1:1080\", found \"{}\"",
+                    listen
+                )));
+            }
+            let users = match section.take("users") {
+                None => Vec::new(),
+                Some(Value::Array(entries)) => entries
+                    .into_iter()
+                    .map(|entry| match entry {
+                        Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+                            Ok([Value::String(username), Value::String(password)])
+                                if (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()) =>
+                            {
+                                Ok((username, password))
+                            }
+                            Ok(_) => Err(HyperportError::Config(
+                                "`socks.users` entries must be [username, password] strings of 1 to 255 bytes"
+                                    .to_string(),
+                            )),
+                            Err(pair) => Err(HyperportError::Config(format!(
+                                "`socks.users` entries must be [username, password], found {} items",
+                                pair.len()
+                            ))),
+                        },
+                        other => Err(invalid("socks.users", "an array of [username, password] entries", &other)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(other) => return Err(invalid("socks.users", "an array of [username, password] entries", &other)),
+            };
+            let settings = SocksConfig {
+                listen,
+                users,
+                handshake_timeout: section.count("handshake_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                connect_timeout: section.count("connect_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+                idle_timeout: section.count("idle_timeout")?.map(|secs| Duration::from_secs(secs as u64)),
+            };
+            section.finish()?;
+            config.socks = Some(settings);
+        }
+
+        if let Some(tracing) = take_table(&mut root, "tracing")? {
+            let mut section = Section::new("tracing", tracing);
+            let endpoint = section.required_string("endpoint")?;
+            if !endpoint.starts_with("http://") {
+                return Err(HyperportError::Config(format!(
+                    "`tracing.endpoint` must be an http:// URL, found \"{}\"",
+                    endpoint
+                )));
+            }
+            let settings = TracingConfig {
+                endpoint,
+                service_name: section.string("service_name")?,
+            };
+            section.finish()?;
+            config.tracing = Some(settings);
+        }
+
+        if let Some(metrics) = take_table(&mut root, "metrics")? {
+            let mut section = Section::new("metrics", metrics);
+            let path = section.string("path")?.unwrap_or_else(|| "/metrics".to_string());
+            if !path.starts_with('/') {
+                return Err(HyperportError::Config(format!("`metrics.path` must be a path, found \"{}\"", path)));
+            }
+            let settings = MetricsConfig {
+                path,
+                ip_filter: section.ip_filter()?,
+            };
+            section.finish()?;
+            config.metrics = Some(settings);
+        }
+
+        if let Some(pages) = take_table(&mut root, "error_pages")? {
+            for (key, value) in pages {
+                let status = key
+                    .parse()
+                    .ok()
+                    .and_then(StatusCode::from_u16)
+                    .filter(|status| status.is_client_error() || status.is_server_error())
+                    .ok_or_else(|| {
+                        HyperportError::Config(format!(
+                            "`error_pages` keys must be 4xx or 5xx status codes, found `{}`",
+                            key
+                        ))
+                    })?;
+                match value {
+                    Value::String(path) => config.error_pages.push((status, PathBuf::from(path))),
+                    other => return Err(invalid(&format!("error_pages.{}", key), "a file path", &other)),
+                }
+            }
+        }
+
+        if let Some(log) = take_table(&mut root, "log")? {
+            let mut section = Section::new("log", log);
+            match section.take("access_log") {
+                None => {}
+                Some(Value::String(target)) => {
+                    config.log.access_log = match target.as_str() {
+                        "stdout" => AccessLogTarget::Stdout,
+                        "off" => AccessLogTarget::Off,
+                        _ => AccessLogTarget::File(target.into()),
+                    }
+                }
+                Some(other) => return Err(invalid("log.access_log", "a string", &other)),
+            }
+            match section.take("format") {
+                None => {}
+                Some(Value::String(format)) => {
+                    config.log.format = match format.as_str() {
+                        "common" => LogFormat::Common,
+                        "json" => LogFormat::Json,
+                        _ => {
+                            return Err(HyperportError::Config(format!(
+                                "`log.format` must be \"common\" or \"json\", found \"{}\"",
+                                format
+                            )))
+                        }
+                    }
+                }
+                Some(other) => return Err(invalid("log.format", "a string", &other)),
+            }
+            if let Some(level) = section.string("level")? {
+                let filter = level.parse().map_err(|e| HyperportError::Config(format!("`log.level`: {}", e)))?;
+                config.log.level = Some(filter);
+            }
+            config.log.output = match section.string("output")?.as_deref() {
+                None | Some("stderr") => LogOutput::Stderr,
+                Some("stdout") => LogOutput::Stdout,
+                Some(path) => LogOutput::File(path.into()),
+            };
+            section.finish()?;
+        }
+
+        // Better not to start than to serve plain HTTP to clients
+        // configured for HTTPS.
+        if root.contains_key("tls") {
+            return Err(HyperportError::Config(
+                "TLS is not supported; terminate it in front of hyperport and remove the [tls] section".to_string(),
+            ));
+        }
+
+        if let Some(key) = root.keys().next() {
+            return Err(HyperportError::Config(format!("unknown key `{}`", key)));
+        }
+        Ok(config)
+    }
+
+    /// Applies the listen addresses, sizing, timeouts and user to `builder`.
+    /// Static mounts, compression and logging settings are left to the
+    /// caller.
+    pub fn apply(&self, mut builder: ServerBuilder) -> ServerBuilder {
+        for addr in &self.listen {
+            builder = builder.bind(addr);
+        }
+        if let Some(workers) = self.workers {
+            builder = builder.workers(workers);
+        }
+        if let Some(pool_size) = self.pool_size {
+            builder = builder.pool_size(pool_size);
+        }
+        if let Some(queue_depth) = self.queue_depth {
+            builder = builder.queue_depth(queue_depth);
+        }
+        if let Some(max) = self.max_connections {
+            builder = builder.max_connections(max);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.header_timeout {
+            builder = builder.header_timeout(timeout);
+        }
+        if let Some(timeout) = self.keep_alive_timeout {
+            builder = builder.keep_alive_timeout(timeout);
+        }
+        if let Some(timeout) = self.write_timeout {
+            builder = builder.write_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            builder = builder.request_timeout(timeout);
+        }
+        if let Some(threshold) = self.slow_request_threshold {
+            builder = builder.slow_request_threshold(threshold);
+        }
+        if let Some(limits) = self.head_limits {
+            builder = builder.head_limits(limits);
+        }
+        if let Some(max) = self.max_body_size {
+            builder = builder.max_body_size(max);
+        }
+        if let Some(threshold) = self.spool_threshold {
+            let dir = self.spool_dir.clone().unwrap_or_else(std::env::temp_dir);
+            builder = builder.spool_bodies(threshold, dir);
+        }
+        if let Some(user) = &self.user {
+            builder = builder.user(user);
+        }
+        if let Some(group) = &self.group {
+            builder = builder.group(group);
+        }
+        for addr in &self.proxy_protocol {
+            builder = builder.proxy_protocol(addr);
+        }
+        if let Some(filter) = &self.ip_filter {
+            builder = builder.ip_filter(filter.clone());
+        }
+        for (addr, filter) in &self.listener_ip_filters {
+            builder = builder.listener_ip_filter(addr, filter.clone());
+        }
+        if let Some(enabled) = self.http2 {
+            builder = builder.http2(enabled);
+        }
+        match &self.server_header {
+            Some(Some(value)) => builder = builder.server_header(value),
+            Some(None) => builder = builder.hide_server_header(),
+            None => {}
+        }
+        if let Some(tracing) = &self.tracing {
+            builder = builder.otlp_endpoint(&tracing.endpoint);
+            if let Some(name) = &tracing.service_name {
+                builder = builder.service_name(name);
+            }
+        }
+        builder
+    }
+}
+
+/// The PROXY protocol version named by `proxy_protocol` in `section`.
+fn proxy_protocol_version(
+    section: &str,
+    value: Option<String>,
+) -> Result<Option<proxy_protocol::Version>, HyperportError> {
+    match value.as_deref() {
+        None => Ok(None),
+        Some("v1") => Ok(Some(proxy_protocol::Version::V1)),
+        Some("v2") => Ok(Some(proxy_protocol::Version::V2)),
+        Some(other) => Err(HyperportError::Config(format!(
+            "`{}.proxy_protocol` must be \"v1\" or \"v2\", found \"{}\"",
+            section, other
+        ))),
+    }
+}
+
+fn take_table(root: &mut Table, name: &str) -> Result<Option<Table>, HyperportError> {
+    match root.remove(name) {
+        None => Ok(None),
+        Some(Value::Table(table)) => Ok(Some(table)),
+        Some(other) => Err(invalid(name, "a table", &other)),
+    }
+}
+
+fn invalid(key: &str, expected: &str, found: &Value) -> HyperportError {
+    HyperportError::Config(format!("`{}` must be {}, found {}", key, expected, found.type_name()))
+}
+
+/// A table whose keys are consumed as they are read, so leftovers can be
+/// reported as unknown.
+struct Section {
+    name: &'static str,
+    table: Table,
+}
+
+impl Section {
+    fn new(name: &'static str, table: Table) -> Self {
+        Section { name, table }
+    }
+
+    fn take(&mut self, key: &str) -> Option<Value> {
+        self.table.remove(key)
+    }
+
+    fn count(&mut self, key: &str) -> Result<Option<usize>, HyperportError> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if n >= 0 => Ok(Some(n as usize)),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "a non-negative integer", &other)),
+        }
+    }
+
+    /// A compression level, from 1 to 9.
+    fn level(&mut self, key: &str) -> Result<Option<u32>, HyperportError> {
+        match self.count(key)? {
+            None => Ok(None),
+            Some(level @ 1..=9) => Ok(Some(level as u32)),
+            Some(level) => Err(HyperportError::Config(format!(
+                "`{}.{}` must be between 1 and 9, found {}",
+                self.name, key, level
+            ))),
+        }
+    }
+
+    /// A count of at least one, such as a rate.
+    fn positive(&mut self, key: &str) -> Result<Option<u32>, HyperportError> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if (1..=u32::MAX as i64).contains(&n) => Ok(Some(n as u32)),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "a positive integer", &other)),
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, HyperportError> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "a string", &other)),
+        }
+    }
+
+    fn boolean(&mut self, key: &str) -> Result<Option<bool>, HyperportError> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(b)),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "a boolean", &other)),
+        }
+    }
+
+    fn strings(&mut self, key: &str) -> Result<Option<Vec<String>>, HyperportError> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Array(items)) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s),
+                    other => Err(invalid(&format!("{}.{}", self.name, key), "a string", &other)),
+                })
+                .collect::<Result<_, _>>()
+                .map(Some),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "an array of strings", &other)),
+        }
+    }
+
+    /// An [`IpFilter`] from the `allow` and `deny` lists, when either is
+    /// given.
+    fn ip_filter(&mut self) -> Result<Option<IpFilter>, HyperportError> {
+        let allow = self.strings("allow")?;
+        let deny = self.strings("deny")?;
+        if allow.is_none() && deny.is_none() {
+            return Ok(None);
+        }
+        let filter = IpFilter::new(&allow.unwrap_or_default(), &deny.unwrap_or_default()).map_err(|e| match e {
+            HyperportError::InvalidAddress(network) => HyperportError::Config(format!(
+                "`{0}.allow` and `{0}.deny` entries must be addresses or networks, found \"{1}\"",
+                self.name, network
+            )),
+            e => e,
+        })?;
+        Ok(Some(filter))
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String, HyperportError> {
+        match self.take(key) {
+            Some(Value::String(s)) => Ok(s),
+            Some(other) => Err(invalid(&format!("{}.{}", self.name, key), "a string", &other)),
+            None => Err(HyperportError::Config(format!("`{}.{}` is required", self.name, key))),
+        }
+    }
+
+    fn finish(self) -> Result<(), HyperportError> {
+        match self.table.keys().next() {
+            Some(key) => Err(HyperportError::Config(format!("unknown key `{}.{}`", self.name, key))),
+            None => Ok(()),
+        }
+    }
+}
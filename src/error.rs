@@ -0,0 +1,110 @@
+//! The crate-wide error type.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::http::chunked::ChunkedError;
+use crate::http::request::{BodyError, ParseError};
+use crate::http::status::StatusCode;
+
+/// Why loading configuration, binding, accepting or reading a request
+/// failed.
+#[derive(Debug)]
+pub enum HyperportError {
+    /// A socket or file operation failed.
+    Io(io::Error),
+    /// A listen address is not a literal `ip:port`.
+    InvalidAddress(String),
+    /// A configuration file is malformed or has invalid settings.
+    Config(String),
+    /// The request head is malformed.
+    Parse(ParseError),
+    /// The request body's framing is missing or invalid.
+    Body(BodyError),
+    /// A chunked request body is malformed. Size violations are reported
+    /// as `TooLarge` instead.
+    Chunked(ChunkedError),
+    /// The peer stalled for longer than the read timeout.
+    Timeout,
+    /// The request exceeds a size limit.
+    TooLarge,
+}
+
+impl HyperportError {
+    /// The status to answer the client with, for errors caused by what the
+    /// client sent. `None` means the connection should just be closed.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            HyperportError::Parse(ParseError::RequestLineTooLong) => Some(StatusCode::UriTooLong),
+            HyperportError::Parse(ParseError::HeaderTooLarge | ParseError::HeadTooLarge | ParseError::TooManyHeaders) => {
+                Some(StatusCode::RequestHeaderFieldsTooLarge)
+            }
+            HyperportError::Parse(ParseError::ExpectationFailed) => Some(StatusCode::ExpectationFailed),
+            HyperportError::Parse(_) | HyperportError::Chunked(_) => Some(StatusCode::BadRequest),
+            HyperportError::Body(BodyError::InvalidLength | BodyError::InvalidContentCoding) => {
+                Some(StatusCode::BadRequest)
+            }
+            HyperportError::Body(BodyError::LengthRequired) => Some(StatusCode::LengthRequired),
+            HyperportError::Body(BodyError::UnsupportedTransferCoding) => Some(StatusCode::NotImplemented),
+            HyperportError::Body(BodyError::UnsupportedContentCoding) => Some(StatusCode::UnsupportedMediaType),
+            HyperportError::Timeout => Some(StatusCode::RequestTimeout),
+            HyperportError::TooLarge => Some(StatusCode::PayloadTooLarge),
+            HyperportError::Io(_) | HyperportError::InvalidAddress(_) | HyperportError::Config(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HyperportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HyperportError::Io(e) => write!(f, "{}", e),
+            HyperportError::InvalidAddress(addr) => write!(f, "invalid listen address: {}", addr),
+            HyperportError::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            HyperportError::Parse(e) => write!(f, "{}", e),
+            HyperportError::Body(e) => write!(f, "{}", e),
+            HyperportError::Chunked(e) => write!(f, "{}", e),
+            HyperportError::Timeout => f.write_str("timed out waiting for the client"),
+            HyperportError::TooLarge => f.write_str("request exceeds size limit"),
+        }
+    }
+}
+
+impl Error for HyperportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HyperportError::Io(e) => Some(e),
+            HyperportError::Parse(e) => Some(e),
+            HyperportError::Body(e) => Some(e),
+            HyperportError::Chunked(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HyperportError {
+    fn from(e: io::Error) -> Self {
+        HyperportError::Io(e)
+    }
+}
+
+impl From<ParseError> for HyperportError {
+    fn from(e: ParseError) -> Self {
+        HyperportError::Parse(e)
+    }
+}
+
+impl From<BodyError> for HyperportError {
+    fn from(e: BodyError) -> Self {
+        HyperportError::Body(e)
+    }
+}
+
+impl From<ChunkedError> for HyperportError {
+    fn from(e: ChunkedError) -> Self {
+        match e {
+            ChunkedError::ChunkTooLarge | ChunkedError::BodyTooLarge => HyperportError::TooLarge,
+            ChunkedError::Malformed => HyperportError::Chunked(e),
+        }
+    }
+}
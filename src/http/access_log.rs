@@ -0,0 +1,161 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use crate::error;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::util::json;
+use crate::util::time::rfc3339;
+
+/// Lines that may wait for the writer thread. Past this, new lines are
+/// dropped rather than making requests wait on log I/O.
+const QUEUE_DEPTH: usize = 4096;
+
+/// How each access log line is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable, close to the Common Log Format:
+    /// `127.0.0.1 - alice [2024-03-01T12:00:00.000Z] "GET / HTTP/1.1" 200 118 0.041ms`,
+    /// with `-` for the user unless one logged in, followed by the request
+    /// ID when there is one.
+    Common,
+    /// One JSON object per line with the fields `time`, `client`, `user`,
+    /// `method`, `target`, `version`, `status`, `bytes`, `duration_ms` and
+    /// `request_id`. `client`, `user`, `status` and `request_id` are `null`
+    /// when unknown. Field names are stable.
+    Json,
+}
+
+/// Middleware that records one line per request: client address, method,
+/// target, status, body bytes, handling time and, behind
+/// [`RequestId`](crate::http::RequestId) and
+/// [`BasicAuth`](crate::http::BasicAuth) or [`JwtAuth`](crate::http::JwtAuth),
+/// the request's ID and user.
+///
+/// Formatting happens on the request thread but the write itself is done by
+/// a dedicated background thread, so a slow disk or terminal never stalls
+/// request handling. Register it first with `Router::wrap` so the time
+/// reported covers the rest of the middleware chain.
+pub struct AccessLog {
+    tx: SyncSender<String>,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    /// Logs to standard output.
+    pub fn stdout() -> Self {
+        AccessLog::with_writer(Box::new(io::stdout()))
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::with_writer(Box::new(file)))
+    }
+
+    fn with_writer(out: Box<dyn Write + Send>) -> Self {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(rx, out))
+            .expect("failed to spawn access log thread");
+        AccessLog {
+            tx,
+            format: LogFormat::Common,
+        }
+    }
+
+    /// Selects the line format. Defaults to [`LogFormat::Common`].
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn record(&self, request: &Request, writer: &ResponseWriter, started: Instant) -> String {
+        let time = rfc3339(SystemTime::now());
+        let client = request.client_ip.map(|ip| ip.to_string());
+        let status = writer.status().map(|status| status.as_u16());
+        let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        match self.format {
+            LogFormat::Common => format!(
+                "{} - {} [{}] \"{} {} {}\" {} {} {:.3}ms{}\n",
+                client.as_deref().unwrap_or("-"),
+                request.user.as_deref().unwrap_or("-"),
+                time,
+                request.method,
+                request.target,
+                request.version,
+                status.map_or("-".to_string(), |status| status.to_string()),
+                writer.body_bytes(),
+                duration_ms,
+                request.request_id.as_deref().map_or(String::new(), |id| format!(" {}", id))
+            ),
+            LogFormat::Json => {
+                let mut line = String::from("{\"time\":");
+                json::write_string(&mut line, &time);
+                line.push_str(",\"client\":");
+                match &client {
+                    Some(client) => json::write_string(&mut line, client),
+                    None => line.push_str("null"),
+                }
+                line.push_str(",\"user\":");
+                match &request.user {
+                    Some(user) => json::write_string(&mut line, user),
+                    None => line.push_str("null"),
+                }
+                line.push_str(",\"method\":");
+                json::write_string(&mut line, &request.method);
+                line.push_str(",\"target\":");
+                json::write_string(&mut line, &request.target);
+                line.push_str(",\"version\":");
+                json::write_string(&mut line, request.version.as_str());
+                line.push_str(&format!(
+                    ",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"request_id\":",
+                    status.map_or("null".to_string(), |status| status.to_string()),
+                    writer.body_bytes(),
+                    duration_ms
+                ));
+                match &request.request_id {
+                    Some(id) => json::write_string(&mut line, id),
+                    None => line.push_str("null"),
+                }
+                line.push_str("}\n");
+                line
+            }
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        let started = Instant::now();
+        let result = next.run(request, writer);
+
+        match self.tx.try_send(self.record(request, writer, started)) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => error!("Access log writer has stopped"),
+        }
+        result
+    }
+}
+
+/// Writer thread body. Flushes whenever the queue runs dry so lines appear
+/// promptly without a syscall per line under load.
+fn write_lines(rx: Receiver<String>, out: Box<dyn Write + Send>) {
+    let mut out = BufWriter::new(out);
+    while let Ok(line) = rx.recv() {
+        let mut result = out.write_all(line.as_bytes());
+        while let Ok(line) = rx.try_recv() {
+            result = result.and_then(|_| out.write_all(line.as_bytes()));
+        }
+        if let Err(e) = result.and_then(|_| out.flush()) {
+            error!("Error writing access log: {}", e);
+        }
+    }
+}
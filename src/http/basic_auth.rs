@@ -0,0 +1,168 @@
+//! HTTP Basic authentication (RFC 7617): a user name and password with
+//! every request, checked against a list of users.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::util::{base64, bcrypt};
+
+/// Credentials remembered once checked against a bcrypt hash. Past this,
+/// the memory is cleared and filled again.
+const MAX_VERIFIED: usize = 1024;
+
+#[derive(Clone, Debug)]
+enum Password {
+    Plain(String),
+    Bcrypt(String),
+}
+
+/// Middleware requiring a user name and password, sent with HTTP Basic
+/// authentication, from one of its users.
+///
+/// Users are added with a password in [`user`](BasicAuth::user), or loaded
+/// with bcrypt hashes from a file in the htpasswd format with
+/// [`htpasswd`](BasicAuth::htpasswd). Requests without valid credentials
+/// get a 401 with a `WWW-Authenticate` challenge naming the realm, which
+/// has browsers ask for them; the others go on with the user in
+/// [`Request::user`], and the [`AccessLog`](crate::http::AccessLog)
+/// records it. Only requests under [`paths`](BasicAuth::paths) are
+/// checked, all of them by default.
+///
+/// CORS preflights carry no credentials, so wrap
+/// [`Cors`](crate::http::Cors) first to answer them. Basic credentials are
+/// merely encoded, so serve them over TLS. bcrypt is slow by design, and
+/// clients send their credentials again with every request, so those that
+/// passed are remembered rather than hashed again.
+///
+/// ```ignore
+/// router.wrap(BasicAuth::new("Admin").htpasswd("/etc/hyperport/htpasswd")?.paths(&["/admin"]));
+/// ```
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, Password>,
+    /// Path prefixes, or empty for every path.
+    paths: Vec<String>,
+    /// `Authorization` values that matched a bcrypt hash, with their user.
+    verified: Mutex<HashMap<String, String>>,
+}
+
+impl BasicAuth {
+    /// No users yet, challenging clients to log in to `realm`, which
+    /// browsers show when asking.
+    pub fn new(realm: &str) -> Self {
+        BasicAuth {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+            paths: Vec::new(),
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a user with a plain-text password. Adding a user again
+    /// replaces its password.
+    pub fn user(mut self, name: &str, password: &str) -> Self {
+        self.users.insert(name.to_string(), Password::Plain(password.to_string()));
+        self
+    }
+
+    /// Adds the users of an htpasswd file, one `name:hash` line each, as
+    /// `htpasswd -B` writes them. Blank lines and `#` comments are
+    /// skipped. Only bcrypt hashes (`$2y$`, `$2b$` or `$2a$`) are
+    /// understood; a line with another is an error.
+    pub fn htpasswd<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, what),
+                )
+            };
+            let (name, hash) = line.split_once(':').ok_or_else(|| invalid("expected name:hash"))?;
+            if !["$2y$", "$2b$", "$2a$"].iter().any(|prefix| hash.starts_with(prefix)) {
+                return Err(invalid("only bcrypt hashes are supported; create them with `htpasswd -B`"));
+            }
+            self.users.insert(name.to_string(), Password::Bcrypt(hash.to_string()));
+        }
+        Ok(self)
+    }
+
+    /// Checks only requests whose paths start with one of `prefixes`,
+    /// taken as whole segments: `/admin` covers `/admin` and
+    /// `/admin/users` but not `/administrator`.
+    pub fn paths<S: AsRef<str>>(mut self, prefixes: &[S]) -> Self {
+        self.paths = prefixes.iter().map(|prefix| prefix.as_ref().trim_end_matches('/').to_string()).collect();
+        self
+    }
+
+    /// The user whose valid credentials `request` carries, if any.
+    pub fn authenticate(&self, request: &Request) -> Option<String> {
+        let value = request.headers.get("authorization")?.trim();
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        if let Some(user) = self.verified.lock().unwrap_or_else(|e| e.into_inner()).get(value) {
+            return Some(user.clone());
+        }
+
+        let decoded = String::from_utf8(base64::decode(encoded.trim())?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        match self.users.get(name)? {
+            Password::Plain(expected) => {
+                bcrypt::constant_time_eq(password.as_bytes(), expected.as_bytes()).then(|| name.to_string())
+            }
+            Password::Bcrypt(hash) => {
+                if !bcrypt::verify(password.as_bytes(), hash) {
+                    return None;
+                }
+                let mut verified = self.verified.lock().unwrap_or_else(|e| e.into_inner());
+                if verified.len() >= MAX_VERIFIED {
+                    verified.clear();
+                }
+                verified.insert(value.to_string(), name.to_string());
+                Some(name.to_string())
+            }
+        }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        if !under_prefixes(&self.paths, &request.path) {
+            return next.run(request, writer);
+        }
+        match self.authenticate(request) {
+            Some(user) => {
+                request.user = Some(user);
+                next.run(request, writer)
+            }
+            None => {
+                let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+                writer.add_header("WWW-Authenticate", &format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm));
+                writer.send_error(StatusCode::Unauthorized)
+            }
+        }
+    }
+}
+
+/// Whether `path` is under one of `prefixes`, taken as whole segments, or
+/// `prefixes` is empty.
+pub(crate) fn under_prefixes(prefixes: &[String], path: &str) -> bool {
+    prefixes.is_empty()
+        || prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+}
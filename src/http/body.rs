@@ -0,0 +1,158 @@
+//! Request bodies on their way to a handler, shared by HTTP/1 and HTTP/2:
+//! collected in memory or spooled to an unnamed file once they pass the
+//! spool threshold, then decoded from their Content-Encoding.
+
+use std::fs::File;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::HyperportError;
+use crate::http::request::{BodyError, Request};
+use crate::util::inflate::{self, GzipDecoder, Inflate, ZlibDecoder};
+use crate::util::tempfile;
+
+/// A request body as received: in memory, or spooled to an unnamed file
+/// once it outgrew the spool threshold.
+pub(crate) enum Received {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl Received {
+    pub(crate) fn store(self, request: &mut Request) {
+        match self {
+            Received::Memory(body) => {
+                request.body = body;
+                request.body_file = None;
+            }
+            Received::File(file) => {
+                request.body = Vec::new();
+                request.body_file = Some(Arc::new(file));
+            }
+        }
+    }
+}
+
+/// Collects a body in memory, moving it to an unnamed file in `dir` once
+/// it passes `threshold`.
+pub(crate) struct Sink<'a> {
+    body: Vec<u8>,
+    file: Option<File>,
+    /// Bytes written so far.
+    len: usize,
+    threshold: Option<usize>,
+    dir: &'a Path,
+}
+
+impl<'a> Sink<'a> {
+    pub(crate) fn new(threshold: Option<usize>, dir: &'a Path) -> Self {
+        Sink {
+            body: Vec::new(),
+            file: None,
+            len: 0,
+            threshold,
+            dir,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.len += data.len();
+        if let Some(file) = &mut self.file {
+            return file.write_all(data);
+        }
+        self.body.extend_from_slice(data);
+        if self.threshold.is_some_and(|threshold| self.body.len() > threshold) {
+            let mut file = tempfile::anonymous(self.dir)?;
+            file.write_all(&self.body)?;
+            self.body = Vec::new();
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Received {
+        match self.file {
+            Some(file) => Received::File(file),
+            None => Received::Memory(self.body),
+        }
+    }
+}
+
+/// Undoes the request's Content-Encoding, so that handlers see the body as
+/// if it had been sent uncompressed; a body that is not encoded is left
+/// alone. Decoding stops as soon as the output outgrows `max_size`, so a
+/// small compressed body cannot expand into an unbounded one, and the
+/// output is spooled like a received body.
+pub(crate) fn decode(
+    request: &mut Request,
+    max_size: usize,
+    spool_threshold: Option<usize>,
+    spool_dir: &Path,
+) -> Result<(), HyperportError> {
+    let codings: Vec<String> = request
+        .headers
+        .get_all("content-encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect();
+    let coding = match codings.as_slice() {
+        [] => return Ok(()),
+        [coding] => coding.as_str(),
+        _ => return Err(BodyError::UnsupportedContentCoding.into()),
+    };
+
+    let mut source = request.body_reader();
+    let mut decoder: Box<dyn Read + '_> = match coding {
+        "gzip" | "x-gzip" => Box::new(GzipDecoder::new(source).map_err(corrupt)?),
+        "deflate" => {
+            // Meant to be zlib-wrapped, but some clients send raw DEFLATE
+            // data instead.
+            let mut header = [0u8; 2];
+            source.read_exact(&mut header).map_err(corrupt)?;
+            if inflate::is_zlib_header(header) {
+                Box::new(ZlibDecoder::new(header, source).map_err(corrupt)?)
+            } else {
+                Box::new(Inflate::new(Cursor::new(header).chain(source)))
+            }
+        }
+        _ => return Err(BodyError::UnsupportedContentCoding.into()),
+    };
+
+    let mut sink = Sink::new(spool_threshold, spool_dir);
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = decoder.read(&mut chunk).map_err(corrupt)?;
+        if n == 0 {
+            break;
+        }
+        if sink.len() + n > max_size {
+            return Err(HyperportError::TooLarge);
+        }
+        sink.write(&chunk[..n])?;
+    }
+    drop(decoder);
+
+    let len = sink.len();
+    sink.finish().store(request);
+    request.headers.remove("content-encoding");
+    if request.headers.get("content-length").is_some() {
+        request.headers.remove("content-length");
+        request.headers.append("Content-Length", &len.to_string());
+    }
+    Ok(())
+}
+
+/// Reports undecodable compressed data as the client's fault; anything
+/// else, such as failing to write a spool file, is the server's.
+fn corrupt(e: io::Error) -> HyperportError {
+    match e.kind() {
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => BodyError::InvalidContentCoding.into(),
+        _ => e.into(),
+    }
+}
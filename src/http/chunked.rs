@@ -0,0 +1,197 @@
+use std::fmt;
+
+use crate::http::request::{is_token_byte, Headers};
+
+/// Why a chunked body was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkedError {
+    /// The framing is not valid chunked encoding, or a trailer field is
+    /// malformed or one too many.
+    Malformed,
+    /// A single chunk declared a size above the decoder's limit.
+    ChunkTooLarge,
+    /// The decoded body grew past the decoder's limit.
+    BodyTooLarge,
+}
+
+impl fmt::Display for ChunkedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ChunkedError::Malformed => "malformed chunked encoding",
+            ChunkedError::ChunkTooLarge => "chunk exceeds size limit",
+            ChunkedError::BodyTooLarge => "chunked body exceeds size limit",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ChunkedError {}
+
+#[derive(Debug)]
+enum State {
+    /// Reading the hex chunk-size line (with optional extensions).
+    Size,
+    /// Copying chunk payload; the count is what remains of this chunk.
+    Data(usize),
+    /// Expecting the CRLF that terminates a chunk's payload.
+    DataEnd,
+    /// Reading trailer lines after the last chunk, until an empty line.
+    Trailer,
+    Done,
+}
+
+/// Incremental decoder for `Transfer-Encoding: chunked` bodies.
+///
+/// Bytes can be fed in arbitrarily sized pieces as they come off the socket;
+/// the decoder keeps whatever partial line it has seen between calls.
+pub struct ChunkedDecoder {
+    state: State,
+    line: Vec<u8>,
+    max_chunk_size: usize,
+    max_body_size: usize,
+    decoded: usize,
+    trailers: Headers,
+}
+
+/// Longest chunk-size or trailer line accepted, extensions included.
+const MAX_LINE: usize = 4096;
+
+/// Most trailer fields accepted after the last chunk.
+const MAX_TRAILERS: usize = 64;
+
+impl ChunkedDecoder {
+    pub fn new(max_chunk_size: usize, max_body_size: usize) -> Self {
+        ChunkedDecoder {
+            state: State::Size,
+            line: Vec::new(),
+            max_chunk_size,
+            max_body_size,
+            decoded: 0,
+            trailers: Headers::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// The trailer fields that followed the last chunk, once the decoder is
+    /// done; empty before then or when there were none.
+    pub fn take_trailers(&mut self) -> Headers {
+        std::mem::take(&mut self.trailers)
+    }
+
+    /// Decodes as much of `input` as possible, appending payload to `out`.
+    ///
+    /// Returns how many bytes of `input` were consumed. Once the terminating
+    /// chunk and trailers have been seen the decoder stops consuming, so any
+    /// remainder belongs to the next request on the connection.
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<usize, ChunkedError> {
+        let mut pos = 0;
+
+        while pos < input.len() {
+            match self.state {
+                State::Size => {
+                    let Some(line) = self.take_line(input, &mut pos)? else {
+                        break;
+                    };
+                    let size = parse_chunk_size(&line)?;
+                    if size > self.max_chunk_size {
+                        return Err(ChunkedError::ChunkTooLarge);
+                    }
+                    if self.decoded.saturating_add(size) > self.max_body_size {
+                        return Err(ChunkedError::BodyTooLarge);
+                    }
+                    self.state = if size == 0 { State::Trailer } else { State::Data(size) };
+                }
+                State::Data(remaining) => {
+                    let n = remaining.min(input.len() - pos);
+                    out.extend_from_slice(&input[pos..pos + n]);
+                    pos += n;
+                    self.decoded += n;
+                    self.state = if n == remaining { State::DataEnd } else { State::Data(remaining - n) };
+                }
+                State::DataEnd => {
+                    let Some(line) = self.take_line(input, &mut pos)? else {
+                        break;
+                    };
+                    if !line.is_empty() {
+                        return Err(ChunkedError::Malformed);
+                    }
+                    self.state = State::Size;
+                }
+                State::Trailer => {
+                    let Some(line) = self.take_line(input, &mut pos)? else {
+                        break;
+                    };
+                    if line.is_empty() {
+                        self.state = State::Done;
+                    } else {
+                        if self.trailers.iter().count() == MAX_TRAILERS {
+                            return Err(ChunkedError::Malformed);
+                        }
+                        let (name, value) = parse_trailer(&line)?;
+                        self.trailers.append(name, &value);
+                    }
+                }
+                State::Done => break,
+            }
+        }
+
+        Ok(pos)
+    }
+
+    /// Accumulates bytes up to the next LF. Returns the completed line without
+    /// its line ending, or `None` if `input` ran out first.
+    fn take_line(&mut self, input: &[u8], pos: &mut usize) -> Result<Option<Vec<u8>>, ChunkedError> {
+        let rest = &input[*pos..];
+        match rest.iter().position(|&b| b == b'\n') {
+            Some(nl) => {
+                self.line.extend_from_slice(&rest[..nl]);
+                *pos += nl + 1;
+                let mut line = std::mem::take(&mut self.line);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if line.len() > MAX_LINE {
+                    return Err(ChunkedError::Malformed);
+                }
+                Ok(Some(line))
+            }
+            None => {
+                self.line.extend_from_slice(rest);
+                *pos = input.len();
+                if self.line.len() > MAX_LINE {
+                    return Err(ChunkedError::Malformed);
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Splits a `Name: value` trailer line, held to the same rules as header
+/// lines in a request head.
+fn parse_trailer(line: &[u8]) -> Result<(&str, String), ChunkedError> {
+    let colon = line.iter().position(|&b| b == b':').ok_or(ChunkedError::Malformed)?;
+    let name = &line[..colon];
+    if name.is_empty() || !name.iter().copied().all(is_token_byte) {
+        return Err(ChunkedError::Malformed);
+    }
+    let name = std::str::from_utf8(name).map_err(|_| ChunkedError::Malformed)?;
+    let value = String::from_utf8_lossy(&line[colon + 1..]);
+    Ok((name, value.trim_matches(|c| c == ' ' || c == '\t').to_string()))
+}
+
+/// Parses `1a;name=value` into 26, ignoring chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ChunkedError> {
+    let digits = match line.iter().position(|&b| b == b';') {
+        Some(semi) => &line[..semi],
+        None => line,
+    };
+    let digits = std::str::from_utf8(digits).map_err(|_| ChunkedError::Malformed)?.trim_end_matches([' ', '\t']);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ChunkedError::Malformed);
+    }
+    usize::from_str_radix(digits, 16).map_err(|_| ChunkedError::ChunkTooLarge)
+}
@@ -0,0 +1,380 @@
+//! An HTTP/1.1 client: the outbound counterpart to the server, for the
+//! proxy and health checks and for handlers calling other services.
+//! Responses are parsed with the same header parser as requests.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::proxy::pool::{Connection, Pool, PoolSettings};
+use crate::http::proxy::resolver::Address;
+use crate::http::request::{parse_headers, HeadLimits, Headers, ParseError, Version};
+use crate::http::status::StatusCode;
+
+/// Methods whose requests may safely be sent twice.
+const IDEMPOTENT: &[&str] = &["GET", "HEAD", "PUT", "DELETE", "OPTIONS", "TRACE"];
+
+/// A parsed response head.
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    pub version: Version,
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// Parses a response head from the front of `buf`, within the default
+    /// [`HeadLimits`], as [`Request::parse`](crate::http::Request::parse)
+    /// does for requests.
+    pub fn parse(buf: &[u8]) -> Result<(ResponseHead, usize), ParseError> {
+        let limits = HeadLimits::default();
+        let head_len = limits.check(buf)?.ok_or(ParseError::Incomplete)?;
+        let mut lines = buf[..head_len]
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+        // `HTTP/1.1 200 OK`; the reason phrase may be empty or missing.
+        let status_line = lines.next().ok_or(ParseError::InvalidStatusLine)?;
+        let status_line = std::str::from_utf8(status_line).map_err(|_| ParseError::InvalidStatusLine)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = match parts.next() {
+            Some("HTTP/1.1") => Version::Http11,
+            Some("HTTP/1.0") => Version::Http10,
+            Some(v) if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::InvalidStatusLine),
+        };
+        let status = parts
+            .next()
+            .filter(|code| code.len() == 3)
+            .and_then(|code| code.parse().ok())
+            .and_then(StatusCode::from_u16)
+            .ok_or(ParseError::InvalidStatusLine)?;
+
+        let headers = parse_headers(lines, &limits)?;
+        Ok((ResponseHead { version, status, headers }, head_len))
+    }
+
+    /// Whether the server keeps the connection open after this response:
+    /// HTTP/1.1 unless it says `Connection: close`, HTTP/1.0 only if it
+    /// says `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 | Version::Http2 => !self.headers.has_token("connection", "close"),
+            Version::Http10 => self.headers.has_token("connection", "keep-alive"),
+        }
+    }
+
+    /// Whether the body is chunked.
+    pub fn is_chunked(&self) -> bool {
+        self.headers.has_token("transfer-encoding", "chunked")
+    }
+
+    /// The Content-Length, if there is one and it is well formed.
+    pub fn content_length(&self) -> io::Result<Option<u64>> {
+        self.headers
+            .get("content-length")
+            .map(|len| len.trim().parse().map_err(|_| invalid("malformed Content-Length")))
+            .transpose()
+    }
+}
+
+/// Reads one response head off `stream`, starting with whatever is already
+/// in `buf`. Returns it with the bytes read past it.
+pub(crate) fn read_head(stream: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<(ResponseHead, Vec<u8>)> {
+    let mut chunk = [0u8; 8192];
+    loop {
+        match ResponseHead::parse(buf) {
+            Ok((head, len)) => return Ok((head, buf.split_off(len))),
+            Err(ParseError::Incomplete) => {}
+            Err(e) => return Err(invalid(&e.to_string())),
+        }
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "server closed the connection"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// A response received by a [`Client`], with its whole body.
+#[derive(Clone, Debug)]
+pub struct ClientResponse {
+    pub version: Version,
+    pub status: StatusCode,
+    pub headers: Headers,
+    /// Dechunked, but otherwise as sent.
+    pub body: Vec<u8>,
+}
+
+/// Sends requests to HTTP/1.1 servers, keeping connections alive between
+/// them.
+///
+/// Idle connections are pooled by `host:port`, with the same limits as a
+/// [`Proxy`](crate::http::Proxy)'s: at most
+/// [`max_idle`](Client::max_idle) per server, each for up to
+/// [`idle_timeout`](Client::idle_timeout). Should a reused connection turn
+/// out to have been closed by the server, an idempotent request is sent
+/// again on a fresh one. Host names are resolved when a server is first
+/// used.
+///
+/// Only `http://` URLs are supported.
+///
+/// ```ignore
+/// let client = Client::new().timeout(Duration::from_secs(5));
+/// let response = client.get("http://127.0.0.1:9000/status")?;
+/// println!("{} {}", response.status.as_u16(), String::from_utf8_lossy(&response.body));
+/// ```
+pub struct Client {
+    settings: PoolSettings,
+    max_body_size: usize,
+    pools: Mutex<HashMap<String, Arc<Pool>>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            settings: PoolSettings::default(),
+            max_body_size: 64 * 1024 * 1024,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Client::default()
+    }
+
+    /// How long connecting to a server may take. Defaults to 5 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a read from or write to a server may stall. Defaults to 60
+    /// seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.io_timeout = timeout;
+        self
+    }
+
+    /// Keeps at most `max` idle connections to each server. Defaults to
+    /// 32; 0 opens a new connection for every request.
+    pub fn max_idle(mut self, max: usize) -> Self {
+        self.settings.max_idle = max;
+        self
+    }
+
+    /// Closes connections left idle for `timeout`. Defaults to 60 seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.idle_timeout = timeout;
+        self
+    }
+
+    /// Fails requests whose response body is larger than `bytes`. Defaults
+    /// to 64 MiB.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> io::Result<ClientResponse> {
+        self.send("GET", url, &[], &[])
+    }
+
+    /// Sends a `method` request for `url` with `headers` and `body`, and
+    /// waits for the whole response. Host, Content-Length and a default
+    /// User-Agent are added; Connection and Transfer-Encoding headers are
+    /// left out.
+    pub fn send(&self, method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<ClientResponse> {
+        let (authority, target) = split_url(url)?;
+        let pool = self.pool(&socket_authority(authority))?;
+        let head = request_head(method, authority, &target, headers, body.len());
+
+        let mut connection = pool.checkout()?;
+        let response = match self.exchange(&mut connection, method, &head, body) {
+            // The server may have closed a pooled connection just as it was
+            // taken; the request never reached it, so try once more.
+            Err(e) if connection.reused && IDEMPOTENT.contains(&method) => {
+                connection = pool.connect().map_err(|_| e)?;
+                self.exchange(&mut connection, method, &head, body)
+            }
+            result => result,
+        };
+        let (response, reusable) = response?;
+        if reusable {
+            pool.checkin(connection);
+        }
+        Ok(response)
+    }
+
+    /// The pool for `authority`, made on first use.
+    fn pool(&self, authority: &str) -> io::Result<Arc<Pool>> {
+        let mut pools = self.pools.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pool) = pools.get(authority) {
+            return Ok(Arc::clone(pool));
+        }
+        let address = Address::resolve(authority)?;
+        let pool = Arc::new(Pool::new(Arc::new(address), self.settings));
+        pools.insert(authority.to_string(), Arc::clone(&pool));
+        Ok(pool)
+    }
+
+    /// Sends one request and reads its response. Returns the response, and
+    /// whether the connection can carry another request.
+    fn exchange(
+        &self,
+        connection: &mut Connection,
+        method: &str,
+        head: &str,
+        body: &[u8],
+    ) -> io::Result<(ClientResponse, bool)> {
+        let stream = &mut connection.stream;
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut buf = Vec::new();
+        let (head, rest) = loop {
+            let (head, rest) = read_head(stream, &mut buf)?;
+            if !head.status.is_informational() {
+                break (head, rest);
+            }
+            buf = rest;
+        };
+        let bodiless = method == "HEAD" || matches!(head.status, StatusCode::NoContent | StatusCode::NotModified);
+        let (body, clean) = if bodiless {
+            (Vec::new(), rest.is_empty())
+        } else {
+            read_body(stream, &head, rest, self.max_body_size)?
+        };
+        let reusable = clean && head.keep_alive();
+        let response = ClientResponse {
+            version: head.version,
+            status: head.status,
+            headers: head.headers,
+            body,
+        };
+        Ok((response, reusable))
+    }
+}
+
+/// Reads the body following `head`, starting with the bytes already read
+/// past it. Returns it, and whether it ended exactly where the framing
+/// said, leaving nothing unread.
+fn read_body(stream: &mut impl Read, head: &ResponseHead, rest: Vec<u8>, max: usize) -> io::Result<(Vec<u8>, bool)> {
+    let too_large = || invalid("response body too large");
+    let mut chunk = [0u8; 16 * 1024];
+
+    if head.is_chunked() {
+        let mut decoder = ChunkedDecoder::new(max, max);
+        let mut input = rest;
+        let mut body = Vec::new();
+        loop {
+            let used = decoder.decode(&input, &mut body).map_err(|e| invalid(&e.to_string()))?;
+            if decoder.is_done() {
+                return Ok((body, used == input.len()));
+            }
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "server closed mid-body"));
+            }
+            input.clear();
+            input.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    match head.content_length()? {
+        Some(len) => {
+            if len > max as u64 {
+                return Err(too_large());
+            }
+            let len = len as usize;
+            let clean = rest.len() <= len;
+            let mut body = rest;
+            body.truncate(len);
+            let remaining = (len - body.len()) as u64;
+            let read = stream.take(remaining).read_to_end(&mut body)?;
+            if (read as u64) < remaining {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "server closed mid-body"));
+            }
+            Ok((body, clean))
+        }
+        None => {
+            // The body runs until the server closes the connection.
+            let mut body = rest;
+            loop {
+                let n = stream.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok((body, false));
+                }
+                if body.len() + n > max {
+                    return Err(too_large());
+                }
+                body.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+fn request_head(method: &str, authority: &str, target: &str, headers: &[(&str, &str)], body_len: usize) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, target, authority);
+    let mut user_agent = false;
+    for (name, value) in headers {
+        if ["host", "content-length", "connection", "transfer-encoding"]
+            .iter()
+            .any(|skipped| name.eq_ignore_ascii_case(skipped))
+        {
+            continue;
+        }
+        user_agent |= name.eq_ignore_ascii_case("user-agent");
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !user_agent {
+        head.push_str("User-Agent: hyperport\r\n");
+    }
+    if body_len > 0 || matches!(method, "POST" | "PUT" | "PATCH") {
+        head.push_str(&format!("Content-Length: {}\r\n", body_len));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+/// The authority and request target of an `http://` URL, the target
+/// defaulting to `/`.
+fn split_url(url: &str) -> io::Result<(&str, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("not an http:// URL: {}", url)))?;
+    let (authority, target) = match rest.find(['/', '?', '#']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("unsupported URL: {}", url)));
+    }
+    // The fragment is for the client alone.
+    let target = target.split_once('#').map_or(target, |(target, _)| target);
+    let target = if target.starts_with('/') { target.to_string() } else { format!("/{}", target) };
+    Ok((authority, target))
+}
+
+/// `authority` as a `host:port` address, with port 80 unless it names
+/// one.
+fn socket_authority(authority: &str) -> String {
+    let has_port = match authority.rsplit_once(':') {
+        Some((host, _)) => !host.starts_with('[') || host.ends_with(']'),
+        None => false,
+    };
+    if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("bad response: {}", msg))
+}
@@ -0,0 +1,278 @@
+use std::io::{self, Write};
+use std::mem;
+use std::sync::Arc;
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::util::deflate::{GzipEncoder, ZlibEncoder};
+
+/// zlib's default trade-off between output size and CPU time.
+const DEFAULT_LEVEL: u32 = 6;
+
+/// Bodies shorter than this gain too little to be worth compressing.
+const DEFAULT_MIN_SIZE: u64 = 256;
+
+/// Media types compressed by default: text and the structured formats that
+/// are text underneath. Images, audio, video and archives are compressed
+/// already.
+const DEFAULT_TYPES: &[&str] = &[
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+#[derive(Clone, Debug)]
+struct Policy {
+    gzip_level: u32,
+    deflate_level: u32,
+    min_size: u64,
+    /// Lowercase media types, or `type/*` to match a whole family.
+    types: Vec<String>,
+}
+
+impl Policy {
+    fn allows_type(&self, media_type: &str) -> bool {
+        self.types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+            Some(family) => media_type.split_once('/').is_some_and(|(t, _)| t == family),
+            None => allowed == media_type,
+        })
+    }
+}
+
+/// Middleware that compresses response bodies with gzip or deflate, as the
+/// client's `Accept-Encoding` allows. gzip wins when both are equally
+/// acceptable.
+///
+/// The body is compressed as the handler writes it, so streamed responses
+/// stay streamed; they lose their Content-Length and go out chunked.
+/// Bodies passed whole to `ResponseWriter::send` are compressed up front
+/// and keep an accurate Content-Length.
+///
+/// Only responses whose Content-Type is on the allowlist are compressed,
+/// and only when their length is unknown or at least the minimum size.
+/// Responses that already carry a Content-Encoding, have no body, are
+/// partial (206) or are marked `Cache-Control: no-transform` are sent
+/// unchanged, as are those from routes opted out with
+/// `Router::disable_compression`. Any response that could have been
+/// compressed gets `Vary: Accept-Encoding`, so caches keep the variants
+/// apart.
+pub struct Compression {
+    policy: Arc<Policy>,
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Compression {
+            policy: Arc::new(Policy {
+                gzip_level: DEFAULT_LEVEL,
+                deflate_level: DEFAULT_LEVEL,
+                min_size: DEFAULT_MIN_SIZE,
+                types: DEFAULT_TYPES.iter().map(|t| t.to_string()).collect(),
+            }),
+        }
+    }
+
+    /// Sets the level of every encoding, from 1 (fastest) to 9 (smallest
+    /// output). Defaults to 6.
+    pub fn level(self, level: u32) -> Self {
+        self.gzip_level(level).deflate_level(level)
+    }
+
+    /// Sets the gzip level, from 1 (fastest) to 9 (smallest output).
+    pub fn gzip_level(mut self, level: u32) -> Self {
+        Arc::make_mut(&mut self.policy).gzip_level = level.clamp(1, 9);
+        self
+    }
+
+    /// Sets the deflate level, from 1 (fastest) to 9 (smallest output).
+    pub fn deflate_level(mut self, level: u32) -> Self {
+        Arc::make_mut(&mut self.policy).deflate_level = level.clamp(1, 9);
+        self
+    }
+
+    /// Leaves bodies of known length shorter than `bytes` uncompressed.
+    /// Defaults to 256.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        Arc::make_mut(&mut self.policy).min_size = bytes;
+        self
+    }
+
+    /// Replaces the media types that get compressed. Each is either exact,
+    /// such as `application/json`, or a family such as `text/*`. Defaults
+    /// to text, JavaScript, JSON, XML, WebAssembly and SVG.
+    pub fn types<S: AsRef<str>>(mut self, types: &[S]) -> Self {
+        Arc::make_mut(&mut self.policy).types = types.iter().map(|t| t.as_ref().trim().to_ascii_lowercase()).collect();
+        self
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        // HEAD is negotiated like GET so that its headers match: the
+        // writer drops the body either way.
+        if next.router.compresses(request) {
+            writer.set_compression(Negotiated {
+                coding: Coding::negotiate(request),
+                policy: Arc::clone(&self.policy),
+            });
+        }
+        next.run(request, writer)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    /// The coding the client prefers, if it accepts either.
+    fn negotiate(request: &Request) -> Option<Coding> {
+        let gzip = quality(request, &["gzip", "x-gzip"]);
+        let deflate = quality(request, &["deflate"]);
+        if gzip > 0.0 && gzip >= deflate {
+            Some(Coding::Gzip)
+        } else if deflate > 0.0 {
+            Some(Coding::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the middleware negotiated for one response. The writer applies it
+/// once the handler starts the response and its headers are known.
+pub(crate) struct Negotiated {
+    /// The coding the client accepts, if any.
+    coding: Option<Coding>,
+    policy: Arc<Policy>,
+}
+
+impl Negotiated {
+    /// Whether a response with this status and headers may be compressed.
+    pub(crate) fn applies<'h>(
+        &self,
+        status: StatusCode,
+        headers: impl Iterator<Item = (&'h str, &'h str)>,
+        content_length: Option<u64>,
+    ) -> bool {
+        if status.is_informational()
+            || matches!(status, StatusCode::NoContent | StatusCode::PartialContent | StatusCode::NotModified)
+            || content_length.is_some_and(|len| len == 0 || len < self.policy.min_size)
+        {
+            return false;
+        }
+
+        let mut media_type = None;
+        for (name, value) in headers {
+            if name.eq_ignore_ascii_case("content-encoding") {
+                return false;
+            }
+            if name.eq_ignore_ascii_case("cache-control")
+                && value.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+            {
+                return false;
+            }
+            if name.eq_ignore_ascii_case("content-type") {
+                media_type = Some(value.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+            }
+        }
+        media_type.is_some_and(|media_type| self.policy.allows_type(&media_type))
+    }
+
+    /// The encoder to compress with, if the client accepts one.
+    pub(crate) fn encoder(&self) -> Option<Encoder> {
+        match self.coding? {
+            Coding::Gzip => Some(Encoder::Gzip(GzipEncoder::new(Vec::new(), self.policy.gzip_level))),
+            Coding::Deflate => Some(Encoder::Deflate(ZlibEncoder::new(Vec::new(), self.policy.deflate_level))),
+        }
+    }
+}
+
+/// Compresses a response body a piece at a time, handing back the output
+/// as it becomes available.
+pub(crate) enum Encoder {
+    Gzip(GzipEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// The Content-Encoding token for the output.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoder::Gzip(_) => "gzip",
+            Encoder::Deflate(_) => "deflate",
+        }
+    }
+
+    pub(crate) fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// Forces out everything written so far, so the client can decode it
+    /// without waiting for more.
+    pub(crate) fn flush(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// The quality the request's Accept-Encoding gives any of `names`, or
+/// `*` failing those; zero when neither is listed.
+pub(crate) fn quality(request: &Request, names: &[&str]) -> f32 {
+    let mut wildcard = 0.0;
+    for element in request.headers.get_all("accept-encoding").flat_map(|value| value.split(',')) {
+        let mut params = element.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("q").then(|| value.trim().parse::<f32>().ok())?
+            })
+            .next()
+            .unwrap_or(1.0);
+        if names.iter().any(|n| name.eq_ignore_ascii_case(n)) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
@@ -0,0 +1,305 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::error::HyperportError;
+use crate::http::body::{self, Received, Sink};
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::error_pages::ErrorPages;
+use crate::http::request::{BodyLength, Headers, ParseError, Request, Version};
+use crate::http::response::ResponseWriter;
+use crate::net::RawTcpStream;
+use crate::server::stats::{self, BYTES_RECEIVED, BYTES_SENT};
+use crate::server::ServerContext;
+use crate::util::tempfile;
+use crate::{debug, error, warn};
+
+/// Largest single chunk accepted in a chunked request body.
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The interim response telling a client that sent `Expect: 100-continue`
+/// to go ahead with its body.
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Serves one request from a connection whose head is already buffered in
+/// `buf`. Runs on a pool thread with the stream in blocking mode.
+///
+/// On return `buf` holds only bytes received past the end of this request.
+/// The result says whether the connection may be reused. A request not
+/// fully received by `deadline` is answered with 408; one whose handler is
+/// still running then has its connection shut down.
+pub fn handle_connection(
+    stream: &mut RawTcpStream,
+    buf: &mut Vec<u8>,
+    ctx: &ServerContext,
+    deadline: Option<Instant>,
+) -> bool {
+    // The route is looked up ahead of dispatch for its body limit, and the
+    // same router then serves the request even if it is replaced meanwhile.
+    // Its error pages answer requests refused on the way.
+    let router = ctx.router();
+    let pages = router.error_pages();
+    let begun = Instant::now();
+
+    // The reactor only dispatches once a head is complete or has broken one
+    // of the limits, which is reported here.
+    let (mut request, head_len) = match Request::parse_with_limits(buf, &ctx.head_limits) {
+        Ok(parsed) => parsed,
+        Err(e) => return reject(stream, ctx, pages, e.into()),
+    };
+    buf.drain(..head_len);
+    // HTTP/1.1 clients must name the host (RFC 9112 §3.2); HTTP/1.0 ones
+    // predate the header and may leave it out.
+    let hosts = request.headers.get_all("host").count();
+    if hosts > 1 || (hosts == 0 && request.version == Version::Http11) {
+        return reject(stream, ctx, pages, ParseError::MissingHost.into());
+    }
+    request.peer_addr = stream.peer_addr().ok();
+    request.local_addr = stream.local_addr().ok();
+    request.client_ip = request.peer_addr.map(|addr| addr.ip());
+
+    let max_size = router.max_body_size_for(&request).unwrap_or(ctx.max_body_size);
+    let mut body = IncomingBody {
+        stream,
+        deadline,
+        read_timeout: ctx.read_timeout,
+        max_size,
+        spool_threshold: ctx.spool_threshold,
+        spool_dir: &ctx.spool_dir,
+        trailers: Headers::new(),
+    };
+    match body.read(buf, &request) {
+        Ok(received) => received.store(&mut request),
+        Err(e) => return reject(stream, ctx, pages, e),
+    }
+    request.trailers = std::mem::take(&mut body.trailers);
+    if let Err(e) = body::decode(&mut request, max_size, ctx.spool_threshold, &ctx.spool_dir) {
+        return reject(stream, ctx, pages, e);
+    }
+
+    let armed = match deadline {
+        Some(deadline) if deadline <= Instant::now() => return reject(stream, ctx, pages, HyperportError::Timeout),
+        Some(deadline) => {
+            // Handlers see the configured read timeout again, not whatever
+            // was left of the deadline during the last body read.
+            if stream.set_read_timeout(ctx.read_timeout).is_err() {
+                return false;
+            }
+            ctx.arm_deadline(stream, deadline)
+        }
+        None => None,
+    };
+
+    let mut writer = ResponseWriter::new(stream, request.version, request.keep_alive());
+    writer.set_deadline(armed);
+    writer.set_error_pages(router.error_pages_for(&request));
+    writer.set_server_header(ctx.server_header.as_deref());
+    if request.method == "HEAD" {
+        writer.set_head_only();
+    }
+    let span = ctx.tracer.as_ref().and_then(|tracer| tracer.start_request(&mut request));
+    let dispatched = Instant::now();
+    let result = router.dispatch(&mut request, &mut writer);
+    if let Err(e) = &result {
+        error!("Error handling {} {}: {}", request.method, request.target, e);
+    }
+    let failed = result.is_err();
+
+    // A handler that failed midway has left the body incomplete, so the
+    // connection cannot be reused; one that failed before writing anything
+    // still gets a 500 from finish().
+    let keep_alive = match result {
+        Err(_) if writer.is_started() => false,
+        _ => writer.finish().is_ok() && writer.keep_alive(),
+    };
+    if let Some(status) = writer.status() {
+        stats::record_response(status);
+    }
+    if let Some(span) = span {
+        span.finish(&request, writer.status(), failed);
+    }
+    log_if_slow(ctx, &request, &writer, begun, dispatched);
+    keep_alive
+}
+
+/// Warns about a request that took longer than the slow request threshold,
+/// splitting its time between reading the body (from `begun`, once the
+/// head was in), handling it (from `dispatched` until the response began)
+/// and writing the response.
+pub(crate) fn log_if_slow(ctx: &ServerContext, request: &Request, writer: &ResponseWriter, begun: Instant, dispatched: Instant) {
+    let Some(threshold) = ctx.slow_request_threshold else {
+        return;
+    };
+    let finished = Instant::now();
+    let total = finished - begun;
+    if total <= threshold {
+        return;
+    }
+    let started = writer.started_at().unwrap_or(finished);
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    warn!(
+        "Slow request: {} {} answered {} in {:.3}ms (read {:.3}ms, handle {:.3}ms, write {:.3}ms)",
+        request.method,
+        request.path,
+        writer.status().map_or("-".to_string(), |status| status.as_u16().to_string()),
+        ms(total),
+        ms(dispatched - begun),
+        ms(started.saturating_duration_since(dispatched)),
+        ms(finished - started)
+    );
+}
+
+/// Reads a request body with blocking reads, none of which may run past
+/// the request deadline.
+struct IncomingBody<'a> {
+    stream: &'a mut RawTcpStream,
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+    /// Larger bodies are refused with 413.
+    max_size: usize,
+    /// Larger bodies go to a file in `spool_dir` rather than memory.
+    spool_threshold: Option<usize>,
+    spool_dir: &'a Path,
+    /// Filled in from the end of a chunked body.
+    trailers: Headers,
+}
+
+impl IncomingBody<'_> {
+    /// Reads the body announced by the request head. One announced as too
+    /// large is refused before any of it is read, and so before a client
+    /// expecting `100 Continue` is told to send it.
+    fn read(&mut self, buf: &mut Vec<u8>, request: &Request) -> Result<Received, HyperportError> {
+        let expects_continue = request.expects_continue()?;
+        let length = request.body_length()?;
+        if matches!(length, BodyLength::Fixed(len) if len > self.max_size) {
+            return Err(HyperportError::TooLarge);
+        }
+        // A client that has started on the body anyway needs no go-ahead.
+        if expects_continue && buf.is_empty() && length != BodyLength::Fixed(0) {
+            self.stream.write_all(CONTINUE)?;
+            BYTES_SENT.fetch_add(CONTINUE.len() as u64, Ordering::Relaxed);
+        }
+        match length {
+            BodyLength::Fixed(len) if self.should_spool(len) => self.spool_exact(buf, len).map(Received::File),
+            BodyLength::Fixed(len) => self.read_exact(buf, len).map(Received::Memory),
+            BodyLength::Chunked => self.read_chunked(buf),
+        }
+    }
+
+    fn should_spool(&self, len: usize) -> bool {
+        self.spool_threshold.is_some_and(|threshold| len > threshold)
+    }
+
+    /// Like `read_exact`, but writes the body to a new unnamed file.
+    fn spool_exact(&mut self, buf: &mut Vec<u8>, len: usize) -> Result<File, HyperportError> {
+        let mut file = tempfile::anonymous(self.spool_dir)?;
+        let buffered = buf.len().min(len);
+        file.write_all(&buf[..buffered])?;
+        buf.drain(..buffered);
+
+        let mut remaining = len - buffered;
+        let mut chunk = [0u8; 64 * 1024];
+        while remaining > 0 {
+            let want = remaining.min(chunk.len());
+            let n = self.read_some(&mut chunk[..want])?;
+            file.write_all(&chunk[..n])?;
+            remaining -= n;
+        }
+        Ok(file)
+    }
+
+    /// Collects exactly `len` body bytes, starting with any already buffered.
+    fn read_exact(&mut self, buf: &mut Vec<u8>, len: usize) -> Result<Vec<u8>, HyperportError> {
+        if buf.len() >= len {
+            return Ok(buf.drain(..len).collect());
+        }
+
+        let mut body = std::mem::take(buf);
+        let mut chunk = [0u8; 8192];
+        while body.len() < len {
+            let want = (len - body.len()).min(chunk.len());
+            let n = self.read_some(&mut chunk[..want])?;
+            body.extend_from_slice(&chunk[..n]);
+        }
+
+        Ok(body)
+    }
+
+    /// Decodes a chunked body, starting with any already-buffered bytes.
+    /// Bytes past the terminating chunk are left in `buf` for the next
+    /// request. Reading stops as soon as the body outgrows the limit, and
+    /// moves to a file once it passes the spool threshold.
+    fn read_chunked(&mut self, buf: &mut Vec<u8>) -> Result<Received, HyperportError> {
+        let mut decoder = ChunkedDecoder::new(MAX_CHUNK_SIZE.min(self.max_size), self.max_size);
+        let mut sink = Sink::new(self.spool_threshold, self.spool_dir);
+        let mut decoded = Vec::new();
+
+        let consumed = decoder.decode(buf, &mut decoded)?;
+        buf.drain(..consumed);
+
+        let mut chunk = [0u8; 8192];
+        loop {
+            sink.write(&decoded)?;
+            decoded.clear();
+            if decoder.is_done() {
+                break;
+            }
+
+            let n = self.read_some(&mut chunk)?;
+            let consumed = decoder.decode(&chunk[..n], &mut decoded)?;
+            buf.extend_from_slice(&chunk[consumed..n]);
+        }
+        self.trailers = decoder.take_trailers();
+
+        Ok(sink.finish())
+    }
+
+    /// One blocking read of body bytes. EOF mid-body is an error, and the
+    /// socket's read timeout, or the deadline, surfaces as `Timeout`.
+    fn read_some(&mut self, chunk: &mut [u8]) -> Result<usize, HyperportError> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(HyperportError::Timeout);
+            }
+            let timeout = self.read_timeout.map_or(remaining, |timeout| timeout.min(remaining));
+            self.stream.set_read_timeout(Some(timeout))?;
+        }
+
+        loop {
+            match self.stream.read(chunk) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    BYTES_RECEIVED.fetch_add(n as u64, Ordering::Relaxed);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(HyperportError::Timeout)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Answers a request that failed before reaching a handler, when the
+/// failure is the client's to hear about. Always ends the connection.
+fn reject(stream: &mut RawTcpStream, ctx: &ServerContext, pages: &ErrorPages, error: HyperportError) -> bool {
+    match error.status() {
+        Some(status) => {
+            stats::record_response(status);
+            send(stream, &pages.closing_response(status, ctx.server_header.as_deref()));
+        }
+        None => debug!("Error reading request: {}", error),
+    }
+    false
+}
+
+fn send(stream: &mut RawTcpStream, response: &[u8]) {
+    if stream.write_all(response).is_ok() {
+        BYTES_SENT.fetch_add(response.len() as u64, Ordering::Relaxed);
+    }
+}
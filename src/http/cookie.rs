@@ -0,0 +1,260 @@
+//! Cookies sent by the client in `Cookie` headers (RFC 6265 §5.4), and
+//! ones set on it with `Set-Cookie`.
+
+use std::fmt;
+
+use crate::http::request::is_token_byte;
+
+/// Cookie name/value pairs in the order the client sent them. Browsers may
+/// send several cookies of the same name for different paths, most specific
+/// first, so [`get`](Cookies::get) returns the first.
+///
+/// ```
+/// use hyperport::http::Cookies;
+///
+/// let cookies = Cookies::parse(r#"session=abc123; theme="dark"; lang=en"#);
+/// assert_eq!(cookies.get("session"), Some("abc123"));
+/// assert_eq!(cookies.get("theme"), Some("dark"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cookies {
+    pairs: Vec<(String, String)>,
+}
+
+impl Cookies {
+    /// Parses one `Cookie` header value. Pairs are separated by `;`, and a
+    /// value may be wrapped in double quotes, which are removed. Pairs
+    /// without a `=` or with an empty name are skipped.
+    pub fn parse(header: &str) -> Self {
+        let mut cookies = Cookies::default();
+        cookies.extend(header);
+        cookies
+    }
+
+    fn extend(&mut self, header: &str) {
+        for pair in header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            self.pairs.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Collects the cookies from every `Cookie` header value, in order.
+    pub(crate) fn from_headers<'a>(headers: impl Iterator<Item = &'a str>) -> Self {
+        let mut cookies = Cookies::default();
+        for header in headers {
+            cookies.extend(header);
+        }
+        cookies
+    }
+
+    /// Returns the first value for `name`. Cookie names are case-sensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value for `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// Cross-site sending policy for a cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Sent on cross-site requests too. Browsers only accept it together
+    /// with [`SetCookie::secure`].
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A cookie for the client to store, serialized into a `Set-Cookie`
+/// header (RFC 6265 §4.1). Each cookie needs a header of its own; add one
+/// per cookie with [`ResponseBuilder::cookie`] or
+/// [`ResponseWriter::add_cookie`].
+///
+/// ```
+/// use std::time::Duration;
+/// use hyperport::http::{SameSite, SetCookie};
+///
+/// let cookie = SetCookie::new("session", "abc123")
+///     .path("/")
+///     .max_age(Duration::from_secs(3600))
+///     .http_only(true)
+///     .secure(true)
+///     .same_site(SameSite::Lax);
+/// assert_eq!(
+///     cookie.to_string(),
+///     "session=abc123; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax"
+/// );
+/// ```
+///
+/// [`ResponseBuilder::cookie`]: crate::http::response::ResponseBuilder::cookie
+/// [`ResponseWriter::add_cookie`]: crate::http::ResponseWriter::add_cookie
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<u64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// # Panics
+    ///
+    /// If `name` is not an HTTP token, or `value` holds characters a cookie
+    /// value cannot: controls, whitespace, `"`, `,`, `;`, `\` or non-ASCII.
+    /// Encode such values first, for example as base64.
+    pub fn new(name: &str, value: &str) -> Self {
+        assert!(
+            !name.is_empty() && name.bytes().all(is_token_byte),
+            "invalid cookie name `{}`",
+            name
+        );
+        assert!(value.bytes().all(is_cookie_octet), "invalid value for cookie `{}`", name);
+        SetCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client delete its cookie called `name`. The
+    /// path and domain must match those it was set with.
+    pub fn removal(name: &str) -> Self {
+        SetCookie::new(name, "").max_age(std::time::Duration::ZERO)
+    }
+
+    /// # Panics
+    ///
+    /// If `path` contains `;` or control characters.
+    pub fn path(mut self, path: &str) -> Self {
+        assert!(path.bytes().all(is_attribute_byte), "invalid cookie path `{}`", path);
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If `domain` contains `;` or control characters.
+    pub fn domain(mut self, domain: &str) -> Self {
+        assert!(domain.bytes().all(is_attribute_byte), "invalid cookie domain `{}`", domain);
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// How long the client should keep the cookie, in whole seconds.
+    /// Without it the cookie lasts until the browser session ends.
+    pub fn max_age(mut self, max_age: std::time::Duration) -> Self {
+        self.max_age = Some(max_age.as_secs());
+        self
+    }
+
+    /// Only send the cookie back over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Hide the cookie from scripts in the page.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Display for SetCookie {
+    /// Writes the header value, without the `Set-Cookie:` name.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// `cookie-octet` from RFC 6265: printable ASCII except `"`, `,`, `;`
+/// and `\`.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+/// What an attribute value may hold: anything but controls and `;`.
+fn is_attribute_byte(b: u8) -> bool {
+    (0x20..0x7f).contains(&b) && b != b';'
+}
@@ -0,0 +1,220 @@
+//! Cross-origin resource sharing (https://fetch.spec.whatwg.org/#http-cors-protocol):
+//! telling browsers which other sites' pages may call this one.
+
+use std::io;
+use std::time::Duration;
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+
+/// Request headers a page may send without the server listing them.
+const SAFELISTED_HEADERS: &[&str] = &["accept", "accept-language", "content-language", "content-type", "range"];
+
+/// Middleware answering CORS preflights and marking the responses to
+/// cross-origin requests from allowed origins as readable by their pages.
+///
+/// An origin is allowed when it matches one of
+/// [`origins`](Cors::origins), and any origin is until that narrows it.
+/// A preflight — an OPTIONS request with `Origin` and
+/// `Access-Control-Request-Method` — from an allowed origin, asking for an
+/// allowed method and headers, gets a 204 with the `Access-Control-*`
+/// headers saying so. Any other preflight is passed on and answered like a
+/// plain OPTIONS request, without them, which the browser takes as a
+/// refusal. Other requests are always passed on; those from allowed
+/// origins get `Access-Control-Allow-Origin` and the related headers on
+/// their responses.
+///
+/// ```ignore
+/// router.wrap(
+///     Cors::new()
+///         .origins(&["https://app.example.com", "https://*.example.com"])
+///         .methods(&["GET", "POST", "DELETE"])
+///         .allow_credentials(true),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cors {
+    /// Lowercase origins, each of which may hold one `*`. `None` for any.
+    origins: Option<Vec<String>>,
+    methods: Vec<String>,
+    /// Lowercase names, or `None` for whatever a preflight asks for.
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: None,
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: None,
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    /// The origins allowed, such as `https://app.example.com`. A `*` in
+    /// the host stands for one or more labels, so `https://*.example.com`
+    /// allows every subdomain of `example.com` over HTTPS, and `*` alone
+    /// allows any origin. Defaults to any origin.
+    pub fn origins<S: AsRef<str>>(mut self, origins: &[S]) -> Self {
+        let origins = origins.iter().map(|origin| origin.as_ref().to_ascii_lowercase()).collect::<Vec<_>>();
+        self.origins = (!origins.iter().any(|origin| origin == "*")).then_some(origins);
+        self
+    }
+
+    /// The methods cross-origin requests may use. Defaults to GET, HEAD
+    /// and POST.
+    pub fn methods<S: AsRef<str>>(mut self, methods: &[S]) -> Self {
+        self.methods = methods.iter().map(|method| method.as_ref().to_ascii_uppercase()).collect();
+        self
+    }
+
+    /// The request headers cross-origin requests may send, beyond the
+    /// safelisted ones browsers never ask about. Defaults to any the
+    /// preflight asks for.
+    pub fn headers<S: AsRef<str>>(mut self, headers: &[S]) -> Self {
+        self.headers = Some(headers.iter().map(|header| header.as_ref().to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// Response headers pages may read, beyond the safelisted ones such as
+    /// Content-Type.
+    pub fn expose_headers<S: AsRef<str>>(mut self, headers: &[S]) -> Self {
+        self.expose_headers = headers.iter().map(|header| header.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Whether requests may carry cookies and HTTP authentication. The
+    /// requesting origin is then named in responses rather than `*`,
+    /// which browsers refuse credentials with.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.allow_credentials = allow;
+        self
+    }
+
+    /// How long browsers may cache a preflight's answer. Defaults to
+    /// leaving it to them, which is 5 seconds in most.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let Some(origins) = &self.origins else {
+            return true;
+        };
+        let origin = origin.to_ascii_lowercase();
+        origins.iter().any(|allowed| match allowed.split_once('*') {
+            Some((prefix, suffix)) => {
+                origin.strip_prefix(prefix).and_then(|rest| rest.strip_suffix(suffix)).is_some_and(|labels| {
+                    !labels.is_empty() && labels.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+                })
+            }
+            None => *allowed == origin,
+        })
+    }
+
+    /// Whether every header named in an `Access-Control-Request-Headers`
+    /// value is allowed.
+    fn allows_headers(&self, requested: &str) -> bool {
+        let Some(allowed) = &self.headers else {
+            return true;
+        };
+        requested
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .all(|name| SAFELISTED_HEADERS.contains(&name.as_str()) || allowed.contains(&name))
+    }
+
+    /// `Access-Control-Allow-Origin` for `origin`: `*` when any origin
+    /// would get the same, so that caches can share the response.
+    fn allow_origin<'a>(&self, origin: &'a str) -> &'a str {
+        if self.origins.is_none() && !self.allow_credentials {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    /// Whether responses depend on the request's `Origin`, as caches need
+    /// telling with `Vary`.
+    fn varies(&self) -> bool {
+        self.origins.is_some() || self.allow_credentials
+    }
+
+    fn preflight(&self, origin: &str, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let requested_headers =
+            request.headers.get_all("access-control-request-headers").collect::<Vec<_>>().join(", ");
+        let methods = self.methods.join(", ");
+        let allow_headers = match &self.headers {
+            Some(headers) => headers.join(", "),
+            None => requested_headers,
+        };
+        let max_age = self.max_age.map(|max_age| max_age.as_secs().to_string());
+
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin", self.allow_origin(origin)),
+            ("Access-Control-Allow-Methods", methods.as_str()),
+        ];
+        if !allow_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", allow_headers.as_str()));
+        }
+        if self.allow_credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true"));
+        }
+        if let Some(max_age) = &max_age {
+            headers.push(("Access-Control-Max-Age", max_age));
+        }
+        headers.push(("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"));
+        writer.send(StatusCode::NoContent, &headers, b"")
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        let Some(origin) = request.headers.get("origin").map(str::to_string) else {
+            return next.run(request, writer);
+        };
+        let allowed = self.allows_origin(&origin);
+        if request.method == "OPTIONS" {
+            if let Some(method) = request.headers.get("access-control-request-method") {
+                let method_allowed = self.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method.trim()));
+                let headers_allowed = request
+                    .headers
+                    .get_all("access-control-request-headers")
+                    .all(|requested| self.allows_headers(requested));
+                if allowed && method_allowed && headers_allowed {
+                    return self.preflight(&origin, request, writer);
+                }
+                writer.add_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+                return next.run(request, writer);
+            }
+        }
+        if self.varies() {
+            writer.add_header("Vary", "Origin");
+        }
+        if allowed {
+            writer.add_header("Access-Control-Allow-Origin", self.allow_origin(&origin));
+            if self.allow_credentials {
+                writer.add_header("Access-Control-Allow-Credentials", "true");
+            }
+            if !self.expose_headers.is_empty() {
+                writer.add_header("Access-Control-Expose-Headers", &self.expose_headers.join(", "));
+            }
+        }
+        next.run(request, writer)
+    }
+}
@@ -0,0 +1,132 @@
+//! Operator-supplied bodies for error responses.
+//!
+//! Register an [`ErrorPage`] for a status with [`Router::error_page`] and it
+//! replaces the built-in page wherever the server answers with that status
+//! on its own: requests it refuses before any handler runs (400, 408, 413,
+//! 431, 503, ...), handlers that fail without responding (500), and the
+//! errors the crate's own handlers send, such as the default 404.
+//! Responses that application handlers build themselves are left alone.
+//!
+//! [`Router::error_page`]: crate::http::Router::error_page
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::http::error_page;
+use crate::http::response::Response;
+use crate::http::static_files::mime_type;
+use crate::http::status::StatusCode;
+use crate::util::time::http_date_now;
+
+/// The body sent for one error status.
+pub struct ErrorPage(Kind);
+
+enum Kind {
+    /// A fixed body in which `{{status}}` and `{{reason}}` are filled in.
+    Template { content_type: String, body: String },
+    Callback(Box<dyn Fn(StatusCode) -> Response + Send + Sync>),
+}
+
+impl ErrorPage {
+    /// Reads a template from `path` now, so a missing file is reported at
+    /// startup rather than when the error happens. Its Content-Type follows
+    /// the file extension. `{{status}}` in the template becomes the code,
+    /// e.g. `404`, and `{{reason}}` the reason phrase, so one file can
+    /// serve several statuses.
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<ErrorPage> {
+        let path = path.as_ref();
+        let body = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(ErrorPage(Kind::Template {
+            content_type: mime_type(path).to_string(),
+            body,
+        }))
+    }
+
+    /// An HTML template held in memory, with the same placeholders as
+    /// [`file`](ErrorPage::file).
+    pub fn html<S: Into<String>>(body: S) -> ErrorPage {
+        ErrorPage(Kind::Template {
+            content_type: "text/html; charset=utf-8".to_string(),
+            body: body.into(),
+        })
+    }
+
+    /// Builds the response with `callback` each time. Its status is replaced
+    /// by the one being reported, and its Connection header may be
+    /// overridden. Errors refused before a request is dispatched are
+    /// rendered on the event loop thread, so the callback must be quick.
+    pub fn callback<F>(callback: F) -> ErrorPage
+    where
+        F: Fn(StatusCode) -> Response + Send + Sync + 'static,
+    {
+        ErrorPage(Kind::Callback(Box::new(callback)))
+    }
+
+    fn render(&self, status: StatusCode) -> Response {
+        match &self.0 {
+            Kind::Template { content_type, body } => {
+                let body = body
+                    .replace("{{status}}", &status.as_u16().to_string())
+                    .replace("{{reason}}", status.reason_phrase());
+                Response::builder().status(status).header("Content-Type", content_type).body(body)
+            }
+            Kind::Callback(callback) => {
+                let response = callback(status);
+                let mut builder = Response::builder().status(status);
+                for (name, value) in response.headers() {
+                    builder = builder.header(name, value);
+                }
+                builder.body(response.body())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ErrorPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Kind::Template { content_type, .. } => write!(f, "ErrorPage::Template({})", content_type),
+            Kind::Callback(_) => f.write_str("ErrorPage::Callback"),
+        }
+    }
+}
+
+/// The pages registered on a router, by status.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorPages {
+    pages: HashMap<StatusCode, ErrorPage>,
+}
+
+impl ErrorPages {
+    pub(crate) fn insert(&mut self, status: StatusCode, page: ErrorPage) {
+        self.pages.insert(status, page);
+    }
+
+    /// The response for `status`: the registered page, or the built-in one.
+    pub(crate) fn response(&self, status: StatusCode) -> Response {
+        match self.pages.get(&status) {
+            Some(page) => page.render(status),
+            None => Response::builder()
+                .status(status)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(error_page(status)),
+        }
+    }
+
+    /// Serializes the response for `status` so that it closes the
+    /// connection afterwards, naming the server as `server` when set.
+    pub(crate) fn closing_response(&self, status: StatusCode, server: Option<&str>) -> Vec<u8> {
+        let response = self.response(status);
+        let mut builder = Response::builder().status(status);
+        if let Some(server) = server {
+            builder = builder.header("Server", server);
+        }
+        for (name, value) in response.headers().filter(|(name, _)| !name.eq_ignore_ascii_case("connection")) {
+            builder = builder.header(name, value);
+        }
+        builder.header("Date", &http_date_now()).header("Connection", "close").body(response.body()).to_bytes()
+    }
+}
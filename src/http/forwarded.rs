@@ -0,0 +1,105 @@
+//! Finding the real client behind reverse proxies, from the
+//! X-Forwarded-For and Forwarded (RFC 7239) headers they add.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::error::HyperportError;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::net::ip_filter::Network;
+
+/// Middleware setting [`Request::client_ip`] from the forwarding headers of
+/// requests that come through trusted proxies.
+///
+/// Headers are only believed when the peer is trusted. The addresses they
+/// list are then walked from the nearest hop back, and the first one that
+/// is not itself a trusted proxy is taken as the client. `Forwarded` is
+/// used when present, else `X-Forwarded-For`.
+///
+/// Wrap it before anything that reads the client address, such as the
+/// [`AccessLog`](crate::http::AccessLog):
+///
+/// ```ignore
+/// router.wrap(TrustedProxies::new(&["127.0.0.1", "10.0.0.0/8"])?);
+/// ```
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Trusts the given addresses and `address/prefix` networks, IPv4 or
+    /// IPv6.
+    pub fn new<S: AsRef<str>>(networks: &[S]) -> Result<TrustedProxies, HyperportError> {
+        Ok(TrustedProxies {
+            networks: Network::parse_all(networks)?,
+        })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client address of `request`, as described on the type.
+    pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let peer = request.peer_addr?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        let hops = if request.headers.get("forwarded").is_some() {
+            request.headers.get_all("forwarded").flat_map(forwarded_for).collect::<Vec<_>>()
+        } else {
+            request
+                .headers
+                .get_all("x-forwarded-for")
+                .flat_map(|value| value.split(','))
+                .map(|hop| parse_node(hop.trim()))
+                .collect()
+        };
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            // An obfuscated or malformed hop ends what can be known; the
+            // proxy that reported it is the last known address.
+            let Some(ip) = hop else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+impl Middleware for TrustedProxies {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        request.client_ip = self.client_ip(request);
+        next.run(request, writer)
+    }
+}
+
+/// The `for` parameters of the elements of one Forwarded header value.
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("for").then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+/// An address as proxies write it: bare, with a port, or in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
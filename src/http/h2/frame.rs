@@ -0,0 +1,157 @@
+//! The HTTP/2 frame layer (RFC 7540 §4 and §6): the nine-byte frame
+//! header, and reading and writing whole frames.
+
+use std::io::{self, ErrorKind};
+use std::sync::atomic::Ordering;
+
+use crate::net::RawTcpStream;
+use crate::server::stats::BYTES_RECEIVED;
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const END_STREAM: u8 = 0x1;
+pub(crate) const ACK: u8 = 0x1;
+pub(crate) const END_HEADERS: u8 = 0x4;
+pub(crate) const PADDED: u8 = 0x8;
+pub(crate) const PRIORITY_FLAG: u8 = 0x20;
+
+pub(crate) const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Error codes for RST_STREAM and GOAWAY (§7).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reason {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+/// Frame payloads up to this size are always allowed (§4.2); it is also
+/// the largest we accept, since we never advertise more.
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// Window size for new streams and the connection until SETTINGS say
+/// otherwise (§6.9.2).
+pub(crate) const DEFAULT_WINDOW: i64 = 65_535;
+
+/// The largest a flow-control window may grow (§6.9.1).
+pub(crate) const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+pub(crate) struct Frame {
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream_id: u32,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The payload of a DATA or HEADERS frame without its padding, or
+    /// `None` if the padding is longer than the frame.
+    pub(crate) fn unpadded(&self) -> Option<&[u8]> {
+        if !self.has(PADDED) {
+            return Some(&self.payload);
+        }
+        let (&pad, rest) = self.payload.split_first()?;
+        rest.len().checked_sub(pad as usize).map(|len| &rest[..len])
+    }
+}
+
+/// Reads one frame, taking bytes from `buf` before the socket. A frame
+/// larger than `max_size` fails with `InvalidData`, before its payload is
+/// read.
+pub(crate) fn read(stream: &mut RawTcpStream, buf: &mut Vec<u8>, max_size: usize) -> io::Result<Frame> {
+    fill(stream, buf, 9)?;
+    let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+    if len > max_size {
+        return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    fill(stream, buf, 9 + len)?;
+    let frame = Frame {
+        kind: buf[3],
+        flags: buf[4],
+        stream_id: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7fff_ffff,
+        payload: buf[9..9 + len].to_vec(),
+    };
+    buf.drain(..9 + len);
+    Ok(frame)
+}
+
+/// Reads until `buf` holds at least `len` bytes.
+pub(crate) fn fill(stream: &mut RawTcpStream, buf: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    let mut chunk = [0u8; 16 * 1024];
+    while buf.len() < len {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                BYTES_RECEIVED.fetch_add(n as u64, Ordering::Relaxed);
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Appends a frame with `payload` to `out`.
+pub(crate) fn encode(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// The `(identifier, value)` pairs of a SETTINGS payload, or `None` if its
+/// length is not a multiple of six.
+pub(crate) fn settings(payload: &[u8]) -> Option<Vec<(u16, u32)>> {
+    if !payload.len().is_multiple_of(6) {
+        return None;
+    }
+    Some(
+        payload
+            .chunks(6)
+            .map(|s| (u16::from_be_bytes([s[0], s[1]]), u32::from_be_bytes([s[2], s[3], s[4], s[5]])))
+            .collect(),
+    )
+}
+
+pub(crate) fn settings_payload(settings: &[(u16, u32)]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for &(id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    payload
+}
+
+/// A four-byte big-endian value with its reserved top bit cleared, as in
+/// WINDOW_UPDATE.
+pub(crate) fn u31(payload: &[u8]) -> Option<u32> {
+    let bytes: [u8; 4] = payload.try_into().ok()?;
+    Some(u32::from_be_bytes(bytes) & 0x7fff_ffff)
+}
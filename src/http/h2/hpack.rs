@@ -0,0 +1,501 @@
+//! HPACK header compression (RFC 7541).
+//!
+//! The decoder implements the whole format, dynamic table included, since
+//! clients lean on it heavily. The encoder keeps no dynamic table and sends
+//! every field as a literal without indexing, naming it by its static table
+//! index where there is one, so it needs no state at all.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::huffman;
+
+/// The static table (RFC 7541 Appendix A); index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// A header field's name and value, as bytes.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// Per-entry overhead counted against the dynamic table size (§4.1).
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A header block that could not be decoded. The connection's dynamic
+/// table can no longer be trusted, so this is a connection error.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("malformed header block")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the header blocks of one connection, in the order they arrive.
+pub(crate) struct Decoder {
+    /// Newest entry first.
+    table: VecDeque<Field>,
+    size: usize,
+    /// Current limit, as last set by the peer.
+    max_size: usize,
+    /// The most the peer may set the limit to: our SETTINGS_HEADER_TABLE_SIZE.
+    capacity: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: capacity,
+            capacity,
+        }
+    }
+
+    /// Decodes a complete header block into `(name, value)` pairs, or
+    /// `None` when they outgrow `max_list_size`, counted as RFC 7540 counts
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. The rest of an oversized block is
+    /// still decoded, for its changes to the dynamic table, but not kept.
+    pub(crate) fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Option<Vec<Field>>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        // Size updates are only allowed before the first field.
+        let mut first = true;
+        while let Some(&byte) = block.first() {
+            let field = if byte & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                self.entry(index)?
+            } else if byte & 0x40 != 0 {
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if byte & 0x20 != 0 {
+                if !first {
+                    return Err(DecodeError);
+                }
+                let size = integer(&mut block, 5)?;
+                if size > self.capacity {
+                    return Err(DecodeError);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Without indexing (0000) or never indexed (0001); neither
+                // touches the table.
+                self.literal(&mut block, 4)?
+            };
+            first = false;
+            list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            if list_size <= max_list_size {
+                fields.push(field);
+            }
+        }
+        Ok((list_size <= max_list_size).then_some(fields))
+    }
+
+    /// A literal field whose name is indexed when the `prefix`-bit index
+    /// is nonzero, and otherwise follows as a string.
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<Field, DecodeError> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn entry(&self, index: usize) -> Result<Field, DecodeError> {
+        match index {
+            0 => Err(DecodeError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index - 62).cloned().ok_or(DecodeError),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        // An entry larger than the whole table empties it and is dropped
+        // (§4.4).
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Evicts the oldest entries until `room` more bytes would fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// An integer with an N-bit prefix (§5.1), taking the first byte's low
+/// `prefix` bits and any continuation bytes from the front of `block`.
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, mut rest) = block.split_first().ok_or(DecodeError)?;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().ok_or(DecodeError)?;
+            rest = tail;
+            // Anything past 28 bits is far beyond every limit we have.
+            if shift > 21 {
+                return Err(DecodeError);
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+/// A string literal (§5.2), Huffman-coded when its first bit says so.
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = block.first().ok_or(DecodeError)? & 0x80 != 0;
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(DecodeError);
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        huffman::decode(data).ok_or(DecodeError)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Encodes the head of a response: `:status`, then `headers`, whose names
+/// must already be lowercase. Trailers have no status.
+pub(crate) fn encode<'h>(status: Option<u16>, headers: impl Iterator<Item = (&'h str, &'h str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    if let Some(status) = status {
+        match STATIC_TABLE[7..14].iter().position(|&(_, value)| value == status.to_string()) {
+            Some(i) => put_integer(&mut block, 0x80, 7, 8 + i),
+            None => {
+                put_integer(&mut block, 0x00, 4, 8);
+                put_string(&mut block, status.to_string().as_bytes());
+            }
+        }
+    }
+    for (name, value) in headers {
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => put_integer(&mut block, 0x00, 4, i + 1),
+            None => {
+                block.push(0x00);
+                put_string(&mut block, name.as_bytes());
+            }
+        }
+        put_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+fn put_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn put_string(block: &mut Vec<u8>, data: &[u8]) {
+    put_integer(block, 0, 7, data.len());
+    block.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<Field> {
+        pairs.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    /// A header block in hex, the fields it decodes to, and the dynamic
+    /// table's size afterwards.
+    type Example<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    /// Decodes `blocks` in turn on one connection, checking each.
+    fn decode_all(decoder: &mut Decoder, blocks: &[Example<'_>]) {
+        for (block, expected, size) in blocks {
+            assert_eq!(decoder.decode(&hex(block), usize::MAX), Ok(Some(fields(expected))));
+            assert_eq!(decoder.size, *size);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        // RFC 7541 C.1.
+        let mut block = Vec::new();
+        put_integer(&mut block, 0, 5, 10);
+        assert_eq!(block, [0x0A]);
+        let mut block = Vec::new();
+        put_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x1F, 0x9A, 0x0A]);
+        assert_eq!(integer(&mut &block[..], 5), Ok(1337));
+        assert_eq!(integer(&mut &[0x2A][..], 8), Ok(42));
+
+        assert_eq!(integer(&mut &[0x1F, 0x9A][..], 5), Err(DecodeError));
+        assert_eq!(integer(&mut &[0x1F, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F][..], 5), Err(DecodeError));
+    }
+
+    #[test]
+    fn requests_without_huffman() {
+        // RFC 7541 C.3.
+        decode_all(
+            &mut Decoder::new(4096),
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")],
+                    57,
+                ),
+                (
+                    "8286 84be 5808 6e6f 2d63 6163 6865",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    110,
+                ),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        // RFC 7541 C.4.
+        decode_all(
+            &mut Decoder::new(4096),
+            &[
+                (
+                    "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                    &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")],
+                    57,
+                ),
+                (
+                    "8286 84be 5886 a8eb 1064 9cbf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "http"),
+                        (":path", "/"),
+                        (":authority", "www.example.com"),
+                        ("cache-control", "no-cache"),
+                    ],
+                    110,
+                ),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    &[
+                        (":method", "GET"),
+                        (":scheme", "https"),
+                        (":path", "/index.html"),
+                        (":authority", "www.example.com"),
+                        ("custom-key", "custom-value"),
+                    ],
+                    164,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn responses_evicting_entries() {
+        // RFC 7541 C.5, with a 256-byte table.
+        let mut decoder = Decoder::new(256);
+        decode_all(
+            &mut decoder,
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a \
+                     3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    &[
+                        (":status", "302"),
+                        ("cache-control", "private"),
+                        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                        ("location", "https://www.example.com"),
+                    ],
+                    222,
+                ),
+                (
+                    "4803 3330 37c1 c0bf",
+                    &[
+                        (":status", "307"),
+                        ("cache-control", "private"),
+                        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                        ("location", "https://www.example.com"),
+                    ],
+                    222,
+                ),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 \
+                     677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 \
+                     553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31",
+                    &[
+                        (":status", "200"),
+                        ("cache-control", "private"),
+                        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                        ("location", "https://www.example.com"),
+                        ("content-encoding", "gzip"),
+                        ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+                    ],
+                    215,
+                ),
+            ],
+        );
+        assert_eq!(decoder.table.len(), 3);
+        assert_eq!(decoder.table[0].0, b"set-cookie");
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut decoder = Decoder::new(4096);
+        decoder.decode(&hex("4003 666f 6f03 6261 72"), usize::MAX).unwrap();
+        assert_eq!(decoder.size, 38);
+        // Shrinking the table to nothing evicts everything.
+        assert_eq!(decoder.decode(&hex("20 be"), usize::MAX), Err(DecodeError));
+        assert!(decoder.table.is_empty());
+        // Only before the first field, and never past our own limit.
+        assert_eq!(Decoder::new(4096).decode(&hex("82 20"), usize::MAX), Err(DecodeError));
+        assert_eq!(Decoder::new(4096).decode(&hex("3fe2 1f"), usize::MAX), Err(DecodeError));
+    }
+
+    #[test]
+    fn rejects_bad_indexes_and_strings() {
+        assert_eq!(Decoder::new(4096).decode(&[0x80], usize::MAX), Err(DecodeError));
+        assert_eq!(Decoder::new(4096).decode(&[0xBE], usize::MAX), Err(DecodeError));
+        assert_eq!(Decoder::new(4096).decode(&hex("0003 6162"), usize::MAX), Err(DecodeError));
+    }
+
+    #[test]
+    fn oversized_lists_still_update_the_table() {
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(decoder.decode(&hex("4003 666f 6f03 6261 72"), 10), Ok(None));
+        assert_eq!(decoder.decode(&hex("be"), usize::MAX), Ok(Some(fields(&[("foo", "bar")]))));
+    }
+
+    #[test]
+    fn encoded_heads_decode() {
+        let headers = [("content-type", "text/html"), ("x-request-id", "abc"), ("set-cookie", "a=1")];
+        let block = encode(Some(200), headers.iter().copied());
+        assert_eq!(block[0], 0x88);
+        let mut expected = vec![(":status", "200")];
+        expected.extend(headers);
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(decoder.decode(&block, usize::MAX), Ok(Some(fields(&expected))));
+        // Nothing is added to the peer's table.
+        assert_eq!(decoder.size, 0);
+
+        let block = encode(Some(418), std::iter::empty());
+        assert_eq!(decoder.decode(&block, usize::MAX), Ok(Some(fields(&[(":status", "418")]))));
+        assert_eq!(encode(None, std::iter::empty()), []);
+    }
+}
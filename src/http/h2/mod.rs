@@ -0,0 +1,949 @@
+//! HTTP/2 (RFC 7540): many concurrent requests over one connection, each
+//! on its own stream.
+//!
+//! One thread reads frames for the whole connection, decoding header
+//! blocks and collecting request bodies, which are spooled and decoded
+//! from their Content-Encoding as HTTP/1 bodies are. Each request, once
+//! complete, is queued on the server's thread pool and served through the
+//! same [`Router`] and [`ResponseWriter`] as HTTP/1, whose output becomes
+//! HEADERS and DATA frames on the stream; when the queue is full the stream
+//! is refused with REFUSED_STREAM, where HTTP/1 would answer 503. Writes
+//! from all streams share the socket under a lock, and wait there for the
+//! peer to open its flow-control windows.
+//!
+//! [`Router`]: crate::http::Router
+
+mod frame;
+mod hpack;
+mod huffman;
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::HyperportError;
+use crate::http::body::{self, Sink};
+use crate::http::connection::log_if_slow;
+use crate::http::request::{is_token_byte, BodyLength, HeadLimits, Request, Version};
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::net::RawTcpStream;
+use crate::server::protocol::HTTP2_PREFACE;
+use crate::server::stats::{self, BYTES_SENT};
+use crate::server::ServerContext;
+use crate::{error, warn};
+
+use frame::{Frame, Reason};
+use hpack::Field;
+
+/// Streams a client may have open at once; more are refused.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// Size of the dynamic table header blocks are decoded with.
+const HEADER_TABLE_SIZE: usize = 4096;
+
+/// How often the reading thread looks up from the socket to notice an
+/// idle connection or a server shutting down.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Headers that only mean something to a single HTTP/1 connection, and
+/// which HTTP/2 forbids (§8.1.2.2).
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Serves an HTTP/2 connection that began with the prior-knowledge
+/// preface, until either side ends it. `buf` holds bytes already read,
+/// starting with the preface. Runs on a pool thread with the stream in
+/// blocking mode.
+pub(crate) fn serve_connection(mut stream: RawTcpStream, mut buf: Vec<u8>, ctx: &Arc<ServerContext>) {
+    if stream.set_read_timeout(ctx.header_timeout).is_err()
+        || frame::fill(&mut stream, &mut buf, HTTP2_PREFACE.len()).is_err()
+        || !buf.starts_with(HTTP2_PREFACE)
+    {
+        return;
+    }
+    buf.drain(..HTTP2_PREFACE.len());
+
+    let writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("Error starting HTTP/2 connection: {}", e);
+            return;
+        }
+    };
+    // Frames are small and written one at a time, so Nagle's algorithm
+    // would hold many of them back waiting for ACKs.
+    if writer.set_write_timeout(ctx.write_timeout).is_err()
+        || writer.set_nodelay(true).is_err()
+        || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+    {
+        return;
+    }
+    let conn = Arc::new(Conn {
+        out: Mutex::new(Output {
+            stream: writer,
+            window: frame::DEFAULT_WINDOW,
+            initial_window: frame::DEFAULT_WINDOW,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            streams: HashMap::new(),
+            serving: 0,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+        write_timeout: ctx.write_timeout,
+    });
+    let settings = frame::settings_payload(&[
+        (frame::SETTINGS_HEADER_TABLE_SIZE, HEADER_TABLE_SIZE as u32),
+        (frame::SETTINGS_ENABLE_PUSH, 0),
+        (frame::SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS as u32),
+        (frame::SETTINGS_MAX_HEADER_LIST_SIZE, ctx.head_limits.max_head_size as u32),
+    ]);
+    if conn.send(frame::SETTINGS, 0, 0, &settings).is_err() {
+        return;
+    }
+
+    let mut reader = Reader {
+        stream: &mut stream,
+        buf,
+        ctx,
+        conn: &conn,
+        decoder: hpack::Decoder::new(HEADER_TABLE_SIZE),
+        incoming: HashMap::new(),
+        continuation: None,
+        last_stream_id: 0,
+        going_away: false,
+    };
+    let result = reader.run();
+    let reason = result.err().unwrap_or(Reason::NoError);
+    if reason != Reason::NoError || !reader.going_away {
+        let mut payload = reader.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(reason as u32).to_be_bytes());
+        let _ = conn.send(frame::GOAWAY, 0, 0, &payload);
+    }
+    // Streams still being served find their writes failing from here, and
+    // the connection is done once they have all finished.
+    let mut out = conn.lock();
+    out.closed = true;
+    conn.changed.notify_all();
+    while out.serving > 0 {
+        out = conn.changed.wait(out).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+/// A request read off a stream, with when its head arrived, or the status
+/// it was refused with.
+type Outcome = Result<(Request, Instant), StatusCode>;
+
+/// A stream whose request body is still arriving.
+struct Incoming<'a> {
+    request: Request,
+    /// The body so far, spooled to a file past the spool threshold as an
+    /// HTTP/1 body would be.
+    body: Sink<'a>,
+    /// When the head was read, for the slow request log.
+    begun: Instant,
+    /// The Content-Length the client announced, which the body must match.
+    length: Option<usize>,
+    max_size: usize,
+}
+
+/// The reading half of a connection: everything it has received that the
+/// streams' threads do not need.
+struct Reader<'a> {
+    stream: &'a mut RawTcpStream,
+    buf: Vec<u8>,
+    ctx: &'a Arc<ServerContext>,
+    conn: &'a Arc<Conn>,
+    decoder: hpack::Decoder,
+    incoming: HashMap<u32, Incoming<'a>>,
+    /// A header block still awaiting CONTINUATION frames: its stream, the
+    /// flags of the HEADERS frame that began it, and the fragments so far.
+    continuation: Option<(u32, u8, Vec<u8>)>,
+    /// The highest stream id the client has opened.
+    last_stream_id: u32,
+    /// Set once either side has sent GOAWAY: no new streams are served,
+    /// and the connection ends when the open ones are done.
+    going_away: bool,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads frames until the connection ends, handing each complete
+    /// request to the pool. Fails with the reason for a connection error.
+    fn run(&mut self) -> Result<(), Reason> {
+        let mut idle_since = Instant::now();
+        loop {
+            let busy = !self.incoming.is_empty() || !self.conn.lock().streams.is_empty();
+            if busy {
+                idle_since = Instant::now();
+            } else if self.going_away {
+                return Ok(());
+            }
+            if !self.going_away && self.ctx.is_shutting_down() {
+                self.go_away()?;
+                continue;
+            }
+
+            let frame = match frame::read(self.stream, &mut self.buf, frame::DEFAULT_MAX_FRAME_SIZE) {
+                Ok(frame) => frame,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let idle_for = idle_since.elapsed();
+                    if !busy && self.ctx.keep_alive_timeout.is_some_and(|timeout| idle_for >= timeout) {
+                        self.go_away()?;
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::InvalidData => return Err(Reason::FrameSizeError),
+                // The peer went away, or the connection failed under us;
+                // there is no one left to tell.
+                Err(_) => {
+                    self.going_away = true;
+                    return Ok(());
+                }
+            };
+            self.handle(frame)?;
+        }
+    }
+
+    fn go_away(&mut self) -> Result<(), Reason> {
+        self.going_away = true;
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(Reason::NoError as u32).to_be_bytes());
+        self.conn.send(frame::GOAWAY, 0, 0, &payload).map_err(|_| Reason::InternalError)
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), Reason> {
+        // Nothing may come between a header block's frames (§6.10).
+        if let Some((stream_id, _, _)) = &self.continuation {
+            if frame.kind != frame::CONTINUATION || frame.stream_id != *stream_id {
+                return Err(Reason::ProtocolError);
+            }
+        }
+
+        match frame.kind {
+            frame::DATA => self.on_data(frame),
+            frame::HEADERS => self.on_headers(frame),
+            frame::CONTINUATION => {
+                let Some((stream_id, flags, mut block)) = self.continuation.take() else {
+                    return Err(Reason::ProtocolError);
+                };
+                block.extend_from_slice(&frame.payload);
+                if block.len() > self.ctx.head_limits.max_head_size {
+                    return Err(Reason::EnhanceYourCalm);
+                }
+                if frame.has(frame::END_HEADERS) {
+                    self.on_header_block(stream_id, flags & frame::END_STREAM != 0, &block)
+                } else {
+                    self.continuation = Some((stream_id, flags, block));
+                    Ok(())
+                }
+            }
+            frame::PRIORITY => match frame.payload.len() {
+                5 if frame.stream_id != 0 => Ok(()),
+                5 => Err(Reason::ProtocolError),
+                _ => Err(Reason::FrameSizeError),
+            },
+            frame::RST_STREAM => {
+                if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+                    return Err(Reason::ProtocolError);
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Reason::FrameSizeError);
+                }
+                self.incoming.remove(&frame.stream_id);
+                if let Some(state) = self.conn.lock().streams.get_mut(&frame.stream_id) {
+                    state.reset = true;
+                }
+                self.conn.changed.notify_all();
+                Ok(())
+            }
+            frame::SETTINGS => self.on_settings(frame),
+            frame::PING => {
+                if frame.stream_id != 0 {
+                    return Err(Reason::ProtocolError);
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Reason::FrameSizeError);
+                }
+                if frame.has(frame::ACK) {
+                    return Ok(());
+                }
+                self.conn.send(frame::PING, frame::ACK, 0, &frame.payload).map_err(|_| Reason::InternalError)
+            }
+            frame::GOAWAY => {
+                // Streams already opened are still answered.
+                self.going_away = true;
+                Ok(())
+            }
+            frame::WINDOW_UPDATE => self.on_window_update(frame),
+            // Clients cannot push (§8.2).
+            frame::PUSH_PROMISE => Err(Reason::ProtocolError),
+            // Unknown frame types are ignored (§4.1).
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Reason> {
+        if frame.stream_id.is_multiple_of(2) {
+            return Err(Reason::ProtocolError);
+        }
+        let mut block = frame.unpadded().ok_or(Reason::ProtocolError)?;
+        if frame.has(frame::PRIORITY_FLAG) {
+            block = block.get(5..).ok_or(Reason::FrameSizeError)?;
+        }
+        if block.len() > self.ctx.head_limits.max_head_size {
+            return Err(Reason::EnhanceYourCalm);
+        }
+        if frame.has(frame::END_HEADERS) {
+            let block = block.to_vec();
+            self.on_header_block(frame.stream_id, frame.has(frame::END_STREAM), &block)
+        } else {
+            self.continuation = Some((frame.stream_id, frame.flags, block.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Handles a complete header block: a new request, or the trailers of
+    /// one whose body is arriving.
+    fn on_header_block(&mut self, stream_id: u32, end_stream: bool, block: &[u8]) -> Result<(), Reason> {
+        // Every block is decoded, even for streams about to be refused, to
+        // keep the dynamic table in step with the client's.
+        let fields = self
+            .decoder
+            .decode(block, self.ctx.head_limits.max_head_size)
+            .map_err(|_| Reason::CompressionError)?;
+
+        if let Some(incoming) = self.incoming.get_mut(&stream_id) {
+            // Trailers, which must end the stream and carry no
+            // pseudo-headers (§8.1).
+            if !end_stream {
+                return Err(Reason::ProtocolError);
+            }
+            let Some(fields) = fields else {
+                if let Some(state) = self.conn.lock().streams.get_mut(&stream_id) {
+                    state.remote_open = false;
+                }
+                return self.refuse(stream_id, StatusCode::RequestHeaderFieldsTooLarge);
+            };
+            for field in &fields {
+                match trailer_field(field) {
+                    Some((name, value)) => incoming.request.trailers.append(name, value),
+                    None => return self.reset(stream_id, Reason::ProtocolError),
+                }
+            }
+            return self.finish_request(stream_id);
+        }
+        if stream_id <= self.last_stream_id {
+            return Err(Reason::StreamClosed);
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away {
+            return Ok(());
+        }
+
+        if self.conn.lock().streams.len() >= MAX_CONCURRENT_STREAMS {
+            return self.reset(stream_id, Reason::RefusedStream);
+        }
+        let initial_window = self.conn.lock().initial_window;
+        self.conn.lock().streams.insert(stream_id, StreamState::new(initial_window, !end_stream));
+
+        let mut request = match fields {
+            Some(fields) => match build_request(&fields, &self.ctx.head_limits) {
+                Ok(request) => request,
+                Err(Refused::Malformed) => return self.reset(stream_id, Reason::ProtocolError),
+                Err(Refused::Status(status)) => return self.refuse(stream_id, status),
+            },
+            None => return self.refuse(stream_id, StatusCode::RequestHeaderFieldsTooLarge),
+        };
+        request.peer_addr = self.stream.peer_addr().ok();
+        request.local_addr = self.stream.local_addr().ok();
+        request.client_ip = request.peer_addr.map(|addr| addr.ip());
+        let Ok(expects_continue) = request.expects_continue() else {
+            return self.refuse(stream_id, StatusCode::ExpectationFailed);
+        };
+
+        let length = match request.headers.get("content-length") {
+            Some(_) => match request.body_length() {
+                Ok(BodyLength::Fixed(len)) => Some(len),
+                _ => return self.reset(stream_id, Reason::ProtocolError),
+            },
+            None => None,
+        };
+        let max_size = self.ctx.router().max_body_size_for(&request).unwrap_or(self.ctx.max_body_size);
+        if length.is_some_and(|len| len > max_size) {
+            return self.refuse(stream_id, StatusCode::PayloadTooLarge);
+        }
+        let begun = Instant::now();
+        let body = Sink::new(self.ctx.spool_threshold, &self.ctx.spool_dir);
+        self.incoming.insert(stream_id, Incoming { request, body, begun, length, max_size });
+        if end_stream {
+            return self.finish_request(stream_id);
+        }
+        if expects_continue {
+            // Its handler has not started, so nothing else is written to
+            // the stream yet.
+            let block = hpack::encode(Some(100), std::iter::empty());
+            self.conn
+                .send(frame::HEADERS, frame::END_HEADERS, stream_id, &block)
+                .map_err(|_| Reason::InternalError)?;
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Reason> {
+        if frame.stream_id == 0 {
+            return Err(Reason::ProtocolError);
+        }
+        let data = frame.unpadded().ok_or(Reason::ProtocolError)?;
+        // The whole frame counts against the windows, padding included, and
+        // is given straight back: bodies are limited by size instead.
+        let len = frame.payload.len() as u32;
+        if len > 0 {
+            self.window_update(0, len)?;
+        }
+
+        let Some(incoming) = self.incoming.get_mut(&frame.stream_id) else {
+            // Data for a stream already answered or reset may still be on
+            // its way, and is dropped.
+            return match frame.stream_id > self.last_stream_id {
+                true => Err(Reason::ProtocolError),
+                false => Ok(()),
+            };
+        };
+        if incoming.body.len() + data.len() > incoming.max_size {
+            self.incoming.remove(&frame.stream_id);
+            return self.refuse(frame.stream_id, StatusCode::PayloadTooLarge);
+        }
+        if let Err(e) = incoming.body.write(data) {
+            error!("Error spooling request body: {}", e);
+            return self.reset(frame.stream_id, Reason::InternalError);
+        }
+
+        if frame.has(frame::END_STREAM) {
+            self.finish_request(frame.stream_id)
+        } else if len > 0 {
+            self.window_update(frame.stream_id, len)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Hands a stream's request to the pool now that the client has sent
+    /// all of it.
+    fn finish_request(&mut self, stream_id: u32) -> Result<(), Reason> {
+        let Some(Incoming { mut request, body, begun, length, .. }) = self.incoming.remove(&stream_id) else {
+            return Ok(());
+        };
+        if let Some(state) = self.conn.lock().streams.get_mut(&stream_id) {
+            state.remote_open = false;
+        }
+        match length {
+            Some(len) if len != body.len() => return self.reset(stream_id, Reason::ProtocolError),
+            Some(_) => {}
+            // Handlers see the body framed the way HTTP/1.1 would frame it.
+            None if body.len() > 0 || matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") => {
+                let len = body.len().to_string();
+                request.headers.append("content-length", &len);
+            }
+            None => {}
+        }
+        body.finish().store(&mut request);
+        self.dispatch(stream_id, Ok((request, begun)))
+    }
+
+    /// Answers a stream with an error page instead of its handler. Any
+    /// body still arriving is discarded.
+    fn refuse(&mut self, stream_id: u32, status: StatusCode) -> Result<(), Reason> {
+        self.incoming.remove(&stream_id);
+        self.dispatch(stream_id, Err(status))
+    }
+
+    /// Queues a stream to be served on the server's pool. When the pool's
+    /// queue is full the stream is refused with REFUSED_STREAM instead,
+    /// which tells the client it was not processed and may be retried.
+    fn dispatch(&mut self, stream_id: u32, outcome: Outcome) -> Result<(), Reason> {
+        let Some(slot) = self.ctx.pool.try_reserve() else {
+            return self.reset(stream_id, Reason::RefusedStream);
+        };
+        let serving = Serving::new(Arc::clone(self.conn), stream_id);
+        let ctx = Arc::clone(self.ctx);
+        slot.execute(move || {
+            serve_stream(&ctx, &serving.conn, stream_id, outcome);
+            drop(serving);
+        });
+        Ok(())
+    }
+
+    fn reset(&mut self, stream_id: u32, reason: Reason) -> Result<(), Reason> {
+        self.incoming.remove(&stream_id);
+        self.conn.lock().streams.remove(&stream_id);
+        self.conn.changed.notify_all();
+        self.conn
+            .send(frame::RST_STREAM, 0, stream_id, &(reason as u32).to_be_bytes())
+            .map_err(|_| Reason::InternalError)
+    }
+
+    fn window_update(&self, stream_id: u32, increment: u32) -> Result<(), Reason> {
+        self.conn
+            .send(frame::WINDOW_UPDATE, 0, stream_id, &increment.to_be_bytes())
+            .map_err(|_| Reason::InternalError)
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Reason> {
+        if frame.stream_id != 0 {
+            return Err(Reason::ProtocolError);
+        }
+        if frame.has(frame::ACK) {
+            return if frame.payload.is_empty() { Ok(()) } else { Err(Reason::FrameSizeError) };
+        }
+        let settings = frame::settings(&frame.payload).ok_or(Reason::FrameSizeError)?;
+        {
+            let mut out = self.conn.lock();
+            for (id, value) in settings {
+                match id {
+                    frame::SETTINGS_INITIAL_WINDOW_SIZE => {
+                        let value = value as i64;
+                        if value > frame::MAX_WINDOW {
+                            return Err(Reason::FlowControlError);
+                        }
+                        // Open streams' windows move by the change (§6.9.2).
+                        let delta = value - out.initial_window;
+                        out.initial_window = value;
+                        for state in out.streams.values_mut() {
+                            state.window += delta;
+                            if state.window > frame::MAX_WINDOW {
+                                return Err(Reason::FlowControlError);
+                            }
+                        }
+                    }
+                    frame::SETTINGS_MAX_FRAME_SIZE => {
+                        if !(frame::DEFAULT_MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                            return Err(Reason::ProtocolError);
+                        }
+                        out.max_frame_size = value as usize;
+                    }
+                    frame::SETTINGS_ENABLE_PUSH if value > 1 => return Err(Reason::ProtocolError),
+                    // We never index response headers, so the size of the
+                    // client's table does not matter, nor does any other
+                    // setting a server never acts on.
+                    _ => {}
+                }
+            }
+        }
+        self.conn.changed.notify_all();
+        self.conn.send(frame::SETTINGS, frame::ACK, 0, &[]).map_err(|_| Reason::InternalError)
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Reason> {
+        let increment = frame::u31(&frame.payload).ok_or(Reason::FrameSizeError)? as i64;
+        if increment == 0 {
+            return match frame.stream_id {
+                0 => Err(Reason::ProtocolError),
+                stream_id => self.reset(stream_id, Reason::ProtocolError),
+            };
+        }
+        let overflowed = {
+            let mut out = self.conn.lock();
+            if frame.stream_id == 0 {
+                out.window += increment;
+                if out.window > frame::MAX_WINDOW {
+                    return Err(Reason::FlowControlError);
+                }
+                false
+            } else {
+                // Updates for streams already finished are harmless.
+                out.streams.get_mut(&frame.stream_id).is_some_and(|state| {
+                    state.window += increment;
+                    state.window > frame::MAX_WINDOW
+                })
+            }
+        };
+        self.conn.changed.notify_all();
+        if overflowed {
+            return self.reset(frame.stream_id, Reason::FlowControlError);
+        }
+        Ok(())
+    }
+}
+
+/// Why a request's header block cannot be served.
+enum Refused {
+    /// It breaks HTTP/2's rules, and the stream is reset.
+    Malformed,
+    /// It is well formed but refused, like an HTTP/1 head would be.
+    Status(StatusCode),
+}
+
+/// A trailer field as text, or `None` if it is a pseudo-header or would
+/// not be allowed among a request's headers.
+fn trailer_field((name, value): &Field) -> Option<(&str, &str)> {
+    let name = std::str::from_utf8(name).ok()?;
+    let value = std::str::from_utf8(value).ok()?;
+    let valid = !name.is_empty()
+        && name.bytes().all(|b| is_token_byte(b) && !b.is_ascii_uppercase())
+        && !value.contains(['\r', '\n', '\0']);
+    valid.then_some((name, value))
+}
+
+/// Turns a request's decoded header fields into a [`Request`], checked and
+/// normalized exactly as an HTTP/1.1 head with the same fields would be.
+fn build_request(fields: &[Field], limits: &HeadLimits) -> Result<Request, Refused> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut scheme = None;
+    let mut headers = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (std::str::from_utf8(name), std::str::from_utf8(value)) else {
+            return Err(Refused::Malformed);
+        };
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(Refused::Malformed);
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // Pseudo-headers come first, each at most once (§8.1.2.1).
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                "scheme" => &mut scheme,
+                _ => return Err(Refused::Malformed),
+            };
+            if !headers.is_empty() || !cookies.is_empty() || slot.replace(value).is_some() {
+                return Err(Refused::Malformed);
+            }
+            continue;
+        }
+        if name.bytes().any(|b| b.is_ascii_uppercase()) || CONNECTION_HEADERS.contains(&name) {
+            return Err(Refused::Malformed);
+        }
+        if name == "te" && value != "trailers" {
+            return Err(Refused::Malformed);
+        }
+        if name == "cookie" {
+            // Cookies may arrive split into one field each (§8.1.2.5).
+            cookies.push(value);
+        } else {
+            headers.push((name, value));
+        }
+    }
+
+    let method = method.ok_or(Refused::Malformed)?;
+    let target = if method == "CONNECT" {
+        authority.ok_or(Refused::Malformed)?
+    } else {
+        scheme.ok_or(Refused::Malformed)?;
+        path.filter(|path| !path.is_empty()).ok_or(Refused::Malformed)?
+    };
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, target);
+    if let Some(authority) = authority.filter(|_| !headers.iter().any(|&(name, _)| name == "host")) {
+        head.push_str(&format!("host: {}\r\n", authority));
+    }
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !cookies.is_empty() {
+        head.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+    head.push_str("\r\n");
+
+    // The pseudo-header line stands in for the request line, so its own
+    // limit applies to it rather than the one on header lines.
+    let (mut request, _) = Request::parse_with_limits(head.as_bytes(), limits)
+        .map_err(|e| match HyperportError::from(e).status() {
+            Some(status) => Refused::Status(status),
+            None => Refused::Malformed,
+        })?;
+    request.version = Version::Http2;
+    Ok(request)
+}
+
+/// Serves one stream's request, or answers it with `status` when it was
+/// refused, then closes the stream.
+fn serve_stream(ctx: &ServerContext, conn: &Conn, stream_id: u32, outcome: Outcome) {
+    let router = ctx.router();
+    // Decoded here rather than by the reading thread, which every other
+    // stream is waiting on.
+    let outcome = outcome.and_then(|(mut request, begun)| {
+        let max_size = router.max_body_size_for(&request).unwrap_or(ctx.max_body_size);
+        match body::decode(&mut request, max_size, ctx.spool_threshold, &ctx.spool_dir) {
+            Ok(()) => Ok((request, begun)),
+            Err(e) => Err(e.status().unwrap_or_else(|| {
+                error!("Error decoding request body: {}", e);
+                StatusCode::InternalServerError
+            })),
+        }
+    });
+    let mut writer = ResponseWriter::http2(StreamSink { conn, stream_id });
+    writer.set_server_header(ctx.server_header.as_deref());
+    let failed = match outcome {
+        Ok((mut request, begun)) => {
+            writer.set_error_pages(router.error_pages_for(&request));
+            if request.method == "HEAD" {
+                writer.set_head_only();
+            }
+            let span = ctx.tracer.as_ref().and_then(|tracer| tracer.start_request(&mut request));
+            let dispatched = Instant::now();
+            let result = router.dispatch(&mut request, &mut writer);
+            if let Err(e) = &result {
+                error!("Error handling {} {}: {}", request.method, request.target, e);
+            }
+            let handler_failed = result.is_err();
+            // As with HTTP/1, a handler that failed before writing anything
+            // gets a 500; one that failed midway has its stream reset.
+            let failed = match result {
+                Err(_) if writer.is_started() => true,
+                _ => writer.finish().is_err(),
+            };
+            if let Some(span) = span {
+                span.finish(&request, writer.status(), handler_failed);
+            }
+            log_if_slow(ctx, &request, &writer, begun, dispatched);
+            failed
+        }
+        Err(status) => {
+            writer.set_error_pages(router.error_pages());
+            writer.send_error(status).is_err()
+        }
+    };
+    if let Some(status) = writer.status() {
+        stats::record_response(status);
+    }
+    drop(writer);
+    conn.close_stream(stream_id, failed);
+}
+
+/// The writing half of a connection, shared by every stream.
+struct Conn {
+    out: Mutex<Output>,
+    /// Signalled when a window opens, a stream is reset or the connection
+    /// closes, any of which may release a blocked writer.
+    changed: Condvar,
+    write_timeout: Option<Duration>,
+}
+
+struct Output {
+    stream: RawTcpStream,
+    /// The connection's send window.
+    window: i64,
+    /// The send window new streams start with.
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Streams whose response is still being written.
+    streams: HashMap<u32, StreamState>,
+    /// Streams handed to the pool and not yet finished with.
+    serving: usize,
+    closed: bool,
+}
+
+/// Counts a stream handed to the pool until dropped, so the connection
+/// can tell when nothing is left. A stream whose handler panicked, and so
+/// never closed it, is reset here.
+struct Serving {
+    conn: Arc<Conn>,
+    stream_id: u32,
+}
+
+impl Serving {
+    fn new(conn: Arc<Conn>, stream_id: u32) -> Self {
+        conn.lock().serving += 1;
+        Serving { conn, stream_id }
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        self.conn.close_stream(self.stream_id, true);
+        self.conn.lock().serving -= 1;
+        self.conn.changed.notify_all();
+    }
+}
+
+struct StreamState {
+    window: i64,
+    /// The client reset the stream.
+    reset: bool,
+    /// We have sent END_STREAM.
+    ended: bool,
+    /// The client has not sent END_STREAM yet.
+    remote_open: bool,
+}
+
+impl StreamState {
+    fn new(window: i64, remote_open: bool) -> Self {
+        StreamState { window, reset: false, ended: false, remote_open }
+    }
+}
+
+impl Conn {
+    fn lock(&self) -> MutexGuard<'_, Output> {
+        self.out.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes a single frame, which is not subject to flow control.
+    fn send(&self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        let mut out = self.lock();
+        let mut bytes = Vec::with_capacity(9 + payload.len());
+        frame::encode(&mut bytes, kind, flags, stream_id, payload);
+        out.write(&bytes)
+    }
+
+    /// Ends a stream once its handler is done. A response that never
+    /// reached END_STREAM is reset; so is a request the client is still
+    /// sending, which will not be read now that it has been answered.
+    fn close_stream(&self, stream_id: u32, failed: bool) {
+        let mut out = self.lock();
+        let Some(state) = out.streams.remove(&stream_id) else {
+            return;
+        };
+        let reason = match state {
+            StreamState { reset: true, .. } => None,
+            StreamState { ended: false, .. } if failed => Some(Reason::InternalError),
+            StreamState { ended: false, .. } => Some(Reason::Cancel),
+            StreamState { remote_open: true, .. } => Some(Reason::NoError),
+            _ => None,
+        };
+        if let Some(reason) = reason {
+            let mut bytes = Vec::with_capacity(13);
+            frame::encode(&mut bytes, frame::RST_STREAM, 0, stream_id, &(reason as u32).to_be_bytes());
+            let _ = out.write(&bytes);
+        }
+    }
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        if let Err(e) = self.stream.write_all(bytes) {
+            self.closed = true;
+            return Err(e);
+        }
+        BYTES_SENT.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The stream's state, or an error if it can no longer be written to.
+    fn writable(&mut self, stream_id: u32) -> io::Result<&mut StreamState> {
+        if self.closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        match self.streams.get_mut(&stream_id) {
+            Some(state) if !state.reset && !state.ended => Ok(state),
+            _ => Err(io::Error::new(ErrorKind::ConnectionReset, "stream reset")),
+        }
+    }
+}
+
+/// Where a [`ResponseWriter`] serving an HTTP/2 stream sends its output.
+pub(crate) struct StreamSink<'a> {
+    conn: &'a Conn,
+    stream_id: u32,
+}
+
+impl StreamSink<'_> {
+    /// Sends the response head as HEADERS, split into CONTINUATION frames
+    /// as needed. Header names are lowercased, and those HTTP/2 forbids
+    /// are dropped.
+    pub(crate) fn headers<'h>(
+        &mut self,
+        status: StatusCode,
+        headers: impl Iterator<Item = (&'h str, &'h str)>,
+        end_stream: bool,
+    ) -> io::Result<()> {
+        self.send_fields(Some(status), headers, end_stream)
+    }
+
+    /// Sends trailers after the body, ending the stream.
+    pub(crate) fn trailers<'h>(&mut self, trailers: impl Iterator<Item = (&'h str, &'h str)>) -> io::Result<()> {
+        self.send_fields(None, trailers, true)
+    }
+
+    fn send_fields<'h>(
+        &mut self,
+        status: Option<StatusCode>,
+        headers: impl Iterator<Item = (&'h str, &'h str)>,
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let lowercase: Vec<(String, &str)> = headers
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+            .collect();
+        let block = hpack::encode(
+            status.map(StatusCode::as_u16),
+            lowercase.iter().map(|(name, value)| (name.as_str(), *value)),
+        );
+
+        let mut out = self.conn.lock();
+        out.writable(self.stream_id)?;
+        let mut bytes = Vec::with_capacity(block.len() + 18);
+        let mut chunks = block.chunks(out.max_frame_size).peekable();
+        let mut kind = frame::HEADERS;
+        let mut flags = if end_stream { frame::END_STREAM } else { 0 };
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            if chunks.peek().is_none() {
+                flags |= frame::END_HEADERS;
+            }
+            frame::encode(&mut bytes, kind, flags, self.stream_id, chunk);
+            if chunks.peek().is_none() {
+                break;
+            }
+            kind = frame::CONTINUATION;
+            flags = 0;
+        }
+        out.write(&bytes)?;
+        if end_stream {
+            out.writable(self.stream_id)?.ended = true;
+        }
+        Ok(())
+    }
+
+    /// Sends body bytes as DATA frames, as the flow-control windows allow,
+    /// with END_STREAM on the last when `end_stream` is set.
+    pub(crate) fn data(&mut self, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        let mut out = self.conn.lock();
+        loop {
+            let max_frame_size = out.max_frame_size as i64;
+            let window = out.window;
+            let state = out.writable(self.stream_id)?;
+            let allowed = window.min(state.window).min(max_frame_size).max(0) as usize;
+            if allowed == 0 && !data.is_empty() {
+                out = self.wait(out)?;
+                continue;
+            }
+            let len = allowed.min(data.len());
+            let last = len == data.len();
+            state.window -= len as i64;
+            state.ended = last && end_stream;
+            out.window -= len as i64;
+
+            let mut bytes = Vec::with_capacity(9 + len);
+            let flags = if last && end_stream { frame::END_STREAM } else { 0 };
+            frame::encode(&mut bytes, frame::DATA, flags, self.stream_id, &data[..len]);
+            out.write(&bytes)?;
+            data = &data[len..];
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for something to change while a window is closed, for no
+    /// longer than the write timeout.
+    fn wait<'a>(&self, out: MutexGuard<'a, Output>) -> io::Result<MutexGuard<'a, Output>> {
+        match self.conn.write_timeout {
+            Some(timeout) => {
+                let (out, result) = self.conn.changed.wait_timeout(out, timeout).unwrap_or_else(|e| e.into_inner());
+                if result.timed_out() {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "flow-control window stayed closed"));
+                }
+                Ok(out)
+            }
+            None => Ok(self.conn.changed.wait(out).unwrap_or_else(|e| e.into_inner())),
+        }
+    }
+}
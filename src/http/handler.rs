@@ -0,0 +1,54 @@
+use std::io;
+
+use crate::http::request::Request;
+use crate::http::response::{Response, ResponseWriter};
+
+/// Produces the response for a request by writing it to `writer`.
+///
+/// Handlers stream rather than return a [`Response`]: the writer knows
+/// whether the request was `HEAD` and drops the body, frames bodies of
+/// unknown length as chunked (or as DATA frames on HTTP/2), and can be kept
+/// open for server-sent events, WebSocket upgrades and file transfers with
+/// sendfile(2), none of which fit in a value built up front. Handlers that
+/// do build the whole reply first can be written as functions returning
+/// one and wrapped with [`respond`].
+///
+/// Closures taking `(&Request, &mut ResponseWriter)` implement this
+/// automatically; implement it by hand for handlers that carry state, such
+/// as [`StaticFiles`](crate::http::StaticFiles).
+pub trait Handler: Send + Sync {
+    fn call(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync,
+{
+    fn call(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        self(request, writer)
+    }
+}
+
+/// A handler sending the [`Response`] that `f` returns for each request.
+///
+/// ```ignore
+/// router.handle("GET", "/health", handler::respond(|_| Response::builder().body("ok")));
+/// ```
+pub fn respond<F>(f: F) -> Respond<F>
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    Respond(f)
+}
+
+/// The handler made by [`respond`].
+pub struct Respond<F>(F);
+
+impl<F> Handler for Respond<F>
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn call(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        writer.respond(&(self.0)(request))
+    }
+}
@@ -0,0 +1,507 @@
+//! Bearer token authentication (RFC 6750) with JSON Web Tokens (RFC 7519):
+//! signed claims about the client, checked with a shared secret or the
+//! issuer's public keys.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http::basic_auth::under_prefixes;
+use crate::http::client::Client;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::util::json::{self, Value};
+use crate::util::{base64, bcrypt, rsa, sha256};
+use crate::{debug, warn};
+
+/// How soon a token signed with a key not in the fetched set may have the
+/// set fetched again, in case the issuer has rotated its keys.
+const MIN_REFETCH: Duration = Duration::from_secs(60);
+
+/// RSA keys shorter than this many bits are ignored (RFC 7518 §3.3).
+const MIN_RSA_BITS: usize = 2048;
+
+/// The claims of a validated token, its payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims {
+    json: String,
+    value: Value,
+}
+
+impl Claims {
+    /// The `sub` claim: whom the token is about, usually a user ID.
+    pub fn subject(&self) -> Option<&str> {
+        self.string("sub")
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        self.value.get(name)?.as_str()
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        self.value.get(name)?.as_f64()
+    }
+
+    pub fn boolean(&self, name: &str) -> Option<bool> {
+        match self.value.get(name)? {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// A claim holding one string or an array of them, such as `aud` or a
+    /// list of roles. Empty if it is missing or holds anything else.
+    pub fn strings(&self, name: &str) -> Vec<&str> {
+        match self.value.get(name) {
+            Some(Value::String(s)) => vec![s.as_str()],
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The payload as the token carried it, for claims of other shapes.
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+}
+
+/// An RSA public key from a JSON Web Key Set.
+#[derive(Clone, Debug)]
+struct RsaKey {
+    kid: Option<String>,
+    n: Vec<u8>,
+    e: Vec<u8>,
+}
+
+/// Keys fetched from [`JwtAuth::jwks_url`], and when that was last tried.
+#[derive(Default)]
+struct Fetched {
+    keys: Arc<Vec<RsaKey>>,
+    attempted: Option<Instant>,
+}
+
+/// The key set at a [`JwtAuth::jwks_url`], shared with the threads
+/// fetching it.
+struct RemoteJwks {
+    url: String,
+    client: Client,
+    fetched: RwLock<Fetched>,
+    /// Set while a fetch is under way, so that requests arriving meanwhile
+    /// go on with the keys already fetched rather than start another.
+    fetching: AtomicBool,
+}
+
+impl RemoteJwks {
+    /// Fetches the set on a thread of its own, unless a fetch is under way
+    /// already.
+    fn refresh(self: &Arc<Self>) {
+        if self.fetching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let jwks = Arc::clone(self);
+        let spawned = thread::Builder::new().name("jwks".to_string()).spawn(move || {
+            jwks.fetch();
+            jwks.fetching.store(false, Ordering::Release);
+        });
+        if let Err(e) = spawned {
+            warn!("Could not start fetching keys from {}: {}", self.url, e);
+            self.fetching.store(false, Ordering::Release);
+        }
+    }
+
+    fn fetch(&self) {
+        let result = self.client.get(&self.url).and_then(|response| {
+            if response.status != StatusCode::Ok {
+                return Err(io::Error::other(format!("status {}", response.status.as_u16())));
+            }
+            std::str::from_utf8(&response.body)
+                .ok()
+                .and_then(parse_jwks)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a JWK set"))
+        });
+        let mut fetched = self.fetched.write().unwrap_or_else(|e| e.into_inner());
+        fetched.attempted = Some(Instant::now());
+        match result {
+            Ok(keys) => {
+                debug!("Fetched {} keys from {}", keys.len(), self.url);
+                fetched.keys = Arc::new(keys);
+            }
+            Err(e) => warn!("Fetching keys from {} failed: {}", self.url, e),
+        }
+    }
+}
+
+/// Middleware requiring a valid JWT in an `Authorization: Bearer` header.
+///
+/// Tokens signed with HS256 are checked against the secret given to
+/// [`hs256`](JwtAuth::hs256), those signed with RS256 against the RSA keys
+/// of a JSON Web Key Set, read from a file with
+/// [`jwks_file`](JwtAuth::jwks_file) or fetched with
+/// [`jwks_url`](JwtAuth::jwks_url); an RS256 token naming a key ID is only
+/// checked against that key. Other algorithms, `none` included, are
+/// refused. The token must have an `exp` claim in the future; `nbf`, when
+/// present, must be in the past; both allow for some clock skew, see
+/// [`leeway`](JwtAuth::leeway). [`issuer`](JwtAuth::issuer) and
+/// [`audience`](JwtAuth::audience) additionally require matching `iss` and
+/// `aud` claims.
+///
+/// Requests without a valid token get a 401 with a `WWW-Authenticate:
+/// Bearer` challenge, saying `invalid_token` and why if there was one. The
+/// others go on with the token's claims in [`Request::claims`] and its
+/// `sub` claim in [`Request::user`]. Only requests under
+/// [`paths`](JwtAuth::paths) are checked, all of them by default.
+///
+/// ```ignore
+/// router.wrap(
+///     JwtAuth::new()
+///         .jwks_url("http://127.0.0.1:8000/.well-known/jwks.json")?
+///         .issuer("https://auth.example.com")
+///         .audience(&["api"])
+///         .paths(&["/api"]),
+/// );
+/// ```
+pub struct JwtAuth {
+    secret: Option<Vec<u8>>,
+    /// Keys from [`jwks_file`](JwtAuth::jwks_file).
+    keys: Vec<RsaKey>,
+    jwks: Option<Arc<RemoteJwks>>,
+    jwks_refresh: Duration,
+    issuer: Option<String>,
+    audience: Vec<String>,
+    leeway: Duration,
+    realm: Option<String>,
+    /// Path prefixes, or empty for every path.
+    paths: Vec<String>,
+}
+
+impl Default for JwtAuth {
+    fn default() -> Self {
+        JwtAuth {
+            secret: None,
+            keys: Vec::new(),
+            jwks: None,
+            jwks_refresh: Duration::from_secs(3600),
+            issuer: None,
+            audience: Vec::new(),
+            leeway: Duration::from_secs(60),
+            realm: None,
+            paths: Vec::new(),
+        }
+    }
+}
+
+impl JwtAuth {
+    /// No keys yet, so no token is accepted until some are given.
+    pub fn new() -> Self {
+        JwtAuth::default()
+    }
+
+    /// Accepts HS256 tokens signed with `secret`.
+    pub fn hs256(mut self, secret: &[u8]) -> Self {
+        self.secret = Some(secret.to_vec());
+        self
+    }
+
+    /// Accepts RS256 tokens signed with the RSA keys of the JSON Web Key
+    /// Set in the file at `path`. Keys of other types, or shorter than 2048
+    /// bits, are skipped.
+    pub fn jwks_file<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let keys = parse_jwks(&text).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a JWK set"))?;
+        self.keys.extend(keys);
+        Ok(self)
+    }
+
+    /// Accepts RS256 tokens signed with the RSA keys of the JSON Web Key
+    /// Set at `url`, which must be `http://` on a loopback address: over
+    /// any other network whoever sits on the path could hand out keys of
+    /// their own and sign whatever tokens they like. Use
+    /// [`jwks_file`](JwtAuth::jwks_file) for sets from elsewhere.
+    ///
+    /// The set is fetched in the background, starting now, then again every
+    /// [`jwks_refresh`](JwtAuth::jwks_refresh), or sooner, though at most
+    /// once a minute, when a token names a key it lacks. Tokens are checked
+    /// against the keys fetched so far meanwhile, and should a fetch fail,
+    /// those stay in use.
+    pub fn jwks_url(mut self, url: &str) -> io::Result<Self> {
+        if !is_loopback_http(url) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "JWK set URLs must be http:// on a loopback address; use a JWK set file instead",
+            ));
+        }
+        let jwks = Arc::new(RemoteJwks {
+            url: url.to_string(),
+            client: Client::new().connect_timeout(Duration::from_secs(2)).timeout(Duration::from_secs(5)),
+            fetched: RwLock::new(Fetched::default()),
+            fetching: AtomicBool::new(false),
+        });
+        jwks.refresh();
+        self.jwks = Some(jwks);
+        Ok(self)
+    }
+
+    /// How long fetched keys are used before the set is fetched again.
+    /// Defaults to an hour.
+    pub fn jwks_refresh(mut self, refresh: Duration) -> Self {
+        self.jwks_refresh = refresh;
+        self
+    }
+
+    /// Requires the `iss` claim to be `issuer`.
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Requires the `aud` claim to name one of `audience`.
+    pub fn audience<S: AsRef<str>>(mut self, audience: &[S]) -> Self {
+        self.audience = audience.iter().map(|aud| aud.as_ref().to_string()).collect();
+        self
+    }
+
+    /// How far the clocks of the issuer and this server may disagree when
+    /// checking `exp` and `nbf`. Defaults to 60 seconds.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The realm named in challenges. Defaults to none.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = Some(realm.to_string());
+        self
+    }
+
+    /// Checks only requests whose paths start with one of `prefixes`,
+    /// taken as whole segments, as for [`BasicAuth::paths`](crate::http::BasicAuth::paths).
+    pub fn paths<S: AsRef<str>>(mut self, prefixes: &[S]) -> Self {
+        self.paths = prefixes.iter().map(|prefix| prefix.as_ref().trim_end_matches('/').to_string()).collect();
+        self
+    }
+
+    /// The claims of `token` if it is valid.
+    pub fn validate(&self, token: &str) -> Option<Claims> {
+        self.check(token).ok()
+    }
+
+    /// The claims of `token`, or why it is not valid.
+    fn check(&self, token: &str) -> Result<Claims, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed token");
+        };
+        let decode = |part: &str| base64::decode_url(part).ok_or("malformed token");
+        let header = String::from_utf8(decode(header)?).ok().and_then(|text| json::parse(&text));
+        let Some(header @ Value::Object(_)) = header else {
+            return Err("malformed token");
+        };
+        let signature = decode(signature)?;
+        // Everything before the signature's dot is what was signed.
+        let signed = token.rsplit_once('.').map_or("", |(signed, _)| signed).as_bytes();
+
+        if header.get("crit").is_some() {
+            return Err("unsupported critical header");
+        }
+        let verified = match header.get("alg").and_then(Value::as_str) {
+            Some("HS256") => match &self.secret {
+                Some(secret) => bcrypt::constant_time_eq(&sha256::hmac_sha256(secret, signed), &signature),
+                None => return Err("unsupported algorithm"),
+            },
+            Some("RS256") if !self.keys.is_empty() || self.jwks.is_some() => {
+                let kid = header.get("kid").and_then(Value::as_str);
+                let fetched = self.fetched_keys(kid);
+                let matches = |key: &&RsaKey| kid.is_none() || key.kid.as_deref() == kid;
+                let mut keys = self.keys.iter().chain(fetched.iter()).filter(matches).peekable();
+                if keys.peek().is_none() {
+                    return Err("unknown key");
+                }
+                keys.any(|key| rsa::verify_sha256(&key.n, &key.e, signed, &signature))
+            }
+            _ => return Err("unsupported algorithm"),
+        };
+        if !verified {
+            return Err("bad signature");
+        }
+
+        let json = String::from_utf8(decode(payload)?).map_err(|_| "malformed token")?;
+        let Some(value @ Value::Object(_)) = json::parse(&json) else {
+            return Err("malformed token");
+        };
+        let claims = Claims { json, value };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let leeway = self.leeway.as_secs_f64();
+        match claims.number("exp") {
+            Some(exp) if now < exp + leeway => {}
+            Some(_) => return Err("token expired"),
+            None => return Err("token has no expiry"),
+        }
+        if claims.value.get("nbf").is_some() && claims.number("nbf").is_none_or(|nbf| now + leeway < nbf) {
+            return Err("token not yet valid");
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.string("iss") != Some(issuer.as_str()) {
+                return Err("wrong issuer");
+            }
+        }
+        if !self.audience.is_empty() && !claims.strings("aud").iter().any(|aud| self.audience.iter().any(|a| a == aud))
+        {
+            return Err("wrong audience");
+        }
+        Ok(claims)
+    }
+
+    /// The keys fetched from [`jwks_url`](JwtAuth::jwks_url) so far. When
+    /// they are due, or when a token names key `kid`, which they lack, and
+    /// they may be fetched again already, a fetch is started for the
+    /// requests to come.
+    fn fetched_keys(&self, kid: Option<&str>) -> Arc<Vec<RsaKey>> {
+        let Some(jwks) = &self.jwks else {
+            return Arc::default();
+        };
+        let fetched = jwks.fetched.read().unwrap_or_else(|e| e.into_inner());
+        let due = match fetched.attempted {
+            None => true,
+            Some(attempted) => {
+                let elapsed = attempted.elapsed();
+                let missing =
+                    kid.is_some() && !self.keys.iter().chain(fetched.keys.iter()).any(|key| key.kid.as_deref() == kid);
+                elapsed >= self.jwks_refresh || (missing && elapsed >= MIN_REFETCH)
+            }
+        };
+        let keys = Arc::clone(&fetched.keys);
+        drop(fetched);
+        if due {
+            jwks.refresh();
+        }
+        keys
+    }
+
+    fn challenge(&self, error: Option<&str>) -> String {
+        let mut params = Vec::new();
+        if let Some(realm) = &self.realm {
+            params.push(format!("realm=\"{}\"", realm.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        if let Some(error) = error {
+            params.push("error=\"invalid_token\"".to_string());
+            params.push(format!("error_description=\"{}\"", error));
+        }
+        if params.is_empty() {
+            "Bearer".to_string()
+        } else {
+            format!("Bearer {}", params.join(", "))
+        }
+    }
+}
+
+impl Middleware for JwtAuth {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        if !under_prefixes(&self.paths, &request.path) {
+            return next.run(request, writer);
+        }
+        let token = request.headers.get("authorization").and_then(|value| {
+            let (scheme, token) = value.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+        });
+        let error = match token.as_deref().map(|token| self.check(token)) {
+            Some(Ok(claims)) => {
+                request.user = claims.subject().map(str::to_string);
+                request.claims = Some(claims);
+                return next.run(request, writer);
+            }
+            Some(Err(error)) => {
+                debug!("Refused bearer token for {} {}: {}", request.method, request.path, error);
+                Some(error)
+            }
+            None => None,
+        };
+        writer.add_header("WWW-Authenticate", &self.challenge(error));
+        writer.send_error(StatusCode::Unauthorized)
+    }
+}
+
+/// Whether `url` is `http://` on `localhost` or a loopback address, the
+/// only place a key set may be fetched from without TLS.
+pub(crate) fn is_loopback_http(url: &str) -> bool {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    if authority.contains('@') {
+        return false;
+    }
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map_or("", |(host, _)| host),
+        None => authority.split(':').next().unwrap_or(""),
+    };
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// The RSA signing keys of a JSON Web Key Set (RFC 7517 §5), skipping
+/// others. `None` if `text` is not a key set.
+fn parse_jwks(text: &str) -> Option<Vec<RsaKey>> {
+    let Some(Value::Array(keys)) = json::parse(text)?.get("keys").cloned() else {
+        return None;
+    };
+    let keys = keys
+        .iter()
+        .filter(|key| key.get("kty").and_then(Value::as_str) == Some("RSA"))
+        .filter(|key| key.get("use").is_none_or(|usage| usage.as_str() == Some("sig")))
+        .filter(|key| key.get("alg").is_none_or(|alg| alg.as_str() == Some("RS256")))
+        .filter_map(|key| {
+            let n = base64::decode_url(key.get("n")?.as_str()?)?;
+            let e = base64::decode_url(key.get("e")?.as_str()?)?;
+            let bits = n.iter().skip_while(|&&b| b == 0).count() * 8;
+            (bits >= MIN_RSA_BITS).then(|| RsaKey {
+                kid: key.get("kid").and_then(Value::as_str).map(str::to_string),
+                n,
+                e,
+            })
+        })
+        .collect();
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_loopback_http_urls_are_fetched() {
+        for url in [
+            "http://localhost/jwks.json",
+            "http://LOCALHOST:8000/jwks.json",
+            "http://127.0.0.1:8000/jwks.json",
+            "http://127.8.9.10/",
+            "http://[::1]:8000/jwks.json",
+        ] {
+            assert!(is_loopback_http(url), "{} should be allowed", url);
+        }
+        for url in [
+            "https://127.0.0.1/jwks.json",
+            "http://auth.internal/jwks.json",
+            "http://10.0.0.1/jwks.json",
+            "http://localhost.evil.com/jwks.json",
+            "http://127.0.0.1@evil.com/jwks.json",
+            "http://[::2]/jwks.json",
+            "http:///jwks.json",
+        ] {
+            assert!(!is_loopback_http(url), "{} should be refused", url);
+        }
+    }
+
+    #[test]
+    fn jwks_url_refuses_other_hosts() {
+        assert!(JwtAuth::new().jwks_url("http://auth.example.com/jwks.json").is_err());
+    }
+}
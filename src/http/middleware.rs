@@ -0,0 +1,52 @@
+use std::io;
+
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::router::Router;
+
+/// Wraps request handling. Implementations can inspect or modify the request,
+/// queue response headers, short-circuit by writing a response themselves,
+/// or call `next.run` and act once the inner layers are done.
+///
+/// Closures taking the same arguments are middleware too:
+///
+/// ```ignore
+/// router.wrap(|request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>| {
+///     writer.add_header("X-Powered-By", "hyperport");
+///     next.run(request, writer)
+/// });
+/// ```
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, &mut ResponseWriter, Next<'_>) -> io::Result<()> + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        self(request, writer, next)
+    }
+}
+
+/// The remainder of the chain: any inner middleware, then the router.
+pub struct Next<'a> {
+    pub(crate) chain: &'a [Box<dyn Middleware>],
+    pub(crate) router: &'a Router,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.handle(
+                request,
+                writer,
+                Next {
+                    chain: rest,
+                    router: self.router,
+                },
+            ),
+            None => self.router.route_request(request, writer),
+        }
+    }
+}
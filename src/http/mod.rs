@@ -0,0 +1,76 @@
+//! HTTP/1.x and HTTP/2 request parsing, response writing and routing, and
+//! a [`Client`] for requests going the other way.
+//!
+//! A [`Router`] maps method and path patterns to handlers, each of which
+//! receives the parsed [`Request`] and a streaming [`ResponseWriter`]. Any
+//! closure of that shape is a [`Handler`]. [`Middleware`] wraps every
+//! dispatch.
+
+pub mod access_log;
+pub mod basic_auth;
+pub(crate) mod body;
+pub mod chunked;
+pub mod client;
+pub mod compression;
+pub(crate) mod connection;
+pub mod cookie;
+pub mod cors;
+pub mod error_pages;
+pub mod forwarded;
+pub(crate) mod h2;
+pub mod handler;
+pub mod jwt_auth;
+pub mod middleware;
+pub mod multipart;
+pub mod proxy;
+pub mod query;
+pub mod rate_limit;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod router;
+pub mod security_headers;
+pub mod sse;
+pub mod static_files;
+pub mod status;
+pub mod websocket;
+
+pub use basic_auth::BasicAuth;
+pub use client::{Client, ClientResponse};
+pub use compression::Compression;
+pub use cookie::{Cookies, SameSite, SetCookie};
+pub use cors::Cors;
+pub use error_pages::ErrorPage;
+pub use forwarded::TrustedProxies;
+pub use handler::Handler;
+pub use jwt_auth::{Claims, JwtAuth};
+pub use access_log::{AccessLog, LogFormat};
+pub use middleware::{Middleware, Next};
+pub use multipart::Multipart;
+pub use proxy::Proxy;
+pub use query::Query;
+pub use rate_limit::{Limit, RateLimit};
+pub use request::{BodyReader, HeadLimits, Headers, Request, Version};
+pub use request_id::RequestId;
+pub use response::{Response, ResponseWriter};
+pub use router::Router;
+pub use security_headers::SecurityHeaders;
+pub use static_files::StaticFiles;
+pub use status::StatusCode;
+
+/// Renders a small HTML page describing `status`.
+pub fn error_page(status: StatusCode) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{}</title>
+</head>
+<body>
+    <h1>{}</h1>
+</body>
+</html>"#,
+        status.reason_phrase(),
+        status
+    )
+}
@@ -0,0 +1,422 @@
+//! Streaming `multipart/form-data` parsing (RFC 7578), as sent by HTML
+//! forms with file inputs.
+//!
+//! [`Multipart`] reads from any [`Read`] source and hands out one [`Part`]
+//! at a time. A part's body is itself read incrementally, so a large file
+//! can be copied elsewhere without holding it in memory.
+//!
+//! ```
+//! use std::io::Read;
+//! use hyperport::http::multipart::Multipart;
+//!
+//! let body = b"--XyZ\r\n\
+//!     Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+//!     Holiday\r\n\
+//!     --XyZ\r\n\
+//!     Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+//!     Content-Type: image/jpeg\r\n\r\n\
+//!     JPEGDATA\r\n\
+//!     --XyZ--\r\n";
+//!
+//! let mut multipart = Multipart::new(&body[..], "XyZ");
+//! let mut part = multipart.next_part().unwrap().unwrap();
+//! assert_eq!(part.name(), "title");
+//! let mut text = String::new();
+//! part.read_to_string(&mut text).unwrap();
+//! assert_eq!(text, "Holiday");
+//!
+//! let part = multipart.next_part().unwrap().unwrap();
+//! assert_eq!(part.filename(), Some("beach.jpg"));
+//! assert_eq!(part.content_type(), Some("image/jpeg"));
+//! drop(part);
+//! assert!(multipart.next_part().unwrap().is_none());
+//! ```
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read};
+
+use crate::http::request::Headers;
+
+/// Bytes requested from the source at a time.
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Size limits applied while parsing. Exceeding one fails the read with
+/// [`MultipartError::TooLarge`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MultipartLimits {
+    /// Largest body of a single part.
+    pub max_part_size: usize,
+    /// Most bytes read from the source in total.
+    pub max_total_size: usize,
+    /// Largest header block of a single part.
+    pub max_header_size: usize,
+    /// Most parts in one body.
+    pub max_parts: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_part_size: 16 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            max_header_size: 8 * 1024,
+            max_parts: 1000,
+        }
+    }
+}
+
+/// Why a multipart body was rejected. Reported as the inner error of an
+/// [`io::Error`] of kind `InvalidData`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MultipartError {
+    /// The framing or part headers are malformed, or the body ended before
+    /// the closing boundary.
+    Malformed,
+    /// A limit in [`MultipartLimits`] was exceeded.
+    TooLarge,
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            MultipartError::Malformed => "malformed multipart body",
+            MultipartError::TooLarge => "multipart body exceeds size limit",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+impl From<MultipartError> for io::Error {
+    fn from(e: MultipartError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    /// Skipping anything before the first boundary.
+    Preamble,
+    /// Just past a boundary: either a part's headers or the final `--`.
+    Boundary,
+    /// Inside a part's body.
+    Body,
+    Done,
+}
+
+/// Reads the parts of a `multipart/form-data` body one after another.
+pub struct Multipart<R> {
+    source: R,
+    /// `\r\n--boundary`, which ends every part.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    limits: MultipartLimits,
+    total: usize,
+    parts: usize,
+    part_size: usize,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Parses `source` with the default [`MultipartLimits`]. `boundary` is
+    /// the `boundary` parameter of the Content-Type.
+    pub fn new(source: R, boundary: &str) -> Self {
+        Multipart::with_limits(source, boundary, MultipartLimits::default())
+    }
+
+    pub fn with_limits(source: R, boundary: &str, limits: MultipartLimits) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Multipart {
+            source,
+            delimiter,
+            // The first boundary may start the body with no line break
+            // before it.
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            limits,
+            total: 0,
+            parts: 0,
+            part_size: 0,
+        }
+    }
+
+    /// Moves on to the next part, skipping whatever is left of the current
+    /// one. `None` once the closing boundary has been read.
+    pub fn next_part(&mut self) -> io::Result<Option<Part<'_, R>>> {
+        loop {
+            match self.state {
+                State::Preamble => {
+                    self.skip_to_delimiter()?;
+                    self.state = State::Boundary;
+                }
+                State::Body => {
+                    let mut sink = [0u8; READ_CHUNK];
+                    while self.read_body(&mut sink)? > 0 {}
+                }
+                State::Boundary => break,
+                State::Done => return Ok(None),
+            }
+        }
+
+        // After a boundary comes `--` for the last one, or a line break.
+        // Trailing whitespace on the boundary line is allowed.
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let line_end = loop {
+            if let Some(pos) = find(&self.buf, b"\r\n") {
+                break pos;
+            }
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(MultipartError::Malformed.into());
+            }
+            self.fill()?;
+        };
+        if !self.buf[..line_end].iter().all(|&b| b == b' ' || b == b'\t') {
+            return Err(MultipartError::Malformed.into());
+        }
+        self.buf.drain(..line_end + 2);
+
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(MultipartError::TooLarge.into());
+        }
+
+        let headers = self.read_headers()?;
+        let disposition = headers.get("content-disposition").ok_or(MultipartError::Malformed)?;
+        let mut params = parse_params(disposition);
+        if !params.next().is_some_and(|(kind, _)| kind.eq_ignore_ascii_case("form-data")) {
+            return Err(MultipartError::Malformed.into());
+        }
+        let mut name = None;
+        let mut filename = None;
+        for (key, value) in params {
+            if key.eq_ignore_ascii_case("name") {
+                name = Some(value);
+            } else if key.eq_ignore_ascii_case("filename") {
+                filename = Some(value);
+            }
+        }
+
+        self.state = State::Body;
+        self.part_size = 0;
+        Ok(Some(Part {
+            name: name.ok_or(MultipartError::Malformed)?,
+            filename,
+            headers,
+            multipart: self,
+        }))
+    }
+
+    /// Reads a part's header block, up to and including the blank line.
+    fn read_headers(&mut self) -> io::Result<Headers> {
+        let end = loop {
+            // A part without headers starts straight after the boundary
+            // line with the blank line.
+            if self.buf.starts_with(b"\r\n") {
+                break 0;
+            }
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                break pos + 2;
+            }
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(MultipartError::TooLarge.into());
+            }
+            self.fill()?;
+        };
+        if end > self.limits.max_header_size {
+            return Err(MultipartError::TooLarge.into());
+        }
+
+        let block = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 2);
+
+        let mut headers = Headers::new();
+        for line in block.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or(MultipartError::Malformed)?;
+            headers.append(name.trim(), value.trim());
+        }
+        Ok(headers)
+    }
+
+    /// Reads body bytes of the current part into `out`. Returns 0 once the
+    /// part's delimiter is reached.
+    fn read_body(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Body || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = State::Boundary;
+                    return Ok(0);
+                }
+                Some(pos) => pos,
+                // Anything that could be the start of the delimiter stays
+                // buffered until more arrives.
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available == 0 {
+                self.fill()?;
+                continue;
+            }
+
+            let n = available.min(out.len());
+            self.part_size += n;
+            if self.part_size > self.limits.max_part_size {
+                return Err(MultipartError::TooLarge.into());
+            }
+            out[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            return Ok(n);
+        }
+    }
+
+    /// Discards everything up to and including the next delimiter.
+    fn skip_to_delimiter(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.buf.drain(..pos + self.delimiter.len());
+                return Ok(());
+            }
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                self.buf.drain(..self.buf.len() - keep);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Reads until at least `len` bytes are buffered.
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        Ok(())
+    }
+
+    /// Appends one read's worth from the source. Running out before the
+    /// closing boundary is an error.
+    fn fill(&mut self) -> io::Result<()> {
+        let start = self.buf.len();
+        self.buf.resize(start + READ_CHUNK, 0);
+        let n = loop {
+            match self.source.read(&mut self.buf[start..]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        };
+        self.buf.truncate(start + n);
+
+        if n == 0 {
+            return Err(MultipartError::Malformed.into());
+        }
+        self.total += n;
+        if self.total > self.limits.max_total_size {
+            return Err(MultipartError::TooLarge.into());
+        }
+        Ok(())
+    }
+}
+
+/// One field or file of a multipart body. Its body is read through the
+/// [`Read`] impl; drop the part to move on to the next.
+pub struct Part<'a, R> {
+    name: String,
+    filename: Option<String>,
+    headers: Headers,
+    multipart: &'a mut Multipart<R>,
+}
+
+impl<R> Part<'_, R> {
+    /// The form field name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The client's name for an uploaded file. Treat it as untrusted: it
+    /// may contain path separators.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The part's Content-Type, if given. Fields without one are plain
+    /// text.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("content-type")
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+}
+
+impl<R: Read> Read for Part<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_body(buf)
+    }
+}
+
+/// Splits a header value like `form-data; name="a"; filename="b.txt"` on
+/// `;`, yielding the leading value with an empty second element, then each
+/// `key=value` parameter. Quoted values are unquoted and unescaped.
+pub(crate) fn parse_params(value: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    let mut rest = value;
+    std::iter::from_fn(move || loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        if rest.is_empty() {
+            return None;
+        }
+
+        let key_end = rest.find(['=', ';']).unwrap_or(rest.len());
+        let key = rest[..key_end].trim().to_string();
+        rest = &rest[key_end..];
+        let Some(after_eq) = rest.strip_prefix('=') else {
+            return Some((key, String::new()));
+        };
+
+        let after_eq = after_eq.trim_start();
+        let value = match after_eq.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        c => value.push(c),
+                    }
+                }
+                rest = &quoted[end..];
+                value
+            }
+            None => {
+                let end = after_eq.find(';').unwrap_or(after_eq.len());
+                rest = &after_eq[end..];
+                after_eq[..end].trim().to_string()
+            }
+        };
+        if key.is_empty() {
+            continue;
+        }
+        return Some((key, value));
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,246 @@
+//! Choosing which of a proxy's upstreams gets each request.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::http::request::Request;
+
+use super::circuit::Circuit;
+use super::health::Health;
+use super::pool::{Pool, PoolSettings};
+use super::resolver::Address;
+
+/// One upstream server of a [`Proxy`](super::Proxy), as seen by a
+/// [`Balancer`].
+pub struct Upstream {
+    pub(crate) pool: Pool,
+    weight: u32,
+    in_flight: AtomicUsize,
+    pub(crate) health: Arc<Health>,
+    pub(crate) circuit: Circuit,
+}
+
+impl Upstream {
+    pub(crate) fn new(address: Arc<Address>, weight: u32, settings: PoolSettings) -> Self {
+        Upstream {
+            pool: Pool::new(address, settings),
+            weight,
+            in_flight: AtomicUsize::new(0),
+            health: Arc::new(Health::new()),
+            circuit: Circuit::new(),
+        }
+    }
+
+    /// The address as given, e.g. `api.internal:8080`.
+    pub fn name(&self) -> &str {
+        self.pool.address().name()
+    }
+
+    /// The address requests currently go to: the latest one the name
+    /// resolved to.
+    pub fn addr(&self) -> SocketAddr {
+        self.pool.addr()
+    }
+
+    /// The weight given with the upstream, at least 1.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Requests currently being proxied to the upstream.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Whether the upstream is taking requests: always, unless a
+    /// [`HealthCheck`](super::HealthCheck) has found it down or its
+    /// [`CircuitBreaker`](super::CircuitBreaker) circuit is open.
+    pub fn is_healthy(&self) -> bool {
+        self.health.is_up() && !self.circuit.is_open()
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    pub(crate) fn begin(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+}
+
+pub(crate) struct InFlight<'a>(&'a Upstream);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A strategy for spreading requests over upstreams.
+///
+/// The built-in strategies pass over upstreams that are not
+/// [healthy](Upstream::is_healthy), unless none are.
+///
+/// Implement it to add a strategy of your own, e.g. one hashing the client
+/// address so that each client sticks to one upstream:
+///
+/// ```ignore
+/// struct ByClient;
+///
+/// impl Balancer for ByClient {
+///     fn pick(&self, upstreams: &[Upstream], request: &Request) -> usize {
+///         let ip = request.client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+///         ip.bytes().fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize)) % upstreams.len()
+///     }
+/// }
+///
+/// let proxy = Proxy::balanced(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 1)])?.balancer(ByClient);
+/// ```
+pub trait Balancer: Send + Sync {
+    /// The index in `upstreams`, which is never empty, of the upstream to
+    /// send `request` to.
+    fn pick(&self, upstreams: &[Upstream], request: &Request) -> usize;
+}
+
+/// Each upstream in turn, ignoring weights. The default.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin::default()
+    }
+}
+
+impl Balancer for RoundRobin {
+    fn pick(&self, upstreams: &[Upstream], _: &Request) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        candidates(upstreams, start).next().unwrap_or(start % upstreams.len())
+    }
+}
+
+/// Each upstream in turn, in proportion to its weight. Turns are spread
+/// out rather than bunched, so weights 5, 1, 1 give `a a b a c a a` and
+/// not `a a a a a b c` (the "smooth" weighted round-robin of nginx).
+#[derive(Debug, Default)]
+pub struct Weighted {
+    current: Mutex<Vec<i64>>,
+}
+
+impl Weighted {
+    pub fn new() -> Self {
+        Weighted::default()
+    }
+}
+
+impl Balancer for Weighted {
+    fn pick(&self, upstreams: &[Upstream], _: &Request) -> usize {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        current.resize(upstreams.len(), 0);
+        let mut total = 0;
+        let mut best = None;
+        for i in candidates(upstreams, 0) {
+            current[i] += upstreams[i].weight as i64;
+            total += upstreams[i].weight as i64;
+            if best.is_none_or(|best| current[i] > current[best]) {
+                best = Some(i);
+            }
+        }
+        let best = best.unwrap_or(0);
+        current[best] -= total;
+        best
+    }
+}
+
+/// The upstream with the fewest requests in flight relative to its weight,
+/// taking them in turn when several are tied.
+#[derive(Debug, Default)]
+pub struct LeastConnections {
+    next: AtomicUsize,
+}
+
+impl LeastConnections {
+    pub fn new() -> Self {
+        LeastConnections::default()
+    }
+}
+
+impl Balancer for LeastConnections {
+    fn pick(&self, upstreams: &[Upstream], _: &Request) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        candidates(upstreams, start)
+            .min_by(|&a, &b| load(&upstreams[a], &upstreams[b]))
+            .unwrap_or(0)
+    }
+}
+
+/// Two upstreams chosen at random, and of those the one with fewer
+/// requests in flight relative to its weight. Nearly as even as
+/// [`LeastConnections`] without every pick looking at every upstream, and
+/// without sending a burst of requests all to the one that was idlest.
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices {
+    random: Random,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new() -> Self {
+        PowerOfTwoChoices::default()
+    }
+}
+
+impl Balancer for PowerOfTwoChoices {
+    fn pick(&self, upstreams: &[Upstream], _: &Request) -> usize {
+        let healthy: Vec<usize> = candidates(upstreams, 0).collect();
+        let len = healthy.len();
+        if len == 1 {
+            return healthy[0];
+        }
+        let a = self.random.below(len);
+        // A second, different upstream.
+        let b = (a + 1 + self.random.below(len - 1)) % len;
+        let (a, b) = (healthy[a], healthy[b]);
+        if load(&upstreams[b], &upstreams[a]).is_lt() {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+/// The indexes of the healthy upstreams, or of all of them when none is
+/// healthy, going round from `start`.
+fn candidates(upstreams: &[Upstream], start: usize) -> impl Iterator<Item = usize> + '_ {
+    let len = upstreams.len();
+    let any_healthy = upstreams.iter().any(Upstream::is_healthy);
+    (0..len)
+        .map(move |offset| (start + offset) % len)
+        .filter(move |&i| !any_healthy || upstreams[i].is_healthy())
+}
+
+/// Compares in-flight requests per unit of weight, without dividing.
+fn load(a: &Upstream, b: &Upstream) -> std::cmp::Ordering {
+    let a_load = a.in_flight() as u64 * b.weight as u64;
+    let b_load = b.in_flight() as u64 * a.weight as u64;
+    a_load.cmp(&b_load)
+}
+
+/// Cheap, unpredictable-enough numbers: a counter run through the
+/// randomly keyed SipHash of the standard library.
+#[derive(Debug, Default)]
+struct Random {
+    keys: RandomState,
+    counter: AtomicU64,
+}
+
+impl Random {
+    /// A number in `0..n`, for `n` above 0.
+    fn below(&self, n: usize) -> usize {
+        let mut hasher = self.keys.build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        (hasher.finish() % n as u64) as usize
+    }
+}
@@ -0,0 +1,163 @@
+//! Circuit breaking: failing fast instead of waiting on an upstream that
+//! keeps failing, and letting single trial requests through to find out
+//! when it has recovered.
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{info, warn};
+
+/// When to stop sending requests to a failing upstream, and for how long.
+///
+/// The circuit of an upstream opens once, within one window, at least
+/// [`min_requests`](CircuitBreaker::min_requests) have been sent to it and
+/// [`failure_percent`](CircuitBreaker::failure_percent) of them failed,
+/// either outright or with a 5xx status. While open, the upstream gets no
+/// requests. After [`open_for`](CircuitBreaker::open_for) it is half-open:
+/// one trial request goes through, and closes the circuit if it succeeds
+/// or opens it again if not.
+///
+/// ```ignore
+/// let proxy = Proxy::new("127.0.0.1:9000")?.circuit_breaker(CircuitBreaker::new().failure_percent(25));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    failure_percent: u32,
+    min_requests: u32,
+    window: Duration,
+    open_for: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failure_percent: 50,
+            min_requests: 10,
+            window: Duration::from_secs(10),
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        CircuitBreaker::default()
+    }
+
+    /// The share of failed requests, from 1 to 100, that opens the
+    /// circuit. Defaults to 50.
+    pub fn failure_percent(mut self, percent: u32) -> Self {
+        self.failure_percent = percent.clamp(1, 100);
+        self
+    }
+
+    /// Requests needed in a window before its failures count. Defaults to
+    /// 10.
+    pub fn min_requests(mut self, count: u32) -> Self {
+        self.min_requests = count.max(1);
+        self
+    }
+
+    /// How long requests are counted for before the counts start over.
+    /// Defaults to 10 seconds.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// How long an open circuit stays open before a trial request is let
+    /// through. Defaults to 30 seconds.
+    pub fn open_for(mut self, duration: Duration) -> Self {
+        self.open_for = duration;
+        self
+    }
+}
+
+/// The circuit of one upstream.
+#[derive(Debug)]
+pub(crate) struct Circuit {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { since: Instant, requests: u32, failures: u32 },
+    Open { until: Instant },
+    /// A trial request is under way.
+    HalfOpen,
+}
+
+impl Circuit {
+    pub(crate) fn new() -> Self {
+        Circuit {
+            state: Mutex::new(State::Closed {
+                since: Instant::now(),
+                requests: 0,
+                failures: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether requests are being refused: open, or half-open with the
+    /// trial request still under way.
+    pub(crate) fn is_open(&self) -> bool {
+        match *self.lock() {
+            State::Closed { .. } => false,
+            State::Open { until } => Instant::now() < until,
+            State::HalfOpen => true,
+        }
+    }
+
+    /// Whether a request may be sent. Once an open circuit's time is up,
+    /// the request allowed is the trial one, and its outcome must be
+    /// [recorded](Circuit::record).
+    pub(crate) fn admit(&self) -> bool {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    /// Counts the outcome of a request that was admitted.
+    pub(crate) fn record(&self, breaker: &CircuitBreaker, addr: SocketAddr, failed: bool) {
+        let now = Instant::now();
+        let mut state = self.lock();
+        match &mut *state {
+            State::Closed { since, requests, failures } => {
+                if now.duration_since(*since) >= breaker.window {
+                    (*since, *requests, *failures) = (now, 0, 0);
+                }
+                *requests += 1;
+                *failures += failed as u32;
+                if *requests >= breaker.min_requests && *failures * 100 >= breaker.failure_percent * *requests {
+                    warn!("Circuit for upstream {} opened: {} of {} requests failed", addr, failures, requests);
+                    *state = State::Open { until: now + breaker.open_for };
+                }
+            }
+            State::HalfOpen if failed => {
+                warn!("Circuit for upstream {} opened again: trial request failed", addr);
+                *state = State::Open { until: now + breaker.open_for };
+            }
+            State::HalfOpen => {
+                info!("Circuit for upstream {} closed", addr);
+                *state = State::Closed {
+                    since: now,
+                    requests: 0,
+                    failures: 0,
+                };
+            }
+            // Sent before the circuit opened.
+            State::Open { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Forward proxying with CONNECT: opening a TCP tunnel to the host and port
+//! a client asks for, most often to carry its TLS connection to an HTTPS
+//! site.
+
+use std::io::{self, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::net::tunnel::relay;
+use crate::warn;
+
+/// Middleware answering CONNECT requests by tunnelling to the requested
+/// `host:port`; any other request is passed on.
+///
+/// Only destinations on the allowlist are reached: ports 443 unless
+/// [`ports`](ConnectProxy::ports) says otherwise, and any host unless
+/// [`hosts`](ConnectProxy::hosts) narrows it. Others get a 403. A
+/// destination that cannot be reached gets a 502, or a 504 if connecting
+/// takes too long. Once the tunnel is up, bytes are relayed both ways until
+/// both sides have closed or it has been idle for
+/// [`idle_timeout`](ConnectProxy::idle_timeout).
+///
+/// ```ignore
+/// router.wrap(ConnectProxy::new().ports(&[443, 8443]).hosts(&["*.example.com"]));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConnectProxy {
+    ports: Vec<u16>,
+    /// Empty for any host.
+    hosts: Vec<String>,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl Default for ConnectProxy {
+    fn default() -> Self {
+        ConnectProxy {
+            ports: vec![443],
+            hosts: Vec::new(),
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl ConnectProxy {
+    pub fn new() -> Self {
+        ConnectProxy::default()
+    }
+
+    /// The destination ports tunnels may be opened to. Defaults to 443.
+    pub fn ports(mut self, ports: &[u16]) -> Self {
+        self.ports = ports.to_vec();
+        self
+    }
+
+    /// The destination hosts tunnels may be opened to, as names or
+    /// addresses, with `*.example.com` standing for any subdomain of
+    /// `example.com`. Defaults to any host.
+    pub fn hosts<S: AsRef<str>>(mut self, hosts: &[S]) -> Self {
+        self.hosts = hosts.iter().map(|host| host.as_ref().to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// How long connecting to the destination may take. Defaults to 10
+    /// seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Closes tunnels that carry nothing either way for `timeout`. Defaults
+    /// to 5 minutes.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    fn allows(&self, host: &str, port: u16) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.ports.contains(&port)
+            && (self.hosts.is_empty()
+                || self.hosts.iter().any(|allowed| match allowed.strip_prefix("*.") {
+                    Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+                    None => *allowed == host,
+                }))
+    }
+
+    fn tunnel(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let Some((host, port)) = authority(&request.target) else {
+            return writer.send_error(StatusCode::BadRequest);
+        };
+        if !self.allows(host, port) {
+            return writer.send_error(StatusCode::Forbidden);
+        }
+        let upstream = match self.connect(host, port) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("Error connecting to {}:{}: {}", host, port, e);
+                let status = match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => StatusCode::GatewayTimeout,
+                    _ => StatusCode::BadGateway,
+                };
+                return writer.send_error(status);
+            }
+        };
+        let client = writer.tunnel()?;
+        relay(client, upstream, self.idle_timeout, |_, _| {})
+    }
+
+    /// A connection to the first of the host's addresses that answers.
+    fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last = io::Error::new(ErrorKind::NotFound, "host has no addresses");
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_write_timeout(Some(self.idle_timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+impl Middleware for ConnectProxy {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        if request.method == "CONNECT" {
+            self.tunnel(request, writer)
+        } else {
+            next.run(request, writer)
+        }
+    }
+}
+
+/// The host and port of a CONNECT target: `host:port`, with an IPv6 host
+/// in brackets.
+fn authority(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.strip_suffix(']')?,
+        None => host,
+    };
+    let port = port.parse().ok().filter(|&port| port != 0)?;
+    (!host.is_empty() && !host.contains(['/', '[', ']'])).then_some((host, port))
+}
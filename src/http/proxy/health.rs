@@ -0,0 +1,189 @@
+//! Active health checks: probing upstreams in the background so that the
+//! balancer only sends requests to those answering.
+
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::http::client::Client;
+use crate::{info, warn};
+
+use super::resolver::Address;
+
+/// How to probe an upstream, and how many results in a row change its
+/// state.
+///
+/// ```ignore
+/// let proxy = Proxy::balanced(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 1)])?
+///     .health_check(HealthCheck::http("/healthz").interval(Duration::from_secs(2)));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthCheck {
+    probe: Probe,
+    interval: Duration,
+    timeout: Duration,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Probe {
+    Tcp,
+    /// A GET for the path, passing on a 2xx or 3xx status.
+    Http(String),
+}
+
+impl HealthCheck {
+    /// Probes by opening a TCP connection.
+    pub fn tcp() -> Self {
+        HealthCheck::new(Probe::Tcp)
+    }
+
+    /// Probes with a GET request for `path`, which passes when the
+    /// upstream answers with a 2xx or 3xx status.
+    pub fn http(path: &str) -> Self {
+        HealthCheck::new(Probe::Http(path.to_string()))
+    }
+
+    fn new(probe: Probe) -> Self {
+        HealthCheck {
+            probe,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+
+    /// Time between probes of each upstream. Defaults to 5 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long a probe may take before it counts as failed. Defaults to 2
+    /// seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Passing probes in a row that bring a down upstream back. Defaults
+    /// to 2; 0 is taken as 1.
+    pub fn healthy_threshold(mut self, count: u32) -> Self {
+        self.healthy_threshold = count.max(1);
+        self
+    }
+
+    /// Failing probes in a row that take an upstream down. Defaults to 3;
+    /// 0 is taken as 1.
+    pub fn unhealthy_threshold(mut self, count: u32) -> Self {
+        self.unhealthy_threshold = count.max(1);
+        self
+    }
+
+    /// Probes `addr`; an HTTP probe goes out through `client`.
+    fn passes(&self, client: &Client, addr: SocketAddr) -> io::Result<()> {
+        let Probe::Http(path) = &self.probe else {
+            TcpStream::connect_timeout(&addr, self.timeout)?;
+            return Ok(());
+        };
+        let url = format!("http://{}{}", addr, path);
+        let response = client.send("GET", &url, &[("User-Agent", "hyperport-health-check")], &[])?;
+        if response.status.is_success() || response.status.is_redirection() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("status {}", response.status.as_u16())))
+        }
+    }
+}
+
+/// Whether an upstream is taking requests. Upstreams start out up.
+#[derive(Debug)]
+pub(crate) struct Health {
+    up: AtomicBool,
+}
+
+impl Health {
+    pub(crate) fn new() -> Self {
+        Health { up: AtomicBool::new(true) }
+    }
+
+    pub(crate) fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs a [`HealthCheck`] against a set of upstreams on a background
+/// thread, until dropped.
+pub(crate) struct HealthChecker {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl HealthChecker {
+    pub(crate) fn spawn(check: HealthCheck, upstreams: Vec<(Arc<Address>, Arc<Health>)>) -> Self {
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            changed: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("health-check".to_string())
+            .spawn(move || run(&thread_shared, &check, &upstreams))
+            .expect("failed to spawn health check thread");
+        HealthChecker { shared }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+fn run(shared: &Shared, check: &HealthCheck, upstreams: &[(Arc<Address>, Arc<Health>)]) {
+    // Results in a row that disagree with each upstream's current state.
+    let mut streaks = vec![0u32; upstreams.len()];
+    // Each probe on a new connection, so that one that cannot be made
+    // counts against the upstream.
+    let client = Client::new().connect_timeout(check.timeout).timeout(check.timeout).max_idle(0);
+    loop {
+        for ((address, health), streak) in upstreams.iter().zip(&mut streaks) {
+            let addr = address.get();
+            let result = check.passes(&client, addr);
+            let up = health.is_up();
+            if result.is_ok() == up {
+                *streak = 0;
+                continue;
+            }
+            *streak += 1;
+            let threshold = if up { check.unhealthy_threshold } else { check.healthy_threshold };
+            if *streak >= threshold {
+                *streak = 0;
+                health.up.store(!up, Ordering::Relaxed);
+                match result {
+                    Ok(()) => info!("Upstream {} is up", addr),
+                    Err(e) => warn!("Upstream {} is down: {}", addr, e),
+                }
+            }
+        }
+
+        let stopped = shared.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        let (stopped, _) = shared
+            .changed
+            .wait_timeout_while(stopped, check.interval, |stopped| !*stopped)
+            .unwrap_or_else(|e| e.into_inner());
+        if *stopped {
+            return;
+        }
+    }
+}
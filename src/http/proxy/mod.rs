@@ -0,0 +1,647 @@
+//! Reverse proxying: forwarding requests to an upstream HTTP/1.1 server and
+//! relaying its responses.
+
+mod balancer;
+mod circuit;
+mod connect;
+mod health;
+pub(crate) mod pool;
+pub(crate) mod resolver;
+
+pub use self::balancer::{Balancer, LeastConnections, PowerOfTwoChoices, RoundRobin, Upstream, Weighted};
+pub use self::circuit::CircuitBreaker;
+pub use self::connect::ConnectProxy;
+pub use self::health::HealthCheck;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::HyperportError;
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::client;
+use crate::http::handler::Handler;
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::net::proxy_protocol::{self, ProxyHeader};
+use crate::net::tunnel::relay;
+use crate::server::stats;
+use crate::{debug, warn};
+
+use self::health::HealthChecker;
+use self::pool::{Connection, Pool, PoolSettings};
+use self::resolver::{Address, Resolver};
+
+/// Headers that describe one connection rather than the message, and so
+/// are not passed from one side of the proxy to the other (RFC 9110 §7.6.1).
+/// Framing headers are left out too, since each side frames for itself.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// How long a resolved upstream address is used before it is looked up
+/// again, unless [`Proxy::dns_ttl`] says otherwise.
+const DEFAULT_DNS_TTL: Duration = Duration::from_secs(30);
+
+/// Methods whose requests may safely be sent twice.
+const IDEMPOTENT: &[&str] = &["GET", "HEAD", "PUT", "DELETE", "OPTIONS", "TRACE"];
+
+/// A handler that forwards requests to upstream servers and streams their
+/// responses back.
+///
+/// Requests go out with their original target and Host header, minus the
+/// hop-by-hop headers, and with the client added to X-Forwarded-For and
+/// Forwarded. With several upstreams, a [`Balancer`] picks one
+/// for each request; [`RoundRobin`] unless [`balancer`](Proxy::balancer)
+/// sets another, and a [`HealthCheck`] can keep it to the upstreams that
+/// are answering.
+///
+/// Upstreams given by host name are resolved when the proxy is made, and
+/// again every [`dns_ttl`](Proxy::dns_ttl) on a background thread, so
+/// that requests never wait on DNS. Requests go to the latest address;
+/// connections to an address the name no longer resolves to are not
+/// reused.
+///
+/// Connections to each upstream are kept alive and pooled between
+/// requests: at most [`max_idle`](Proxy::max_idle) wait idle, each for up
+/// to [`idle_timeout`](Proxy::idle_timeout), and none is used for longer
+/// than [`max_lifetime`](Proxy::max_lifetime). Idle connections the
+/// upstream has closed are noticed and dropped before they are used;
+/// should one fail anyway, an idempotent request is retried once on a
+/// fresh connection. Beyond that, idempotent requests are only retried,
+/// with backoff, if [`retries`](Proxy::retries) allows.
+///
+/// WebSocket handshakes are passed on with their Upgrade header. Once the
+/// upstream accepts one, the connection is handed over and frames are
+/// relayed both ways until either side closes, or neither sends anything
+/// for the [`timeout`](Proxy::timeout).
+///
+/// An upstream that cannot be reached gets the client a 502, and one that
+/// is too slow a 504.
+///
+/// ```ignore
+/// let api = Arc::new(Proxy::new("127.0.0.1:9000")?.max_idle(64));
+/// router.route("GET", "/api/{*path}", move |request, writer| api.call(request, writer));
+///
+/// let app = Proxy::balanced(&[("10.0.0.1:80", 3), ("10.0.0.2:80", 1)])?.balancer(Weighted::new());
+/// ```
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    balancer: Box<dyn Balancer>,
+    /// Stops the health checks when the proxy is dropped.
+    checker: Option<HealthChecker>,
+    /// Stops the re-resolution of named upstreams when the proxy is
+    /// dropped.
+    resolver: Option<Resolver>,
+    retry: RetryPolicy,
+    breaker: Option<CircuitBreaker>,
+    proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+impl Proxy {
+    /// A proxy to `upstream`, a `host:port` address. A host name is first
+    /// resolved here, then kept up to date in the background.
+    pub fn new(upstream: &str) -> Result<Proxy, HyperportError> {
+        Proxy::balanced(&[(upstream, 1)])
+    }
+
+    /// A proxy spreading requests over `upstreams`, given as `host:port`
+    /// addresses with their weights. Weights only matter to balancers that
+    /// use them, such as [`Weighted`]; a weight of 0 is taken as 1.
+    pub fn balanced(upstreams: &[(&str, u32)]) -> Result<Proxy, HyperportError> {
+        if upstreams.is_empty() {
+            return Err(HyperportError::InvalidAddress("no upstreams".to_string()));
+        }
+        let settings = PoolSettings::default();
+        let upstreams: Vec<Upstream> = upstreams
+            .iter()
+            .map(|&(upstream, weight)| {
+                let address = Address::resolve(upstream)
+                    .map_err(|_| HyperportError::InvalidAddress(upstream.to_string()))?;
+                Ok(Upstream::new(Arc::new(address), weight.max(1), settings))
+            })
+            .collect::<Result<_, HyperportError>>()?;
+        let addresses = upstreams.iter().map(|upstream| Arc::clone(upstream.pool.address())).collect();
+        Ok(Proxy {
+            upstreams,
+            balancer: Box::new(RoundRobin::new()),
+            checker: None,
+            resolver: Resolver::spawn(DEFAULT_DNS_TTL, addresses),
+            retry: RetryPolicy {
+                attempts: 0,
+                backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_secs(1),
+            },
+            breaker: None,
+            proxy_protocol: None,
+        })
+    }
+
+    /// Picks upstreams with `balancer`.
+    pub fn balancer<B: Balancer + 'static>(mut self, balancer: B) -> Self {
+        self.balancer = Box::new(balancer);
+        self
+    }
+
+    /// Probes the upstreams in the background as `check` says, taking those
+    /// that fail out of rotation until they pass again. Replaces any
+    /// earlier health check.
+    pub fn health_check(mut self, check: HealthCheck) -> Self {
+        let upstreams = self
+            .upstreams
+            .iter()
+            .map(|upstream| (Arc::clone(upstream.pool.address()), Arc::clone(&upstream.health)));
+        self.checker = Some(HealthChecker::spawn(check, upstreams.collect()));
+        self
+    }
+
+    /// How long the addresses of upstreams given by host name are used
+    /// before they are looked up again. Defaults to 30 seconds; zero
+    /// resolves them once, when the proxy is made.
+    pub fn dns_ttl(mut self, ttl: Duration) -> Self {
+        // The old resolver stops as it is dropped.
+        self.resolver = None;
+        if !ttl.is_zero() {
+            let addresses = self.upstreams.iter().map(|upstream| Arc::clone(upstream.pool.address()));
+            self.resolver = Resolver::spawn(ttl, addresses.collect());
+        }
+        self
+    }
+
+    /// Stops sending requests to upstreams that keep failing, as `breaker`
+    /// says. A request finding every circuit open gets a 503 at once.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Retries idempotent requests up to `attempts` times when the upstream
+    /// cannot be reached, fails mid-exchange or answers with a 5xx status,
+    /// each time on another upstream while there are untried ones. No
+    /// retries by default.
+    pub fn retries(mut self, attempts: u32) -> Self {
+        self.retry.attempts = attempts;
+        self
+    }
+
+    /// Waits `initial` before the first retry, doubling the wait for each
+    /// one after. Defaults to 50 milliseconds.
+    pub fn retry_backoff(mut self, initial: Duration) -> Self {
+        self.retry.backoff = initial;
+        self
+    }
+
+    /// Caps the wait between retries at `max`. Defaults to 1 second.
+    pub fn max_retry_backoff(mut self, max: Duration) -> Self {
+        self.retry.max_backoff = max;
+        self
+    }
+
+    /// Opens each upstream connection with a PROXY protocol header in
+    /// `version`, giving the client's address and the one it connected to,
+    /// for upstreams that expect one. The client is
+    /// [`Request::client_ip`]. Since a header speaks for one client,
+    /// connections are then used for one request each instead of pooled.
+    pub fn proxy_protocol(mut self, version: proxy_protocol::Version) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Keeps at most `max` idle connections to each upstream. Defaults to
+    /// 32; 0 opens a new connection for every request.
+    pub fn max_idle(self, max: usize) -> Self {
+        self.configure(|settings| settings.max_idle = max)
+    }
+
+    /// Closes connections left idle for `timeout`. Defaults to 60 seconds;
+    /// keep it below the upstreams' own keep-alive timeout.
+    pub fn idle_timeout(self, timeout: Duration) -> Self {
+        self.configure(|settings| settings.idle_timeout = timeout)
+    }
+
+    /// Retires connections once they have been open for `lifetime`, so that
+    /// load spreads over upstream restarts and DNS changes. No limit by
+    /// default.
+    pub fn max_lifetime(self, lifetime: Duration) -> Self {
+        self.configure(|settings| settings.max_lifetime = Some(lifetime))
+    }
+
+    /// How long connecting to an upstream may take. Defaults to 5 seconds.
+    pub fn connect_timeout(self, timeout: Duration) -> Self {
+        self.configure(|settings| settings.connect_timeout = timeout)
+    }
+
+    /// How long a read from or write to an upstream may stall. Defaults to
+    /// 60 seconds.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.configure(|settings| settings.io_timeout = timeout)
+    }
+
+    fn configure(mut self, change: impl FnOnce(&mut PoolSettings)) -> Self {
+        let mut settings = self.upstreams[0].pool.settings();
+        change(&mut settings);
+        for upstream in &mut self.upstreams {
+            upstream.pool = Pool::new(Arc::clone(upstream.pool.address()), settings);
+        }
+        self
+    }
+
+    /// The upstreams, in the order given.
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    pub fn handle(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let idempotent = IDEMPOTENT.contains(&request.method.as_str());
+        let mut tried = Vec::new();
+        let mut attempt = 0;
+        loop {
+            let index = self.pick(request, &tried);
+            tried.push(index);
+            let upstream = &self.upstreams[index];
+            if self.breaker.is_some() && !upstream.circuit.admit() {
+                // Fail fast, unless another upstream is left to try.
+                if (0..self.upstreams.len()).any(|i| !tried.contains(&i)) {
+                    continue;
+                }
+                stats::PROXY_CIRCUIT_REJECTIONS.fetch_add(1, Ordering::Relaxed);
+                return writer.send_error(StatusCode::ServiceUnavailable);
+            }
+            attempt += 1;
+            let can_retry = idempotent && attempt <= self.retry.attempts;
+
+            debug!("Proxying {} {} to {}", request.method, request.target, upstream.addr());
+            let in_flight = upstream.begin();
+            let preamble = self.proxy_protocol.map(|version| proxy_header(request).encode(version));
+            let outcome = exchange(&upstream.pool, request, preamble.as_deref());
+            if let Some(breaker) = &self.breaker {
+                let failed = outcome.as_ref().map_or(true, |(_, head)| head.status.is_server_error());
+                upstream.circuit.record(breaker, upstream.addr(), failed);
+            }
+            let failure = match outcome {
+                // A 5xx is passed on as it is once there is no retrying it.
+                Ok((connection, head)) if head.status == StatusCode::SwitchingProtocols => {
+                    return relay_upgrade(connection, head, writer, upstream.pool.settings().io_timeout);
+                }
+                Ok((mut connection, head)) if !(can_retry && head.status.is_server_error()) => {
+                    let reusable = relay_response(&mut connection, head, request, writer)?;
+                    if reusable && preamble.is_none() {
+                        upstream.pool.checkin(connection);
+                    }
+                    return Ok(());
+                }
+                // The unread body leaves the connection unfit for reuse, so
+                // it is dropped.
+                Ok((_, head)) => format!("{} {}", head.status.as_u16(), head.status.reason_phrase()),
+                Err(e) if can_retry => e.to_string(),
+                Err(e) => {
+                    stats::PROXY_FAILURES.fetch_add(1, Ordering::Relaxed);
+                    warn!("Error proxying to {}: {}", upstream.addr(), e);
+                    let status = match e.kind() {
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => StatusCode::GatewayTimeout,
+                        _ => StatusCode::BadGateway,
+                    };
+                    return writer.send_error(status);
+                }
+            };
+            drop(in_flight);
+
+            let delay = self.retry.backoff(attempt);
+            warn!(
+                "Retrying {} {} in {:?}: {} from {} (attempt {} of {})",
+                request.method,
+                request.target,
+                delay,
+                failure,
+                upstream.addr(),
+                attempt,
+                self.retry.attempts + 1
+            );
+            stats::PROXY_RETRIES.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+        }
+    }
+
+    /// The balancer's choice, unless that upstream was already tried for
+    /// this request and another, preferably healthy, has not been.
+    fn pick(&self, request: &Request, tried: &[usize]) -> usize {
+        let chosen = self.balancer.pick(&self.upstreams, request).min(self.upstreams.len() - 1);
+        if !tried.contains(&chosen) {
+            return chosen;
+        }
+        (0..self.upstreams.len())
+            .filter(|i| !tried.contains(i))
+            .min_by_key(|&i| !self.upstreams[i].is_healthy())
+            .unwrap_or(chosen)
+    }
+}
+
+/// How failed requests are retried.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    /// Retries after the first attempt.
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The wait before the retry following attempt `attempt`, counting
+    /// from 1: `backoff`, doubling each time, up to `max_backoff`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Sends `request` on a pooled connection to the upstream behind `pool` and
+/// reads the response head. With a `preamble` to open the connection with,
+/// a new connection is used instead.
+fn exchange(pool: &Pool, request: &Request, preamble: Option<&[u8]>) -> io::Result<(Connection, ResponseHead)> {
+    if let Some(preamble) = preamble {
+        let mut connection = pool.connect()?;
+        connection.stream.write_all(preamble)?;
+        let head = send_request(&mut connection, request)?;
+        return Ok((connection, head));
+    }
+    let mut connection = pool.checkout()?;
+    match send_request(&mut connection, request) {
+        Ok(head) => Ok((connection, head)),
+        // The upstream may have closed a pooled connection just as it was
+        // taken; the request never reached it, so try once more.
+        Err(e) if connection.reused && IDEMPOTENT.contains(&request.method.as_str()) => {
+            let mut fresh = pool.connect().map_err(|_| e)?;
+            let head = send_request(&mut fresh, request)?;
+            Ok((fresh, head))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The PROXY protocol header for an upstream connection carrying `request`.
+/// A client found behind trusted proxies has no known port, and is given 0.
+fn proxy_header(request: &Request) -> ProxyHeader {
+    let (Some(peer), Some(local)) = (request.peer_addr, request.local_addr) else {
+        return ProxyHeader::Local;
+    };
+    let source = match request.client_ip {
+        Some(ip) if ip != peer.ip() => SocketAddr::new(ip, 0),
+        _ => peer,
+    };
+    ProxyHeader::Proxied {
+        source,
+        destination: local,
+    }
+}
+
+impl Handler for Proxy {
+    fn call(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        self.handle(request, writer)
+    }
+}
+
+/// A response head received from the upstream, and any body bytes that
+/// arrived with it.
+struct ResponseHead {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    /// Whether the upstream will keep the connection open afterwards.
+    keep_alive: bool,
+    rest: Vec<u8>,
+}
+
+/// Forwards `request` and reads the head of the final response, skipping
+/// any interim 1xx responses other than the 101 accepting a WebSocket
+/// handshake.
+fn send_request(connection: &mut Connection, request: &Request) -> io::Result<ResponseHead> {
+    let body_len = request.body_len()?;
+    let upgrade = is_websocket_upgrade(request);
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.target);
+    let dropped = connection_tokens(request.headers.get_all("connection"));
+    for (name, value) in request.headers.iter() {
+        let appended = name.eq_ignore_ascii_case("x-forwarded-for") || name.eq_ignore_ascii_case("forwarded");
+        if !is_hop_by_hop(name, &dropped) && !appended {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    add_forwarding_headers(&mut head, request);
+    if upgrade {
+        head.push_str("Connection: Upgrade\r\nUpgrade: websocket\r\n");
+    }
+    if request.headers.get("host").is_none() {
+        head.push_str(&format!("Host: {}\r\n", connection.stream.peer_addr()?));
+    }
+    if body_len > 0 || request.headers.get("content-length").is_some() {
+        head.push_str(&format!("Content-Length: {}\r\n", body_len));
+    }
+    head.push_str("\r\n");
+
+    let stream = &mut connection.stream;
+    stream.write_all(head.as_bytes())?;
+    io::copy(&mut request.body_reader(), stream)?;
+    stream.flush()?;
+
+    let mut buf = Vec::new();
+    loop {
+        let head = read_head(stream, &mut buf)?;
+        if !head.status.is_informational() || (upgrade && head.status == StatusCode::SwitchingProtocols) {
+            return Ok(head);
+        }
+        buf = head.rest;
+    }
+}
+
+/// Whether `request` is a WebSocket opening handshake, the only upgrade
+/// passed on to upstreams.
+fn is_websocket_upgrade(request: &Request) -> bool {
+    request.method == "GET"
+        && request.headers.has_token("upgrade", "websocket")
+        && request.headers.has_token("connection", "upgrade")
+}
+
+/// Passes an upstream's acceptance of a WebSocket handshake on to the
+/// client, then relays between the two connections until they close.
+fn relay_upgrade(
+    connection: Connection,
+    head: ResponseHead,
+    writer: &mut ResponseWriter,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let dropped = connection_tokens(header_values(&head.headers, "connection"));
+    let headers: Vec<(&str, &str)> = head
+        .headers
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name, &dropped))
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let mut client = writer.upgrade("websocket", &headers)?;
+    // Frames the upstream sent straight after its handshake.
+    client.write_all(&head.rest)?;
+    relay(client, connection.stream, idle_timeout, |_, _| {})
+}
+
+/// Adds the peer to the X-Forwarded-For and Forwarded lists the request
+/// came with, and says which host and scheme it was sent to unless an
+/// earlier proxy already did.
+fn add_forwarding_headers(head: &mut String, request: &Request) {
+    let joined = |name| request.headers.get_all(name).collect::<Vec<_>>().join(", ");
+    let (forwarded_for, forwarded) = (joined("x-forwarded-for"), joined("forwarded"));
+    let host = request.headers.get("host");
+
+    let Some(peer) = request.peer_addr.map(|addr| addr.ip().to_canonical()) else {
+        for (name, value) in [("X-Forwarded-For", forwarded_for), ("Forwarded", forwarded)] {
+            if !value.is_empty() {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        return;
+    };
+    let mut element = match peer {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = host.filter(|host| !host.contains('"')) {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    element.push_str(";proto=http");
+    let append = |list: String, entry: String| if list.is_empty() { entry } else { format!("{}, {}", list, entry) };
+    head.push_str(&format!("X-Forwarded-For: {}\r\n", append(forwarded_for, peer.to_string())));
+    head.push_str(&format!("Forwarded: {}\r\n", append(forwarded, element)));
+
+    if request.headers.get("x-forwarded-proto").is_none() {
+        head.push_str("X-Forwarded-Proto: http\r\n");
+    }
+    if let Some(host) = host.filter(|_| request.headers.get("x-forwarded-host").is_none()) {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+}
+
+/// Reads one response head, starting with whatever is already in `buf`.
+fn read_head(stream: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<ResponseHead> {
+    let (head, rest) = client::read_head(stream, buf)?;
+    Ok(ResponseHead {
+        status: head.status,
+        keep_alive: head.keep_alive(),
+        headers: head.headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        rest,
+    })
+}
+
+/// Streams the response to the client. Returns whether the upstream
+/// connection ended cleanly and can carry another request.
+fn relay_response(
+    connection: &mut Connection,
+    head: ResponseHead,
+    request: &Request,
+    writer: &mut ResponseWriter,
+) -> io::Result<bool> {
+    let dropped = connection_tokens(header_values(&head.headers, "connection"));
+    let headers: Vec<(&str, &str)> = head
+        .headers
+        .iter()
+        .map(|(n, v)| (n.as_str(), v.as_str()))
+        .filter(|(name, _)| !is_hop_by_hop(name, &dropped))
+        .collect();
+    let stream = &mut connection.stream;
+
+    let bodiless = request.method == "HEAD"
+        || matches!(head.status, StatusCode::NoContent | StatusCode::NotModified);
+    let chunked = header_values(&head.headers, "transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    let length = header_values(&head.headers, "content-length")
+        .next()
+        .map(|len| len.trim().parse::<u64>().map_err(|_| invalid("malformed Content-Length")))
+        .transpose()?;
+
+    if bodiless {
+        // A HEAD response keeps the length of the body it describes.
+        writer.start(head.status, &headers, if request.method == "HEAD" { length } else { Some(0) })?;
+        return Ok(head.keep_alive && head.rest.is_empty());
+    }
+
+    if chunked {
+        writer.start(head.status, &headers, None)?;
+        let mut decoder = ChunkedDecoder::new(usize::MAX, usize::MAX);
+        let mut input = head.rest;
+        let mut out = Vec::new();
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let used = decoder.decode(&input, &mut out).map_err(|e| invalid(&e.to_string()))?;
+            writer.write_all(&out)?;
+            out.clear();
+            if decoder.is_done() {
+                // Passed on after the body, e.g. a gRPC status.
+                for (name, value) in decoder.take_trailers().iter() {
+                    writer.add_trailer(name, value);
+                }
+                return Ok(head.keep_alive && used == input.len());
+            }
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "upstream closed mid-body"));
+            }
+            input.clear();
+            input.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    match length {
+        Some(len) => {
+            writer.start(head.status, &headers, Some(len))?;
+            let early = head.rest.len().min(len as usize);
+            writer.write_all(&head.rest[..early])?;
+            let remaining = len - early as u64;
+            let copied = io::copy(&mut (&mut *stream).take(remaining), writer)?;
+            if copied < remaining {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "upstream closed mid-body"));
+            }
+            Ok(head.keep_alive && head.rest.len() == early)
+        }
+        None => {
+            // The body runs until the upstream closes the connection.
+            writer.start(head.status, &headers, None)?;
+            writer.write_all(&head.rest)?;
+            io::copy(stream, writer)?;
+            Ok(false)
+        }
+    }
+}
+
+fn header_values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .iter()
+        .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// The lowercase options of the Connection headers in `values`, each of
+/// which names a further header that is not to be forwarded.
+fn connection_tokens<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    values
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, dropped: &[String]) -> bool {
+    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop)) || dropped.iter().any(|d| name.eq_ignore_ascii_case(d))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("bad upstream response: {}", msg))
+}
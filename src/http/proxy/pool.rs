@@ -0,0 +1,155 @@
+//! Keep-alive connections to an upstream, kept between proxied requests so
+//! that each one does not pay for a new TCP handshake.
+
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::resolver::Address;
+
+/// Limits on the idle connections a [`Pool`] keeps.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PoolSettings {
+    /// Most idle connections kept; more are closed as they are returned.
+    pub(crate) max_idle: usize,
+    /// How long a connection may sit idle before it is closed.
+    pub(crate) idle_timeout: Duration,
+    /// How long a connection may be used in all, `None` for no limit.
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) connect_timeout: Duration,
+    /// Read and write timeout on upstream sockets.
+    pub(crate) io_timeout: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_idle: 32,
+            idle_timeout: Duration::from_secs(60),
+            max_lifetime: None,
+            connect_timeout: Duration::from_secs(5),
+            io_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A connection checked out of the pool for one exchange.
+pub(crate) struct Connection {
+    pub(crate) stream: TcpStream,
+    created: Instant,
+    /// Whether it carried an earlier exchange, and so may have been closed
+    /// by the upstream in the meantime.
+    pub(crate) reused: bool,
+}
+
+struct Idle {
+    stream: TcpStream,
+    /// The upstream's address when it was opened.
+    addr: SocketAddr,
+    created: Instant,
+    since: Instant,
+}
+
+/// Idle connections to one upstream, most recently used last.
+pub(crate) struct Pool {
+    address: Arc<Address>,
+    settings: PoolSettings,
+    idle: Mutex<Vec<Idle>>,
+}
+
+impl Pool {
+    pub(crate) fn new(address: Arc<Address>, settings: PoolSettings) -> Self {
+        Pool {
+            address,
+            settings,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn address(&self) -> &Arc<Address> {
+        &self.address
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.address.get()
+    }
+
+    pub(crate) fn settings(&self) -> PoolSettings {
+        self.settings
+    }
+
+    /// The most recently used idle connection still fit for use, or a new
+    /// one. Connections found expired, closed by the upstream or open to
+    /// an address it has since moved from are dropped on the way.
+    pub(crate) fn checkout(&self) -> io::Result<Connection> {
+        let now = Instant::now();
+        let addr = self.addr();
+        loop {
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            let Some(idle) = idle else {
+                break;
+            };
+            if idle.addr == addr && !self.expired(idle.created, idle.since, now) && is_open(&idle.stream) {
+                return Ok(Connection {
+                    stream: idle.stream,
+                    created: idle.created,
+                    reused: true,
+                });
+            }
+        }
+        self.connect()
+    }
+
+    /// A new connection, bypassing the idle ones.
+    pub(crate) fn connect(&self) -> io::Result<Connection> {
+        let stream = TcpStream::connect_timeout(&self.addr(), self.settings.connect_timeout)?;
+        stream.set_read_timeout(Some(self.settings.io_timeout))?;
+        stream.set_write_timeout(Some(self.settings.io_timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            created: Instant::now(),
+            reused: false,
+        })
+    }
+
+    /// Returns a connection whose last exchange completed cleanly, keeping
+    /// it for reuse unless it is past its lifetime or the pool is full.
+    pub(crate) fn checkin(&self, connection: Connection) {
+        let now = Instant::now();
+        if self.expired(connection.created, now, now) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.retain(|conn| !self.expired(conn.created, conn.since, now));
+        if idle.len() < self.settings.max_idle {
+            let addr = match connection.stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+            idle.push(Idle {
+                stream: connection.stream,
+                addr,
+                created: connection.created,
+                since: now,
+            });
+        }
+    }
+
+    fn expired(&self, created: Instant, idle_since: Instant, now: Instant) -> bool {
+        now.duration_since(idle_since) >= self.settings.idle_timeout
+            || self.settings.max_lifetime.is_some_and(|lifetime| now.duration_since(created) >= lifetime)
+    }
+}
+
+/// Whether an idle connection is still open: nothing to read yet. The
+/// upstream closing it, or sending anything unprompted, shows up as a
+/// readable socket.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(stream.peek(&mut [0u8; 1]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    open && stream.set_nonblocking(false).is_ok()
+}
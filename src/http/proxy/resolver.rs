@@ -0,0 +1,121 @@
+//! Upstream addresses given as host names, resolved again in the
+//! background as their cached results expire, so that requests never wait
+//! on DNS and upstreams moving to new addresses are followed.
+
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{info, warn};
+
+/// An upstream's address as given, and what it currently resolves to.
+#[derive(Debug)]
+pub(crate) struct Address {
+    name: String,
+    /// Whether `name` is a host name rather than a literal address.
+    named: bool,
+    current: RwLock<SocketAddr>,
+}
+
+impl Address {
+    /// Resolves `name`, a `host:port` address, for the first time. Unlike
+    /// the re-resolution that follows, this blocks.
+    pub(crate) fn resolve(name: &str) -> io::Result<Address> {
+        let named = name.parse::<SocketAddr>().is_err();
+        Ok(Address {
+            name: name.to_string(),
+            named,
+            current: RwLock::new(lookup(name)?),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The address from the latest successful lookup.
+    pub(crate) fn get(&self) -> SocketAddr {
+        *self.current.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set(&self, addr: SocketAddr) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = addr;
+    }
+}
+
+/// The first address `name` resolves to.
+fn lookup(name: &str) -> io::Result<SocketAddr> {
+    name.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "host has no addresses"))
+}
+
+/// Looks up a set of named upstream addresses again every `ttl` on a
+/// background thread, until dropped. A lookup that fails keeps the
+/// address from the last one that did not.
+pub(crate) struct Resolver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Resolver {
+    /// A resolver for those of `addresses` given as host names, or `None`
+    /// when there are none.
+    pub(crate) fn spawn(ttl: Duration, addresses: Vec<Arc<Address>>) -> Option<Self> {
+        let addresses: Vec<_> = addresses.into_iter().filter(|address| address.named).collect();
+        if addresses.is_empty() {
+            return None;
+        }
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            changed: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("resolver".to_string())
+            .spawn(move || run(&thread_shared, ttl, &addresses))
+            .expect("failed to spawn resolver thread");
+        Some(Resolver { shared })
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+fn run(shared: &Shared, ttl: Duration, addresses: &[Arc<Address>]) {
+    let mut next = Instant::now() + ttl;
+    loop {
+        let stopped = shared.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        let wait = next.saturating_duration_since(Instant::now());
+        let (stopped, _) = shared
+            .changed
+            .wait_timeout_while(stopped, wait, |stopped| !*stopped)
+            .unwrap_or_else(|e| e.into_inner());
+        if *stopped {
+            return;
+        }
+        drop(stopped);
+
+        for address in addresses {
+            match lookup(&address.name) {
+                Ok(addr) if addr != address.get() => {
+                    info!("Upstream {} moved from {} to {}", address.name, address.get(), addr);
+                    address.set(addr);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Error resolving upstream {}, keeping {}: {}", address.name, address.get(), e),
+            }
+        }
+        next = Instant::now() + ttl;
+    }
+}
@@ -0,0 +1,66 @@
+//! Parsed `application/x-www-form-urlencoded` pairs, as found in query
+//! strings and HTML form submissions.
+
+use crate::util::percent;
+
+/// Name/value pairs in the order they appeared. A name may occur more than
+/// once, as in `?tag=a&tag=b`.
+///
+/// ```
+/// use hyperport::http::Query;
+///
+/// let query = Query::parse("page=2&sort=name&tag=a%20b&tag=c");
+/// assert_eq!(query.get("page"), Some("2"));
+/// assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["a b", "c"]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Splits `input` on `&` and each pair on its first `=`, then decodes
+    /// `%XX` escapes and `+` as a space. A pair without `=` has an empty
+    /// value; empty pairs are skipped.
+    pub fn parse(input: &str) -> Self {
+        let pairs = input
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent::decode_lossy(name, true), percent::decode_lossy(value, true))
+            })
+            .collect();
+        Query { pairs }
+    }
+
+    /// Returns the first value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value for `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(n, _)| n == name)
+    }
+
+    /// All pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
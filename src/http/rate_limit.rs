@@ -0,0 +1,192 @@
+//! Limiting how fast each client may send requests, with a token bucket
+//! per client.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::server::stats;
+
+/// Buckets kept before full ones, which have nothing to remember, are
+/// swept out.
+const SWEEP_AT: usize = 65536;
+
+/// A sustained rate and how far a client may burst above it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    /// Tokens added per second.
+    rate: f64,
+    /// Tokens a bucket holds when full.
+    burst: f64,
+}
+
+impl Limit {
+    /// `requests` a second, any of which may come at once.
+    pub fn per_second(requests: u32) -> Self {
+        Limit::new(requests, Duration::from_secs(1))
+    }
+
+    /// `requests` a minute, any of which may come at once.
+    pub fn per_minute(requests: u32) -> Self {
+        Limit::new(requests, Duration::from_secs(60))
+    }
+
+    /// `requests` every `period`, any of which may come at once.
+    ///
+    /// # Panics
+    ///
+    /// If `requests` or `period` is zero.
+    pub fn new(requests: u32, period: Duration) -> Self {
+        assert!(requests > 0 && !period.is_zero(), "a rate limit must allow some requests");
+        Limit {
+            rate: requests as f64 / period.as_secs_f64(),
+            burst: requests as f64,
+        }
+    }
+
+    /// How many requests may come at once, before the rate applies.
+    /// Defaults to the number per period; at least one.
+    pub fn burst(mut self, requests: u32) -> Self {
+        self.burst = requests.max(1) as f64;
+        self
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Clone, Debug)]
+enum Key {
+    ClientIp,
+    Header(String),
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens held at `now`.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.limit.rate).min(self.limit.burst)
+    }
+}
+
+/// Middleware refusing clients that send requests faster than their
+/// [`Limit`] with 429 and a `Retry-After` saying when to try again.
+///
+/// Each client has a token bucket, which holds up to the limit's burst and
+/// refills at its rate; every request takes a token, and a request finding
+/// none is refused. Clients are told apart by
+/// [`Request::client_ip`], so wrap [`TrustedProxies`](crate::http::TrustedProxies)
+/// first when behind a proxy, or by a header such as an API key with
+/// [`key_header`](RateLimit::key_header).
+///
+/// Routes given a limit of their own with
+/// [`Router::rate_limit`](crate::http::Router::rate_limit) have separate
+/// buckets, shared by the methods registered for the same path; other
+/// requests take from the default limit, and go unlimited without one.
+///
+/// ```ignore
+/// router.wrap(RateLimit::new(Limit::per_second(10).burst(20)));
+/// router.rate_limit("POST", "/login", Limit::per_minute(5));
+/// ```
+pub struct RateLimit {
+    limit: Option<Limit>,
+    key: Key,
+    /// By route path, empty for the default limit, and client.
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl RateLimit {
+    /// Limits every request to `limit`, except on routes with their own.
+    pub fn new(limit: Limit) -> Self {
+        RateLimit {
+            limit: Some(limit),
+            ..RateLimit::routes_only()
+        }
+    }
+
+    /// Limits only the routes given a limit with
+    /// [`Router::rate_limit`](crate::http::Router::rate_limit).
+    pub fn routes_only() -> Self {
+        RateLimit {
+            limit: None,
+            key: Key::ClientIp,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives each value of header `name` a bucket, instead of each client
+    /// address. Requests without the header fall back to their address.
+    pub fn key_header(mut self, name: &str) -> Self {
+        self.key = Key::Header(name.to_string());
+        self
+    }
+
+    fn client_key(&self, request: &Request) -> String {
+        let header = match &self.key {
+            Key::Header(name) => request.headers.get(name),
+            Key::ClientIp => None,
+        };
+        match header {
+            Some(value) => format!("h:{}", value),
+            None => request.client_ip.map_or_else(String::new, |ip| ip.to_string()),
+        }
+    }
+
+    /// Takes a token from the bucket for `scope` and `client`, or returns
+    /// how long until one is available.
+    fn take(&self, scope: &str, client: String, limit: Limit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= SWEEP_AT {
+            buckets.retain(|_, bucket| bucket.tokens_at(now) < bucket.limit.burst);
+        }
+        let bucket = buckets.entry((scope.to_string(), client)).or_insert(Bucket {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens_at(now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        let (scope, limit) = match next.router.rate_limit_for(request) {
+            Some((path, limit)) => (path, limit),
+            None => match self.limit {
+                Some(limit) => ("", limit),
+                None => return next.run(request, writer),
+            },
+        };
+        match self.take(scope, self.client_key(request), limit) {
+            Ok(()) => {
+                stats::RATE_LIMIT_ALLOWED.fetch_add(1, Ordering::Relaxed);
+                next.run(request, writer)
+            }
+            Err(wait) => {
+                stats::RATE_LIMIT_REJECTED.fetch_add(1, Ordering::Relaxed);
+                // Whole seconds, rounded up so that the retry finds a token.
+                let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                writer.add_header("Retry-After", &secs.max(1).to_string());
+                writer.send_error(StatusCode::TooManyRequests)
+            }
+        }
+    }
+}
@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use crate::http::cookie::Cookies;
+use crate::http::jwt_auth::Claims;
+use crate::http::multipart::{self, Multipart};
+use crate::http::query::Query;
+use crate::util::percent;
+
+/// HTTP protocol versions understood by the parser.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    /// Requests read off HTTP/2 streams; never parsed from a request line.
+    Http2,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2.0",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Ordered header list with case-insensitive name lookup. Duplicate names
+/// are kept as separate entries, in the order they were received.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    /// Returns the first value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value for `name`, in arrival order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every header as a `(name, value)` pair, in arrival order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Adds a value without touching existing entries of the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Removes every value for `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// True when any comma-separated element of any `name` header equals
+    /// `token`, compared case-insensitively (e.g. `Connection: close`).
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+/// Size limits on a request head, so that a client cannot make the server
+/// buffer or parse unbounded input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeadLimits {
+    /// Longest request line, without its line ending. Longer ones get 414.
+    pub max_request_line: usize,
+    /// Longest single header line. Longer ones get 431.
+    pub max_header_size: usize,
+    /// Largest whole head, request line and blank line included. Larger
+    /// ones get 431.
+    pub max_head_size: usize,
+    /// Most header fields in one request. More get 431.
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_request_line: 8 * 1024,
+            max_header_size: 8 * 1024,
+            max_head_size: 64 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+impl HeadLimits {
+    /// Checks a head that may still be arriving. Returns its length once
+    /// complete, `None` while more may be read, or the limit it breaks.
+    /// The limits on individual headers are left to [`Request::parse_with_limits`].
+    pub fn check(&self, buf: &[u8]) -> Result<Option<usize>, ParseError> {
+        let line_len = match buf.iter().position(|&b| b == b'\n') {
+            Some(nl) if nl > 0 && buf[nl - 1] == b'\r' => nl - 1,
+            Some(nl) => nl,
+            None => buf.len(),
+        };
+        if line_len > self.max_request_line {
+            return Err(ParseError::RequestLineTooLong);
+        }
+
+        match find_head_end(buf) {
+            Some(len) if len <= self.max_head_size => Ok(Some(len)),
+            None if buf.len() < self.max_head_size => Ok(None),
+            _ => Err(ParseError::HeadTooLarge),
+        }
+    }
+}
+
+/// Why a request head could not be parsed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The blank line ending the head has not arrived yet.
+    Incomplete,
+    InvalidRequestLine,
+    /// A response's status line is malformed.
+    InvalidStatusLine,
+    InvalidHeader,
+    UnsupportedVersion,
+    /// The path has a malformed `%XX` escape, or decodes to something other
+    /// than UTF-8 without NUL bytes.
+    InvalidTarget,
+    /// The request line is longer than [`HeadLimits::max_request_line`].
+    RequestLineTooLong,
+    /// A header line is longer than [`HeadLimits::max_header_size`].
+    HeaderTooLarge,
+    /// The head is larger than [`HeadLimits::max_head_size`].
+    HeadTooLarge,
+    /// There are more than [`HeadLimits::max_headers`] header fields.
+    TooManyHeaders,
+    /// An HTTP/1.1 request has no Host header, or a request has several.
+    MissingHost,
+    /// Expect asks for something other than `100-continue`.
+    ExpectationFailed,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseError::Incomplete => "incomplete request head",
+            ParseError::InvalidRequestLine => "invalid request line",
+            ParseError::InvalidStatusLine => "invalid status line",
+            ParseError::InvalidHeader => "invalid header line",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::InvalidTarget => "invalid request target",
+            ParseError::RequestLineTooLong => "request line too long",
+            ParseError::HeaderTooLarge => "header line too large",
+            ParseError::HeadTooLarge => "request head too large",
+            ParseError::TooManyHeaders => "too many header fields",
+            ParseError::MissingHost => "missing or repeated Host header",
+            ParseError::ExpectationFailed => "unsupported expectation",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How the body following a request head is delimited.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    /// Exactly this many bytes follow the head (possibly zero).
+    Fixed(usize),
+    /// The body uses chunked transfer coding and ends with a zero chunk.
+    Chunked,
+}
+
+/// Why the framing of a request body could not be determined, or its
+/// content coding undone.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyError {
+    /// Content-Length is malformed or given several conflicting values.
+    InvalidLength,
+    /// The method carries a body but no length was supplied.
+    LengthRequired,
+    /// Transfer-Encoding names a coding other than a final `chunked`.
+    UnsupportedTransferCoding,
+    /// Content-Encoding names something other than a single `gzip` or
+    /// `deflate`.
+    UnsupportedContentCoding,
+    /// The compressed body is corrupt or truncated.
+    InvalidContentCoding,
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            BodyError::InvalidLength => "invalid Content-Length",
+            BodyError::LengthRequired => "request body length required",
+            BodyError::UnsupportedTransferCoding => "unsupported transfer coding",
+            BodyError::UnsupportedContentCoding => "unsupported content coding",
+            BodyError::InvalidContentCoding => "corrupt compressed body",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+/// A parsed request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// The request-target exactly as sent, e.g. `/search?q=rust`.
+    pub target: String,
+    /// The target without its query string, percent-decoded, with `.` and
+    /// `..` segments resolved and repeated slashes collapsed, e.g.
+    /// `/search`. Routing matches against this, so `/a/./b`, `//a/b` and
+    /// `/a/%62` all reach the same route, and no path climbs above `/`.
+    pub path: String,
+    pub version: Version,
+    pub headers: Headers,
+    /// The request body, empty until it has been read off the connection,
+    /// or when it was spooled to [`body_file`](Request::body_file).
+    pub body: Vec<u8>,
+    /// A body larger than the server's spool threshold, kept in an unnamed
+    /// temporary file instead of memory. [`body_reader`](Request::body_reader)
+    /// reads either kind.
+    pub body_file: Option<Arc<File>>,
+    /// Trailer fields sent after a chunked body, or after an HTTP/2 body,
+    /// once the body has been read. They are kept apart from `headers`
+    /// because a client may put anything there, framing fields included.
+    pub trailers: Headers,
+    /// Values captured by the matched route's `{name}` segments.
+    pub params: HashMap<String, String>,
+    /// Address of the client, when the connection reports one.
+    pub peer_addr: Option<SocketAddr>,
+    /// Address the client connected to.
+    pub local_addr: Option<SocketAddr>,
+    /// The client the request originated from: the peer's address, unless
+    /// [`TrustedProxies`](crate::http::TrustedProxies) found it forwarded
+    /// on behalf of another.
+    pub client_ip: Option<IpAddr>,
+    /// The ID [`RequestId`](crate::http::RequestId) gave the request, for
+    /// correlating it with other services' logs.
+    pub request_id: Option<String>,
+    /// The user the request was authenticated as, by
+    /// [`BasicAuth`](crate::http::BasicAuth), or the `sub` claim of the
+    /// token [`JwtAuth`](crate::http::JwtAuth) validated.
+    pub user: Option<String>,
+    /// The claims of the token [`JwtAuth`](crate::http::JwtAuth) validated.
+    pub claims: Option<Claims>,
+}
+
+impl Request {
+    /// Parses a request head from the front of `buf`, within the default
+    /// [`HeadLimits`].
+    ///
+    /// Returns the request and the number of bytes it occupied, so callers
+    /// reading from a socket can keep whatever follows. When the head is
+    /// still incomplete `ParseError::Incomplete` is returned and the caller
+    /// should read more and try again.
+    pub fn parse(buf: &[u8]) -> Result<(Request, usize), ParseError> {
+        Request::parse_with_limits(buf, &HeadLimits::default())
+    }
+
+    /// Like [`parse`](Request::parse), failing as soon as the head breaks
+    /// one of `limits`, even before it is complete.
+    pub fn parse_with_limits(buf: &[u8], limits: &HeadLimits) -> Result<(Request, usize), ParseError> {
+        let head_len = limits.check(buf)?.ok_or(ParseError::Incomplete)?;
+        let mut lines = buf[..head_len]
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+        let request_line = lines.next().ok_or(ParseError::InvalidRequestLine)?;
+        let request_line = std::str::from_utf8(request_line).map_err(|_| ParseError::InvalidRequestLine)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        if !method.bytes().all(is_token_byte) {
+            return Err(ParseError::InvalidRequestLine);
+        }
+
+        let raw_path = target.split_once('?').map_or(target, |(path, _)| path);
+        let path = if raw_path.starts_with('/') {
+            let decoded = percent::decode(raw_path).ok_or(ParseError::InvalidTarget)?;
+            if decoded.contains('\0') {
+                return Err(ParseError::InvalidTarget);
+            }
+            normalize_path(&decoded)
+        } else {
+            // `*` or an absolute or authority form, which are not paths.
+            raw_path.to_string()
+        };
+
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        let headers = parse_headers(lines, limits)?;
+
+        Ok((
+            Request {
+                method: method.to_string(),
+                target: target.to_string(),
+                path,
+                version,
+                headers,
+                body: Vec::new(),
+                body_file: None,
+                trailers: Headers::new(),
+                params: HashMap::new(),
+                peer_addr: None,
+                local_addr: None,
+                client_ip: None,
+                request_id: None,
+                user: None,
+                claims: None,
+            },
+            head_len,
+        ))
+    }
+
+    /// The raw query string after `?`, if the target has one.
+    pub fn query_string(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// The query string parsed into decoded name/value pairs; empty when
+    /// there is none. Parsed afresh on each call.
+    pub fn query(&self) -> Query {
+        self.query_string().map(Query::parse).unwrap_or_default()
+    }
+
+    /// Reads the body from the start, wherever it is kept.
+    pub fn body_reader(&self) -> BodyReader<'_> {
+        let source = match &self.body_file {
+            Some(file) => BodySource::File(file),
+            None => BodySource::Memory(&self.body),
+        };
+        BodyReader { source, pos: 0 }
+    }
+
+    /// The length of the body, wherever it is kept.
+    pub fn body_len(&self) -> io::Result<u64> {
+        match &self.body_file {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(self.body.len() as u64),
+        }
+    }
+
+    /// The media type of the body, lowercased and without parameters, e.g.
+    /// `text/html` for `Content-Type: text/html; charset=utf-8`.
+    pub fn content_type(&self) -> Option<String> {
+        let value = self.headers.get("content-type")?;
+        let media_type = value.split(';').next().unwrap_or("").trim();
+        Some(media_type.to_ascii_lowercase())
+    }
+
+    /// The body of an HTML form submission, parsed into decoded pairs like
+    /// the query string. `None` unless the Content-Type is
+    /// `application/x-www-form-urlencoded`. Parsed afresh on each call.
+    pub fn form(&self) -> Option<Query> {
+        if self.content_type()? != "application/x-www-form-urlencoded" {
+            return None;
+        }
+        let mut body = Vec::new();
+        self.body_reader().read_to_end(&mut body).ok()?;
+        Some(Query::parse(&String::from_utf8_lossy(&body)))
+    }
+
+    /// A reader over the parts of a `multipart/form-data` body, with the
+    /// default limits. `None` unless the Content-Type is multipart with a
+    /// boundary. Use [`Multipart::with_limits`] on the body for others.
+    pub fn multipart(&self) -> Option<Multipart<BodyReader<'_>>> {
+        Some(Multipart::new(self.body_reader(), &self.multipart_boundary()?))
+    }
+
+    /// The `boundary` parameter of a `multipart/form-data` Content-Type.
+    pub fn multipart_boundary(&self) -> Option<String> {
+        if self.content_type()? != "multipart/form-data" {
+            return None;
+        }
+        let value = self.headers.get("content-type")?;
+        multipart::parse_params(value)
+            .skip(1)
+            .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+            .map(|(_, boundary)| boundary)
+            .filter(|boundary| (1..=70).contains(&boundary.len()))
+    }
+
+    /// The cookies from the request's `Cookie` headers. Parsed afresh on
+    /// each call.
+    pub fn cookies(&self) -> Cookies {
+        Cookies::from_headers(self.headers.get_all("cookie"))
+    }
+
+    /// Returns the path parameter `name` captured by the router.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// HTTP/1.1 connections persist unless the client sends `Connection: close`;
+    /// HTTP/1.0 ones only persist when it asks for `Connection: keep-alive`.
+    /// HTTP/2 connections outlive any one request.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("connection", "close") {
+            return false;
+        }
+
+        match self.version {
+            Version::Http11 | Version::Http2 => true,
+            Version::Http10 => self.headers.has_token("connection", "keep-alive"),
+        }
+    }
+
+    /// Whether the client waits for a `100 Continue` before sending its
+    /// body. Expectations other than `100-continue` cannot be met. HTTP/1.0
+    /// clients predate Expect, so theirs is ignored (RFC 9110 §10.1.1).
+    pub fn expects_continue(&self) -> Result<bool, ParseError> {
+        if self.version == Version::Http10 {
+            return Ok(false);
+        }
+        let mut expects = false;
+        for value in self.headers.get_all("expect") {
+            if !value.trim().eq_ignore_ascii_case("100-continue") {
+                return Err(ParseError::ExpectationFailed);
+            }
+            expects = true;
+        }
+        Ok(expects)
+    }
+
+    /// Determines how many body bytes follow the head.
+    ///
+    /// Requests without a Content-Length have no body, except for methods
+    /// that are expected to carry one, which must say how long it is.
+    pub fn body_length(&self) -> Result<BodyLength, BodyError> {
+        if self.headers.get("transfer-encoding").is_some() {
+            // A request carrying both framings is a classic smuggling vector;
+            // refuse it rather than guess which one an upstream would honour.
+            if self.headers.get("content-length").is_some() {
+                return Err(BodyError::InvalidLength);
+            }
+
+            let codings: Vec<&str> = self
+                .headers
+                .get_all("transfer-encoding")
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .collect();
+
+            return match codings.as_slice() {
+                [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(BodyLength::Chunked),
+                _ => Err(BodyError::UnsupportedTransferCoding),
+            };
+        }
+
+        let mut length = None;
+        for value in self.headers.get_all("content-length") {
+            // Some clients fold duplicates into a list: "Content-Length: 5, 5".
+            for part in value.split(',') {
+                let part = part.trim();
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(BodyError::InvalidLength);
+                }
+                let n: usize = part.parse().map_err(|_| BodyError::InvalidLength)?;
+                match length {
+                    Some(existing) if existing != n => return Err(BodyError::InvalidLength),
+                    _ => length = Some(n),
+                }
+            }
+        }
+
+        match length {
+            Some(n) => Ok(BodyLength::Fixed(n)),
+            None if matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") => {
+                Err(BodyError::LengthRequired)
+            }
+            None => Ok(BodyLength::Fixed(0)),
+        }
+    }
+}
+
+/// Reads a request body, from memory or from its spool file. Each reader
+/// keeps its own position, so several can be used at once.
+pub struct BodyReader<'a> {
+    source: BodySource<'a>,
+    pos: u64,
+}
+
+enum BodySource<'a> {
+    Memory(&'a [u8]),
+    File(&'a File),
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.source {
+            BodySource::Memory(body) => {
+                let mut rest = body.get(self.pos as usize..).unwrap_or(&[]);
+                rest.read(buf)?
+            }
+            BodySource::File(file) => file.read_at(buf, self.pos)?,
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Parses the header lines of a request or response head, stopping at the
+/// blank line that ends it.
+pub(crate) fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a [u8]>,
+    limits: &HeadLimits,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    let mut count = 0;
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if line.len() > limits.max_header_size {
+            return Err(ParseError::HeaderTooLarge);
+        }
+        count += 1;
+        if count > limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        // Obsolete line folding is rejected outright (RFC 7230 §3.2.4).
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        let colon = line.iter().position(|&b| b == b':').ok_or(ParseError::InvalidHeader)?;
+        let name = &line[..colon];
+        if name.is_empty() || !name.iter().copied().all(is_token_byte) {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        let name = std::str::from_utf8(name).map_err(|_| ParseError::InvalidHeader)?;
+        let value = String::from_utf8_lossy(&line[colon + 1..]);
+        headers.append(name, value.trim_matches(|c| c == ' ' || c == '\t'));
+    }
+    Ok(headers)
+}
+
+/// Returns the offset just past the blank line terminating the request head.
+/// Bare `\n` line endings are tolerated alongside `\r\n`.
+pub fn find_head_end(buf: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while let Some(pos) = buf[i..].iter().position(|&b| b == b'\n') {
+        let nl = i + pos;
+        match &buf[nl + 1..] {
+            [b'\n', ..] => return Some(nl + 2),
+            [b'\r', b'\n', ..] => return Some(nl + 3),
+            _ => i = nl + 1,
+        }
+    }
+    None
+}
+
+/// Resolves `.` and `..` segments (RFC 3986 §5.2.4) and drops empty ones,
+/// keeping a trailing slash. `..` at the root stays at the root.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    // A path ending in a slash, `.` or `..` names a directory.
+    let mut trailing_slash = path.ends_with('/');
+    for segment in path.split('/') {
+        match segment {
+            "" => {}
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = path.ends_with('/');
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// `tchar` from RFC 7230: the characters allowed in methods and header names.
+pub(crate) fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
@@ -0,0 +1,92 @@
+//! Request IDs, for following one request through the logs of every
+//! service it passes.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+
+/// Incoming IDs longer than this are replaced rather than propagated.
+const MAX_LEN: usize = 200;
+
+/// Middleware giving every request an ID in [`Request::request_id`].
+///
+/// An ID the client or a proxy in front already sent in `X-Request-Id` is
+/// kept, so that one ID follows the request across services; otherwise a
+/// new random one is made up. Either way the header is set on the request,
+/// where a [`Proxy`](crate::http::Proxy) passes it upstream, and echoed on
+/// the response. The [`AccessLog`](crate::http::AccessLog) records it.
+///
+/// ```ignore
+/// router.wrap(RequestId::new());
+/// ```
+#[derive(Debug)]
+pub struct RequestId {
+    header: String,
+    trust_incoming: bool,
+    keys: RandomState,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        RequestId {
+            header: "X-Request-Id".to_string(),
+            trust_incoming: true,
+            keys: RandomState::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads and writes the ID in header `name` instead of `X-Request-Id`.
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = name.to_string();
+        self
+    }
+
+    /// Whether to keep IDs that arrive with requests. With this off, as
+    /// suits a server facing clients directly, every request gets a new one.
+    pub fn trust_incoming(mut self, trust: bool) -> Self {
+        self.trust_incoming = trust;
+        self
+    }
+
+    /// A new ID: 32 hex digits from a counter run through the randomly
+    /// keyed SipHash, so that they neither repeat nor give away how many
+    /// requests came before.
+    fn generate(&self) -> String {
+        let mut hasher = self.keys.build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        let high = hasher.finish();
+        hasher.write_u8(0);
+        format!("{:016x}{:016x}", high, hasher.finish())
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        let incoming = request.headers.get(&self.header).filter(|id| self.trust_incoming && is_valid(id));
+        let id = incoming.map_or_else(|| self.generate(), str::to_string);
+        request.headers.remove(&self.header);
+        request.headers.append(&self.header, &id);
+        writer.add_header(&self.header, &id);
+        request.request_id = Some(id);
+        next.run(request, writer)
+    }
+}
+
+/// Whether an incoming ID is safe to repeat in headers and log lines:
+/// short, and visible ASCII only.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
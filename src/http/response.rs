@@ -0,0 +1,747 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use crate::http::compression::{Encoder, Negotiated};
+use crate::http::cookie::SetCookie;
+use crate::http::error_pages::ErrorPages;
+use crate::http::h2::StreamSink;
+use crate::http::request::Version;
+use crate::http::status::StatusCode;
+use crate::net::RawTcpStream;
+use crate::server::stats::BYTES_SENT;
+use crate::server::watchdog::Armed;
+use crate::util::time::http_date_now;
+
+/// Largest single sendfile(2) call; keeps one transfer from monopolising
+/// the socket buffer accounting for too long.
+const SENDFILE_CHUNK: usize = 1024 * 1024;
+
+/// Body bytes gathered before a chunk is emitted. Handlers writing many small
+/// pieces get reasonably sized chunks; `flush()` sends whatever is pending.
+const CHUNK_BUFFER: usize = 8 * 1024;
+
+enum Framing {
+    /// Content-Length was sent; this many bytes are still owed.
+    Length(u64),
+    /// Transfer-Encoding: chunked.
+    Chunked,
+    /// HTTP/1.0 peer with unknown length: the body ends when we close.
+    UntilClose,
+    /// HTTP/2 DATA frames, the last of which ends the stream.
+    Frames,
+    /// The answer to a HEAD request: the head describes a body that is
+    /// never sent, so writes are dropped.
+    Discard,
+}
+
+/// Where a response goes: straight onto an HTTP/1 connection, or into
+/// frames on an HTTP/2 stream.
+enum Output<'a> {
+    Http1(&'a mut RawTcpStream),
+    Http2(StreamSink<'a>),
+}
+
+enum State {
+    Head,
+    Body(Framing),
+    Finished,
+}
+
+/// Writes one response onto a connection, streaming the body as the
+/// handler produces it rather than requiring it all up front.
+///
+/// Call `start` with the status and headers, write the body through the
+/// `io::Write` impl, then `finish`. When no length is given the body is sent
+/// with chunked transfer coding, or on HTTP/2 simply ends with the stream.
+pub struct ResponseWriter<'a> {
+    output: Output<'a>,
+    version: Version,
+    keep_alive: bool,
+    state: State,
+    buf: Vec<u8>,
+    /// Headers queued (typically by middleware) for whoever starts the response.
+    extra_headers: Vec<(String, String)>,
+    /// Headers sent only if the response has none of the same name.
+    default_headers: Vec<(String, String)>,
+    /// Trailer fields queued for after the body.
+    trailers: Vec<(String, String)>,
+    status: Option<StatusCode>,
+    body_bytes: u64,
+    /// The request deadline, armed until the response is finished.
+    deadline: Option<Armed>,
+    /// Compression negotiated by middleware, settled when the response starts.
+    compression: Option<Negotiated>,
+    /// Compresses the body, once the response has started compressed.
+    encoder: Option<Encoder>,
+    /// The router's error pages, for `send_error`.
+    error_pages: Option<&'a ErrorPages>,
+    /// Set when answering a HEAD request.
+    head_only: bool,
+    /// The `Server` header for responses that do not set their own.
+    server_header: Option<&'a str>,
+    /// When the response was started, for the slow request log.
+    started_at: Option<Instant>,
+}
+
+impl<'a> ResponseWriter<'a> {
+    pub fn new(stream: &'a mut RawTcpStream, version: Version, keep_alive: bool) -> Self {
+        ResponseWriter::with_output(Output::Http1(stream), version, keep_alive)
+    }
+
+    /// A writer for the response on an HTTP/2 stream.
+    pub(crate) fn http2(sink: StreamSink<'a>) -> Self {
+        ResponseWriter::with_output(Output::Http2(sink), Version::Http2, true)
+    }
+
+    fn with_output(output: Output<'a>, version: Version, keep_alive: bool) -> Self {
+        ResponseWriter {
+            output,
+            version,
+            keep_alive,
+            state: State::Head,
+            buf: Vec::new(),
+            extra_headers: Vec::new(),
+            default_headers: Vec::new(),
+            trailers: Vec::new(),
+            status: None,
+            body_bytes: 0,
+            deadline: None,
+            compression: None,
+            encoder: None,
+            error_pages: None,
+            head_only: false,
+            server_header: None,
+            started_at: None,
+        }
+    }
+
+    /// Whether the connection can carry another request once this response
+    /// is finished. Becomes false if the body framing could not be completed.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    pub fn is_started(&self) -> bool {
+        !matches!(self.state, State::Head)
+    }
+
+    /// Whether this answers a HEAD request. Its head goes out as it would
+    /// for GET, Content-Length included, but body writes are dropped, so
+    /// handlers need not check; one producing an endless or costly body
+    /// can use this to stop early.
+    pub fn is_head(&self) -> bool {
+        self.head_only
+    }
+
+    /// Queues a header to be sent with the response head. Has no effect once
+    /// the response has started.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.extra_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Queues a header to be sent with the response head unless the
+    /// response already has one of the same name, from the handler, an
+    /// upstream or [`add_header`](ResponseWriter::add_header). Has no effect
+    /// once the response has started.
+    pub fn add_default_header(&mut self, name: &str, value: &str) {
+        self.default_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Queues a trailer field, sent after the body when the response
+    /// finishes. Only chunked HTTP/1.1 bodies and HTTP/2 streams can carry
+    /// trailers, so with any other framing they are dropped. A `Trailer`
+    /// header naming them, among the headers the response starts with,
+    /// tells clients to expect them and has an HTTP/1.1 body sent chunked
+    /// even when its length is known.
+    pub fn add_trailer(&mut self, name: &str, value: &str) {
+        self.trailers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Queues a `Set-Cookie` header for `cookie`. Has no effect once the
+    /// response has started.
+    pub fn add_cookie(&mut self, cookie: &SetCookie) {
+        self.add_header("Set-Cookie", &cookie.to_string());
+    }
+
+    /// The status sent, once the response has started.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// Body bytes sent so far, after any compression and excluding framing.
+    pub fn body_bytes(&self) -> u64 {
+        self.body_bytes
+    }
+
+    /// Sends the status line and headers.
+    /// Framing and Connection headers are added here and must not be in
+    /// `headers`.
+    pub fn start(&mut self, status: StatusCode, headers: &[(&str, &str)], content_length: Option<u64>) -> io::Result<()> {
+        if self.is_started() {
+            return Err(io::Error::other("response already started"));
+        }
+        self.started_at = Some(Instant::now());
+
+        let content_length = match self.take_encoder(status, headers, content_length) {
+            Some(encoder) => {
+                self.encoder = Some(encoder);
+                None
+            }
+            None => content_length,
+        };
+        // 204 and 304 responses never have a body, so they carry no framing.
+        let bodiless = matches!(status, StatusCode::NoContent | StatusCode::NotModified);
+        let extra = self.extra_headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let all = headers.iter().copied().chain(extra);
+        let declares_trailers = all.clone().any(|(n, _)| n.eq_ignore_ascii_case("trailer"));
+        let framing = match content_length {
+            _ if bodiless => Framing::Length(0),
+            Some(_) if declares_trailers && self.version == Version::Http11 => Framing::Chunked,
+            Some(len) => Framing::Length(len),
+            None if self.version == Version::Http2 => Framing::Frames,
+            None if self.version == Version::Http11 => Framing::Chunked,
+            None => {
+                // Only a body that is actually sent needs the close to end it.
+                self.keep_alive &= self.head_only;
+                Framing::UntilClose
+            }
+        };
+
+        // A compressed body is a different representation from the one a
+        // strong ETag names, so the tag is weakened.
+        let compressed = self.encoder.is_some();
+        let is_strong_etag = |name: &str, value: &str| name.eq_ignore_ascii_case("etag") && value.starts_with('"');
+        let weak_etag = all.clone().find(|&(n, v)| compressed && is_strong_etag(n, v)).map(|(_, etag)| format!("W/{}", etag));
+        let content_length = match framing {
+            Framing::Length(len) if !bodiless => Some(len.to_string()),
+            _ => None,
+        };
+        // Every response is dated (RFC 9110 §6.6.1) unless the handler, or
+        // the upstream a proxy relays, already dated it.
+        let date = (!all.clone().any(|(n, _)| n.eq_ignore_ascii_case("date"))).then(http_date_now);
+        let server = self.server_header.filter(|_| !all.clone().any(|(n, _)| n.eq_ignore_ascii_case("server")));
+        let defaults = self
+            .default_headers
+            .iter()
+            .filter(|(name, _)| !all.clone().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        let sent = all
+            .filter(|&(n, v)| !(compressed && is_strong_etag(n, v)))
+            .chain(defaults)
+            .chain(weak_etag.as_deref().map(|etag| ("ETag", etag)))
+            .chain(content_length.as_deref().map(|len| ("Content-Length", len)))
+            .chain(date.as_deref().map(|date| ("Date", date)))
+            .chain(server.map(|server| ("Server", server)));
+
+        if let Output::Http2(sink) = &mut self.output {
+            let end_stream =
+                self.head_only || (matches!(framing, Framing::Length(0)) && !declares_trailers && self.trailers.is_empty());
+            sink.headers(status, sent, end_stream)?;
+            self.state = if self.head_only {
+                State::Body(Framing::Discard)
+            } else if end_stream {
+                State::Finished
+            } else {
+                State::Body(framing)
+            };
+            self.status = Some(status);
+            return Ok(());
+        }
+
+        let mut head = serialize_head(status, sent);
+        if let Framing::Chunked = framing {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        head.push_str(if self.keep_alive { "Connection: keep-alive\r\n" } else { "Connection: close\r\n" });
+        head.push_str("\r\n");
+
+        self.state = State::Body(if self.head_only { Framing::Discard } else { framing });
+        self.status = Some(status);
+        self.send_raw(head.as_bytes())
+    }
+
+    /// Sends a complete response with a body known up front.
+    pub fn send(&mut self, status: StatusCode, headers: &[(&str, &str)], body: &[u8]) -> io::Result<()> {
+        // The whole body is at hand, so it can be compressed before the head
+        // goes out and still be sent with its length.
+        if let Some(mut encoder) = self.take_encoder(status, headers, Some(body.len() as u64)) {
+            let mut compressed = encoder.encode(body)?;
+            compressed.extend_from_slice(&encoder.finish()?);
+            self.start(status, headers, Some(compressed.len() as u64))?;
+            self.write_all(&compressed)?;
+            return self.finish();
+        }
+
+        self.start(status, headers, Some(body.len() as u64))?;
+        self.write_all(body)?;
+        self.finish()
+    }
+
+    /// Sends a complete, prebuilt [`Response`].
+    pub fn respond(&mut self, response: &Response) -> io::Result<()> {
+        let headers: Vec<(&str, &str)> = response.headers().collect();
+        self.send(response.status(), &headers, response.body())
+    }
+
+    /// Sends the error page for `status`: the one registered with
+    /// [`Router::error_page`](crate::http::Router::error_page), or the
+    /// built-in page.
+    pub fn send_error(&mut self, status: StatusCode) -> io::Result<()> {
+        let response = match self.error_pages {
+            Some(pages) => pages.response(status),
+            None => ErrorPages::default().response(status),
+        };
+        self.respond(&response)
+    }
+
+    /// Flushes any buffered body and terminates it. Unstarted responses are
+    /// turned into a 500, since the handler never produced anything.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.state {
+            State::Head => return self.send_error(StatusCode::InternalServerError),
+            State::Finished => return Ok(()),
+            State::Body(Framing::Discard) => {
+                self.state = State::Finished;
+                return Ok(());
+            }
+            State::Body(_) => {}
+        }
+
+        if let Some(encoder) = self.encoder.take() {
+            let rest = encoder.finish()?;
+            self.write_body(&rest)?;
+        }
+        if let Output::Http2(sink) = &mut self.output {
+            if let State::Body(Framing::Length(remaining)) = self.state {
+                if remaining > 0 {
+                    self.state = State::Finished;
+                    return Err(io::Error::other("response body shorter than Content-Length"));
+                }
+            }
+            // Whatever is still buffered goes out with END_STREAM, unless
+            // trailers follow to end the stream instead.
+            let pending = std::mem::take(&mut self.buf);
+            self.state = State::Finished;
+            sink.data(&pending, self.trailers.is_empty())?;
+            BYTES_SENT.fetch_add(pending.len() as u64, Ordering::Relaxed);
+            if !self.trailers.is_empty() {
+                sink.trailers(self.trailers.iter().map(|(n, v)| (n.as_str(), v.as_str())))?;
+            }
+            return Ok(());
+        }
+        self.flush_buffer()?;
+        match self.state {
+            State::Body(Framing::Chunked) => {
+                let mut last = String::from("0\r\n");
+                for (name, value) in &self.trailers {
+                    last.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                last.push_str("\r\n");
+                self.send_raw(last.as_bytes())?
+            }
+            State::Body(Framing::Length(remaining)) if remaining > 0 => {
+                self.keep_alive = false;
+                self.state = State::Finished;
+                return Err(io::Error::other("response body shorter than Content-Length"));
+            }
+            _ => {}
+        }
+        self.state = State::Finished;
+        Ok(())
+    }
+
+    /// Applies what the compression middleware negotiated to this response,
+    /// once it starts.
+    pub(crate) fn set_compression(&mut self, negotiated: Negotiated) {
+        self.compression = Some(negotiated);
+    }
+
+    /// Settles compression for a response about to start. If its body is
+    /// to be compressed, queues the headers saying so and returns the
+    /// encoder to use.
+    fn take_encoder(
+        &mut self,
+        status: StatusCode,
+        headers: &[(&str, &str)],
+        content_length: Option<u64>,
+    ) -> Option<Encoder> {
+        let negotiated = self.compression.take()?;
+        let extra = self.extra_headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let mut all = headers.iter().copied().chain(extra);
+        if !negotiated.applies(status, all.clone(), content_length) {
+            return None;
+        }
+        // A handler that varies by encoding itself has said so already.
+        let varies = all.any(|(name, value)| {
+            name.eq_ignore_ascii_case("vary")
+                && value.split(',').any(|field| field.trim().eq_ignore_ascii_case("accept-encoding"))
+        });
+        if !varies {
+            self.add_header("Vary", "Accept-Encoding");
+        }
+        let encoder = negotiated.encoder()?;
+        self.add_header("Content-Encoding", encoder.name());
+        Some(encoder)
+    }
+
+    pub(crate) fn set_error_pages(&mut self, pages: &'a ErrorPages) {
+        self.error_pages = Some(pages);
+    }
+
+    /// Marks this as the answer to a HEAD request.
+    pub(crate) fn set_head_only(&mut self) {
+        self.head_only = true;
+    }
+
+    /// When the head was sent, once it has been.
+    pub(crate) fn started_at(&self) -> Option<Instant> {
+        self.started_at
+    }
+
+    pub(crate) fn set_server_header(&mut self, value: Option<&'a str>) {
+        self.server_header = value;
+    }
+
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Armed>) {
+        self.deadline = deadline;
+    }
+
+    /// Exempts the rest of this response from the server's request timeout,
+    /// for long-lived responses such as event streams.
+    pub fn clear_deadline(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Answers with `101 Switching Protocols` to `protocol` and returns an
+    /// owned handle to the socket for the new protocol to use. The HTTP
+    /// connection is finished: it will not be reused for further requests.
+    pub fn upgrade(&mut self, protocol: &str, headers: &[(&str, &str)]) -> io::Result<RawTcpStream> {
+        if self.is_started() {
+            return Err(io::Error::other("response already started"));
+        }
+        if let Output::Http2(_) = self.output {
+            return Err(io::Error::new(ErrorKind::Unsupported, "HTTP/2 streams cannot be upgraded"));
+        }
+
+        let upgrade = [("Upgrade", protocol), ("Connection", "Upgrade")];
+        let extra = self.extra_headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let all = upgrade.into_iter().chain(headers.iter().copied()).chain(extra);
+        let mut head = serialize_head(StatusCode::SwitchingProtocols, all);
+        head.push_str("\r\n");
+
+        self.keep_alive = false;
+        self.state = State::Finished;
+        self.status = Some(StatusCode::SwitchingProtocols);
+        self.send_raw(head.as_bytes())?;
+
+        // The upgraded protocol decides for itself how long a peer may stay
+        // quiet; the server's read and request timeouts no longer apply.
+        self.clear_deadline();
+        self.stream()?.set_read_timeout(None)?;
+        self.stream()?.try_clone()
+    }
+
+    /// Answers a CONNECT request with `200 OK` and returns an owned handle
+    /// to the socket, which from then on carries the tunnel. As with
+    /// [`upgrade`](ResponseWriter::upgrade), the HTTP connection is
+    /// finished.
+    pub fn tunnel(&mut self) -> io::Result<RawTcpStream> {
+        if self.is_started() {
+            return Err(io::Error::other("response already started"));
+        }
+        if let Output::Http2(_) = self.output {
+            return Err(io::Error::new(ErrorKind::Unsupported, "HTTP/2 streams cannot carry tunnels"));
+        }
+
+        // A 2xx answer to CONNECT has no body, and so no framing headers.
+        let extra = self.extra_headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let mut head = serialize_head(StatusCode::Ok, extra);
+        head.push_str("\r\n");
+
+        self.keep_alive = false;
+        self.state = State::Finished;
+        self.status = Some(StatusCode::Ok);
+        self.send_raw(head.as_bytes())?;
+
+        self.clear_deadline();
+        self.stream()?.set_read_timeout(None)?;
+        self.stream()?.try_clone()
+    }
+
+    /// Writes `len` bytes of `file`, starting at its current position, as
+    /// part of the body.
+    ///
+    /// With a Content-Length response this uses sendfile(2) so the data goes
+    /// from the page cache to the socket without passing through userspace.
+    /// Chunked bodies, HTTP/2 streams, and files the kernel cannot sendfile
+    /// from, fall back to an ordinary buffered copy.
+    pub fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        let remaining = match self.state {
+            State::Body(Framing::Discard) => {
+                file.seek(SeekFrom::Current(len as i64))?;
+                return Ok(());
+            }
+            State::Body(Framing::Length(remaining)) if matches!(self.output, Output::Http1(_)) => remaining,
+            _ => return self.copy_file(file, len),
+        };
+        if len > remaining {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "response body exceeds Content-Length"));
+        }
+
+        self.flush_buffer()?;
+        let start = file.stream_position()? as i64;
+        let end = start + len as i64;
+        let mut offset = start;
+
+        while offset < end {
+            let want = ((end - offset) as usize).min(SENDFILE_CHUNK);
+            let before = offset;
+            match self.stream()?.sendfile(file.as_raw_fd(), &mut offset, want) {
+                Ok(0) => {
+                    // The file shrank underneath us; the promised length can
+                    // no longer be honoured.
+                    self.keep_alive = false;
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof));
+                }
+                Ok(n) => self.account_body(n as u64),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // Only fall back before anything was sent; afterwards the
+                // offsets of the file and the body would disagree.
+                Err(e) if before == start && is_sendfile_unsupported(&e) => {
+                    return self.copy_file(file, len);
+                }
+                Err(e) => {
+                    self.keep_alive = false;
+                    return Err(e);
+                }
+            }
+        }
+
+        file.seek(SeekFrom::Start(end as u64))?;
+        Ok(())
+    }
+
+    fn copy_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        let mut chunk = vec![0u8; 64 * 1024];
+        let mut left = len;
+        while left > 0 {
+            let want = (left as usize).min(chunk.len());
+            match file.read(&mut chunk[..want]) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    self.write_all(&chunk[..n])?;
+                    left -= n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Records body bytes that bypassed the write buffer.
+    fn account_body(&mut self, n: u64) {
+        if let State::Body(Framing::Length(remaining)) = &mut self.state {
+            *remaining -= n;
+        }
+        self.body_bytes += n;
+        BYTES_SENT.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The connection, when the response goes straight onto one.
+    fn stream(&mut self) -> io::Result<&mut RawTcpStream> {
+        match &mut self.output {
+            Output::Http1(stream) => Ok(stream),
+            Output::Http2(_) => Err(io::Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let result = match &mut self.output {
+            Output::Http1(stream) => stream.write_all(bytes),
+            Output::Http2(sink) => sink.data(bytes, false),
+        };
+        if let Err(e) = result {
+            self.keep_alive = false;
+            return Err(e);
+        }
+        BYTES_SENT.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl ResponseWriter<'_> {
+    /// Appends body bytes, after any compression, to the write buffer.
+    fn write_body(&mut self, data: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            State::Head => Err(io::Error::other("response not started")),
+            State::Finished => Err(io::Error::other("response already finished")),
+            State::Body(Framing::Discard) => Ok(data.len()),
+            State::Body(Framing::Length(remaining)) => {
+                if data.len() as u64 > *remaining {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "response body exceeds Content-Length"));
+                }
+                *remaining -= data.len() as u64;
+                self.body_bytes += data.len() as u64;
+                self.buf.extend_from_slice(data);
+                if self.buf.len() >= CHUNK_BUFFER {
+                    self.flush_buffer()?;
+                }
+                Ok(data.len())
+            }
+            State::Body(_) => {
+                self.body_bytes += data.len() as u64;
+                self.buf.extend_from_slice(data);
+                if self.buf.len() >= CHUNK_BUFFER {
+                    self.flush_buffer()?;
+                }
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let pending = std::mem::take(&mut self.buf);
+        if let State::Body(Framing::Chunked) = self.state {
+            let mut framed = Vec::with_capacity(pending.len() + 16);
+            framed.extend_from_slice(format!("{:x}\r\n", pending.len()).as_bytes());
+            framed.extend_from_slice(&pending);
+            framed.extend_from_slice(b"\r\n");
+            self.send_raw(&framed)
+        } else {
+            self.send_raw(&pending)
+        }
+    }
+}
+
+impl Write for ResponseWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let compressed = match &mut self.encoder {
+            Some(encoder) => encoder.encode(data)?,
+            None => return self.write_body(data),
+        };
+        self.write_body(&compressed)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(encoder) = &mut self.encoder {
+            let pending = encoder.flush()?;
+            self.write_body(&pending)?;
+        }
+        self.flush_buffer()
+    }
+}
+
+/// A complete response held in memory: status, headers in the order they
+/// were added, and body. Suited to small replies built up front; stream
+/// anything larger through a [`ResponseWriter`].
+///
+/// ```
+/// use hyperport::http::{Response, StatusCode};
+///
+/// let response = Response::builder()
+///     .status(StatusCode::NotFound)
+///     .header("Content-Type", "text/plain")
+///     .body("no such page");
+/// assert!(response.to_bytes().starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response {
+                status: StatusCode::Ok,
+                headers: Vec::new(),
+                body: Vec::new(),
+            },
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Serializes the response as an HTTP/1.1 message framed by
+    /// Content-Length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = serialize_head(self.status, self.headers());
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// Builds a [`Response`]. The status defaults to 200.
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    /// Appends a header. Content-Length and Transfer-Encoding are derived
+    /// from the body when the response is sent, so those are ignored.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("transfer-encoding") {
+            self.response.headers.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// Appends a `Set-Cookie` header. Call once per cookie.
+    pub fn cookie(self, cookie: &SetCookie) -> Self {
+        self.header("Set-Cookie", &cookie.to_string())
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.response.body = body.into();
+        self.response
+    }
+}
+
+/// Renders the status line and `headers`, in order, without the blank line
+/// that ends the head.
+fn serialize_head<'h>(status: StatusCode, headers: impl Iterator<Item = (&'h str, &'h str)>) -> String {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    head
+}
+
+/// Errors meaning sendfile(2) cannot be used for this file/socket pair,
+/// as opposed to the transfer itself failing.
+fn is_sendfile_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP))
+}
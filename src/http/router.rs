@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::http::error_pages::{ErrorPage, ErrorPages};
+use crate::http::handler::Handler;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::rate_limit::Limit;
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::security_headers::SecurityHeaders;
+use crate::http::status::StatusCode;
+use crate::net::ip_filter::IpFilter;
+
+enum Segment {
+    Literal(String),
+    /// `{name}`: captures exactly one path segment.
+    Param(String),
+    /// `{*name}`: captures the rest of the path, slashes included. Only
+    /// valid as the final segment.
+    CatchAll(String),
+}
+
+/// A compiled route path such as `/users/{id}/posts/{post_id}`.
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(path: &str) -> Self {
+        let segments = path
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => match name.strip_prefix('*') {
+                        Some(rest) => Segment::CatchAll(rest.to_string()),
+                        None => Segment::Param(name.to_string()),
+                    },
+                    None => Segment::Literal(segment.to_string()),
+                }
+            })
+            .collect();
+
+        Pattern { segments }
+    }
+
+    /// Matches `path` against the pattern, returning captured parameters.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut rest = path.trim_start_matches('/');
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Segment::CatchAll(name) = segment {
+                params.insert(name.clone(), rest.to_string());
+                return Some(params);
+            }
+
+            let (part, remainder) = match rest.split_once('/') {
+                Some((part, remainder)) => (part, Some(remainder)),
+                None => (rest, None),
+            };
+
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    params.insert(name.clone(), part.to_string());
+                }
+                _ => return None,
+            }
+
+            match remainder {
+                Some(remainder) => rest = remainder,
+                None if i + 1 == self.segments.len() => return Some(params),
+                // Only a catch-all may match an absent trailing segment.
+                None => {
+                    return match self.segments.get(i + 1) {
+                        Some(Segment::CatchAll(name)) if i + 2 == self.segments.len() => {
+                            params.insert(name.clone(), String::new());
+                            Some(params)
+                        }
+                        _ => None,
+                    };
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Ranks overlapping routes: literal segments beat parameters, and
+    /// parameters beat a catch-all, so `/users/me` wins over `/users/{id}`.
+    fn specificity(&self) -> (usize, usize) {
+        let literals = self.segments.iter().filter(|s| matches!(s, Segment::Literal(_))).count();
+        let params = self.segments.iter().filter(|s| matches!(s, Segment::Param(_))).count();
+        (literals, params)
+    }
+}
+
+/// Answers an OPTIONS request given the methods its path allows, already
+/// formatted for an `Allow` header.
+type OptionsHook = Box<dyn Fn(&Request, &mut ResponseWriter, &str) -> io::Result<()> + Send + Sync>;
+
+struct Route {
+    method: String,
+    /// The path as registered, to find the route again by.
+    path: String,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+    /// Overrides the server-wide request body limit.
+    max_body_size: Option<usize>,
+    /// Cleared to keep the compression middleware off this route.
+    compress: bool,
+    /// Replaces the rate limit middleware's default limit.
+    rate_limit: Option<Limit>,
+    /// Clients refused with 403.
+    ip_filter: Option<IpFilter>,
+    /// Replaces the security headers middleware's headers.
+    security_headers: Option<SecurityHeaders>,
+}
+
+/// Dispatches requests to handlers registered by method and path.
+/// Paths may contain `{name}` parameters and a trailing `{*name}` catch-all;
+/// captured values are available through `Request::param`. Requests matching
+/// no route go to the not-found handler. Middleware registered with `wrap`
+/// runs around every request, matched or not.
+///
+/// A request whose path has routes, but none for its method, is answered
+/// with 405 and an `Allow` header listing the methods there are. OPTIONS
+/// requests without a route of their own are answered from those routes,
+/// or from every route with `OPTIONS *`; see
+/// [`on_options`](Router::on_options).
+///
+/// A router can also serve several sites on one port: requests whose Host
+/// header names a site registered with [`host`](Router::host) go to that
+/// site's router, and all others to this one's own routes, making it the
+/// default host.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+    options: OptionsHook,
+    middleware: Vec<Box<dyn Middleware>>,
+    error_pages: ErrorPages,
+    /// Virtual hosts by lowercase name, in the order registered.
+    hosts: Vec<(String, Router)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_: &Request, writer: &mut ResponseWriter| writer.send_error(StatusCode::NotFound)),
+            options: Box::new(|_: &Request, writer: &mut ResponseWriter, allow: &str| {
+                writer.send(StatusCode::NoContent, &[("Allow", allow)], b"")
+            }),
+            middleware: Vec::new(),
+            error_pages: ErrorPages::default(),
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Adds a middleware layer. The first one added is the outermost.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Registers `handler` for `method` requests matching `path`.
+    pub fn route<F>(&mut self, method: &str, path: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.handle(method, path, handler)
+    }
+
+    /// Like `route`, but accepts any [`Handler`] rather than only closures.
+    /// (Closures passed here need their argument types spelled out, which is
+    /// why `route`, `get` and `post` take `Fn` directly.)
+    pub fn handle<H: Handler + 'static>(&mut self, method: &str, path: &str, handler: H) -> &mut Self {
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            pattern: Pattern::parse(path),
+            handler: Box::new(handler),
+            max_body_size: None,
+            compress: true,
+            rate_limit: None,
+            ip_filter: None,
+            security_headers: None,
+        });
+        self
+    }
+
+    /// Sets the largest request body accepted by the route registered for
+    /// `method` and `path`, in place of the server-wide limit. Larger bodies
+    /// are refused with 413 before the handler runs, so an upload route can
+    /// allow more than the rest of the site, or an API route less.
+    ///
+    /// # Panics
+    ///
+    /// If no route has been registered for `method` and `path`.
+    pub fn max_body_size(&mut self, method: &str, path: &str, max: usize) -> &mut Self {
+        for route in self.routes_for(method, path) {
+            route.max_body_size = Some(max);
+        }
+        self
+    }
+
+    /// Keeps the [`Compression`](crate::http::Compression) middleware from
+    /// compressing responses of the route registered for `method` and
+    /// `path`, for content that is compressed already or must reach the
+    /// client byte for byte.
+    ///
+    /// # Panics
+    ///
+    /// If no route has been registered for `method` and `path`.
+    pub fn disable_compression(&mut self, method: &str, path: &str) -> &mut Self {
+        for route in self.routes_for(method, path) {
+            route.compress = false;
+        }
+        self
+    }
+
+    /// Gives the route registered for `method` and `path` a rate limit of
+    /// its own, applied by the [`RateLimit`](crate::http::RateLimit)
+    /// middleware in place of its default: tighter for a login form, say,
+    /// or looser for a cheap health check.
+    ///
+    /// # Panics
+    ///
+    /// If no route has been registered for `method` and `path`.
+    pub fn rate_limit(&mut self, method: &str, path: &str, limit: Limit) -> &mut Self {
+        for route in self.routes_for(method, path) {
+            route.rate_limit = Some(limit);
+        }
+        self
+    }
+
+    /// Answers requests for the route registered for `method` and `path`
+    /// with 403 unless `filter` allows their [`Request::client_ip`], as
+    /// for an admin page only reachable from the office network. Wrap
+    /// [`TrustedProxies`](crate::http::TrustedProxies) when behind a proxy
+    /// so that the client's own address is the one checked.
+    ///
+    /// # Panics
+    ///
+    /// If no route has been registered for `method` and `path`.
+    pub fn ip_filter(&mut self, method: &str, path: &str, filter: IpFilter) -> &mut Self {
+        for route in self.routes_for(method, path) {
+            route.ip_filter = Some(filter.clone());
+        }
+        self
+    }
+
+    /// Gives responses of the route registered for `method` and `path` the
+    /// headers of `headers`, in place of those the
+    /// [`SecurityHeaders`] middleware adds to others.
+    ///
+    /// # Panics
+    ///
+    /// If no route has been registered for `method` and `path`.
+    pub fn security_headers(&mut self, method: &str, path: &str, headers: SecurityHeaders) -> &mut Self {
+        for route in self.routes_for(method, path) {
+            route.security_headers = Some(headers.clone());
+        }
+        self
+    }
+
+    /// The routes registered for `method` and `path`, of which there must
+    /// be at least one.
+    fn routes_for(&mut self, method: &str, path: &str) -> impl Iterator<Item = &mut Route> {
+        let matching: Vec<&mut Route> = self
+            .routes
+            .iter_mut()
+            .filter(|route| route.method.eq_ignore_ascii_case(method) && route.path == path)
+            .collect();
+        assert!(!matching.is_empty(), "no route registered for {} {}", method, path);
+        matching.into_iter()
+    }
+
+    pub fn get<F>(&mut self, path: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post<F>(&mut self, path: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("POST", path, handler)
+    }
+
+    /// Replaces the handler for requests that match no route, which by
+    /// default sends a plain 404 page. The replacement runs inside the
+    /// middleware chain like any route and may answer however it likes: a
+    /// branded page, a JSON error for API clients, or a redirect.
+    ///
+    /// ```ignore
+    /// router.fallback(|request, writer| {
+    ///     if request.path.starts_with("/api/") {
+    ///         writer.send(StatusCode::NotFound, &[("Content-Type", "application/json")], br#"{"error":"not found"}"#)
+    ///     } else {
+    ///         writer.send(StatusCode::Found, &[("Location", "/")], b"")
+    ///     }
+    /// });
+    /// ```
+    pub fn fallback<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.handle_fallback(handler)
+    }
+
+    /// Like `fallback`, but accepts any [`Handler`], such as a struct
+    /// holding a 404 page loaded once at startup.
+    pub fn handle_fallback<H: Handler + 'static>(&mut self, handler: H) -> &mut Self {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Replaces how OPTIONS requests without a route of their own are
+    /// answered. `hook` gets the methods the request's path allows, as an
+    /// `Allow` value such as `GET, HEAD, POST, OPTIONS`, and writes the
+    /// response; by default it is a 204 carrying just that header. Paths
+    /// no route matches still go to the not-found handler.
+    ///
+    /// A CORS preflight, say, can be answered here for trusted origins:
+    ///
+    /// ```ignore
+    /// router.on_options(|request, writer, allow| {
+    ///     let mut headers = vec![("Allow", allow)];
+    ///     if let Some(origin) = request.headers.get("origin").filter(|o| *o == "https://app.example.com") {
+    ///         headers.extend([("Access-Control-Allow-Origin", origin), ("Access-Control-Allow-Methods", allow)]);
+    ///     }
+    ///     writer.send(StatusCode::NoContent, &headers, b"")
+    /// });
+    /// ```
+    pub fn on_options<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&Request, &mut ResponseWriter, &str) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.options = Box::new(hook);
+        self
+    }
+
+    /// Replaces the built-in page for `status` wherever the server sends it
+    /// on its own, including requests refused before they reach a route;
+    /// see [`error_pages`](crate::http::error_pages). Registering a page
+    /// for the same status again replaces it.
+    ///
+    /// ```ignore
+    /// router
+    ///     .error_page(StatusCode::NotFound, ErrorPage::file("errors/404.html")?)
+    ///     .error_page(StatusCode::ServiceUnavailable, ErrorPage::file("errors/busy.html")?);
+    /// ```
+    pub fn error_page(&mut self, status: StatusCode, page: ErrorPage) -> &mut Self {
+        self.error_pages.insert(status, page);
+        self
+    }
+
+    /// Serves requests for the host `name` with `router`, a complete site
+    /// with its own routes, middleware, error pages and not-found handler.
+    /// This router's middleware still runs first, around every host, so
+    /// that access logging and the like need registering only once.
+    ///
+    /// Names are matched against the Host header without its port and
+    /// regardless of case. A name starting with `*.` matches any subdomain,
+    /// e.g. `*.example.com` takes `www.example.com` but not `example.com`;
+    /// exact names win over wildcards. Requests for no registered name,
+    /// or without a Host header, are served by this router's own routes.
+    ///
+    /// ```ignore
+    /// let mut blog = Router::new();
+    /// blog.handle("GET", "/{*path}", StaticFiles::new("/srv/blog"));
+    /// let mut router = Router::new();
+    /// router.wrap(AccessLog::stdout()).host("blog.example.com", blog);
+    /// ```
+    pub fn host(&mut self, name: &str, router: Router) -> &mut Self {
+        self.hosts.push((name.trim_end_matches('.').to_ascii_lowercase(), router));
+        self
+    }
+
+    /// Runs the request through the middleware chain and then its route.
+    pub fn dispatch(&self, request: &mut Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        Next {
+            chain: &self.middleware,
+            router: self,
+        }
+        .run(request, writer)
+    }
+
+    /// The error pages of the default host, for requests refused before
+    /// their Host header is known.
+    pub(crate) fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    /// The error pages of the host serving `request`.
+    pub(crate) fn error_pages_for(&self, request: &Request) -> &ErrorPages {
+        &self.site_for(request).error_pages
+    }
+
+    /// The body limit set for the route `request` will be dispatched to,
+    /// if any.
+    pub(crate) fn max_body_size_for(&self, request: &Request) -> Option<usize> {
+        let site = self.site_for(request);
+        site.find(request).and_then(|(route, _)| route.max_body_size)
+    }
+
+    /// Whether responses to `request` may be compressed: true unless its
+    /// route has compression disabled.
+    pub(crate) fn compresses(&self, request: &Request) -> bool {
+        let site = self.site_for(request);
+        site.find(request).is_none_or(|(route, _)| route.compress)
+    }
+
+    /// The path and limit of the route `request` will be dispatched to,
+    /// if it has a rate limit of its own.
+    pub(crate) fn rate_limit_for(&self, request: &Request) -> Option<(&str, Limit)> {
+        let site = self.site_for(request);
+        let (route, _) = site.find(request)?;
+        route.rate_limit.map(|limit| (route.path.as_str(), limit))
+    }
+
+    /// The security headers of the route `request` will be dispatched to,
+    /// if it has its own.
+    pub(crate) fn security_headers_for(&self, request: &Request) -> Option<&SecurityHeaders> {
+        let site = self.site_for(request);
+        let (route, _) = site.find(request)?;
+        route.security_headers.as_ref()
+    }
+
+    /// Runs the handler registered for the request, or the not-found handler.
+    /// Path parameters of the matched route are stored on the request.
+    /// Requests for a virtual host go through that host's router instead.
+    pub(crate) fn route_request(&self, request: &mut Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let site = self.site_for(request);
+        if !std::ptr::eq(site, self) {
+            return site.dispatch(request, writer);
+        }
+        match self.find(request) {
+            Some((route, params)) => {
+                let allowed = |filter: &IpFilter| request.client_ip.is_some_and(|ip| filter.allows(ip));
+                if !route.ip_filter.as_ref().is_none_or(allowed) {
+                    return writer.send_error(StatusCode::Forbidden);
+                }
+                request.params = params;
+                route.handler.call(request, writer)
+            }
+            // The path exists, just not for this method.
+            None => match self.allowed_methods(request) {
+                Some(allow) if request.method == "OPTIONS" => (self.options)(request, writer, &allow),
+                Some(allow) => {
+                    writer.add_header("Allow", &allow);
+                    writer.send_error(StatusCode::MethodNotAllowed)
+                }
+                None => self.not_found.call(request, writer),
+            },
+        }
+    }
+
+    /// The methods of the routes matching the request's path, or of every
+    /// route for `OPTIONS *`, with HEAD wherever GET is served and OPTIONS
+    /// always; `None` when no route matches.
+    fn allowed_methods(&self, request: &Request) -> Option<String> {
+        let any_path = request.method == "OPTIONS" && request.path == "*";
+        let mut methods: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let matches = any_path || route.pattern.matches(&request.path).is_some();
+            if matches && !methods.contains(&route.method.as_str()) {
+                methods.push(&route.method);
+            }
+        }
+        if methods.is_empty() {
+            return None;
+        }
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        if !methods.contains(&"OPTIONS") {
+            methods.push("OPTIONS");
+        }
+        Some(methods.join(", "))
+    }
+
+    /// The router for the host `request` names: a virtual host's, or this
+    /// one when none matches.
+    fn site_for(&self, request: &Request) -> &Router {
+        if self.hosts.is_empty() {
+            return self;
+        }
+        let Some(name) = request.headers.get("host").map(host_name) else {
+            return self;
+        };
+        let exact = self.hosts.iter().find(|(host, _)| *host == name);
+        let wildcard = || {
+            self.hosts.iter().find(|(host, _)| {
+                host.strip_prefix('*').is_some_and(|suffix| suffix.starts_with('.') && name.ends_with(suffix))
+            })
+        };
+        exact.or_else(wildcard).map_or(self, |(_, router)| router)
+    }
+
+    /// The most specific route matching the request, with the parameters
+    /// it captures. HEAD requests without a route of their own go to the
+    /// GET route, whose body the response writer then drops.
+    fn find(&self, request: &Request) -> Option<(&Route, HashMap<String, String>)> {
+        let found = self.find_for(&request.method, &request.path);
+        match request.method.as_str() {
+            "HEAD" => found.or_else(|| self.find_for("GET", &request.path)),
+            _ => found,
+        }
+    }
+
+    fn find_for(&self, method: &str, path: &str) -> Option<(&Route, HashMap<String, String>)> {
+        // Reversed so that, among equally specific routes, max_by_key picks
+        // the one registered first.
+        self.routes
+            .iter()
+            .rev()
+            .filter(|route| route.method == method)
+            .filter_map(|route| route.pattern.matches(path).map(|params| (route, params)))
+            .max_by_key(|(route, _)| route.pattern.specificity())
+    }
+}
+
+/// A Host header value reduced to the name alone: lowercase, without the
+/// port or a trailing dot. IPv6 literals keep their brackets.
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(host, |(literal, _)| &host[..literal.len() + 2]),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
@@ -0,0 +1,110 @@
+//! Response headers that have browsers enforce security policies: HTTPS
+//! only, no content sniffing, no framing by other sites, and so on.
+
+use std::io;
+use std::time::Duration;
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+
+/// Middleware adding security headers to every response, unless the
+/// response sets the same header itself.
+///
+/// By default these are `X-Content-Type-Options: nosniff`,
+/// `X-Frame-Options: SAMEORIGIN` and `Referrer-Policy:
+/// strict-origin-when-cross-origin`. `Strict-Transport-Security` and
+/// `Content-Security-Policy` depend too much on the site to have defaults,
+/// and are only sent once set with [`hsts`](SecurityHeaders::hsts) and
+/// [`content_security_policy`](SecurityHeaders::content_security_policy).
+///
+/// A route may have headers of its own with
+/// [`Router::security_headers`](crate::http::Router::security_headers),
+/// which replace these for its responses: a page meant to be embedded
+/// elsewhere can go without `X-Frame-Options`, say, or one with inline
+/// scripts get a looser policy.
+///
+/// ```ignore
+/// router.wrap(
+///     SecurityHeaders::new()
+///         .hsts(Duration::from_secs(365 * 24 * 3600), true)
+///         .content_security_policy("default-src 'self'"),
+/// );
+/// router.security_headers("GET", "/widget", SecurityHeaders::new().remove("X-Frame-Options"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders::none()
+            .header("X-Content-Type-Options", "nosniff")
+            .frame_options("SAMEORIGIN")
+            .referrer_policy("strict-origin-when-cross-origin")
+    }
+}
+
+impl SecurityHeaders {
+    /// The default headers.
+    pub fn new() -> Self {
+        SecurityHeaders::default()
+    }
+
+    /// No headers, to be added one by one.
+    pub fn none() -> Self {
+        SecurityHeaders { headers: Vec::new() }
+    }
+
+    /// `Strict-Transport-Security`: browsers use only HTTPS for the site
+    /// for `max_age`, and for its subdomains too if `include_subdomains`.
+    /// Browsers ignore it over plain HTTP, so it only takes effect when a
+    /// TLS-terminating proxy passes it on.
+    pub fn hsts(self, max_age: Duration, include_subdomains: bool) -> Self {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        self.header("Strict-Transport-Security", &value)
+    }
+
+    /// `X-Frame-Options`: `DENY`, or `SAMEORIGIN` to allow framing by the
+    /// site's own pages.
+    pub fn frame_options(self, value: &str) -> Self {
+        self.header("X-Frame-Options", value)
+    }
+
+    /// `Referrer-Policy`, such as `no-referrer` or `same-origin`.
+    pub fn referrer_policy(self, value: &str) -> Self {
+        self.header("Referrer-Policy", value)
+    }
+
+    /// `Content-Security-Policy`, such as `default-src 'self'`.
+    pub fn content_security_policy(self, policy: &str) -> Self {
+        self.header("Content-Security-Policy", policy)
+    }
+
+    /// Sets header `name` to `value`, replacing any value it had.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self = self.remove(name);
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Stops sending header `name`.
+    pub fn remove(mut self, name: &str) -> Self {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, request: &mut Request, writer: &mut ResponseWriter, next: Next<'_>) -> io::Result<()> {
+        let headers = next.router.security_headers_for(request).unwrap_or(self);
+        for (name, value) in &headers.headers {
+            writer.add_default_header(name, value);
+        }
+        next.run(request, writer)
+    }
+}
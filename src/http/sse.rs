@@ -0,0 +1,128 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::time::Duration;
+
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+
+/// Events buffered between producers and the connection before `send`
+/// starts blocking, so a slow client applies backpressure.
+const EVENT_BUFFER: usize = 64;
+
+/// One Server-Sent Event.
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<u64>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Event {
+            data: data.to_string(),
+            ..Event::default()
+        }
+    }
+
+    /// Sets the event type, dispatched to `addEventListener(name)` listeners.
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(strip_newlines(name));
+        self
+    }
+
+    /// Sets the id the browser echoes back in `Last-Event-ID` on reconnect.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(strip_newlines(id));
+        self
+    }
+
+    /// Tells the client how long to wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry.as_millis() as u64);
+        self
+    }
+
+    /// Wire format: one field per line, multi-line data split into several
+    /// `data:` lines, terminated by a blank line.
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {}", event);
+        }
+        if let Some(id) = &self.id {
+            let _ = writeln!(out, "id: {}", id);
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry);
+        }
+        for line in self.data.split('\n') {
+            let _ = writeln!(out, "data: {}", line.trim_end_matches('\r'));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+fn strip_newlines(s: &str) -> String {
+    s.replace(['\r', '\n'], "")
+}
+
+/// The client went away; further events cannot be delivered.
+#[derive(Debug)]
+pub struct Disconnected;
+
+/// Handle for pushing events to a connected client from any thread.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: SyncSender<Event>,
+}
+
+impl EventSender {
+    /// Queues an event, blocking while the buffer is full.
+    pub fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.tx.send(event).map_err(|_| Disconnected)
+    }
+}
+
+/// Turns the response into an event stream.
+///
+/// `setup` receives the sender and typically hands it to a producer thread.
+/// The calling thread then writes events as they arrive and emits a comment
+/// line whenever `keep_alive` passes without one, so proxies don't time the
+/// stream out. Returns once every sender is dropped or the client
+/// disconnects. A HEAD request gets the head alone, without `setup` being
+/// called.
+pub fn stream<F>(writer: &mut ResponseWriter, keep_alive: Duration, setup: F) -> io::Result<()>
+where
+    F: FnOnce(EventSender),
+{
+    writer.start(
+        StatusCode::Ok,
+        &[
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+            ("X-Accel-Buffering", "no"),
+        ],
+        None,
+    )?;
+    if writer.is_head() {
+        return Ok(());
+    }
+    writer.clear_deadline();
+    writer.flush()?;
+
+    let (tx, rx) = mpsc::sync_channel(EVENT_BUFFER);
+    setup(EventSender { tx });
+
+    loop {
+        match rx.recv_timeout(keep_alive) {
+            Ok(event) => writer.write_all(event.encode().as_bytes())?,
+            Err(RecvTimeoutError::Timeout) => writer.write_all(b":\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+    }
+}
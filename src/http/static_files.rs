@@ -0,0 +1,606 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http::compression;
+use crate::http::handler::Handler;
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::util::json;
+use crate::util::percent;
+use crate::util::time::{http_date, parse_http_date};
+
+/// Serves files from a directory tree.
+///
+/// A `Range` header asking for a single byte range is answered with
+/// `206 Partial Content` and just that slice, so players can seek and
+/// downloads can resume; a range lying past the end of the file gets 416.
+/// Requests for several ranges at once are served the whole file.
+///
+/// Every file is sent with an ETag derived from its size and modification
+/// time, and a Last-Modified date. A request whose `If-None-Match` lists
+/// that tag, or failing that whose `If-Modified-Since` is no older than
+/// the file, gets `304 Not Modified` and no body. `If-Range` is honoured,
+/// so a resumed download whose file has changed gets the whole new file.
+///
+/// With [`precompressed`](StaticFiles::precompressed) on, a `foo.js.br` or
+/// `foo.js.gz` next to `foo.js` is sent in its place, with the matching
+/// Content-Encoding, to clients that accept it.
+///
+/// A directory without an index file is a 404 unless
+/// [`directory_listing`](StaticFiles::directory_listing) is on, in which
+/// case its entries are listed with their sizes and modification times: as
+/// an HTML page, or as JSON to clients that prefer `application/json`.
+///
+/// For single-page apps, [`spa_fallback`](StaticFiles::spa_fallback) answers
+/// page navigations to paths that do not exist with the root index file,
+/// leaving the app's client-side router to draw the page.
+///
+/// Mount it on a catch-all route; the captured `{*path}` parameter (or the
+/// whole request path when the route has none) is resolved under the root:
+///
+/// ```ignore
+/// let files = StaticFiles::new("./public")
+///     .cache_control("/assets/*", "public, max-age=31536000, immutable")
+///     .cache_control("/index.html", "no-cache");
+/// router.handle("GET", "/static/{*path}", files);
+/// ```
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    /// Cache-Control values by file pattern, first match wins.
+    cache_control: Vec<(String, String)>,
+    precompressed: bool,
+    directory_listing: bool,
+    spa_fallback: bool,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            index: "index.html".to_string(),
+            cache_control: Vec::new(),
+            precompressed: false,
+            directory_listing: false,
+            spa_fallback: false,
+        }
+    }
+
+    /// Serves the root index file in place of a 404 for paths that do not
+    /// exist, when the request accepts `text/html` and the path does not
+    /// look like an asset: its last segment has no extension, or `.html`.
+    /// A missing `/app.js` or `/logo.png` is still a 404, so broken asset
+    /// links do not come back as HTML.
+    pub fn spa_fallback(mut self, enabled: bool) -> Self {
+        self.spa_fallback = enabled;
+        self
+    }
+
+    /// Lists the entries of directories that have no index file, rather
+    /// than answering 404. Hidden entries, whose names start with a dot,
+    /// are left out. Off by default, so that production sites do not
+    /// expose their layout.
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.directory_listing = enabled;
+        self
+    }
+
+    /// Serves compressed copies made at build time, `foo.js.br` and
+    /// `foo.js.gz`, in place of `foo.js` when the client's Accept-Encoding
+    /// allows. Off by default, since looking for them costs a `stat` or two
+    /// per request.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Sends `Cache-Control: value` with files matching `pattern`, a path
+    /// relative to the root such as `/index.html`, in which `*` stands for
+    /// any run of characters, slashes included: `/assets/*` covers the
+    /// whole `assets` tree. Rules are tried in the order added and the
+    /// first match applies; files matching none get no Cache-Control.
+    pub fn cache_control(mut self, pattern: &str, value: &str) -> Self {
+        self.cache_control.push((pattern.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn handle(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let relative = match request.param("path") {
+            Some(path) => path,
+            None => &request.path,
+        };
+
+        let path = match self.resolve(relative) {
+            Some(path) => path,
+            None => return writer.send_error(StatusCode::Forbidden),
+        };
+
+        let path = if self.spa_fallback && !path.exists() && is_page_navigation(request, relative) {
+            self.root.join(&self.index)
+        } else {
+            path
+        };
+
+        let path = if path.is_dir() {
+            let index = path.join(&self.index);
+            if self.directory_listing && !index.is_file() {
+                return send_listing(request, writer, &path, relative);
+            }
+            index
+        } else {
+            path
+        };
+        // The index file, or the one the SPA fallback chose, may itself be
+        // a symlink out of the tree.
+        if !self.contains(&path) {
+            return writer.send_error(StatusCode::Forbidden);
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => return writer.send_error(open_error_status(&e)),
+        };
+
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return writer.send_error(StatusCode::NotFound);
+        }
+        let (mut file, metadata, encoding) = match self.precompressed_variant(request, &path) {
+            Some((file, metadata, encoding)) => (file, metadata, Some(encoding)),
+            None => (file, metadata, None),
+        };
+
+        let len = metadata.len();
+        let content_type = mime_type(&path);
+        let etag = entity_tag(&metadata);
+        let modified = metadata.modified().ok().map(whole_seconds);
+        let last_modified = modified.map(http_date);
+        let mut validators = vec![("ETag", etag.as_str())];
+        if let Some(date) = &last_modified {
+            validators.push(("Last-Modified", date));
+        }
+        // Sent with 304s too, so revalidation refreshes the cached policy.
+        if let Some(value) = self.cache_policy(&path) {
+            validators.push(("Cache-Control", value));
+        }
+        if self.precompressed {
+            validators.push(("Vary", "Accept-Encoding"));
+        }
+        let encoding = encoding.map(|encoding| ("Content-Encoding", encoding));
+
+        if not_modified(request, &etag, modified) {
+            return writer.send(StatusCode::NotModified, &validators, b"");
+        }
+
+        let range = request.headers.get("range").filter(|_| range_applies(request, &etag, modified));
+        match range.map(|range| parse_range(range, len)) {
+            Some(RangeRequest::Satisfiable(start, end)) => {
+                let content_range = format!("bytes {}-{}/{}", start, end, len);
+                let mut headers = vec![("Content-Type", content_type), ("Content-Range", &content_range)];
+                headers.extend(encoding);
+                headers.extend_from_slice(&validators);
+                writer.start(StatusCode::PartialContent, &headers, Some(end - start + 1))?;
+                file.seek(SeekFrom::Start(start))?;
+                writer.send_file(&mut file, end - start + 1)
+            }
+            Some(RangeRequest::Unsatisfiable) => {
+                writer.add_header("Content-Range", &format!("bytes */{}", len));
+                writer.send_error(StatusCode::RangeNotSatisfiable)
+            }
+            Some(RangeRequest::Ignored) | None => {
+                let mut headers = vec![("Content-Type", content_type), ("Accept-Ranges", "bytes")];
+                headers.extend(encoding);
+                headers.extend_from_slice(&validators);
+                writer.start(StatusCode::Ok, &headers, Some(len))?;
+                writer.send_file(&mut file, len)
+            }
+        }
+    }
+
+    /// A precompressed copy of the file at `path` that the client accepts,
+    /// preferring Brotli to gzip when it likes both equally, with its
+    /// metadata and Content-Encoding.
+    fn precompressed_variant(&self, request: &Request, path: &Path) -> Option<(File, Metadata, &'static str)> {
+        if !self.precompressed {
+            return None;
+        }
+        let br = compression::quality(request, &["br"]);
+        let gzip = compression::quality(request, &["gzip", "x-gzip"]);
+        let mut candidates = [("br", "br", br), ("gz", "gzip", gzip)];
+        if gzip > br {
+            candidates.swap(0, 1);
+        }
+
+        candidates.into_iter().filter(|&(_, _, quality)| quality > 0.0).find_map(|(extension, encoding, _)| {
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(extension);
+            let variant = PathBuf::from(variant);
+            if !self.contains(&variant) {
+                return None;
+            }
+            let file = File::open(variant).ok()?;
+            let metadata = file.metadata().ok().filter(Metadata::is_file)?;
+            Some((file, metadata, encoding))
+        })
+    }
+
+    /// The Cache-Control value for the file at `path`, which lies under the
+    /// root.
+    fn cache_policy(&self, path: &Path) -> Option<&str> {
+        let relative = path.strip_prefix(&self.root).ok()?.to_str()?;
+        let relative = format!("/{}", relative);
+        self.cache_control
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, &relative))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Maps a URL path onto the root, refusing anything that would escape it.
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+
+        for component in Path::new(relative.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        self.contains(&path).then_some(path)
+    }
+
+    /// Whether `path`, once symlinks inside the tree are followed, still
+    /// lies under the root. Paths that do not exist are let through, to be
+    /// answered with 404.
+    fn contains(&self, path: &Path) -> bool {
+        match (path.canonicalize(), self.root.canonicalize()) {
+            (Ok(real), Ok(root)) => real.starts_with(&root),
+            _ => true,
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        self.handle(request, writer)
+    }
+}
+
+/// A strong entity tag from the file's size and modification time, which
+/// changes whenever the file is rewritten.
+fn entity_tag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    format!("\"{:x}-{:x}\"", modified, metadata.len())
+}
+
+/// Whether the `If-None-Match` value `tags` names `etag`. The comparison is
+/// weak, as the header requires: `W/"x"` matches `"x"`.
+fn etag_listed(tags: &str, etag: &str) -> bool {
+    tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Matches `text` against `pattern`, in which `*` matches any run of
+/// characters and everything else matches itself.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has swallowed.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` take one more character and retry from there.
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// HTTP dates have whole-second precision, so modification times are
+/// compared at that precision too.
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+}
+
+/// Whether the client's cached copy is current. `If-None-Match` decides
+/// when present; `If-Modified-Since` is only consulted without it.
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if request.headers.get("if-none-match").is_some() {
+        return request.headers.get_all("if-none-match").any(|tags| etag_listed(tags, etag));
+    }
+    match (request.headers.get("if-modified-since").and_then(parse_http_date), modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Whether a `Range` header may be acted on. With `If-Range`, only if it
+/// names the file as it is now, by its ETag (compared strongly) or its
+/// exact modification date; otherwise the whole file is sent.
+fn range_applies(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    match request.headers.get("if-range").map(str::trim) {
+        None => true,
+        Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == etag,
+        Some(date) => parse_http_date(date).is_some_and(|date| modified == Some(date)),
+    }
+}
+
+/// How to answer a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// Send the inclusive byte range.
+    Satisfiable(u64, u64),
+    /// The range lies entirely past the end of the file: 416.
+    Unsatisfiable,
+    /// Malformed, in another unit, or several ranges: send the whole file,
+    /// as if no range had been asked for.
+    Ignored,
+}
+
+/// Interprets `range` (`bytes=0-99`, `bytes=100-` or `bytes=-100`) for a
+/// file of `len` bytes. An end past the file is cut short to fit.
+fn parse_range(range: &str, len: u64) -> RangeRequest {
+    let Some((unit, spec)) = range.split_once('=') else {
+        return RangeRequest::Ignored;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+    // Digits only: `parse` alone would also take a leading `+`.
+    let number = |s: &str| s.bytes().all(|b| b.is_ascii_digit()).then(|| s.parse::<u64>().ok()).flatten();
+
+    if first.is_empty() {
+        // A suffix: the last so many bytes.
+        return match number(last) {
+            Some(0) => RangeRequest::Unsatisfiable,
+            Some(_) if len == 0 => RangeRequest::Unsatisfiable,
+            Some(suffix) => RangeRequest::Satisfiable(len.saturating_sub(suffix), len - 1),
+            None => RangeRequest::Ignored,
+        };
+    }
+
+    let Some(start) = number(first) else {
+        return RangeRequest::Ignored;
+    };
+    let end = match last {
+        "" => u64::MAX,
+        last => match number(last) {
+            Some(end) if end >= start => end,
+            _ => return RangeRequest::Ignored,
+        },
+    };
+    if start >= len {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable(start, end.min(len - 1))
+}
+
+/// One entry of a directory listing.
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Lists the directory at `dir`, reached through the URL path `relative`
+/// under the mount, as JSON if the client prefers it and as HTML otherwise.
+fn send_listing(request: &Request, writer: &mut ResponseWriter, dir: &Path, relative: &str) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return writer.send_error(open_error_status(&e)),
+    };
+    let mut listing: Vec<Entry> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if name.starts_with('.') {
+                return None;
+            }
+            // Follows symlinks, so a link is listed as what it points to.
+            let metadata = fs::metadata(entry.path()).ok()?;
+            Some(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified().ok().map(whole_seconds),
+            })
+        })
+        .collect();
+    listing.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let (content_type, body) = if prefers_json(request) {
+        ("application/json", listing_json(&listing))
+    } else {
+        ("text/html; charset=utf-8", listing_html(&request.path, relative, &listing))
+    };
+    writer.send(StatusCode::Ok, &[("Content-Type", content_type), ("Vary", "Accept")], body.as_bytes())
+}
+
+/// Whether the request's Accept header ranks `application/json` above
+/// `text/html`.
+fn prefers_json(request: &Request) -> bool {
+    accept_quality(request, "application/json") > accept_quality(request, "text/html")
+}
+
+/// Whether the request looks like a browser loading a page at `relative`
+/// rather than fetching an asset: it names `text/html` in its Accept
+/// header, and the path's last segment has no extension other than `.html`.
+fn is_page_navigation(request: &Request, relative: &str) -> bool {
+    let last = relative.rsplit('/').next().unwrap_or("");
+    let page = match Path::new(last).extension().and_then(|ext| ext.to_str()) {
+        None => true,
+        Some(ext) => ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"),
+    };
+    page && accept_quality(request, "text/html") > 0.0
+}
+
+/// The quality the request's Accept header gives exactly `media_type`;
+/// zero when it is not listed. Wildcards are not consulted, since every
+/// client sends them.
+fn accept_quality(request: &Request, media_type: &str) -> f32 {
+    for element in request.headers.get_all("accept").flat_map(|value| value.split(',')) {
+        let mut params = element.split(';');
+        if !params.next().unwrap_or("").trim().eq_ignore_ascii_case(media_type) {
+            continue;
+        }
+        return params
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("q").then(|| value.trim().parse::<f32>().ok())?
+            })
+            .next()
+            .unwrap_or(1.0);
+    }
+    0.0
+}
+
+/// `[{"name": .., "type": "file" | "directory", "size": .., "modified": ..}, ..]`,
+/// with `modified` an HTTP date or null.
+fn listing_json(listing: &[Entry]) -> String {
+    let mut out = String::from("[");
+    for (i, entry) in listing.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        json::write_string(&mut out, &entry.name);
+        out.push_str(if entry.is_dir { ",\"type\":\"directory\"" } else { ",\"type\":\"file\"" });
+        out.push_str(&format!(",\"size\":{},\"modified\":", entry.size));
+        match entry.modified {
+            Some(modified) => json::write_string(&mut out, &http_date(modified)),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+/// An HTML table of `listing`, linking each entry by its absolute URL so
+/// the page works with or without a trailing slash on `url_path`.
+fn listing_html(url_path: &str, relative: &str, listing: &[Entry]) -> String {
+    let base: String = url_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{}", percent::encode(segment)))
+        .collect();
+    let title = escape_html(&format!("Index of {}/", url_path.trim_end_matches('/')));
+
+    let mut rows = String::new();
+    if !relative.trim_matches('/').is_empty() {
+        rows.push_str(&format!("        <tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n", parent(&base)));
+    }
+    for entry in listing {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        let modified = entry.modified.map(http_date).unwrap_or_default();
+        rows.push_str(&format!(
+            "        <tr><td><a href=\"{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            base,
+            percent::encode(&entry.name),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            size,
+            modified
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    <table>
+        <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>
+{rows}    </table>
+</body>
+</html>"#
+    )
+}
+
+/// `path` without its last segment.
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn open_error_status(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        _ => StatusCode::NotFound,
+    }
+}
+
+/// Guesses a Content-Type from the file extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::{self, ErrorKind};
+use std::sync::atomic::Ordering;
+
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::net::RawTcpStream;
+use crate::server::stats::{BYTES_RECEIVED, BYTES_SENT};
+use crate::util::base64;
+use crate::util::sha1::sha1;
+
+/// Appended to the client's key before hashing (RFC 6455 §1.3).
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest reassembled message accepted from a client.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A complete WebSocket message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Close with an optional status code and reason.
+    Close(Option<(u16, String)>),
+}
+
+/// Performs the server side of the opening handshake.
+///
+/// On success the 101 response has been sent and the returned socket speaks
+/// WebSocket; the HTTP connection ends when the handler returns. If the
+/// request is not a valid upgrade an error response is written instead and
+/// `None` is returned.
+pub fn upgrade(request: &Request, writer: &mut ResponseWriter) -> io::Result<Option<WebSocket>> {
+    let headers = &request.headers;
+    let key = headers.get("sec-websocket-key").map(str::trim).unwrap_or("");
+
+    if request.method != "GET"
+        || !headers.has_token("upgrade", "websocket")
+        || !headers.has_token("connection", "upgrade")
+        || key.is_empty()
+    {
+        writer.add_header("Upgrade", "websocket");
+        writer.send_error(StatusCode::UpgradeRequired)?;
+        return Ok(None);
+    }
+
+    if headers.get("sec-websocket-version").map(str::trim) != Some("13") {
+        writer.add_header("Sec-WebSocket-Version", "13");
+        writer.send_error(StatusCode::BadRequest)?;
+        return Ok(None);
+    }
+
+    let accept = accept_key(key);
+    let stream = writer.upgrade("websocket", &[("Sec-WebSocket-Accept", &accept)])?;
+    Ok(Some(WebSocket::new(stream)))
+}
+
+/// `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    base64::encode(&sha1(&input))
+}
+
+/// Server end of an upgraded WebSocket connection.
+///
+/// It owns its socket, so a handler may move it to another thread. Pings are
+/// answered automatically (and still reported), and a received Close is
+/// echoed before being returned from `recv`.
+pub struct WebSocket {
+    stream: RawTcpStream,
+    closed: bool,
+    /// Opcode and data of a fragmented message still being received. Kept
+    /// here because control frames may arrive between its fragments.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl WebSocket {
+    fn new(stream: RawTcpStream) -> Self {
+        WebSocket {
+            stream,
+            closed: false,
+            partial: None,
+        }
+    }
+
+    /// Waits for the next message, reassembling fragmented ones.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+
+            match opcode {
+                OP_PING => {
+                    self.write_frame(OP_PONG, &payload)?;
+                    return Ok(Message::Ping(payload));
+                }
+                OP_PONG => return Ok(Message::Pong(payload)),
+                OP_CLOSE => {
+                    let close = parse_close(&payload)?;
+                    if !self.closed {
+                        self.write_frame(OP_CLOSE, &payload[..payload.len().min(2)])?;
+                        self.closed = true;
+                    }
+                    return Ok(Message::Close(close));
+                }
+                OP_TEXT | OP_BINARY if self.partial.is_none() => self.partial = Some((opcode, payload)),
+                OP_CONTINUATION => match self.partial.as_mut() {
+                    Some((_, data)) => {
+                        if data.len() + payload.len() > MAX_MESSAGE_SIZE {
+                            self.fail(1009)?;
+                            return Err(io::Error::new(ErrorKind::InvalidData, "message too large"));
+                        }
+                        data.extend_from_slice(&payload);
+                    }
+                    None => return self.protocol_error("continuation without a message"),
+                },
+                _ => return self.protocol_error("unexpected opcode"),
+            }
+
+            if fin {
+                let (opcode, data) = self.partial.take().unwrap();
+                return if opcode == OP_TEXT {
+                    match String::from_utf8(data) {
+                        Ok(text) => Ok(Message::Text(text)),
+                        Err(_) => {
+                            self.fail(1007)?;
+                            Err(io::Error::new(ErrorKind::InvalidData, "text message is not UTF-8"))
+                        }
+                    }
+                } else {
+                    Ok(Message::Binary(data))
+                };
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, data),
+            Message::Ping(data) => self.write_frame(OP_PING, data),
+            Message::Pong(data) => self.write_frame(OP_PONG, data),
+            Message::Close(close) => {
+                let (code, reason) = close.clone().unwrap_or((1000, String::new()));
+                self.close(code, &reason)
+            }
+        }
+    }
+
+    /// Starts the closing handshake. Keep calling `recv` to wait for the
+    /// peer's Close, or drop the socket to end it abruptly.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..reason.len().min(123)]);
+        self.closed = true;
+        self.write_frame(OP_CLOSE, &payload)
+    }
+
+    fn fail(&mut self, code: u16) -> io::Result<()> {
+        self.close(code, "")
+    }
+
+    fn protocol_error<T>(&mut self, msg: &str) -> io::Result<T> {
+        self.fail(1002)?;
+        Err(io::Error::new(ErrorKind::InvalidData, msg.to_string()))
+    }
+
+    fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut header = [0u8; 2];
+        self.read_exact(&mut header)?;
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        if header[0] & 0x70 != 0 {
+            return self.protocol_error("reserved bits set");
+        }
+        // Clients must mask every frame (RFC 6455 §5.1).
+        if header[1] & 0x80 == 0 {
+            return self.protocol_error("unmasked client frame");
+        }
+
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.read_exact(&mut ext)?;
+                u16::from_be_bytes(ext) as u64
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                self.read_exact(&mut ext)?;
+                u64::from_be_bytes(ext)
+            }
+            n => n as u64,
+        };
+
+        let is_control = opcode & 0x08 != 0;
+        if is_control && (len > 125 || !fin) {
+            return self.protocol_error("invalid control frame");
+        }
+        if len > MAX_MESSAGE_SIZE as u64 {
+            self.fail(1009)?;
+            return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+        }
+
+        let mut mask = [0u8; 4];
+        self.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        self.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok((fin, opcode, payload))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            n if n < 126 => frame.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        BYTES_SENT.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.stream.read(&mut buf[filled..]) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    BYTES_RECEIVED.fetch_add(n as u64, Ordering::Relaxed);
+                    filled += n;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_close(payload: &[u8]) -> io::Result<Option<(u16, String)>> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(io::Error::new(ErrorKind::InvalidData, "truncated close frame")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            let reason = String::from_utf8_lossy(&payload[2..]).into_owned();
+            Ok(Some((code, reason)))
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! hyperport is a small HTTP/1.1 server built directly on Linux sockets,
+//! epoll and a bounded thread pool.
+//!
+//! - [`net`] wraps the raw socket syscalls.
+//! - [`http`] parses requests, writes responses and routes them to handlers.
+//! - [`server`] ties both together into a multi-threaded server.
+//! - [`config`] loads server settings from a TOML file.
+//! - [`log`] is the leveled logging everything reports through.
+//!
+//! ```no_run
+//! use hyperport::http::{Router, StatusCode};
+//! use hyperport::server::Server;
+//!
+//! let mut router = Router::new();
+//! router.get("/", |_, writer| writer.send(StatusCode::Ok, &[], b"hello"));
+//!
+//! Server::builder().bind("[::]:8080").router(router).run().unwrap();
+//! ```
+
+pub mod config;
+pub mod error;
+pub mod http;
+pub mod log;
+pub mod net;
+pub mod server;
+mod util;
+
+pub use error::HyperportError;
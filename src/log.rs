@@ -0,0 +1,245 @@
+//! Leveled logging for the library and the servers built on it.
+//!
+//! Messages are written with the [`error!`](crate::error!),
+//! [`warn!`](crate::warn!), [`info!`](crate::info!),
+//! [`debug!`](crate::debug!) and [`trace!`](crate::trace!) macros, each
+//! tagged with the module it comes from. A [`Filter`] decides which levels
+//! are written for which modules, `info` and above everywhere until
+//! [`set_filter`] says otherwise, and lines go to standard error until
+//! [`set_output`] says otherwise:
+//!
+//! ```text
+//! 2024-03-01T12:00:00.000Z WARN  hyperport::http::proxy::health: Upstream 10.0.0.5:80 is down: connection refused
+//! ```
+//!
+//! Access logs are separate; see [`AccessLog`](crate::http::AccessLog).
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::util::time::rfc3339;
+
+/// How important a message is, from most to least.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Something failed that should not have.
+    Error = 1,
+    /// Something is wrong but being coped with, such as an upstream down.
+    Warn,
+    /// Normal but notable events: startup, reloads, statistics.
+    Info,
+    /// Per-connection and per-request detail, for troubleshooting.
+    Debug,
+    /// Everything else.
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Which levels are written for which modules: a default, and overrides
+/// for modules and everything under them. `None` turns logging off.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    default: Option<Level>,
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    /// Writes messages at `level` and above from every module.
+    pub fn new(level: Option<Level>) -> Self {
+        Filter {
+            default: level,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Writes messages at `level` and above from `target`, a module path
+    /// such as `hyperport::http::proxy`, and the modules under it.
+    pub fn target(mut self, target: &str, level: Option<Level>) -> Self {
+        self.targets.retain(|(t, _)| t != target);
+        self.targets.push((target.to_string(), level));
+        self
+    }
+
+    /// Whether a message at `level` from module `target` is written. The
+    /// most specific override naming it or a parent module applies.
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        let within = |t: &str| target.strip_prefix(t).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"));
+        let max = self
+            .targets
+            .iter()
+            .filter(|(t, _)| within(t))
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |&(_, max)| max);
+        max.is_some_and(|max| level <= max)
+    }
+
+    /// The least important level written anywhere, as stored in
+    /// [`MAX_LEVEL`].
+    fn max(&self) -> u8 {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .chain([self.default])
+            .map(|level| level.map_or(0, |level| level as u8))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(Some(Level::Info))
+    }
+}
+
+/// Parses a comma-separated list of a default level and `module=level`
+/// overrides, e.g. `warn,hyperport::http::proxy=debug`. Levels are `off`,
+/// `error`, `warn`, `info`, `debug` and `trace`; the default, if left out,
+/// is `info`.
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut filter = Filter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filter = filter.target(target.trim(), parse_level(level.trim())?),
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+fn parse_level(s: &str) -> Result<Option<Level>, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "off" => Ok(None),
+        "error" => Ok(Some(Level::Error)),
+        "warn" => Ok(Some(Level::Warn)),
+        "info" => Ok(Some(Level::Info)),
+        "debug" => Ok(Some(Level::Debug)),
+        "trace" => Ok(Some(Level::Trace)),
+        _ => Err(format!(
+            "unknown log level `{}` (expected off, error, warn, info, debug or trace)",
+            s
+        )),
+    }
+}
+
+/// The least important level any module writes, so that most disabled
+/// messages are turned away without taking the filter's lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// `None` until [`set_filter`] is first called, meaning the default.
+static FILTER: RwLock<Option<Filter>> = RwLock::new(None);
+
+/// `None` for standard error.
+static OUTPUT: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Replaces the filter deciding which messages are written.
+pub fn set_filter(filter: Filter) {
+    let mut current = FILTER.write().unwrap_or_else(|e| e.into_inner());
+    MAX_LEVEL.store(filter.max(), Ordering::Relaxed);
+    *current = Some(filter);
+}
+
+/// Sends log lines to `out` instead of standard error. Each line is
+/// written whole with a single call.
+pub fn set_output(out: Box<dyn Write + Send>) {
+    *OUTPUT.lock().unwrap_or_else(|e| e.into_inner()) = Some(out);
+}
+
+/// Whether a message at `level` from module `target` would be written.
+pub fn enabled(level: Level, target: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    match &*FILTER.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(filter) => filter.enabled(level, target),
+        None => level <= Level::Info,
+    }
+}
+
+/// Writes one line. Called by the macros once [`enabled`] has agreed.
+#[doc(hidden)]
+pub fn write(level: Level, target: &str, message: fmt::Arguments<'_>) {
+    let line = format!("{} {:<5} {}: {}\n", rfc3339(SystemTime::now()), level, target, message);
+    let mut output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    // There is nowhere left to report a failure to write the log.
+    let _ = match output.as_mut() {
+        Some(out) => out.write_all(line.as_bytes()).and_then(|_| out.flush()),
+        None => io::stderr().write_all(line.as_bytes()),
+    };
+}
+
+/// Logs a message at `level`, formatted as by `format!`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level, module_path!()) {
+            $crate::log::write(level, module_path!(), format_args!($($arg)+));
+        }
+    }};
+}
+
+/// Logs a message at [`Level::Error`](crate::log::Level::Error).
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Error, $($arg)+)
+    };
+}
+
+/// Logs a message at [`Level::Warn`](crate::log::Level::Warn).
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Warn, $($arg)+)
+    };
+}
+
+/// Logs a message at [`Level::Info`](crate::log::Level::Info).
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Info, $($arg)+)
+    };
+}
+
+/// Logs a message at [`Level::Debug`](crate::log::Level::Debug).
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Debug, $($arg)+)
+    };
+}
+
+/// Logs a message at [`Level::Trace`](crate::log::Level::Trace).
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Trace, $($arg)+)
+    };
+}
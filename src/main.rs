@@ -1,285 +1,683 @@
-use std::net::SocketAddr;
-use std::thread;
-use std::os::unix::io::RawFd;
-use std::mem;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+//! The hyperport server: static files, reverse proxies and forwarders as
+//! configured. The streaming, WebSocket and SSE demos live in
+//! `examples/demo.rs`.
 
-static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
-static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+mod cli;
 
-struct RawTcpStream {
-    fd: RawFd,
-}
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::process::{self, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use hyperport::config::{AccessLogTarget, BalancerKind, Config, LogConfig, LogOutput, StaticMount};
+use hyperport::http::proxy::{ConnectProxy, LeastConnections, PowerOfTwoChoices, RoundRobin, Weighted};
+use hyperport::http::{
+    AccessLog, BasicAuth, Compression, Cors, ErrorPage, Handler, JwtAuth, Limit, Proxy, RateLimit, RequestId, Router,
+    SecurityHeaders, StaticFiles, StatusCode, TrustedProxies,
+};
+use hyperport::log::{self, Level};
+use hyperport::net::forward::{Forwarder, ForwarderHandle, Protocol};
+use hyperport::net::socks::{Socks5, Socks5Handle};
+use hyperport::net::systemd;
+use hyperport::server::stats::{self, Metrics};
+use hyperport::server::signal::Signals;
+use hyperport::server::upgrade;
+use hyperport::server::{Server, ServerHandle};
+use hyperport::{error, info, warn, HyperportError};
+
+use crate::cli::{Args, USAGE};
+
+const LISTEN_ADDR: &str = "[::]:8080";
+const STATIC_ROOT: &str = "./public";
+
+/// How long a new binary has to start accepting before the upgrade is
+/// abandoned.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an upgraded-away process waits for its connections to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Methods forwarded by `[[proxy]]` mounts.
+const PROXY_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
 
-impl RawTcpStream {
-    fn from_raw_fd(fd: RawFd) -> Self {
-        RawTcpStream { fd }
+fn main() {
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\nRun `hyperport --help` for usage.", e);
+        process::exit(2);
+    });
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+    // Until the config file has been read.
+    if let Some(filter) = &args.log_level {
+        log::set_filter(filter.clone());
     }
 
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        let bytes_read = unsafe {
-            libc::read(
-                self.fd,
-                buf.as_mut_ptr() as *mut libc::c_void,
-                buf.len(),
-            )
-        };
-
-        if bytes_read < 0 {
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(bytes_read as usize)
-        }
+    let mut inherited = systemd::listen_fds().unwrap_or_else(|e| {
+        error!("Error taking sockets from systemd: {}", e);
+        process::exit(1);
+    });
+    if inherited.is_empty() {
+        inherited = upgrade::inherited_listeners().unwrap_or_else(|e| {
+            error!("Error taking sockets from the previous process: {}", e);
+            process::exit(1);
+        });
     }
 
-    fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
-        let mut total_written = 0;
-        
-        while total_written < buf.len() {
-            let bytes_written = unsafe {
-                libc::write(
-                    self.fd,
-                    buf[total_written..].as_ptr() as *const libc::c_void,
-                    buf.len() - total_written,
-                )
-            };
+    // Blocked before any thread, the access log writer included, is
+    // spawned, so that only the signal thread ever receives them.
+    let signals = Signals::block(&[libc::SIGHUP, libc::SIGUSR2]).unwrap_or_else(|e| {
+        error!("Error blocking signals: {}", e);
+        process::exit(1);
+    });
 
-            if bytes_written < 0 {
-                return Err(std::io::Error::last_os_error());
-            }
+    let config = load_config(&args).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    configure_logging(&config.log).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let router = build_router(&config).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    // Bound before the server drops privileges, so that low ports work.
+    let forwarders = spawn_forwarders(&config).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let socks = spawn_socks(&config).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
 
-            total_written += bytes_written as usize;
+    // Defaults for this example; the config file overrides any of them.
+    let mut builder = Server::builder()
+        .workers(4)
+        .pool_size(64)
+        .queue_depth(1024)
+        .read_timeout(Duration::from_secs(30))
+        .header_timeout(Duration::from_secs(10))
+        .keep_alive_timeout(Duration::from_secs(5))
+        .spool_bodies(1024 * 1024, env::temp_dir())
+        .write_timeout(Duration::from_secs(30));
+    let mut startup = config.clone();
+    if inherited.is_empty() {
+        if startup.listen.is_empty() {
+            startup.listen.push(LISTEN_ADDR.to_string());
+        }
+    } else {
+        // Sockets passed in by systemd or by the process being upgraded
+        // replace the configured addresses.
+        startup.listen.clear();
+        for listener in inherited {
+            builder = builder.listen_on(listener);
         }
+    }
 
-        Ok(())
+    let server = startup.apply(builder).router(router).build().unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    for addr in server.local_addrs() {
+        info!("Server running on http://{}", addr);
+    }
+    for forwarder in &forwarders {
+        let protocol = if forwarder.protocol() == Protocol::Udp { "udp" } else { "tcp" };
+        info!("Forwarding {} {} to {}", protocol, forwarder.local_addr(), forwarder.target());
+    }
+    if let Some(socks) = &socks {
+        info!("SOCKS5 proxy on {}", socks.local_addr());
+    }
+    if let Err(e) = upgrade::notify_ready() {
+        error!("Error notifying the previous process: {}", e);
     }
-}
 
-impl Drop for RawTcpStream {
-    fn drop(&mut self) {
-        unsafe {
-            libc::close(self.fd);
+    let handle = server.handle();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || handle_signals(signals, args, config, handle))
+        .expect("failed to spawn signal thread");
+
+    // Always running, since a reload may turn info messages back on.
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        if !log::enabled(Level::Info, module_path!()) {
+            continue;
         }
-    }
+        let (user_us, sys_us) = stats::cpu_time();
+        info!(
+            "Connections: {} | Bytes sent: {} | CPU: {:.2}ms user, {:.2}ms sys",
+            stats::connections(),
+            stats::bytes_sent(),
+            user_us as f64 / 1000.0,
+            sys_us as f64 / 1000.0
+        );
+    });
+
+    server.run();
 }
 
-struct CustomTcpListener {
-    fd: RawFd,
+/// Reads the config file, if any, and applies command-line overrides.
+fn load_config(args: &Args) -> Result<Config, HyperportError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // Command-line options take precedence over the config file.
+    if args.log_level.is_some() {
+        config.log.level = args.log_level.clone();
+    }
+    if !args.bind.is_empty() {
+        config.listen = args.bind.clone();
+    }
+    if let Some(port) = args.port {
+        config.listen = vec![format!("[::]:{}", port)];
+    }
+    if let Some(root) = &args.root {
+        config.static_mounts = vec![StaticMount {
+            mount: "/static".to_string(),
+            root: root.clone(),
+            cache_control: Vec::new(),
+            precompressed: false,
+            directory_listing: false,
+            spa_fallback: false,
+            host: None,
+            ip_filter: None,
+        }];
+    }
+    if args.workers.is_some() {
+        config.workers = args.workers;
+    }
+    if args.user.is_some() {
+        config.user = args.user.clone();
+    }
+    if args.group.is_some() {
+        config.group = args.group.clone();
+    }
+    Ok(config)
 }
 
-impl CustomTcpListener {
-    fn bind(addr: &str) -> Result<Self, std::io::Error> {
-        let socket_addr: SocketAddr = addr.parse().unwrap();
-        
-        let fd = unsafe {
-            libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0)
-        };
-        
-        if fd < 0 {
-            return Err(std::io::Error::last_os_error());
-        }
-
-        let reuse = 1i32;
-        unsafe {
-            if libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEADDR,
-                &reuse as *const i32 as *const libc::c_void,
-                mem::size_of::<i32>() as libc::socklen_t,
-            ) < 0 {
-                libc::close(fd);
-                return Err(std::io::Error::last_os_error());
+/// Starts the `[[forward]]` port forwarders of `config`.
+fn spawn_forwarders(config: &Config) -> Result<Vec<ForwarderHandle>, HyperportError> {
+    config
+        .forwards
+        .iter()
+        .map(|settings| {
+            let mut forwarder = match settings.protocol {
+                Protocol::Tcp => Forwarder::new(&settings.listen, &settings.target)?,
+                Protocol::Udp => Forwarder::udp(&settings.listen, &settings.target)?,
+            };
+            if let Some(timeout) = settings.connect_timeout {
+                forwarder = forwarder.connect_timeout(timeout);
             }
-        }
-
-        let sockaddr = match socket_addr {
-            SocketAddr::V4(addr) => {
-                let mut sockaddr_in: libc::sockaddr_in = unsafe { mem::zeroed() };
-                sockaddr_in.sin_family = libc::AF_INET as u16;
-                sockaddr_in.sin_port = addr.port().to_be();
-                sockaddr_in.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
-                sockaddr_in
+            if let Some(timeout) = settings.idle_timeout {
+                forwarder = forwarder.idle_timeout(timeout);
             }
-            SocketAddr::V6(_) => panic!("IPv6 not supported in this example"),
-        };
-
-        unsafe {
-            let bind_result = libc::bind(
-                fd,
-                &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
-                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
-            );
-            
-            if bind_result < 0 {
-                libc::close(fd);
-                return Err(std::io::Error::last_os_error());
+            if let Some(version) = settings.proxy_protocol {
+                forwarder = forwarder.proxy_protocol(version);
             }
+            if let Some(max) = settings.max_sessions {
+                forwarder = forwarder.max_sessions(max);
+            }
+            forwarder.spawn()
+        })
+        .collect()
+}
+
+/// Starts the `[socks]` SOCKS5 proxy of `config`, if it has one.
+fn spawn_socks(config: &Config) -> Result<Option<Socks5Handle>, HyperportError> {
+    let Some(settings) = &config.socks else {
+        return Ok(None);
+    };
+    let mut socks = Socks5::new(&settings.listen);
+    for (username, password) in &settings.users {
+        socks = socks.user(username, password);
+    }
+    if let Some(timeout) = settings.handshake_timeout {
+        socks = socks.handshake_timeout(timeout);
+    }
+    if let Some(timeout) = settings.connect_timeout {
+        socks = socks.connect_timeout(timeout);
+    }
+    if let Some(timeout) = settings.idle_timeout {
+        socks = socks.idle_timeout(timeout);
+    }
+    socks.spawn().map(Some)
+}
 
-            if libc::listen(fd, 128) < 0 {
-                libc::close(fd);
-                return Err(std::io::Error::last_os_error());
+/// Builds the routes, static mounts and their virtual hosts, access log,
+/// compression and error pages described by `config`.
+fn build_router(config: &Config) -> io::Result<Router> {
+    let mut router = Router::new();
+    if !config.trusted_proxies.is_empty() {
+        let trusted = TrustedProxies::new(&config.trusted_proxies)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        router.wrap(trusted);
+    }
+    if let Some(header) = &config.request_id {
+        router.wrap(RequestId::new().header(header));
+    }
+    if log::enabled(Level::Info, module_path!()) {
+        let format = config.log.format;
+        match &config.log.access_log {
+            AccessLogTarget::Stdout => {
+                router.wrap(AccessLog::stdout().format(format));
+            }
+            AccessLogTarget::File(path) => {
+                let log = AccessLog::file(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                router.wrap(log.format(format));
             }
+            AccessLogTarget::Off => {}
         }
-
-        Ok(CustomTcpListener { fd })
+    }
+    if let Some(settings) = &config.security_headers {
+        let mut headers = SecurityHeaders::new();
+        if let Some(max_age) = settings.hsts {
+            headers = headers.hsts(max_age, settings.hsts_subdomains);
+        }
+        if !settings.content_type_options {
+            headers = headers.remove("X-Content-Type-Options");
+        }
+        let configured = [
+            ("X-Frame-Options", &settings.frame_options),
+            ("Referrer-Policy", &settings.referrer_policy),
+            ("Content-Security-Policy", &settings.content_security_policy),
+        ];
+        for (name, value) in configured {
+            headers = match value.as_deref() {
+                None => headers,
+                Some("") => headers.remove(name),
+                Some(value) => headers.header(name, value),
+            };
+        }
+        router.wrap(headers);
+    }
+    if let Some(settings) = &config.cors {
+        let mut cors = Cors::new();
+        if let Some(origins) = &settings.origins {
+            cors = cors.origins(origins);
+        }
+        if let Some(methods) = &settings.methods {
+            cors = cors.methods(methods);
+        }
+        if let Some(headers) = &settings.headers {
+            cors = cors.headers(headers);
+        }
+        if let Some(headers) = &settings.expose_headers {
+            cors = cors.expose_headers(headers);
+        }
+        if let Some(credentials) = settings.credentials {
+            cors = cors.allow_credentials(credentials);
+        }
+        if let Some(max_age) = settings.max_age {
+            cors = cors.max_age(max_age);
+        }
+        router.wrap(cors);
+    }
+    if let Some(settings) = &config.rate_limit {
+        let mut limit = Limit::new(settings.requests, settings.period);
+        if let Some(burst) = settings.burst {
+            limit = limit.burst(burst);
+        }
+        let mut rate_limit = RateLimit::new(limit);
+        if let Some(header) = &settings.key {
+            rate_limit = rate_limit.key_header(header);
+        }
+        router.wrap(rate_limit);
+    } else if config.proxies.iter().any(|route| route.rate_limit.is_some()) {
+        router.wrap(RateLimit::routes_only());
+    }
+    if let Some(settings) = &config.basic_auth {
+        let mut auth = BasicAuth::new(&settings.realm);
+        for (name, password) in &settings.users {
+            auth = auth.user(name, password);
+        }
+        if let Some(path) = &settings.htpasswd {
+            auth = auth
+                .htpasswd(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+        if let Some(paths) = &settings.paths {
+            auth = auth.paths(paths);
+        }
+        router.wrap(auth);
+    }
+    if let Some(settings) = &config.jwt {
+        let mut jwt = JwtAuth::new();
+        if let Some(secret) = &settings.secret {
+            jwt = jwt.hs256(secret.as_bytes());
+        }
+        if let Some(url) = &settings.jwks_url {
+            jwt = jwt.jwks_url(url)?;
+        }
+        if let Some(path) = &settings.jwks_file {
+            jwt = jwt
+                .jwks_file(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+        if let Some(refresh) = settings.jwks_refresh {
+            jwt = jwt.jwks_refresh(refresh);
+        }
+        if let Some(issuer) = &settings.issuer {
+            jwt = jwt.issuer(issuer);
+        }
+        if let Some(audience) = &settings.audience {
+            jwt = jwt.audience(audience);
+        }
+        if let Some(leeway) = settings.leeway {
+            jwt = jwt.leeway(leeway);
+        }
+        if let Some(realm) = &settings.realm {
+            jwt = jwt.realm(realm);
+        }
+        if let Some(paths) = &settings.paths {
+            jwt = jwt.paths(paths);
+        }
+        router.wrap(jwt);
+    }
+    if let Some(settings) = &config.connect {
+        let mut connect = ConnectProxy::new();
+        if let Some(ports) = &settings.ports {
+            connect = connect.ports(ports);
+        }
+        if let Some(hosts) = &settings.hosts {
+            connect = connect.hosts(hosts);
+        }
+        if let Some(timeout) = settings.connect_timeout {
+            connect = connect.connect_timeout(timeout);
+        }
+        if let Some(timeout) = settings.idle_timeout {
+            connect = connect.idle_timeout(timeout);
+        }
+        router.wrap(connect);
+    }
+    if let Some(settings) = &config.compression {
+        let mut compression = Compression::new();
+        if let Some(level) = settings.gzip_level {
+            compression = compression.gzip_level(level);
+        }
+        if let Some(level) = settings.deflate_level {
+            compression = compression.deflate_level(level);
+        }
+        if let Some(bytes) = settings.min_size {
+            compression = compression.min_size(bytes);
+        }
+        if let Some(types) = &settings.types {
+            compression = compression.types(types);
+        }
+        router.wrap(compression);
+    }
+    router.fallback(|request, writer| {
+        let wants_json = request.headers.get("accept").is_some_and(|accept| accept.contains("application/json"));
+        if wants_json {
+            writer.send(StatusCode::NotFound, &[("Content-Type", "application/json")], br#"{"error":"not found"}"#)
+        } else {
+            writer.send_error(StatusCode::NotFound)
+        }
+    });
+    // The welcome page would outrank a mount's `/{*path}` at `/` itself,
+    // hiding its index.html or SPA fallback.
+    let covers_root = |mount: &str, host: Option<&String>| host.is_none() && mount.trim_end_matches('/').is_empty();
+    let root_mounted = config.static_mounts.iter().any(|mount| covers_root(&mount.mount, mount.host.as_ref()))
+        || config.proxies.iter().any(|route| covers_root(&route.mount, route.host.as_ref()));
+    if !root_mounted {
+        router.get("/", |_, writer| {
+            let html_body = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Hello World</title>
+</head>
+<body>
+    <h1>Hello, World!</h1>
+</body>
+</html>"#;
+            writer.send(StatusCode::Ok, &[("Content-Type", "text/html; charset=utf-8")], html_body.as_bytes())
+        });
     }
 
-    fn accept(&self) -> Result<RawTcpStream, std::io::Error> {
-        let mut client_addr: libc::sockaddr_in = unsafe { mem::zeroed() };
-        let mut addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    if let Some(settings) = &config.metrics {
+        router.handle("GET", &settings.path, Metrics);
+        if let Some(filter) = &settings.ip_filter {
+            router.ip_filter("GET", &settings.path, filter.clone());
+        }
+    }
 
-        let client_fd = unsafe {
-            libc::accept(
-                self.fd,
-                &mut client_addr as *mut libc::sockaddr_in as *mut libc::sockaddr,
-                &mut addr_len,
-            )
+    add_error_pages(&mut router, config)?;
+
+    let mounts = if config.static_mounts.is_empty() {
+        vec![StaticMount {
+            mount: "/static".to_string(),
+            root: STATIC_ROOT.into(),
+            cache_control: Vec::new(),
+            precompressed: false,
+            directory_listing: false,
+            spa_fallback: false,
+            host: None,
+            ip_filter: None,
+        }]
+    } else {
+        config.static_mounts.clone()
+    };
+    // Mounts and proxies naming a host make up that virtual host's site; the
+    // rest are served for every other name.
+    let mut sites: Vec<(String, Router)> = Vec::new();
+    for mount in &mounts {
+        let path = format!("{}/{{*path}}", mount.mount.trim_end_matches('/'));
+        let files = mount
+            .cache_control
+            .iter()
+            .fold(StaticFiles::new(&mount.root), |files, (pattern, value)| files.cache_control(pattern, value))
+            .precompressed(mount.precompressed)
+            .directory_listing(mount.directory_listing)
+            .spa_fallback(mount.spa_fallback);
+        let site = site(&mut router, &mut sites, mount.host.as_ref(), config)?;
+        site.handle("GET", &path, files);
+        if let Some(filter) = &mount.ip_filter {
+            site.ip_filter("GET", &path, filter.clone());
+        }
+    }
+    for route in &config.proxies {
+        let path = format!("{}/{{*path}}", route.mount.trim_end_matches('/'));
+        let upstreams: Vec<(&str, u32)> = route.upstreams.iter().map(|(addr, w)| (addr.as_str(), *w)).collect();
+        let mut proxy = Proxy::balanced(&upstreams).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        proxy = match route.balancer {
+            BalancerKind::RoundRobin => proxy.balancer(RoundRobin::new()),
+            BalancerKind::Weighted => proxy.balancer(Weighted::new()),
+            BalancerKind::LeastConnections => proxy.balancer(LeastConnections::new()),
+            BalancerKind::PowerOfTwoChoices => proxy.balancer(PowerOfTwoChoices::new()),
         };
-
-        if client_fd < 0 {
-            return Err(std::io::Error::last_os_error());
+        if let Some(check) = &route.health_check {
+            proxy = proxy.health_check(check.clone());
         }
-
-        Ok(RawTcpStream::from_raw_fd(client_fd))
+        if let Some(breaker) = &route.circuit_breaker {
+            proxy = proxy.circuit_breaker(breaker.clone());
+        }
+        if let Some(attempts) = route.retries {
+            proxy = proxy.retries(attempts);
+        }
+        if let Some(backoff) = route.retry_backoff {
+            proxy = proxy.retry_backoff(backoff);
+        }
+        if let Some(max) = route.max_retry_backoff {
+            proxy = proxy.max_retry_backoff(max);
+        }
+        if let Some(max) = route.max_idle {
+            proxy = proxy.max_idle(max);
+        }
+        if let Some(timeout) = route.idle_timeout {
+            proxy = proxy.idle_timeout(timeout);
+        }
+        if let Some(lifetime) = route.max_lifetime {
+            proxy = proxy.max_lifetime(lifetime);
+        }
+        if let Some(ttl) = route.dns_ttl {
+            proxy = proxy.dns_ttl(ttl);
+        }
+        if let Some(version) = route.proxy_protocol {
+            proxy = proxy.proxy_protocol(version);
+        }
+        let proxy = Arc::new(proxy);
+        let site = site(&mut router, &mut sites, route.host.as_ref(), config)?;
+        for method in PROXY_METHODS {
+            let proxy = Arc::clone(&proxy);
+            site.route(method, &path, move |request, writer| proxy.call(request, writer));
+            if let Some(requests) = route.rate_limit {
+                site.rate_limit(method, &path, Limit::per_second(requests));
+            }
+            if let Some(filter) = &route.ip_filter {
+                site.ip_filter(method, &path, filter.clone());
+            }
+        }
+    }
+    for (name, site) in sites {
+        router.host(&name, site);
     }
+    Ok(router)
 }
 
-impl Drop for CustomTcpListener {
-    fn drop(&mut self) {
-        unsafe {
-            libc::close(self.fd);
+/// The router serving `host`: the main one when no host is named, else
+/// that host's site, created on first use.
+fn site<'a>(
+    router: &'a mut Router,
+    sites: &'a mut Vec<(String, Router)>,
+    host: Option<&String>,
+    config: &Config,
+) -> io::Result<&'a mut Router> {
+    let Some(host) = host else {
+        return Ok(router);
+    };
+    let index = match sites.iter().position(|(name, _)| name == host) {
+        Some(index) => index,
+        None => {
+            let mut site = Router::new();
+            add_error_pages(&mut site, config)?;
+            sites.push((host.clone(), site));
+            sites.len() - 1
         }
-    }
+    };
+    Ok(&mut sites[index].1)
 }
 
-fn get_rusage() -> (u64, u64) {
-    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
-    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut rusage) };
-    
-    let user_time = (rusage.ru_utime.tv_sec as u64 * 1000000) + rusage.ru_utime.tv_usec as u64;
-    let sys_time = (rusage.ru_stime.tv_sec as u64 * 1000000) + rusage.ru_stime.tv_usec as u64;
-    (user_time, sys_time)
+/// Sends the server's own log lines where `[log]` says, filtered by its
+/// level.
+fn configure_logging(settings: &LogConfig) -> io::Result<()> {
+    let output: Box<dyn Write + Send> = match &settings.output {
+        LogOutput::Stderr => Box::new(io::stderr()),
+        LogOutput::Stdout => Box::new(io::stdout()),
+        LogOutput::File(path) => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+        ),
+    };
+    log::set_output(output);
+    log::set_filter(settings.level.clone().unwrap_or_default());
+    Ok(())
 }
 
-fn print_stats() {
-    let (user_us, sys_us) = get_rusage();
-    let conn = CONNECTIONS.load(Ordering::Relaxed);
-    let bytes = BYTES_SENT.load(Ordering::Relaxed);
-    
-    println!("Connections: {} | Bytes sent: {} | CPU: {:.2}ms user, {:.2}ms sys", 
-             conn, bytes, user_us as f64 / 1000.0, sys_us as f64 / 1000.0);
+fn add_error_pages(router: &mut Router, config: &Config) -> io::Result<()> {
+    for (status, path) in &config.error_pages {
+        router.error_page(*status, ErrorPage::file(path)?);
+    }
+    Ok(())
 }
 
-fn main() {
-    let listener = CustomTcpListener::bind("127.0.0.1:8080").unwrap();
-    println!("Server running on http://127.0.0.1:8080");
-
-    thread::spawn(|| {
-        loop {
-            thread::sleep(std::time::Duration::from_secs(5));
-            print_stats();
-        }
-    });
-
+/// Reloads the configuration on SIGHUP and hands over to a new binary on
+/// SIGUSR2.
+fn handle_signals(signals: Signals, args: Args, mut current: Config, server: ServerHandle) {
     loop {
-        match listener.accept() {
-            Ok(stream) => {
-                CONNECTIONS.fetch_add(1, Ordering::Relaxed);
-                thread::spawn(|| {
-                    handle_connection(stream);
-                });
+        match signals.wait() {
+            Ok(libc::SIGHUP) => reload(&args, &mut current, &server),
+            Ok(_) => {
+                if upgrade_binary(&server) {
+                    // Whatever has not drained by now is cut off.
+                    thread::sleep(DRAIN_TIMEOUT);
+                    process::exit(0);
+                }
             }
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                error!("Error waiting for signals: {}", e);
+                return;
             }
         }
     }
 }
 
-fn handle_connection(mut stream: RawTcpStream) {
-    let mut buffer = [0; 1024];
-    
-    match stream.read(&mut buffer) {
-        Ok(bytes_read) => {
-            let request = String::from_utf8_lossy(&buffer[..bytes_read]);
-            
-            match parse_request(&request) {
-                Ok((method, path)) => {
-                    println!("Request: {} {}", method, path);
-                    send_ok_response(&mut stream);
-                }
-                Err(_) => {
-                    send_bad_request_response(&mut stream);
-                }
-            }
-        }
+/// Swaps in a router built from the re-read config. A config that fails to
+/// load leaves the running one in place.
+fn reload(args: &Args, current: &mut Config, server: &ServerHandle) {
+    let reloaded = load_config(args).and_then(|config| {
+        configure_logging(&config.log)?;
+        let router = build_router(&config)?;
+        Ok((config, router))
+    });
+    let (config, router) = match reloaded {
+        Ok(reloaded) => reloaded,
         Err(e) => {
-            eprintln!("Error reading from stream: {}", e);
+            error!("Reload failed, keeping the current configuration: {}", e);
+            return;
         }
-    }
-}
+    };
+    server.set_router(router);
 
-fn parse_request(request: &str) -> Result<(String, String), &'static str> {
-    let lines: Vec<&str> = request.lines().collect();
-    if lines.is_empty() {
-        return Err("Empty request");
-    }
-    
-    let request_line = lines[0];
-    let parts: Vec<&str> = request_line.split_whitespace().collect();
-    
-    if parts.len() < 3 {
-        return Err("Invalid request line");
-    }
-    
-    let method = parts[0].to_string();
-    let path = parts[1].to_string();
-    
-    Ok((method, path))
+    if !same_server_settings(current, &config) {
+        warn!("Listen addresses, sizing, timeouts and user only change on restart");
+    }
+    info!("Configuration reloaded");
+    *current = config;
 }
 
-fn send_ok_response(stream: &mut RawTcpStream) {
-    let html_body = r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Hello World</title>
-</head>
-<body>
-    <h1>Hello, World!</h1>
-</body>
-</html>"#;
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
-        html_body.len(),
-        html_body
-    );
-
-    if let Ok(_) = stream.write_all(response.as_bytes()) {
-        BYTES_SENT.fetch_add(response.len() as u64, Ordering::Relaxed);
+/// Re-executes the binary this process was started from, which may have
+/// been replaced on disk, with the same arguments. Once it is accepting
+/// connections this server stops and drains. Returns whether it did.
+fn upgrade_binary(server: &ServerHandle) -> bool {
+    let mut argv = env::args_os();
+    let Some(program) = argv.next() else {
+        return false;
+    };
+    let mut command = Command::new(program);
+    command.args(argv);
+
+    match server.upgrade(command, UPGRADE_TIMEOUT) {
+        Ok(child) => {
+            info!("Upgraded to process {}; draining connections", child.id());
+            server.shutdown();
+            true
+        }
+        Err(e) => {
+            error!("Upgrade failed, carrying on: {}", e);
+            false
+        }
     }
 }
 
-fn send_bad_request_response(stream: &mut RawTcpStream) {
-    let html_body = r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Bad Request</title>
-</head>
-<body>
-    <h1>400 Bad Request</h1>
-</body>
-</html>"#;
-
-    let response = format!(
-        "HTTP/1.1 400 Bad Request\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
-        html_body.len(),
-        html_body
-    );
-
-    if let Ok(_) = stream.write_all(response.as_bytes()) {
-        BYTES_SENT.fetch_add(response.len() as u64, Ordering::Relaxed);
-    }
-}
\ No newline at end of file
+/// Whether the settings fixed at startup are unchanged.
+fn same_server_settings(a: &Config, b: &Config) -> bool {
+    a.listen == b.listen
+        && a.workers == b.workers
+        && a.pool_size == b.pool_size
+        && a.queue_depth == b.queue_depth
+        && a.max_connections == b.max_connections
+        && a.read_timeout == b.read_timeout
+        && a.header_timeout == b.header_timeout
+        && a.keep_alive_timeout == b.keep_alive_timeout
+        && a.write_timeout == b.write_timeout
+        && a.request_timeout == b.request_timeout
+        && a.head_limits == b.head_limits
+        && a.max_body_size == b.max_body_size
+        && a.spool_threshold == b.spool_threshold
+        && a.spool_dir == b.spool_dir
+        && a.user == b.user
+        && a.group == b.group
+}
@@ -0,0 +1,404 @@
+//! Port forwarding: relaying everything sent to one port on to a fixed
+//! target address, byte for byte or datagram for datagram, without any
+//! HTTP involved.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::HyperportError;
+use crate::net::proxy_protocol::{self, ProxyHeader};
+use crate::net::tunnel::{relay, Direction};
+use crate::net::{self, CustomTcpListener, ListenerConfig, RawTcpStream};
+use crate::server::stats;
+use crate::{error, warn};
+
+/// Largest UDP payload.
+const MAX_DATAGRAM: usize = 65535;
+
+/// What a [`Forwarder`] carries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// Listens on one address and forwards what arrives to a target.
+///
+/// Over TCP, each connection gets a thread relaying bytes both ways until
+/// both sides have closed or the connection sits idle too long.
+///
+/// Over UDP, each client address gets a session: a socket of its own
+/// towards the target, whose replies are sent back to that client, like a
+/// NAT mapping. Sessions quiet for the idle timeout are dropped. One
+/// thread serves every session.
+///
+/// ```ignore
+/// let ssh = Forwarder::new("0.0.0.0:2222", "10.0.0.5:22")?.connect_timeout(Duration::from_secs(3)).spawn()?;
+/// let dns = Forwarder::udp("0.0.0.0:53", "10.0.0.2:53")?.idle_timeout(Duration::from_secs(30)).spawn()?;
+/// println!("{} bytes to the target so far", ssh.bytes_to_target());
+/// ```
+pub struct Forwarder {
+    protocol: Protocol,
+    listen: String,
+    target: SocketAddr,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    proxy_protocol: Option<proxy_protocol::Version>,
+    max_sessions: usize,
+}
+
+impl Forwarder {
+    /// A TCP forwarder from `listen`, a literal socket address, to
+    /// `target`, a `host:port` address. A host name is resolved once, here.
+    pub fn new(listen: &str, target: &str) -> Result<Forwarder, HyperportError> {
+        let target = target
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| HyperportError::InvalidAddress(target.to_string()))?;
+        Ok(Forwarder {
+            protocol: Protocol::Tcp,
+            listen: listen.to_string(),
+            target,
+            connect_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(300),
+            proxy_protocol: None,
+            max_sessions: 4096,
+        })
+    }
+
+    /// A UDP forwarder from `listen` to `target`, as for
+    /// [`new`](Forwarder::new). The idle timeout defaults to 60 seconds.
+    pub fn udp(listen: &str, target: &str) -> Result<Forwarder, HyperportError> {
+        let forwarder = Forwarder::new(listen, target)?;
+        Ok(Forwarder {
+            protocol: Protocol::Udp,
+            idle_timeout: Duration::from_secs(60),
+            ..forwarder
+        })
+    }
+
+    /// How long connecting to the target may take; the client's connection
+    /// is closed if it does not succeed in time. Defaults to 5 seconds.
+    /// TCP only.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Closes connections, or drops UDP sessions, that carry nothing either
+    /// way for `timeout`. Defaults to 5 minutes over TCP and 60 seconds
+    /// over UDP.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Opens each connection to the target with a PROXY protocol header in
+    /// `version`, for targets that need the client's address. TCP only.
+    pub fn proxy_protocol(mut self, version: proxy_protocol::Version) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Most UDP sessions open at once; datagrams from further clients are
+    /// dropped until one expires. Defaults to 4096.
+    pub fn max_sessions(mut self, max: usize) -> Self {
+        self.max_sessions = max.max(1);
+        self
+    }
+
+    /// Binds the listening socket and starts forwarding on a background
+    /// thread.
+    pub fn spawn(self) -> Result<ForwarderHandle, HyperportError> {
+        // SO_REUSEPORT lets a process taking over in a binary upgrade bind
+        // the same address while this one still runs.
+        let config = ListenerConfig {
+            reuse_port: true,
+            ..ListenerConfig::default()
+        };
+        let (socket, local_addr, fd) = match self.protocol {
+            Protocol::Tcp => {
+                let listener = CustomTcpListener::bind(&self.listen, &config)?;
+                let (addr, fd) = (listener.local_addr()?, listener.as_raw_fd());
+                (Socket::Tcp(listener), addr, fd)
+            }
+            Protocol::Udp => {
+                let socket = net::bind_udp(&self.listen, &config)?;
+                socket.set_nonblocking(true)?;
+                let (addr, fd) = (socket.local_addr()?, socket.as_raw_fd());
+                (Socket::Udp(socket), addr, fd)
+            }
+        };
+        let shared = Arc::new(Shared {
+            protocol: self.protocol,
+            local_addr,
+            target: self.target,
+            listener_fd: fd,
+            stopped: AtomicBool::new(false),
+            connections: AtomicU64::new(0),
+            bytes_to_target: AtomicU64::new(0),
+            bytes_from_target: AtomicU64::new(0),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("forward".to_string())
+            .spawn(move || match socket {
+                Socket::Tcp(listener) => accept_loop(listener, self, thread_shared),
+                Socket::Udp(socket) => udp_loop(socket, &self, &thread_shared),
+            })?;
+        Ok(ForwarderHandle { shared })
+    }
+}
+
+enum Socket {
+    Tcp(CustomTcpListener),
+    Udp(UdpSocket),
+}
+
+struct Shared {
+    protocol: Protocol,
+    local_addr: SocketAddr,
+    target: SocketAddr,
+    /// The listening socket, shut down to stop the accept loop.
+    listener_fd: RawFd,
+    stopped: AtomicBool,
+    connections: AtomicU64,
+    bytes_to_target: AtomicU64,
+    bytes_from_target: AtomicU64,
+}
+
+impl Shared {
+    fn opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        stats::FORWARDED_CONNECTIONS[self.protocol as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn relayed(&self, direction: Direction, n: usize) {
+        let protocol = self.protocol as usize;
+        let (own, global) = match direction {
+            Direction::ToUpstream => (&self.bytes_to_target, &stats::FORWARDED_BYTES_TO_TARGET[protocol]),
+            Direction::ToClient => (&self.bytes_from_target, &stats::FORWARDED_BYTES_FROM_TARGET[protocol]),
+        };
+        own.fetch_add(n as u64, Ordering::Relaxed);
+        global.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// A running [`Forwarder`]: its address and counters, and a way to stop it.
+#[derive(Clone)]
+pub struct ForwarderHandle {
+    shared: Arc<Shared>,
+}
+
+impl ForwarderHandle {
+    pub fn protocol(&self) -> Protocol {
+        self.shared.protocol
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.shared.target
+    }
+
+    /// Connections accepted, or UDP sessions opened, since the forwarder
+    /// started.
+    pub fn connections(&self) -> u64 {
+        self.shared.connections.load(Ordering::Relaxed)
+    }
+
+    /// Bytes relayed from clients to the target.
+    pub fn bytes_to_target(&self) -> u64 {
+        self.shared.bytes_to_target.load(Ordering::Relaxed)
+    }
+
+    /// Bytes relayed from the target back to clients.
+    pub fn bytes_from_target(&self) -> u64 {
+        self.shared.bytes_from_target.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting connections. Those already open carry on until they
+    /// close; UDP sessions end with the forwarder.
+    pub fn stop(&self) {
+        if !self.shared.stopped.swap(true, Ordering::AcqRel) && self.shared.protocol == Protocol::Tcp {
+            // Wakes the accept loop, which then sees the flag. The UDP loop
+            // sees it within a second by itself.
+            unsafe { libc::shutdown(self.shared.listener_fd, libc::SHUT_RDWR) };
+        }
+    }
+}
+
+fn accept_loop(listener: CustomTcpListener, forwarder: Forwarder, shared: Arc<Shared>) {
+    let forwarder = Arc::new(forwarder);
+    while !shared.stopped.load(Ordering::Acquire) {
+        let client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(HyperportError::Io(e)) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                if !shared.stopped.load(Ordering::Acquire) {
+                    stats::ACCEPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    error!("Error accepting connection to forward: {}", e);
+                    // Backs off from descriptor exhaustion and the like.
+                    thread::sleep(Duration::from_millis(100));
+                }
+                continue;
+            }
+        };
+        shared.opened();
+        let (forwarder, shared) = (Arc::clone(&forwarder), Arc::clone(&shared));
+        let spawned = thread::Builder::new()
+            .name("forward-connection".to_string())
+            .spawn(move || {
+                if let Err(e) = forward(client, &forwarder, &shared) {
+                    warn!("Error forwarding to {}: {}", forwarder.target, e);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Error starting forwarding thread: {}", e);
+        }
+    }
+}
+
+fn forward(client: RawTcpStream, forwarder: &Forwarder, shared: &Shared) -> io::Result<()> {
+    let mut upstream = match TcpStream::connect_timeout(&forwarder.target, forwarder.connect_timeout) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let _ = client.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+    };
+    upstream.set_nodelay(true)?;
+    upstream.set_write_timeout(Some(forwarder.idle_timeout))?;
+    if let Some(version) = forwarder.proxy_protocol {
+        let header = match (client.peer_addr(), client.local_addr()) {
+            (Ok(source), Ok(destination)) => ProxyHeader::Proxied { source, destination },
+            _ => ProxyHeader::Local,
+        };
+        upstream.write_all(&header.encode(version))?;
+    }
+    relay(client, upstream, forwarder.idle_timeout, |direction, n| shared.relayed(direction, n))
+}
+
+/// One client's mapping to the target.
+struct Session {
+    client: SocketAddr,
+    /// Connected to the target, so only its datagrams arrive.
+    upstream: UdpSocket,
+    last_active: Instant,
+}
+
+/// Serves every UDP session on this thread: datagrams from clients go out
+/// on their session's socket, and replies on those sockets go back to the
+/// client from the listening one.
+fn udp_loop(socket: UdpSocket, forwarder: &Forwarder, shared: &Shared) {
+    let mut sessions: Vec<Session> = Vec::new();
+    let mut by_client: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    // Often enough to expire sessions and notice being stopped.
+    let tick = forwarder.idle_timeout.clamp(Duration::from_millis(10), Duration::from_secs(1));
+
+    while !shared.stopped.load(Ordering::Acquire) {
+        let mut polled: Vec<libc::pollfd> = [socket.as_raw_fd()]
+            .into_iter()
+            .chain(sessions.iter().map(|session| session.upstream.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = tick.as_millis() as i32;
+        let ready = unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, timeout) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                error!("Error polling forwarded UDP sockets: {}", e);
+                thread::sleep(Duration::from_millis(100));
+            }
+            continue;
+        }
+        let now = Instant::now();
+
+        // Replies from the target, back to each session's client.
+        for (session, pollfd) in sessions.iter_mut().zip(&polled[1..]) {
+            if pollfd.revents == 0 {
+                continue;
+            }
+            // ICMP errors for earlier datagrams surface here too, and are
+            // nothing to relay.
+            while let Ok(n) = session.upstream.recv(&mut buf) {
+                session.last_active = now;
+                if socket.send_to(&buf[..n], session.client).is_ok() {
+                    shared.relayed(Direction::ToClient, n);
+                }
+            }
+        }
+
+        // Datagrams from clients, opening sessions for new ones.
+        if polled[0].revents != 0 {
+            loop {
+                let (n, client) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("Error receiving UDP datagram to forward: {}", e);
+                        break;
+                    }
+                };
+                let index = match by_client.get(&client) {
+                    Some(&index) => index,
+                    None if sessions.len() >= forwarder.max_sessions => continue,
+                    None => match open_session(forwarder.target, client, now) {
+                        Ok(session) => {
+                            shared.opened();
+                            sessions.push(session);
+                            by_client.insert(client, sessions.len() - 1);
+                            sessions.len() - 1
+                        }
+                        Err(e) => {
+                            warn!("Error opening UDP session to {}: {}", forwarder.target, e);
+                            continue;
+                        }
+                    },
+                };
+                let session = &mut sessions[index];
+                session.last_active = now;
+                if session.upstream.send(&buf[..n]).is_ok() {
+                    shared.relayed(Direction::ToUpstream, n);
+                }
+            }
+        }
+
+        let before = sessions.len();
+        sessions.retain(|session| now.duration_since(session.last_active) < forwarder.idle_timeout);
+        if sessions.len() != before {
+            by_client = sessions.iter().enumerate().map(|(i, session)| (session.client, i)).collect();
+        }
+    }
+}
+
+fn open_session(target: SocketAddr, client: SocketAddr, now: Instant) -> io::Result<Session> {
+    let any = match target {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let upstream = UdpSocket::bind(any)?;
+    upstream.connect(target)?;
+    upstream.set_nonblocking(true)?;
+    Ok(Session {
+        client,
+        upstream,
+        last_active: now,
+    })
+}
@@ -0,0 +1,94 @@
+//! Allow and deny lists of networks, for turning away unwanted clients by
+//! address.
+
+use std::net::IpAddr;
+
+use crate::error::HyperportError;
+
+/// Addresses allowed and denied, as single addresses or `address/prefix`
+/// networks, IPv4 or IPv6.
+///
+/// An address on the deny list is refused. Otherwise it is allowed if the
+/// allow list is empty or it is on it. IPv4-mapped IPv6 addresses count as
+/// the IPv4 addresses they stand for.
+///
+/// The server applies one to connections as they are accepted, see
+/// [`ServerBuilder::ip_filter`](crate::server::ServerBuilder::ip_filter),
+/// closing refused ones before reading anything from them; a router
+/// applies one to requests for a route, see
+/// [`Router::ip_filter`](crate::http::Router::ip_filter).
+///
+/// ```ignore
+/// let filter = IpFilter::new(&["10.0.0.0/8", "192.168.1.0/24"], &["10.0.0.66"])?;
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<Network>,
+    deny: Vec<Network>,
+}
+
+impl IpFilter {
+    pub fn new<S: AsRef<str>, T: AsRef<str>>(allow: &[S], deny: &[T]) -> Result<IpFilter, HyperportError> {
+        Ok(IpFilter {
+            allow: Network::parse_all(allow)?,
+            deny: Network::parse_all(deny)?,
+        })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|network| network.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip)))
+    }
+}
+
+/// An address with a prefix length, matching the addresses that share
+/// those leading bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    pub(crate) fn parse(text: &str) -> Option<Network> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= bits)?,
+            None => bits,
+        };
+        Some(Network { addr, prefix })
+    }
+
+    /// Parses every entry, failing on the first that is not an address or
+    /// network.
+    pub(crate) fn parse_all<S: AsRef<str>>(networks: &[S]) -> Result<Vec<Network>, HyperportError> {
+        networks
+            .iter()
+            .map(|network| {
+                let network = network.as_ref();
+                Network::parse(network).ok_or_else(|| HyperportError::InvalidAddress(network.to_string()))
+            })
+            .collect()
+    }
+
+    /// Whether `ip`, already canonical, is in the network.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,551 @@
+//! Thin wrappers over raw libc sockets.
+//!
+//! [`CustomTcpListener`] binds and accepts, [`RawTcpStream`] owns a
+//! connected socket and exposes the handful of syscalls the server needs.
+//! [`systemd`] picks up listeners passed in by socket activation,
+//! [`ip_filter`] decides which client addresses are let in, and [`socks`]
+//! serves SOCKS5 clients on top of them.
+
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::HyperportError;
+use crate::net::ip_filter::IpFilter;
+
+pub mod forward;
+pub mod ip_filter;
+pub mod proxy_protocol;
+pub mod socks;
+pub mod systemd;
+pub(crate) mod tunnel;
+
+/// A connected TCP socket that closes its descriptor on drop.
+pub struct RawTcpStream {
+    fd: RawFd,
+    /// The client's address, and the one it connected to, when the peer is
+    /// a proxy that reported them.
+    proxied: Option<(SocketAddr, SocketAddr)>,
+}
+
+impl RawTcpStream {
+    /// Takes ownership of an already connected socket descriptor.
+    pub fn from_raw_fd(fd: RawFd) -> Self {
+        RawTcpStream { fd, proxied: None }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let bytes_read = unsafe {
+            libc::read(
+                self.fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+
+        if bytes_read < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(bytes_read as usize)
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let bytes_written = unsafe {
+            libc::write(
+                self.fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        };
+
+        if bytes_written < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(bytes_written as usize)
+        }
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        let mut total_written = 0;
+
+        while total_written < buf.len() {
+            match self.write(&buf[total_written..]) {
+                Ok(n) => total_written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies up to `count` bytes from `file_fd` at `*offset` straight to the
+    /// socket with sendfile(2), advancing `offset` by the amount sent.
+    pub fn sendfile(&mut self, file_fd: RawFd, offset: &mut i64, count: usize) -> Result<usize, std::io::Error> {
+        let sent = unsafe { libc::sendfile(self.fd, file_fd, offset as *mut i64, count) };
+
+        if sent < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    /// Duplicates the descriptor, giving an independently owned handle to the
+    /// same socket.
+    pub fn try_clone(&self) -> Result<RawTcpStream, std::io::Error> {
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(RawTcpStream {
+            fd,
+            proxied: self.proxied,
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        set_nonblocking(self.fd, nonblocking)
+    }
+
+    /// Shuts down reading, writing or both. Calls blocked on the socket in
+    /// other threads, or on duplicates of it, return immediately.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        let how = match how {
+            Shutdown::Read => libc::SHUT_RD,
+            Shutdown::Write => libc::SHUT_WR,
+            Shutdown::Both => libc::SHUT_RDWR,
+        };
+        if unsafe { libc::shutdown(self.fd, how) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Address of the connected peer, or of the client a proxy connected
+    /// for, once [`set_proxied`](RawTcpStream::set_proxied) has been told
+    /// it.
+    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        if let Some((source, _)) = self.proxied {
+            return Ok(source);
+        }
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getpeername(self.fd, &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        sockaddr_to(&storage).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    /// Address of the local end, or the one the client of a proxy
+    /// connected to, once [`set_proxied`](RawTcpStream::set_proxied) has
+    /// been told it.
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        if let Some((_, destination)) = self.proxied {
+            return Ok(destination);
+        }
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockname(self.fd, &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        sockaddr_to(&storage).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    /// Reports `source` as the peer and `destination` as the local address
+    /// from now on, e.g. the addresses from a [PROXY protocol](proxy_protocol)
+    /// header.
+    pub fn set_proxied(&mut self, source: SocketAddr, destination: SocketAddr) {
+        self.proxied = Some((source, destination));
+    }
+
+    /// Bounds how long a blocking read may wait (SO_RCVTIMEO). Reads that
+    /// time out fail with `WouldBlock`. `None` waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        set_timeout_option(self.fd, libc::SO_RCVTIMEO, timeout)
+    }
+
+    /// Bounds how long a blocking write may wait (SO_SNDTIMEO).
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        set_timeout_option(self.fd, libc::SO_SNDTIMEO, timeout)
+    }
+
+    /// Sends small writes straight away rather than holding them back to
+    /// coalesce with later ones (TCP_NODELAY).
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), std::io::Error> {
+        set_int_option(self.fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, nodelay as i32)
+    }
+}
+
+impl AsRawFd for RawTcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for RawTcpStream {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A listening TCP socket.
+pub struct CustomTcpListener {
+    fd: RawFd,
+    /// Whether connections open with a PROXY protocol header.
+    proxy_protocol: bool,
+    /// Which peers may connect.
+    ip_filter: Option<Arc<IpFilter>>,
+}
+
+/// Socket-level options applied when a listener is created.
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    /// For IPv6 addresses, set IPV6_V6ONLY so the socket refuses
+    /// IPv4-mapped traffic. When false an `[::]` listener is dual-stack.
+    pub v6_only: bool,
+    /// Set SO_REUSEPORT so several sockets can bind the same address and
+    /// have the kernel distribute incoming connections between them.
+    pub reuse_port: bool,
+    /// Length of the kernel accept queue passed to listen(2).
+    pub backlog: i32,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            v6_only: false,
+            reuse_port: false,
+            backlog: 128,
+        }
+    }
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd, nonblocking: bool) -> Result<(), std::io::Error> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let flags = if nonblocking {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn set_int_option(fd: RawFd, level: i32, name: i32, value: i32) -> Result<(), std::io::Error> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_timeout_option(fd: RawFd, name: i32, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+    // A zero timeval means "no timeout" to the kernel, so round tiny
+    // durations up rather than silently disabling the limit.
+    let timeval = match timeout {
+        Some(d) => {
+            let d = d.max(Duration::from_micros(1));
+            libc::timeval {
+                tv_sec: d.as_secs() as libc::time_t,
+                tv_usec: d.subsec_micros() as libc::suseconds_t,
+            }
+        }
+        None => libc::timeval { tv_sec: 0, tv_usec: 0 },
+    };
+
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &timeval as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn sockaddr_from(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr_in = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sockaddr_in.sin_family = libc::AF_INET as u16;
+            sockaddr_in.sin_port = addr.port().to_be();
+            sockaddr_in.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr_in6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sockaddr_in6.sin6_family = libc::AF_INET6 as u16;
+            sockaddr_in6.sin6_port = addr.port().to_be();
+            sockaddr_in6.sin6_flowinfo = addr.flowinfo();
+            sockaddr_in6.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr_in6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Binds a UDP socket to `addr`, a literal socket address, with the same
+/// SO_REUSEADDR, SO_REUSEPORT and IPV6_V6ONLY handling as
+/// [`CustomTcpListener::bind`]; the backlog does not apply.
+pub(crate) fn bind_udp(addr: &str, config: &ListenerConfig) -> Result<UdpSocket, HyperportError> {
+    let socket_addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| HyperportError::InvalidAddress(addr.to_string()))?;
+    let domain = match socket_addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // Owned from here on, so early returns close it.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if config.reuse_port {
+        set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    }
+    if domain == libc::AF_INET6 {
+        set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, config.v6_only as i32)?;
+    }
+
+    let (sockaddr, sockaddr_len) = sockaddr_from(&socket_addr);
+    let bound = unsafe {
+        libc::bind(fd, &sockaddr as *const libc::sockaddr_storage as *const libc::sockaddr, sockaddr_len)
+    };
+    if bound < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+fn sockaddr_to(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let sockaddr_in = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sockaddr_in.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sockaddr_in.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sockaddr_in6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sockaddr_in6.sin6_addr.s6_addr);
+            // IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d;
+            // report them as the IPv4 peers they are.
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return Some(SocketAddr::V4(SocketAddrV4::new(v4, u16::from_be(sockaddr_in6.sin6_port))));
+            }
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(sockaddr_in6.sin6_port),
+                sockaddr_in6.sin6_flowinfo,
+                sockaddr_in6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+impl CustomTcpListener {
+    /// Binds and listens on `addr`, which must be a literal socket address
+    /// such as `0.0.0.0:8080` or `[::]:8080`.
+    pub fn bind(addr: &str, config: &ListenerConfig) -> Result<Self, HyperportError> {
+        let socket_addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| HyperportError::InvalidAddress(addr.to_string()))?;
+
+        let domain = match socket_addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+
+        let fd = unsafe {
+            libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+        };
+        
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let listener = CustomTcpListener::from_raw_fd(fd);
+
+        set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+
+        if config.reuse_port {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+
+        // Linux defaults IPV6_V6ONLY from net.ipv6.bindv6only, so always set
+        // it explicitly rather than depending on host sysctls.
+        if domain == libc::AF_INET6 {
+            set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, config.v6_only as i32)?;
+        }
+
+        let (sockaddr, sockaddr_len) = sockaddr_from(&socket_addr);
+
+        unsafe {
+            let bind_result = libc::bind(
+                fd,
+                &sockaddr as *const libc::sockaddr_storage as *const libc::sockaddr,
+                sockaddr_len,
+            );
+            
+            if bind_result < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            if libc::listen(fd, config.backlog) < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(listener)
+    }
+
+    /// Takes ownership of a socket that is already bound and listening,
+    /// such as one inherited from a service manager.
+    pub fn from_raw_fd(fd: RawFd) -> Self {
+        CustomTcpListener {
+            fd,
+            proxy_protocol: false,
+            ip_filter: None,
+        }
+    }
+
+    /// Duplicates the descriptor, giving an independently owned handle to
+    /// the same listening socket.
+    pub fn try_clone(&self) -> Result<CustomTcpListener, std::io::Error> {
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(CustomTcpListener {
+            fd,
+            proxy_protocol: self.proxy_protocol,
+            ip_filter: self.ip_filter.clone(),
+        })
+    }
+
+    /// Expects every connection accepted to open with a [PROXY
+    /// protocol](proxy_protocol) header, taking the client address from it.
+    /// Connections without one are closed.
+    pub fn set_proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Closes connections from peers `filter` refuses as soon as they are
+    /// accepted. Behind a PROXY protocol load balancer, the peer is the
+    /// load balancer.
+    pub fn set_ip_filter(&mut self, filter: Option<Arc<IpFilter>>) {
+        self.ip_filter = filter;
+    }
+
+    pub fn ip_filter(&self) -> Option<&IpFilter> {
+        self.ip_filter.as_deref()
+    }
+
+    /// Address the socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockname(self.fd, &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        sockaddr_to(&storage).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    /// Accepts a pending connection along with the peer address, when the
+    /// kernel reports one in a family we understand.
+    pub fn accept(&self) -> Result<(RawTcpStream, Option<SocketAddr>), HyperportError> {
+        let mut client_addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let client_fd = unsafe {
+            libc::accept4(
+                self.fd,
+                &mut client_addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut addr_len,
+                libc::SOCK_CLOEXEC,
+            )
+        };
+
+        if client_fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok((RawTcpStream::from_raw_fd(client_fd), sockaddr_to(&client_addr)))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        set_nonblocking(self.fd, nonblocking)
+    }
+}
+
+impl AsRawFd for CustomTcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for CustomTcpListener {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
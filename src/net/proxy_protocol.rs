@@ -0,0 +1,222 @@
+//! The PROXY protocol (versions 1 and 2): a header a load balancer sends
+//! ahead of the client's own bytes, giving the client's address, which the
+//! server would otherwise only know as the load balancer's.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Signature opening a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// What a PROXY protocol header says about the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// Relayed for a client: its address, and the address it connected to.
+    Proxied { source: SocketAddr, destination: SocketAddr },
+    /// Opened by the load balancer itself, e.g. for a health check, or for
+    /// a client whose address it could not express; the connection's own
+    /// peer address stands.
+    Local,
+}
+
+/// The format of a header to send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    /// The text format, readable by every implementation.
+    #[default]
+    V1,
+    /// The binary format.
+    V2,
+}
+
+impl ProxyHeader {
+    /// The header encoded as `version`. With one address IPv4 and the other
+    /// IPv6, the IPv4 one is sent as an IPv4-mapped IPv6 address, since a
+    /// header has one family for both.
+    ///
+    /// ```
+    /// use hyperport::net::proxy_protocol::{ProxyHeader, Version};
+    ///
+    /// let header = ProxyHeader::Proxied {
+    ///     source: "203.0.113.7:51234".parse().unwrap(),
+    ///     destination: "192.0.2.1:443".parse().unwrap(),
+    /// };
+    /// assert_eq!(header.encode(Version::V1), b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n");
+    /// ```
+    pub fn encode(&self, version: Version) -> Vec<u8> {
+        let addrs = match *self {
+            ProxyHeader::Proxied { source, destination } => Some(same_family(source, destination)),
+            ProxyHeader::Local => None,
+        };
+        match version {
+            Version::V1 => match addrs {
+                Some((source, destination)) => format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes(),
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            },
+            Version::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                let mut addresses = Vec::new();
+                let (command, family) = match addrs {
+                    Some((source, destination)) => {
+                        for addr in [source, destination] {
+                            match addr.ip() {
+                                IpAddr::V4(ip) => addresses.extend_from_slice(&ip.octets()),
+                                IpAddr::V6(ip) => addresses.extend_from_slice(&ip.octets()),
+                            }
+                        }
+                        addresses.extend_from_slice(&source.port().to_be_bytes());
+                        addresses.extend_from_slice(&destination.port().to_be_bytes());
+                        // PROXY, over TCP on IPv4 or IPv6.
+                        (0x21, if source.is_ipv4() { 0x11 } else { 0x21 })
+                    }
+                    // LOCAL, unspecified family.
+                    None => (0x20, 0x00),
+                };
+                header.push(command);
+                header.push(family);
+                header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                header.extend_from_slice(&addresses);
+                header
+            }
+        }
+    }
+}
+
+/// `source` and `destination` in one family: IPv4 if both are IPv4 or
+/// IPv4-mapped, else IPv6.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (source, destination) = (canonical(source), canonical(destination));
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let mapped = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    (mapped(source), mapped(destination))
+}
+
+/// A connection that should have opened with a PROXY protocol header but
+/// did not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidHeader(&'static str);
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid PROXY protocol header: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+/// Parses a version 1 or 2 header from the front of `buf`, the first bytes
+/// received on a connection. Returns the header and its length, or `None`
+/// while `buf` holds only part of one.
+///
+/// ```
+/// use hyperport::net::proxy_protocol::{self, ProxyHeader};
+///
+/// let buf = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET / HTTP/1.1\r\n";
+/// let (header, len) = proxy_protocol::parse(buf).unwrap().unwrap();
+/// assert_eq!(len, 44);
+/// assert!(matches!(header, ProxyHeader::Proxied { source, .. } if source.port() == 51234));
+/// ```
+pub fn parse(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, InvalidHeader> {
+    if buf.len() < V2_SIGNATURE.len() && (V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(buf)) {
+        return Ok(None);
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        Err(InvalidHeader("missing"))
+    }
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`,
+/// or `PROXY UNKNOWN` and anything up to the CRLF.
+fn parse_v1(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, InvalidHeader> {
+    let Some(end) = buf.windows(2).take(V1_MAX_LEN - 1).position(|w| w == b"\r\n") else {
+        return if buf.len() >= V1_MAX_LEN { Err(InvalidHeader("line too long")) } else { Ok(None) };
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| InvalidHeader("not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let header = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => ProxyHeader::Local,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |text: &str| -> Result<IpAddr, InvalidHeader> {
+                let ip: IpAddr = text.parse().map_err(|_| InvalidHeader("bad address"))?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return Err(InvalidHeader("address does not match family"));
+                }
+                Ok(ip)
+            };
+            let port = |text: &str| text.parse::<u16>().map_err(|_| InvalidHeader("bad port"));
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }
+        }
+        _ => return Err(InvalidHeader("malformed line")),
+    };
+    Ok(Some((header, end + 2)))
+}
+
+/// The binary format: signature, version and command, address family and
+/// protocol, length, then the addresses and any TLVs, which are skipped.
+fn parse_v2(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, InvalidHeader> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(InvalidHeader("unsupported version"));
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[16..len];
+
+    let header = match (version_command & 0x0f, buf[13] >> 4) {
+        // LOCAL
+        (0, _) => ProxyHeader::Local,
+        // PROXY over IPv4 or IPv6; any other family leaves nothing to use.
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = |at: usize| {
+                let octets: [u8; 4] = addresses[at..at + 4].try_into().expect("4 bytes");
+                IpAddr::V4(Ipv4Addr::from(octets))
+            };
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(0), u16::from_be_bytes([addresses[8], addresses[9]])),
+                destination: SocketAddr::new(ip(4), u16::from_be_bytes([addresses[10], addresses[11]])),
+            }
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = addresses[at..at + 16].try_into().expect("16 bytes");
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(0), u16::from_be_bytes([addresses[32], addresses[33]])),
+                destination: SocketAddr::new(ip(16), u16::from_be_bytes([addresses[34], addresses[35]])),
+            }
+        }
+        (1, 1 | 2) => return Err(InvalidHeader("addresses cut short")),
+        (1, _) => ProxyHeader::Local,
+        _ => return Err(InvalidHeader("unsupported command")),
+    };
+    Ok(Some((header, len)))
+}
@@ -0,0 +1,356 @@
+//! A SOCKS5 server (RFC 1928): clients name a host and port, and get a TCP
+//! connection to it relayed through this one, optionally after logging in
+//! with a username and password (RFC 1929).
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::HyperportError;
+use crate::net::tunnel::relay;
+use crate::net::{CustomTcpListener, ListenerConfig, RawTcpStream};
+use crate::server::stats;
+use crate::{error, warn};
+
+const VERSION: u8 = 5;
+/// The version of the username/password subnegotiation.
+const AUTH_VERSION: u8 = 1;
+
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes.
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NETWORK_UNREACHABLE: u8 = 0x03;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const TTL_EXPIRED: u8 = 0x06;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Listens on one address and serves SOCKS5 clients, with a thread per
+/// connection: once the handshake has named a destination and it has been
+/// reached, bytes are relayed both ways until both sides have closed or
+/// the connection sits idle too long.
+///
+/// Only the CONNECT command is supported, to IPv4 and IPv6 addresses and
+/// host names. Without users, no login is asked for; with them, clients
+/// must log in as one.
+///
+/// ```ignore
+/// let socks = Socks5::new("127.0.0.1:1080").user("alice", "secret").spawn()?;
+/// println!("{} connections so far", socks.connections());
+/// ```
+pub struct Socks5 {
+    listen: String,
+    /// Username and password pairs; empty when no login is needed.
+    users: Vec<(String, String)>,
+    handshake_timeout: Duration,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl Socks5 {
+    /// A server listening on `listen`, a literal socket address.
+    pub fn new(listen: &str) -> Socks5 {
+        Socks5 {
+            listen: listen.to_string(),
+            users: Vec::new(),
+            handshake_timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+
+    /// Accepts a login as `username` with `password`. Once any user is
+    /// added, clients that do not log in are turned away.
+    pub fn user(mut self, username: &str, password: &str) -> Self {
+        self.users.push((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// How long a client has to get through the handshake and name its
+    /// destination. Defaults to 10 seconds.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// How long connecting to the destination may take. Defaults to 10
+    /// seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Closes connections that carry nothing either way for `timeout`.
+    /// Defaults to 5 minutes.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Binds the listening socket and starts accepting on a background
+    /// thread.
+    pub fn spawn(self) -> Result<Socks5Handle, HyperportError> {
+        // SO_REUSEPORT lets a process taking over in a binary upgrade bind the
+        // same address while this one still runs.
+        let config = ListenerConfig {
+            reuse_port: true,
+            ..ListenerConfig::default()
+        };
+        let listener = CustomTcpListener::bind(&self.listen, &config)?;
+        let shared = Arc::new(Shared {
+            local_addr: listener.local_addr()?,
+            listener_fd: listener.as_raw_fd(),
+            stopped: AtomicBool::new(false),
+            connections: AtomicU64::new(0),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("socks".to_string())
+            .spawn(move || accept_loop(listener, self, thread_shared))?;
+        Ok(Socks5Handle { shared })
+    }
+
+    fn authorizes(&self, username: &[u8], password: &[u8]) -> bool {
+        self.users.iter().any(|(name, pass)| name.as_bytes() == username && pass.as_bytes() == password)
+    }
+}
+
+struct Shared {
+    local_addr: SocketAddr,
+    /// The listening socket, shut down to stop the accept loop.
+    listener_fd: RawFd,
+    stopped: AtomicBool,
+    connections: AtomicU64,
+}
+
+/// A running [`Socks5`] server: its address and a count of connections,
+/// and a way to stop it.
+#[derive(Clone)]
+pub struct Socks5Handle {
+    shared: Arc<Shared>,
+}
+
+impl Socks5Handle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    /// Connections accepted since the server started.
+    pub fn connections(&self) -> u64 {
+        self.shared.connections.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting connections. Those already open carry on until they
+    /// close.
+    pub fn stop(&self) {
+        if !self.shared.stopped.swap(true, Ordering::AcqRel) {
+            // Wakes the accept loop, which then sees the flag.
+            unsafe { libc::shutdown(self.shared.listener_fd, libc::SHUT_RDWR) };
+        }
+    }
+}
+
+fn accept_loop(listener: CustomTcpListener, server: Socks5, shared: Arc<Shared>) {
+    let server = Arc::new(server);
+    while !shared.stopped.load(Ordering::Acquire) {
+        let client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(HyperportError::Io(e)) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                if !shared.stopped.load(Ordering::Acquire) {
+                    stats::ACCEPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    error!("Error accepting SOCKS connection: {}", e);
+                    // Backs off from descriptor exhaustion and the like.
+                    thread::sleep(Duration::from_millis(100));
+                }
+                continue;
+            }
+        };
+        shared.connections.fetch_add(1, Ordering::Relaxed);
+        let server = Arc::clone(&server);
+        let spawned = thread::Builder::new().name("socks-connection".to_string()).spawn(move || {
+            if let Err(e) = serve(client, &server) {
+                if !matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::InvalidData) {
+                    warn!("Error serving SOCKS client: {}", e);
+                }
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Error starting SOCKS thread: {}", e);
+        }
+    }
+}
+
+/// Runs the handshake with one client, then relays to the destination it
+/// asked for.
+fn serve(mut client: RawTcpStream, server: &Socks5) -> io::Result<()> {
+    client.set_read_timeout(Some(server.handshake_timeout))?;
+    client.set_write_timeout(Some(server.handshake_timeout))?;
+
+    // Greeting: the version and the authentication methods offered.
+    let [version, count] = read_array(&mut client)?;
+    if version != VERSION {
+        return Err(invalid("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; count as usize];
+    read_exact(&mut client, &mut methods)?;
+    let method = if server.users.is_empty() { NO_AUTH } else { USERNAME_PASSWORD };
+    if !methods.contains(&method) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHODS])?;
+        return Ok(());
+    }
+    client.write_all(&[VERSION, method])?;
+    if method == USERNAME_PASSWORD && !log_in(&mut client, server)? {
+        return Ok(());
+    }
+
+    // Request: the command, a reserved byte and the destination.
+    let [version, command, _, address_type] = read_array(&mut client)?;
+    if version != VERSION {
+        return Err(invalid("request with a version other than 5"));
+    }
+    let destination = match address_type {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = read_array(&mut client)?;
+            Destination::Addr(Ipv4Addr::from(octets).into())
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = read_array(&mut client)?;
+            Destination::Addr(Ipv6Addr::from(octets).into())
+        }
+        ATYP_DOMAIN => {
+            let [len] = read_array(&mut client)?;
+            let mut name = vec![0u8; len as usize];
+            read_exact(&mut client, &mut name)?;
+            match String::from_utf8(name) {
+                Ok(name) => Destination::Name(name),
+                Err(_) => return reply(&mut client, HOST_UNREACHABLE, None),
+            }
+        }
+        _ => return reply(&mut client, ADDRESS_TYPE_NOT_SUPPORTED, None),
+    };
+    let port = u16::from_be_bytes(read_array(&mut client)?);
+    if command != CMD_CONNECT {
+        return reply(&mut client, COMMAND_NOT_SUPPORTED, None);
+    }
+
+    let upstream = match destination.connect(port, server.connect_timeout) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let code = match e.kind() {
+                ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => TTL_EXPIRED,
+                ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+                ErrorKind::HostUnreachable | ErrorKind::NotFound => HOST_UNREACHABLE,
+                _ => GENERAL_FAILURE,
+            };
+            reply(&mut client, code, None)?;
+            let _ = client.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+    };
+    upstream.set_nodelay(true)?;
+    upstream.set_write_timeout(Some(server.idle_timeout))?;
+    reply(&mut client, SUCCEEDED, upstream.local_addr().ok())?;
+    client.set_read_timeout(None)?;
+    relay(client, upstream, server.idle_timeout, |_, _| {})
+}
+
+/// The username/password subnegotiation. Returns whether the client logged
+/// in as one of the server's users.
+fn log_in(client: &mut RawTcpStream, server: &Socks5) -> io::Result<bool> {
+    let [version, len] = read_array(client)?;
+    if version != AUTH_VERSION {
+        return Err(invalid("unknown username/password version"));
+    }
+    let mut username = vec![0u8; len as usize];
+    read_exact(client, &mut username)?;
+    let [len] = read_array(client)?;
+    let mut password = vec![0u8; len as usize];
+    read_exact(client, &mut password)?;
+
+    let authorized = server.authorizes(&username, &password);
+    client.write_all(&[AUTH_VERSION, if authorized { 0x00 } else { 0x01 }])?;
+    Ok(authorized)
+}
+
+/// Where a client asked to be connected.
+enum Destination {
+    Addr(IpAddr),
+    Name(String),
+}
+
+impl Destination {
+    /// A connection to the address, or the first of the name's addresses
+    /// that answers.
+    fn connect(&self, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = match self {
+            Destination::Addr(ip) => vec![SocketAddr::new(*ip, port)],
+            Destination::Name(name) => (name.as_str(), port).to_socket_addrs()?.collect(),
+        };
+        let mut last = io::Error::new(ErrorKind::NotFound, "host has no addresses");
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+/// Sends a reply with `code` and the address the server connected from,
+/// or all zeros when there is none.
+fn reply(client: &mut RawTcpStream, code: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut message = vec![VERSION, code, 0];
+    match bound.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)) {
+        SocketAddr::V4(addr) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    message.extend_from_slice(&bound.map_or(0, |addr| addr.port()).to_be_bytes());
+    client.write_all(&message)
+}
+
+fn read_array<const N: usize>(client: &mut RawTcpStream) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    read_exact(client, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_exact(client: &mut RawTcpStream, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match client.read(&mut buf[filled..]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,89 @@
+//! systemd socket activation, as described in sd_listen_fds(3).
+//!
+//! A socket unit binds the listening sockets itself, possibly on privileged
+//! ports, and starts the service with them open from descriptor 3 onwards.
+//! `LISTEN_PID` names the process they are meant for and `LISTEN_FDS` how
+//! many there are.
+
+use std::env;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::net::CustomTcpListener;
+
+/// First descriptor passed by the service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes ownership of the listening sockets passed by systemd.
+///
+/// Returns an empty list when the process was not socket-activated, or the
+/// sockets were meant for another process. The variables are removed from
+/// the environment so that child processes do not claim the sockets too.
+/// Call this once, early, before spawning threads.
+pub fn listen_fds() -> io::Result<Vec<CustomTcpListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, format!("invalid LISTEN_PID `{}`", pid)))?;
+    if pid != std::process::id() {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = count
+        .parse()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, format!("invalid LISTEN_FDS `{}`", count)))?;
+
+    let listeners: Vec<CustomTcpListener> = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(CustomTcpListener::from_raw_fd)
+        .collect();
+    for listener in &listeners {
+        check_listener(listener)?;
+    }
+    Ok(listeners)
+}
+
+/// Marks an inherited descriptor close-on-exec and makes sure it is a
+/// listening stream socket: UDP or FIFO units cannot be served.
+fn check_listener(listener: &CustomTcpListener) -> io::Result<()> {
+    let fd = listener.fd;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let socket_type = get_int_option(fd, libc::SO_TYPE)?;
+    let listening = get_int_option(fd, libc::SO_ACCEPTCONN)?;
+    if socket_type != libc::SOCK_STREAM || listening == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("descriptor {} passed by systemd is not a listening stream socket", fd),
+        ));
+    }
+    Ok(())
+}
+
+fn get_int_option(fd: RawFd, name: i32) -> io::Result<i32> {
+    let mut value: i32 = 0;
+    let mut len = mem::size_of::<i32>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut i32 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(value)
+    }
+}
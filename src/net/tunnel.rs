@@ -0,0 +1,110 @@
+//! Relaying bytes both ways between a client and an upstream: for CONNECT
+//! tunnels and upgraded connections once HTTP is done with them, and for
+//! plain port forwarding.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::net::RawTcpStream;
+
+/// One end of a tunnel.
+trait End: AsRawFd {
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn send(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn close_write(&self);
+}
+
+impl End for RawTcpStream {
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn close_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+impl End for TcpStream {
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn close_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+/// Which way bytes went through a relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    ToUpstream,
+    ToClient,
+}
+
+/// Copies bytes both ways between `client` and `upstream` on this thread,
+/// passing on each side's end of stream to the other, until both have
+/// ended, either fails, or neither sends anything for `idle_timeout`.
+/// `relayed` is told of each batch of bytes passed on.
+pub(crate) fn relay(
+    mut client: RawTcpStream,
+    mut upstream: TcpStream,
+    idle_timeout: Duration,
+    mut relayed: impl FnMut(Direction, usize),
+) -> io::Result<()> {
+    client.set_write_timeout(Some(idle_timeout))?;
+    let mut ends: [&mut dyn End; 2] = [&mut client, &mut upstream];
+    let mut open = [true, true];
+    let mut buf = vec![0u8; 16 * 1024];
+    let timeout = idle_timeout.as_millis().min(i32::MAX as u128) as i32;
+
+    while open[0] || open[1] {
+        // poll(2) skips entries with a negative descriptor.
+        let mut polled = [0, 1].map(|i| libc::pollfd {
+            fd: if open[i] { ends[i].as_raw_fd() } else { -1 },
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let ready = unsafe { libc::poll(polled.as_mut_ptr(), 2, timeout) };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if ready == 0 {
+            return Ok(());
+        }
+        for i in 0..2 {
+            if polled[i].revents == 0 {
+                continue;
+            }
+            let [client, upstream] = &mut ends;
+            let (from, to) = if i == 0 { (client, upstream) } else { (upstream, client) };
+            match from.receive(&mut buf) {
+                Ok(0) => {
+                    open[i] = false;
+                    to.close_write();
+                }
+                Ok(n) => {
+                    to.send(&buf[..n])?;
+                    relayed(if i == 0 { Direction::ToUpstream } else { Direction::ToClient }, n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                // A reset ends the tunnel for both sides.
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+    Ok(())
+}
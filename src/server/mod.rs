@@ -0,0 +1,670 @@
+//! The multi-threaded server: per-worker epoll event loops that hand
+//! complete request heads to a shared thread pool.
+
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod pool;
+mod privileges;
+pub mod protocol;
+mod reactor;
+pub mod signal;
+pub mod stats;
+pub(crate) mod trace;
+pub mod upgrade;
+pub(crate) mod watchdog;
+
+use crate::error;
+use crate::error::HyperportError;
+use crate::http::{HeadLimits, Request, ResponseWriter, Router};
+use crate::net::ip_filter::IpFilter;
+use crate::net::{CustomTcpListener, ListenerConfig, RawTcpStream};
+use pool::ThreadPool;
+use protocol::{Http2, Protocols};
+use reactor::{EventFd, Reactor};
+use trace::Tracer;
+use watchdog::{Armed, Watchdog};
+
+/// Tunables for a running server.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// Number of event loops. Each owns its own SO_REUSEPORT socket and
+    /// epoll instance so the kernel balances new connections across them.
+    pub workers: usize,
+    /// Threads available to run requests once their head has arrived.
+    /// Each open HTTP/2 connection also holds one to read its frames.
+    pub pool_size: usize,
+    /// Requests allowed to wait for a pool thread before new ones get 503.
+    pub queue_depth: usize,
+    /// Most client connections open at once. Connections beyond it are
+    /// answered with 503 and closed straight away. Keep it comfortably below
+    /// the process's file descriptor limit. `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// How long a blocking body read may stall. `None` disables the limit.
+    pub read_timeout: Option<Duration>,
+    /// How long a client has from the first byte of a request until the end
+    /// of its head, and a new connection until that first byte. Clients
+    /// trickling a head in slowly are answered with 408 once it passes, so
+    /// they cannot hold a connection open indefinitely. `None` falls back to
+    /// the read timeout.
+    pub header_timeout: Option<Duration>,
+    /// How long a kept-alive connection may sit idle waiting for its next
+    /// request before it is closed, freeing its descriptor. `None` falls
+    /// back to the read timeout.
+    pub keep_alive_timeout: Option<Duration>,
+    /// How long a blocking write to a slow client may stall.
+    pub write_timeout: Option<Duration>,
+    /// Overall time allowed from the first byte of a request until its
+    /// response is complete. A request still being received is answered
+    /// with 408; one whose handler is still running has its connection shut
+    /// down, failing whatever I/O the handler is blocked on. WebSocket
+    /// upgrades and SSE streams are exempt once they start.
+    pub request_timeout: Option<Duration>,
+    pub listener: ListenerConfig,
+    /// Size limits on request heads.
+    pub head_limits: HeadLimits,
+    /// Largest request body accepted, unless the route sets its own with
+    /// [`Router::max_body_size`]. A larger Content-Length is answered with
+    /// 413 straight away; a chunked body is cut off once it grows past it.
+    pub max_body_size: usize,
+    /// Bodies larger than this are written to an unnamed file in
+    /// `spool_dir` instead of being held in memory, and reach handlers as
+    /// [`Request::body_file`]. `None` keeps every body in memory.
+    pub spool_threshold: Option<usize>,
+    /// Where spooled bodies are written.
+    pub spool_dir: PathBuf,
+    /// User to switch to once the listening sockets are bound, so that
+    /// requests are not handled as root.
+    pub user: Option<String>,
+    /// Group to switch to; defaults to the user's primary group.
+    pub group: Option<String>,
+    /// Listen addresses, bound or inherited, whose connections open with a
+    /// PROXY protocol header (version 1 or 2) from a load balancer in front.
+    /// The client address it gives replaces the load balancer's as the
+    /// connection's peer. Connections without a valid header are closed.
+    pub proxy_protocol: Vec<String>,
+    /// Which peers may connect to listeners without a filter of their own
+    /// in `listener_ip_filters`. Connections from others are closed as
+    /// soon as they are accepted, unanswered. `None` lets everyone in.
+    pub ip_filter: Option<IpFilter>,
+    /// Filters for particular listen addresses, bound or inherited, in
+    /// place of `ip_filter`.
+    pub listener_ip_filters: Vec<(String, IpFilter)>,
+    /// Serves HTTP/2 to clients that open with its prior-knowledge
+    /// preface, each connection's streams concurrently. The request timeout
+    /// does not apply to its requests. On by default.
+    pub http2: bool,
+    /// The `Server` header sent with every response that does not set its
+    /// own; `None` leaves it out. Defaults to [`DEFAULT_SERVER_HEADER`].
+    pub server_header: Option<String>,
+    /// An OTLP/HTTP traces endpoint, e.g. `http://127.0.0.1:4318/v1/traces`,
+    /// to export a span for every connection and request to. Requests
+    /// carrying a W3C `traceparent` header join the caller's trace, and
+    /// proxied requests pass it on. Off when `None`, the default.
+    pub otlp_endpoint: Option<String>,
+    /// The `service.name` exported spans are attributed to.
+    pub service_name: String,
+    /// Requests taking longer than this, from their head being read to
+    /// their response being written, are logged as warnings with how long
+    /// reading the body, handling and writing the response took. Off when
+    /// `None`, the default.
+    pub slow_request_threshold: Option<Duration>,
+}
+
+/// The `Server` header sent unless configured otherwise.
+pub const DEFAULT_SERVER_HEADER: &str = concat!("hyperport/", env!("CARGO_PKG_VERSION"));
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        ServerOptions {
+            workers,
+            pool_size: workers * 16,
+            queue_depth: 1024,
+            max_connections: None,
+            read_timeout: None,
+            header_timeout: None,
+            keep_alive_timeout: None,
+            write_timeout: None,
+            request_timeout: None,
+            listener: ListenerConfig::default(),
+            head_limits: HeadLimits::default(),
+            max_body_size: 64 * 1024 * 1024,
+            spool_threshold: None,
+            spool_dir: env::temp_dir(),
+            user: None,
+            group: None,
+            proxy_protocol: Vec::new(),
+            ip_filter: None,
+            listener_ip_filters: Vec::new(),
+            http2: true,
+            server_header: Some(DEFAULT_SERVER_HEADER.to_string()),
+            otlp_endpoint: None,
+            service_name: "hyperport".to_string(),
+            slow_request_threshold: None,
+        }
+    }
+}
+
+/// State shared by every worker and pool thread of one server.
+pub struct ServerContext {
+    router: RwLock<Arc<Router>>,
+    pub protocols: Protocols,
+    /// Runs requests once their head is in, and HTTP/2 streams once
+    /// their request is.
+    pub(crate) pool: ThreadPool,
+    pub read_timeout: Option<Duration>,
+    /// This and the keep-alive timeout are already resolved to the read
+    /// timeout when not set separately.
+    pub header_timeout: Option<Duration>,
+    pub keep_alive_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub head_limits: HeadLimits,
+    pub max_body_size: usize,
+    pub spool_threshold: Option<usize>,
+    pub spool_dir: PathBuf,
+    pub server_header: Option<String>,
+    pub slow_request_threshold: Option<Duration>,
+    /// Set when spans are exported.
+    pub(crate) tracer: Option<Tracer>,
+    /// Running only when there is a request timeout.
+    watchdog: Option<Watchdog>,
+    max_connections: Option<usize>,
+    /// Connections currently open on this server.
+    open_connections: Arc<AtomicUsize>,
+    shutting_down: AtomicBool,
+    /// One per event loop, to wake them when shutdown begins.
+    wakers: Mutex<Vec<Arc<EventFd>>>,
+    /// A duplicate of each listening socket, for handing to a successor
+    /// process on upgrade. Closed at shutdown.
+    listeners: Mutex<Vec<CustomTcpListener>>,
+}
+
+impl ServerContext {
+    /// The router currently serving requests. A request holds on to the
+    /// router it started with even if it is replaced meanwhile.
+    pub fn router(&self) -> Arc<Router> {
+        Arc::clone(&self.router.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn set_router(&self, router: Router) {
+        *self.router.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(router);
+    }
+
+    /// Shuts `stream` down at `deadline` unless the returned guard is
+    /// dropped first. `None` when there is no request timeout, or the
+    /// watchdog could not take the socket.
+    pub(crate) fn arm_deadline(&self, stream: &RawTcpStream, deadline: Instant) -> Option<Armed> {
+        self.watchdog.as_ref()?.arm(stream, deadline).ok()
+    }
+
+    /// Whether the connection limit has been reached.
+    fn at_connection_limit(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.open_connections.load(Ordering::Acquire) >= max)
+    }
+
+    /// Whether the event loops should stop accepting and wind down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Acquire)
+    }
+
+    fn add_waker(&self, waker: Arc<EventFd>) {
+        self.wakers.lock().unwrap_or_else(|e| e.into_inner()).push(waker);
+    }
+
+    fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Release);
+        self.listeners.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        for waker in self.wakers.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            waker.notify();
+        }
+    }
+}
+
+/// A bound server, ready to [`run`](Server::run).
+pub struct Server {
+    /// Each worker's event loop, over its listeners, one per listen address.
+    reactors: Vec<Reactor>,
+    context: Arc<ServerContext>,
+}
+
+impl Server {
+    /// Starts configuring a server. See [`ServerBuilder`].
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Binds one listening socket per worker. Binding everything up front
+    /// means a bad address fails here rather than inside a worker thread.
+    pub fn bind(addr: &str, options: ServerOptions, router: Router) -> Result<Self, HyperportError> {
+        Server::bind_all(&[addr], options, router)
+    }
+
+    /// Like `bind`, but every worker listens on each of `addrs`.
+    pub fn bind_all(addrs: &[&str], options: ServerOptions, router: Router) -> Result<Self, HyperportError> {
+        Server::with_listeners(addrs, Vec::new(), options, router)
+    }
+
+    /// Binds `addrs` for every worker, and has every worker also accept
+    /// from each of the already listening sockets in `inherited`.
+    fn with_listeners(
+        addrs: &[&str],
+        inherited: Vec<CustomTcpListener>,
+        options: ServerOptions,
+        router: Router,
+    ) -> Result<Self, HyperportError> {
+        let workers = options.workers.max(1);
+        let listener_config = ListenerConfig {
+            reuse_port: options.listener.reuse_port || workers > 1,
+            ..options.listener
+        };
+
+        let proxy_protocol = options
+            .proxy_protocol
+            .iter()
+            .map(|addr| addr.parse::<SocketAddr>().map_err(|_| HyperportError::InvalidAddress(addr.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let default_filter = options.ip_filter.map(Arc::new);
+        let listener_filters = options
+            .listener_ip_filters
+            .into_iter()
+            .map(|(addr, filter)| match addr.parse::<SocketAddr>() {
+                Ok(addr) => Ok((addr, Arc::new(filter))),
+                Err(_) => Err(HyperportError::InvalidAddress(addr)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let ip_filter = |addr: Option<SocketAddr>| {
+            let own = listener_filters.iter().find(|(listen, _)| Some(*listen) == addr);
+            own.map(|(_, filter)| filter).or(default_filter.as_ref()).cloned()
+        };
+
+        let mut listeners = (0..workers)
+            .map(|_| {
+                addrs
+                    .iter()
+                    .map(|addr| {
+                        let mut listener = CustomTcpListener::bind(addr, &listener_config)?;
+                        let addr = addr.parse::<SocketAddr>().ok();
+                        listener.set_proxy_protocol(addr.is_some_and(|addr| proxy_protocol.contains(&addr)));
+                        listener.set_ip_filter(ip_filter(addr));
+                        Ok(listener)
+                    })
+                    .collect::<Result<Vec<_>, HyperportError>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // An inherited socket cannot be bound again per worker, so the
+        // workers share it through duplicated descriptors instead.
+        for mut listener in inherited {
+            let addr = listener.local_addr()?;
+            if proxy_protocol.contains(&addr) {
+                listener.set_proxy_protocol(true);
+            }
+            listener.set_ip_filter(ip_filter(Some(addr)));
+            for worker in &mut listeners[1..] {
+                worker.push(listener.try_clone()?);
+            }
+            listeners[0].push(listener);
+        }
+        let spares = listeners[0]
+            .iter()
+            .map(CustomTcpListener::try_clone)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        privileges::drop_privileges(options.user.as_deref(), options.group.as_deref())?;
+
+        let mut protocols = Protocols::new();
+        if options.http2 {
+            protocols.register(Http2);
+        }
+
+        let context = Arc::new(ServerContext {
+            router: RwLock::new(Arc::new(router)),
+            protocols,
+            pool: ThreadPool::new(options.pool_size, options.queue_depth),
+            read_timeout: options.read_timeout,
+            header_timeout: options.header_timeout.or(options.read_timeout),
+            keep_alive_timeout: options.keep_alive_timeout.or(options.read_timeout),
+            write_timeout: options.write_timeout,
+            request_timeout: options.request_timeout,
+            head_limits: options.head_limits,
+            max_body_size: options.max_body_size,
+            spool_threshold: options.spool_threshold,
+            spool_dir: options.spool_dir,
+            server_header: options.server_header,
+            slow_request_threshold: options.slow_request_threshold,
+            tracer: options.otlp_endpoint.map(|endpoint| Tracer::new(endpoint, options.service_name)),
+            watchdog: options.request_timeout.map(|_| Watchdog::new()),
+            max_connections: options.max_connections,
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            shutting_down: AtomicBool::new(false),
+            wakers: Mutex::new(Vec::new()),
+            listeners: Mutex::new(spares),
+        });
+
+        // Like binding, setting up each worker's epoll instance fails here
+        // rather than leaving one worker dead while the others serve.
+        let reactors = listeners
+            .into_iter()
+            .map(|listeners| Reactor::new(listeners, Arc::clone(&context)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Server { reactors, context })
+    }
+
+    /// Addresses the server is listening on, one per listener.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.reactors
+            .first()
+            .into_iter()
+            .flat_map(Reactor::listeners)
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// A handle for changing the running server from another thread.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            context: Arc::clone(&self.context),
+        }
+    }
+
+    /// Runs every worker's event loop until they all exit, which happens
+    /// once a [`ServerHandle::shutdown`] has finished draining. A worker
+    /// that cannot start, or whose loop fails, shuts the whole server down,
+    /// since the kernel would go on queueing connections on its sockets.
+    pub fn run(self) {
+        let mut workers = Vec::new();
+        for (id, mut reactor) in self.reactors.into_iter().enumerate() {
+            let context = Arc::clone(&self.context);
+            let spawned = thread::Builder::new().name(format!("worker-{}", id)).spawn(move || {
+                if let Err(e) = reactor.run() {
+                    error!("Event loop failed: {}", e);
+                    context.shutdown();
+                }
+            });
+            match spawned {
+                Ok(worker) => workers.push(worker),
+                Err(e) => {
+                    error!("Could not start worker {}: {}", id, e);
+                    self.context.shutdown();
+                    break;
+                }
+            }
+        }
+
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("A worker thread panicked");
+                self.context.shutdown();
+            }
+        }
+    }
+}
+
+/// Changes a running [`Server`], e.g. from a signal-handling thread.
+#[derive(Clone)]
+pub struct ServerHandle {
+    context: Arc<ServerContext>,
+}
+
+impl ServerHandle {
+    /// Serves every request from now on with `router`. Requests already
+    /// being handled finish with the old one, and no connection is closed.
+    pub fn set_router(&self, router: Router) {
+        self.context.set_router(router);
+    }
+
+    /// Stops accepting connections and closes idle ones. Requests in
+    /// progress are finished, after which their connections are closed and
+    /// [`Server::run`] returns. Long-lived ones such as WebSockets keep it
+    /// waiting, so callers wanting a bound should exit after a grace period.
+    pub fn shutdown(&self) {
+        self.context.shutdown();
+    }
+}
+
+/// Configures and starts a [`Server`].
+///
+/// ```no_run
+/// use std::time::{Duration, Instant};
+/// use hyperport::http::StatusCode;
+/// use hyperport::server::Server;
+///
+/// Server::builder()
+///     .bind("127.0.0.1:3000")
+///     .workers(2)
+///     .read_timeout(Duration::from_secs(30))
+///     .handler(|_, writer| writer.send(StatusCode::Ok, &[], b"hello"))
+///     .run()
+///     .unwrap();
+/// ```
+pub struct ServerBuilder {
+    addrs: Vec<String>,
+    inherited: Vec<CustomTcpListener>,
+    options: ServerOptions,
+    router: Router,
+}
+
+impl ServerBuilder {
+    fn new() -> Self {
+        ServerBuilder {
+            addrs: Vec::new(),
+            inherited: Vec::new(),
+            options: ServerOptions::default(),
+            router: Router::new(),
+        }
+    }
+
+    /// Adds an address to listen on; call it again to listen on several.
+    /// Defaults to `[::]:8080` when neither an address nor a listener is
+    /// given.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    /// Also accepts connections from a socket that is already listening,
+    /// e.g. one from [`systemd::listen_fds`](crate::net::systemd::listen_fds).
+    pub fn listen_on(mut self, listener: CustomTcpListener) -> Self {
+        self.inherited.push(listener);
+        self
+    }
+
+    /// Number of event loop threads. See [`ServerOptions::workers`].
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.options.workers = workers;
+        self
+    }
+
+    /// Number of threads running request handlers.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.options.pool_size = pool_size;
+        self
+    }
+
+    /// Requests allowed to queue for a pool thread before new ones get 503.
+    pub fn queue_depth(mut self, queue_depth: usize) -> Self {
+        self.options.queue_depth = queue_depth;
+        self
+    }
+
+    /// See [`ServerOptions::max_connections`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.options.max_connections = Some(max);
+        self
+    }
+
+    /// See [`ServerOptions::read_timeout`].
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.read_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerOptions::header_timeout`].
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.options.header_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerOptions::keep_alive_timeout`].
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.options.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerOptions::write_timeout`].
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.options.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Runs as `user` once the listening sockets are bound. See
+    /// [`ServerOptions::user`].
+    pub fn user(mut self, user: &str) -> Self {
+        self.options.user = Some(user.to_string());
+        self
+    }
+
+    /// Runs as `group` once the listening sockets are bound.
+    pub fn group(mut self, group: &str) -> Self {
+        self.options.group = Some(group.to_string());
+        self
+    }
+
+    /// Expects a PROXY protocol header on connections to `addr`, one of the
+    /// addresses bound or listened on. See [`ServerOptions::proxy_protocol`].
+    pub fn proxy_protocol(mut self, addr: &str) -> Self {
+        self.options.proxy_protocol.push(addr.to_string());
+        self
+    }
+
+    /// Closes connections from peers `filter` refuses, on every listener
+    /// without a filter of its own. See [`ServerOptions::ip_filter`].
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.options.ip_filter = Some(filter);
+        self
+    }
+
+    /// Closes connections to `addr`, one of the addresses bound or listened
+    /// on, from peers `filter` refuses. See
+    /// [`ServerOptions::listener_ip_filters`].
+    pub fn listener_ip_filter(mut self, addr: &str, filter: IpFilter) -> Self {
+        self.options.listener_ip_filters.push((addr.to_string(), filter));
+        self
+    }
+
+    /// Whether to serve HTTP/2. See [`ServerOptions::http2`].
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.options.http2 = enabled;
+        self
+    }
+
+    /// Replaces the `Server` header. See [`ServerOptions::server_header`].
+    pub fn server_header(mut self, value: &str) -> Self {
+        self.options.server_header = Some(value.to_string());
+        self
+    }
+
+    /// Leaves the `Server` header out of responses.
+    pub fn hide_server_header(mut self) -> Self {
+        self.options.server_header = None;
+        self
+    }
+
+    /// Exports spans to an OTLP/HTTP collector. See
+    /// [`ServerOptions::otlp_endpoint`].
+    pub fn otlp_endpoint(mut self, url: &str) -> Self {
+        self.options.otlp_endpoint = Some(url.to_string());
+        self
+    }
+
+    /// See [`ServerOptions::service_name`].
+    pub fn service_name(mut self, name: &str) -> Self {
+        self.options.service_name = name.to_string();
+        self
+    }
+
+    /// See [`ServerOptions::slow_request_threshold`].
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.options.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// See [`ServerOptions::request_timeout`].
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.options.request_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerOptions::head_limits`].
+    pub fn head_limits(mut self, limits: HeadLimits) -> Self {
+        self.options.head_limits = limits;
+        self
+    }
+
+    /// See [`ServerOptions::max_body_size`].
+    pub fn max_body_size(mut self, max: usize) -> Self {
+        self.options.max_body_size = max;
+        self
+    }
+
+    /// Spools request bodies larger than `threshold` bytes to unnamed
+    /// files in `dir`. See [`ServerOptions::spool_threshold`].
+    pub fn spool_bodies<P: Into<PathBuf>>(mut self, threshold: usize, dir: P) -> Self {
+        self.options.spool_threshold = Some(threshold);
+        self.options.spool_dir = dir.into();
+        self
+    }
+
+    /// Socket options for the listening sockets.
+    pub fn listener(mut self, config: ListenerConfig) -> Self {
+        self.options.listener = config;
+        self
+    }
+
+    /// Serves requests with `router`.
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Serves every request with a single handler.
+    pub fn handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let mut router = Router::new();
+        router.fallback(handler);
+        self.router = router;
+        self
+    }
+
+    /// Binds the listening sockets without starting the event loops.
+    pub fn build(self) -> Result<Server, HyperportError> {
+        let mut addrs: Vec<&str> = self.addrs.iter().map(String::as_str).collect();
+        if addrs.is_empty() && self.inherited.is_empty() {
+            addrs.push("[::]:8080");
+        }
+        Server::with_listeners(&addrs, self.inherited, self.options, self.router)
+    }
+
+    /// Binds and runs the server until every worker exits.
+    pub fn run(self) -> Result<(), HyperportError> {
+        self.build()?.run();
+        Ok(())
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct State {
+    queue: VecDeque<Job>,
+    /// Queue slots promised to outstanding `Slot`s but not yet filled.
+    reserved: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+    queue_depth: usize,
+}
+
+/// Fixed-size pool of worker threads fed from a bounded job queue.
+///
+/// Callers reserve a queue slot before building a job so that, when the
+/// queue is full, they still own whatever the job would have consumed and
+/// can shed load themselves (e.g. answer 503 on the connection).
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+}
+
+/// A reserved place in the pool's queue. Dropping it unused releases it.
+pub struct Slot {
+    shared: Arc<Shared>,
+    used: bool,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, queue_depth: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(queue_depth),
+                reserved: 0,
+            }),
+            available: Condvar::new(),
+            queue_depth: queue_depth.max(1),
+        });
+
+        for id in 0..size.max(1) {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("pool-{}", id))
+                .spawn(move || worker(shared))
+                .expect("failed to spawn pool thread");
+        }
+
+        ThreadPool { shared }
+    }
+
+    /// Reserves room for one job, or returns `None` if the queue is full.
+    pub fn try_reserve(&self) -> Option<Slot> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.queue.len() + state.reserved >= self.shared.queue_depth {
+            return None;
+        }
+        state.reserved += 1;
+
+        Some(Slot {
+            shared: Arc::clone(&self.shared),
+            used: false,
+        })
+    }
+}
+
+impl Slot {
+    pub fn execute<F>(mut self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.used = true;
+        let mut state = self.shared.state.lock().unwrap();
+        state.reserved -= 1;
+        state.queue.push_back(Box::new(job));
+        drop(state);
+        self.shared.available.notify_one();
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.used {
+            self.shared.state.lock().unwrap().reserved -= 1;
+        }
+    }
+}
+
+fn worker(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(job) = state.queue.pop_front() {
+                    break job;
+                }
+                state = shared.available.wait(state).unwrap();
+            }
+        };
+
+        // A panicking job must not take its worker down with it, or the
+        // pool would silently shrink.
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("Worker job panicked");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Switching to an unprivileged user once the listening sockets are bound,
+//! so that a server started as root to bind port 80 does not go on to
+//! handle requests as root.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use crate::error::HyperportError;
+
+/// Switches the whole process to `user`, and to `group` or else the user's
+/// primary group. Supplementary groups are reset to those of the user.
+/// With only `group`, just the group is changed.
+///
+/// Already running as the requested user and group is not an error, so the
+/// same settings work whether or not the server is started as root.
+pub(crate) fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), HyperportError> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    if let Some(gid) = gid {
+        if unsafe { libc::getegid() } != gid || unsafe { libc::getgid() } != gid {
+            let groups = match &user {
+                Some(user) => unsafe { libc::initgroups(user.name.as_ptr(), gid) },
+                None => unsafe { libc::setgroups(1, &gid) },
+            };
+            if groups < 0 || unsafe { libc::setgid(gid) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+    }
+
+    if let Some(user) = user {
+        if unsafe { libc::geteuid() } != user.uid || unsafe { libc::getuid() } != user.uid {
+            if unsafe { libc::setuid(user.uid) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            // setuid(2) from root drops every id at once; make sure there
+            // is really no way back.
+            if user.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(io::Error::other("root privileges could be regained after dropping them").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+struct User {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+fn lookup_user(name: &str) -> Result<User, HyperportError> {
+    let cname = CString::new(name).map_err(|_| HyperportError::Config(format!("invalid user name `{}`", name)))?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    lookup(name, "user", |buf| {
+        let err = unsafe { libc::getpwnam_r(cname.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        (err, !result.is_null())
+    })?;
+
+    Ok(User {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        name: cname,
+    })
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, HyperportError> {
+    let cname = CString::new(name).map_err(|_| HyperportError::Config(format!("invalid group name `{}`", name)))?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+
+    lookup(name, "group", |buf| {
+        let err = unsafe { libc::getgrnam_r(cname.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+        (err, !result.is_null())
+    })?;
+
+    Ok(group.gr_gid)
+}
+
+/// Runs a `get*nam_r` style lookup, which reports its error code and
+/// whether an entry was found, growing the string buffer until the entry
+/// fits.
+fn lookup(
+    name: &str,
+    kind: &str,
+    mut call: impl FnMut(&mut [libc::c_char]) -> (libc::c_int, bool),
+) -> Result<(), HyperportError> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        match call(&mut buf) {
+            (0, true) => return Ok(()),
+            (0, false) => return Err(HyperportError::Config(format!("unknown {} `{}`", kind, name))),
+            (libc::ERANGE, _) if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            (err, _) => return Err(io::Error::from_raw_os_error(err).into()),
+        }
+    }
+}
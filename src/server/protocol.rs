@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::http::connection::handle_connection;
+use crate::http::h2;
+use crate::net::RawTcpStream;
+use crate::server::ServerContext;
+
+/// An application protocol that can own a connection once it is chosen.
+///
+/// Connections are cleartext, so a protocol claims one by recognising its
+/// preface (HTTP/2 prior knowledge, say); the rest are HTTP/1.x.
+pub trait Protocol: Send + Sync {
+    /// Whether the first bytes of a cleartext connection belong to this
+    /// protocol. Only consulted for protocols other than the default.
+    fn matches_preface(&self, _buf: &[u8]) -> bool {
+        false
+    }
+
+    /// Serves the connection. `buf` holds bytes already read from it, and
+    /// `deadline` is when the request they begin must be finished by.
+    /// Returns the stream and any unconsumed bytes if the connection should
+    /// go back to the event loop to wait for more input.
+    fn serve(
+        &self,
+        stream: RawTcpStream,
+        buf: Vec<u8>,
+        ctx: &Arc<ServerContext>,
+        deadline: Option<Instant>,
+    ) -> Option<(RawTcpStream, Vec<u8>)>;
+}
+
+/// Most pipelined requests served in one dispatch before the connection
+/// goes back to the event loop, so that a client with a long pipeline
+/// cannot hold on to a pool thread.
+const MAX_PIPELINED: usize = 16;
+
+/// HTTP/1.x, the default protocol. Requests pipelined behind the first are
+/// served in turn while their heads are already buffered; otherwise the
+/// connection returns to the event loop to wait for the next one.
+pub struct Http1;
+
+impl Protocol for Http1 {
+    fn serve(
+        &self,
+        mut stream: RawTcpStream,
+        mut buf: Vec<u8>,
+        ctx: &Arc<ServerContext>,
+        deadline: Option<Instant>,
+    ) -> Option<(RawTcpStream, Vec<u8>)> {
+        let mut deadline = deadline;
+        for _ in 0..MAX_PIPELINED {
+            if !handle_connection(&mut stream, &mut buf, ctx, deadline) {
+                return None;
+            }
+            // The reactor dispatches on a complete head or a broken limit;
+            // anything short of that needs more input first.
+            if matches!(ctx.head_limits.check(&buf), Ok(None)) {
+                break;
+            }
+            deadline = ctx.request_timeout.map(|timeout| Instant::now() + timeout);
+        }
+        Some((stream, buf))
+    }
+}
+
+/// HTTP/2, which keeps the connection for as long as it is open and serves
+/// its streams concurrently. Connections reach it with the
+/// prior-knowledge preface.
+pub struct Http2;
+
+impl Protocol for Http2 {
+    /// Matches the start of the preface too, in case the rest of it has
+    /// not arrived yet.
+    fn matches_preface(&self, buf: &[u8]) -> bool {
+        buf.starts_with(&HTTP2_PREFACE[..16])
+    }
+
+    fn serve(
+        &self,
+        stream: RawTcpStream,
+        buf: Vec<u8>,
+        ctx: &Arc<ServerContext>,
+        _deadline: Option<Instant>,
+    ) -> Option<(RawTcpStream, Vec<u8>)> {
+        h2::serve_connection(stream, buf, ctx);
+        None
+    }
+}
+
+/// Registered protocols, with HTTP/1.x serving whatever none of them
+/// claims.
+pub struct Protocols {
+    protocols: Vec<Arc<dyn Protocol>>,
+    default: Arc<dyn Protocol>,
+}
+
+impl Protocols {
+    /// A registry containing only HTTP/1.x.
+    pub fn new() -> Self {
+        Protocols {
+            protocols: Vec::new(),
+            default: Arc::new(Http1),
+        }
+    }
+
+    /// Adds a protocol, consulted before those registered earlier.
+    pub fn register<P: Protocol + 'static>(&mut self, protocol: P) -> &mut Self {
+        self.protocols.insert(0, Arc::new(protocol));
+        self
+    }
+
+    /// Picks the protocol for a cleartext connection from its first bytes:
+    /// whichever one its preface announces, or HTTP/1.x otherwise. `None`
+    /// when the client speaks HTTP/2 but it is not enabled.
+    pub fn for_cleartext(&self, buf: &[u8]) -> Option<Arc<dyn Protocol>> {
+        if let Some(protocol) = self.protocols.iter().find(|p| p.matches_preface(buf)) {
+            return Some(Arc::clone(protocol));
+        }
+        if buf.starts_with(HTTP2_PREFACE) {
+            return None;
+        }
+        Some(Arc::clone(&self.default))
+    }
+}
+
+impl Default for Protocols {
+    fn default() -> Self {
+        Protocols::new()
+    }
+}
+
+/// Connection preface sent by HTTP/2 clients using prior knowledge.
+pub const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
@@ -0,0 +1,660 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::HyperportError;
+use crate::http::request::HeadLimits;
+use crate::http::status::StatusCode;
+use crate::net::proxy_protocol::{self, ProxyHeader};
+use crate::net::{CustomTcpListener, RawTcpStream};
+use crate::server::stats::{
+    self, OpenConnection, ACCEPT_ERRORS, BYTES_RECEIVED, BYTES_SENT, CONNECTIONS, DENIED_CONNECTIONS,
+    REJECTED_CONNECTIONS,
+};
+use crate::server::ServerContext;
+use crate::{debug, error};
+
+/// epoll token reserved for the eventfd used to wake the loop.
+const WAKER_TOKEN: u64 = 0;
+
+/// epoll token of the first listening socket; the others follow it.
+const FIRST_LISTENER_TOKEN: u64 = 1;
+
+/// Thin wrapper over an epoll instance.
+pub struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    pub fn new() -> Result<Self, std::io::Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32, token: u64) -> Result<(), std::io::Error> {
+        let mut event = libc::epoll_event { events, u64: token };
+        if unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn add(&self, fd: RawFd, events: u32, token: u64) -> Result<(), std::io::Error> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    pub fn modify(&self, fd: RawFd, events: u32, token: u64) -> Result<(), std::io::Error> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    pub fn delete(&self, fd: RawFd) -> Result<(), std::io::Error> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Waits for events, retrying transparently when interrupted by a signal.
+    pub fn wait(&self, events: &mut [libc::epoll_event], timeout_ms: i32) -> Result<usize, std::io::Error> {
+        loop {
+            let n = unsafe {
+                libc::epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, timeout_ms)
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Counter-style eventfd used to interrupt epoll_wait from another thread.
+pub(crate) struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    fn new() -> Result<Self, std::io::Error> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(EventFd { fd })
+    }
+
+    pub(crate) fn notify(&self) {
+        let one = 1u64;
+        unsafe {
+            libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    fn drain(&self) {
+        let mut count = 0u64;
+        unsafe {
+            libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8);
+        }
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Lets pool threads give a kept-alive connection back to the event loop
+/// it came from, together with any bytes already read past the last request.
+#[derive(Clone)]
+pub struct ReactorHandle {
+    sender: Sender<(RawTcpStream, Vec<u8>, OpenConnection)>,
+    waker: Arc<EventFd>,
+    /// Connections handed to the pool and not yet finished with.
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ReactorHandle {
+    fn resume(&self, stream: RawTcpStream, buf: Vec<u8>, open: OpenConnection) {
+        if self.sender.send((stream, buf, open)).is_ok() {
+            self.waker.notify();
+        }
+    }
+}
+
+/// Counts a connection handed to the pool until dropped, even by a
+/// panicking handler, so a draining loop can tell when nothing is left.
+struct InFlight(ReactorHandle);
+
+impl InFlight {
+    fn new(handle: ReactorHandle) -> Self {
+        handle.in_flight.fetch_add(1, Ordering::AcqRel);
+        InFlight(handle)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.0.waker.notify();
+    }
+}
+
+/// Where a connection is in its request/response cycle.
+enum State {
+    /// Accumulating bytes until the blank line that ends the request head.
+    Reading { buf: Vec<u8> },
+    /// Draining a serialized response; `written` bytes are already sent.
+    Writing { response: Vec<u8>, written: usize },
+}
+
+struct Connection {
+    stream: RawTcpStream,
+    state: State,
+    /// When the connection is closed if it still has no complete request,
+    /// mirrored in the reactor's timer set.
+    deadline: Option<Instant>,
+    /// When the first byte of the pending request arrived, for the
+    /// request timeout.
+    started: Option<Instant>,
+    open: OpenConnection,
+    /// Set on connections from a PROXY protocol listener until their header
+    /// has been read.
+    awaiting_proxy_header: bool,
+}
+
+/// What the event loop should do with a connection after servicing it.
+enum Next {
+    WaitReadable,
+    WaitWritable,
+    /// A full request head is buffered; run it on the worker pool.
+    Dispatch,
+    Close,
+}
+
+/// Single-threaded event loop that owns its listeners (one per listen
+/// address) and every connection accepted from them. All sockets are non-blocking; a connection is a small
+/// state machine advanced whenever epoll reports it ready.
+///
+/// The loop itself only does I/O. Once a request head has arrived the
+/// connection is handed to the shared worker pool, which runs the request
+/// with blocking I/O so handlers can be written straightforwardly.
+///
+/// When the server shuts down the loop closes its listeners and idle
+/// connections, then returns once every remaining one has finished.
+pub struct Reactor {
+    epoll: Epoll,
+    listeners: Vec<CustomTcpListener>,
+    context: Arc<ServerContext>,
+    connections: HashMap<u64, Connection>,
+    /// Every connection's deadline, earliest first, so the loop can sleep
+    /// until the next one and expire connections without scanning them all.
+    timers: BTreeSet<(Instant, u64)>,
+    next_token: u64,
+    handle: ReactorHandle,
+    resumed: Receiver<(RawTcpStream, Vec<u8>, OpenConnection)>,
+}
+
+impl Reactor {
+    pub fn new(listeners: Vec<CustomTcpListener>, context: Arc<ServerContext>) -> Result<Self, std::io::Error> {
+        let epoll = Epoll::new()?;
+        for (token, listener) in (FIRST_LISTENER_TOKEN..).zip(&listeners) {
+            listener.set_nonblocking(true)?;
+            // A listener shared with other workers should wake only one of
+            // them per connection.
+            epoll.add(listener.as_raw_fd(), (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32, token)?;
+        }
+
+        let waker = Arc::new(EventFd::new()?);
+        epoll.add(waker.fd, libc::EPOLLIN as u32, WAKER_TOKEN)?;
+        context.add_waker(Arc::clone(&waker));
+        let (sender, resumed) = mpsc::channel();
+
+        Ok(Reactor {
+            epoll,
+            next_token: FIRST_LISTENER_TOKEN + listeners.len() as u64,
+            listeners,
+            context,
+            connections: HashMap::new(),
+            timers: BTreeSet::new(),
+            handle: ReactorHandle {
+                sender,
+                waker,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+            },
+            resumed,
+        })
+    }
+
+    /// The sockets this loop accepts from.
+    pub fn listeners(&self) -> &[CustomTcpListener] {
+        &self.listeners
+    }
+
+    pub fn run(&mut self) -> Result<(), std::io::Error> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 1024];
+
+        loop {
+            // Checked before waiting, so a shutdown that began before this
+            // loop's waker was registered is not missed.
+            if self.context.is_shutting_down() {
+                self.drain();
+                if self.connections.is_empty() && self.handle.in_flight.load(Ordering::Acquire) == 0 {
+                    return Ok(());
+                }
+            }
+
+            // Rounded up, so the loop does not wake just short of a deadline
+            // and spin until it passes.
+            let timeout_ms = match self.timers.first() {
+                Some(&(deadline, _)) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    remaining.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+            let n = self.epoll.wait(&mut events, timeout_ms)?;
+
+            for event in &events[..n] {
+                let token = event.u64;
+                let listener = token.wrapping_sub(FIRST_LISTENER_TOKEN) as usize;
+                if listener < self.listeners.len() {
+                    self.accept_all(listener);
+                } else if token == WAKER_TOKEN {
+                    self.handle.waker.drain();
+                    while let Ok((stream, buf, open)) = self.resumed.try_recv() {
+                        self.resume(stream, buf, open);
+                    }
+                } else {
+                    self.service(token, event.events);
+                }
+            }
+
+            self.expire(Instant::now());
+        }
+    }
+
+    /// Stops accepting and closes connections waiting for a new request.
+    /// Those mid-request are left to finish.
+    fn drain(&mut self) {
+        for listener in self.listeners.drain(..) {
+            let _ = self.epoll.delete(listener.as_raw_fd());
+        }
+
+        let idle: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| matches!(&conn.state, State::Reading { buf } if buf.is_empty()))
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            self.deregister(token);
+        }
+    }
+
+    /// Closes connections whose deadline has passed: idle keep-alive
+    /// connections past the keep-alive timeout, and clients trickling a
+    /// head in a byte at a time past the header or request timeout. The
+    /// latter are told so with a 408 first.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(deadline, token)) = self.timers.first() {
+            if deadline > now {
+                break;
+            }
+            let Some(conn) = self.deregister(token) else {
+                self.timers.remove(&(deadline, token));
+                continue;
+            };
+
+            if conn.started.is_some() && matches!(conn.state, State::Reading { .. }) {
+                send_error(&self.context, conn.stream, StatusCode::RequestTimeout);
+            }
+        }
+    }
+
+    /// Drains the accept queue of the listener at `index`.
+    fn accept_all(&mut self, index: usize) {
+        loop {
+            match self.listeners[index].accept() {
+                Ok((stream, peer)) => {
+                    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                    // Refused before reading a byte; dropping the stream
+                    // closes it.
+                    if let Some(filter) = self.listeners[index].ip_filter() {
+                        if !peer.is_some_and(|peer| filter.allows(peer.ip())) {
+                            DENIED_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                            if let Some(peer) = peer {
+                                debug!("Refused connection from {}", peer);
+                            }
+                            continue;
+                        }
+                    }
+                    if let Some(peer) = peer {
+                        debug!("Accepted connection from {}", peer);
+                    }
+                    if self.context.at_connection_limit() {
+                        reject_overloaded(&self.context, stream);
+                        continue;
+                    }
+                    // Socket timeouts only affect blocking calls, so these
+                    // govern the pool threads; the event loop enforces its
+                    // own deadline on request heads.
+                    let configured = stream
+                        .set_nonblocking(true)
+                        .and_then(|_| stream.set_read_timeout(self.context.read_timeout))
+                        .and_then(|_| stream.set_write_timeout(self.context.write_timeout));
+                    if let Err(e) = configured {
+                        error!("Error configuring connection: {}", e);
+                        continue;
+                    }
+
+                    let span = self.context.tracer.as_ref().map(|tracer| tracer.connection_span(peer));
+                    let open = OpenConnection::new(&self.context.open_connections, span);
+                    // A new client gets no longer to start its request
+                    // than to finish the head.
+                    let idle_timeout = self.context.header_timeout;
+                    match self.register(stream, Vec::with_capacity(1024), open, idle_timeout) {
+                        Ok(token) => {
+                            let proxy_protocol = self.listeners[index].proxy_protocol();
+                            if let Some(conn) = self.connections.get_mut(&token) {
+                                conn.awaiting_proxy_header = proxy_protocol;
+                            }
+                        }
+                        Err(e) => error!("Error registering connection: {}", e),
+                    }
+                }
+                Err(HyperportError::Io(e)) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(HyperportError::Io(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    ACCEPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    error!("Error accepting connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Starts watching a connection. With nothing buffered yet it may wait
+    /// `idle_timeout` for the next request to begin.
+    fn register(
+        &mut self,
+        stream: RawTcpStream,
+        buf: Vec<u8>,
+        open: OpenConnection,
+        idle_timeout: Option<Duration>,
+    ) -> Result<u64, std::io::Error> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let events = (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        self.epoll.add(stream.as_raw_fd(), events, token)?;
+
+        let now = Instant::now();
+        let (started, deadline) = if buf.is_empty() {
+            (None, idle_timeout.map(|timeout| now + timeout))
+        } else {
+            (Some(now), self.reading_deadline(now))
+        };
+        self.connections.insert(token, Connection {
+            stream,
+            started,
+            state: State::Reading { buf },
+            deadline: None,
+            open,
+            awaiting_proxy_header: false,
+        });
+        self.set_deadline(token, deadline);
+        Ok(token)
+    }
+
+    /// When a request that began at `started` must have its head in, by
+    /// the header timeout or the request timeout, whichever is sooner.
+    fn reading_deadline(&self, started: Instant) -> Option<Instant> {
+        let head = self.context.header_timeout.map(|timeout| started + timeout);
+        let request = self.context.request_timeout.map(|timeout| started + timeout);
+        head.into_iter().chain(request).min()
+    }
+
+    /// Replaces a connection's deadline, keeping the timer set in step.
+    fn set_deadline(&mut self, token: u64, deadline: Option<Instant>) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if let Some(old) = std::mem::replace(&mut conn.deadline, deadline) {
+            self.timers.remove(&(old, token));
+        }
+        if let Some(deadline) = deadline {
+            self.timers.insert((deadline, token));
+        }
+    }
+
+    /// Re-registers a kept-alive connection handed back by a pool thread.
+    /// While shutting down, one with no further request buffered is closed.
+    fn resume(&mut self, stream: RawTcpStream, buf: Vec<u8>, open: OpenConnection) {
+        if buf.is_empty() && self.context.is_shutting_down() {
+            return;
+        }
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Error configuring connection: {}", e);
+            return;
+        }
+
+        let idle_timeout = self.context.keep_alive_timeout;
+        match self.register(stream, buf, open, idle_timeout) {
+            // The client may already have sent its next request, in which
+            // case no further readiness event will arrive for it.
+            Ok(token) => self.service(token, 0),
+            Err(e) => error!("Error registering connection: {}", e),
+        }
+    }
+
+    fn service(&mut self, token: u64, events: u32) {
+        let (mut next, began) = match self.connections.get_mut(&token) {
+            Some(conn) => {
+                let idle = conn.started.is_none();
+                let next = if events & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0 {
+                    Next::Close
+                } else {
+                    advance(conn, &self.context.head_limits)
+                };
+                (next, conn.started.filter(|_| idle))
+            }
+            None => return,
+        };
+
+        // The keep-alive timeout no longer applies once a request begins.
+        if let Some(started) = began {
+            let deadline = self.reading_deadline(started);
+            self.set_deadline(token, deadline);
+        }
+
+        if let Next::Dispatch = next {
+            next = self.dispatch(token);
+        }
+
+        let result = match next {
+            Next::WaitReadable | Next::Dispatch => Ok(()),
+            Next::WaitWritable => {
+                let fd = self.connections[&token].stream.as_raw_fd();
+                self.epoll.modify(fd, libc::EPOLLOUT as u32, token)
+            }
+            Next::Close => Err(std::io::Error::from(ErrorKind::ConnectionAborted)),
+        };
+
+        if result.is_err() {
+            self.deregister(token);
+        }
+    }
+
+    /// Moves a connection with a complete request head onto the pool. When
+    /// the pool's queue is full the request is refused with a 503 instead.
+    fn dispatch(&mut self, token: u64) -> Next {
+        let slot = match self.context.pool.try_reserve() {
+            Some(slot) => slot,
+            None => {
+                let conn = self.connections.get_mut(&token).unwrap();
+                stats::record_response(StatusCode::ServiceUnavailable);
+                conn.state = State::Writing {
+                    response: self
+                        .context
+                        .router()
+                        .error_pages()
+                        .closing_response(StatusCode::ServiceUnavailable, self.context.server_header.as_deref()),
+                    written: 0,
+                };
+                return advance(conn, &self.context.head_limits);
+            }
+        };
+
+        if let Some(conn) = self.deregister(token) {
+            if let Err(e) = conn.stream.set_nonblocking(false) {
+                error!("Error configuring connection: {}", e);
+                return Next::Close;
+            }
+
+            let buf = match conn.state {
+                State::Reading { buf } => buf,
+                State::Writing { .. } => unreachable!("dispatched a connection mid-write"),
+            };
+            let deadline = conn.started.zip(self.context.request_timeout).map(|(started, timeout)| started + timeout);
+            let in_flight = InFlight::new(self.handle.clone());
+            let context = Arc::clone(&self.context);
+            let stream = conn.stream;
+            // Still open while the pool works on it, and handed back along
+            // with the stream if it is kept alive.
+            let open = conn.open;
+            slot.execute(move || {
+                serve(stream, buf, deadline, open, &context, &in_flight.0);
+                drop(in_flight);
+            });
+        }
+
+        Next::WaitReadable
+    }
+
+    fn deregister(&mut self, token: u64) -> Option<Connection> {
+        let conn = self.connections.remove(&token)?;
+        let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        if let Some(deadline) = conn.deadline {
+            self.timers.remove(&(deadline, token));
+        }
+        Some(conn)
+    }
+}
+
+/// Runs one request on a pool thread. The stream is in blocking mode here.
+/// Persistent connections go back to their event loop afterwards; anything
+/// else is closed when `stream` drops.
+fn serve(
+    stream: RawTcpStream,
+    buf: Vec<u8>,
+    deadline: Option<Instant>,
+    open: OpenConnection,
+    context: &Arc<ServerContext>,
+    handle: &ReactorHandle,
+) {
+    // No protocol claiming the connection means nothing we can speak to it;
+    // dropping the stream closes it.
+    let Some(protocol) = context.protocols.for_cleartext(&buf) else {
+        return;
+    };
+
+    if let Some((stream, buf)) = protocol.serve(stream, buf, context, deadline) {
+        handle.resume(stream, buf, open);
+    }
+}
+
+/// Answers a connection over the server's limit with a 503 and closes it.
+fn reject_overloaded(context: &ServerContext, stream: RawTcpStream) {
+    REJECTED_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    send_error(context, stream, StatusCode::ServiceUnavailable);
+}
+
+/// Sends an error page and closes the connection. The write is best effort:
+/// the socket's send buffer normally has room for the short response, and
+/// the client gets no more than a reset otherwise.
+fn send_error(context: &ServerContext, mut stream: RawTcpStream, status: StatusCode) {
+    let response = context.router().error_pages().closing_response(status, context.server_header.as_deref());
+    if stream.set_nonblocking(true).is_ok() {
+        if let Ok(n) = stream.write(&response) {
+            BYTES_SENT.fetch_add(n as u64, Ordering::Relaxed);
+            stats::record_response(status);
+        }
+    }
+}
+
+/// Drives a connection's state machine as far as it can go without blocking.
+/// A head is dispatched as soon as it is complete, or breaks one of
+/// `limits` so that a pool thread can answer with the matching error.
+fn advance(conn: &mut Connection, limits: &HeadLimits) -> Next {
+    loop {
+        match &mut conn.state {
+            State::Reading { buf } => {
+                if conn.awaiting_proxy_header {
+                    match proxy_protocol::parse(buf) {
+                        Ok(Some((header, len))) => {
+                            if let ProxyHeader::Proxied { source, destination } = header {
+                                conn.stream.set_proxied(source, destination);
+                            }
+                            buf.drain(..len);
+                            conn.awaiting_proxy_header = false;
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(_) => return Next::Close,
+                    }
+                } else if !matches!(limits.check(buf), Ok(None)) {
+                    return Next::Dispatch;
+                }
+
+                let mut chunk = [0u8; 4096];
+                match conn.stream.read(&mut chunk) {
+                    Ok(0) => return Next::Close,
+                    Ok(n) => {
+                        BYTES_RECEIVED.fetch_add(n as u64, Ordering::Relaxed);
+                        if buf.is_empty() {
+                            conn.started = Some(Instant::now());
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Next::WaitReadable,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        debug!("Error reading from stream: {}", e);
+                        return Next::Close;
+                    }
+                }
+            }
+            State::Writing { response, written } => {
+                match conn.stream.write(&response[*written..]) {
+                    Ok(n) => {
+                        *written += n;
+                        BYTES_SENT.fetch_add(n as u64, Ordering::Relaxed);
+                        if *written == response.len() {
+                            return Next::Close;
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Next::WaitWritable,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => return Next::Close,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Synchronous signal handling.
+//!
+//! Rather than installing handlers, which may only call async-signal-safe
+//! functions, the signals are blocked in every thread and a dedicated
+//! thread waits for them with `sigwait(2)`.
+
+use std::io;
+use std::mem;
+
+use libc::c_int;
+
+/// A set of signals blocked in the calling thread and every thread it
+/// spawns afterwards.
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    /// Blocks `signals`. Call this before spawning any threads: the mask is
+    /// inherited, and a thread that still has them unblocked would receive
+    /// them instead of [`wait`](Signals::wait), typically terminating the
+    /// process.
+    pub fn block(signals: &[c_int]) -> io::Result<Self> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for &signal in signals {
+            if unsafe { libc::sigaddset(&mut set, signal) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(Signals { set })
+    }
+
+    /// Blocks until one of the signals is pending and returns it.
+    pub fn wait(&self) -> io::Result<c_int> {
+        let mut signal: c_int = 0;
+        let err = unsafe { libc::sigwait(&self.set, &mut signal) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(signal)
+    }
+}
@@ -0,0 +1,233 @@
+//! Process-wide counters updated by the connection handling code, and a
+//! handler exposing them in the Prometheus text format.
+
+use std::fmt::Write as _;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::http::handler::Handler;
+use crate::http::request::Request;
+use crate::http::response::ResponseWriter;
+use crate::http::status::StatusCode;
+use crate::server::trace::ConnectionSpan;
+
+pub(crate) static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static ACCEPT_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static REJECTED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static DENIED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+pub(crate) static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+pub(crate) static PROXY_RETRIES: AtomicU64 = AtomicU64::new(0);
+pub(crate) static PROXY_FAILURES: AtomicU64 = AtomicU64::new(0);
+pub(crate) static PROXY_CIRCUIT_REJECTIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static RATE_LIMIT_ALLOWED: AtomicU64 = AtomicU64::new(0);
+pub(crate) static RATE_LIMIT_REJECTED: AtomicU64 = AtomicU64::new(0);
+/// Forwarder counters, indexed by [`Protocol`](crate::net::forward::Protocol).
+pub(crate) static FORWARDED_CONNECTIONS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+pub(crate) static FORWARDED_BYTES_TO_TARGET: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+pub(crate) static FORWARDED_BYTES_FROM_TARGET: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+/// Responses sent, indexed by status class: 1xx at 0 through 5xx at 4.
+static RESPONSES: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+
+/// Counts a connection as open for as long as the guard lives, both in the
+/// process-wide gauge and in its server's own count. It also carries the
+/// connection's span, when tracing, which ends with it.
+pub(crate) struct OpenConnection {
+    server: Arc<AtomicUsize>,
+    _span: Option<ConnectionSpan>,
+}
+
+impl OpenConnection {
+    pub(crate) fn new(server: &Arc<AtomicUsize>, span: Option<ConnectionSpan>) -> Self {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        server.fetch_add(1, Ordering::AcqRel);
+        OpenConnection {
+            server: Arc::clone(server),
+            _span: span,
+        }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        self.server.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Records a response with `status` having been sent.
+pub(crate) fn record_response(status: StatusCode) {
+    let class = (status.as_u16() / 100) as usize;
+    if let Some(counter) = RESPONSES.get(class.wrapping_sub(1)) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Total connections accepted since startup.
+pub fn connections() -> u64 {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Connections currently open, whether idle, being read or being served.
+pub fn open_connections() -> u64 {
+    OPEN_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Total bytes written to clients since startup.
+pub fn bytes_sent() -> u64 {
+    BYTES_SENT.load(Ordering::Relaxed)
+}
+
+/// Total bytes read from clients since startup.
+pub fn bytes_received() -> u64 {
+    BYTES_RECEIVED.load(Ordering::Relaxed)
+}
+
+/// User and system CPU time consumed by the process, in microseconds.
+pub fn cpu_time() -> (u64, u64) {
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut rusage) };
+
+    let user_time = (rusage.ru_utime.tv_sec as u64 * 1000000) + rusage.ru_utime.tv_usec as u64;
+    let sys_time = (rusage.ru_stime.tv_sec as u64 * 1000000) + rusage.ru_stime.tv_usec as u64;
+    (user_time, sys_time)
+}
+
+/// Renders every counter in the Prometheus text exposition format.
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "hyperport_connections_accepted_total",
+        "counter",
+        "Connections accepted.",
+        &[("", connections())],
+    );
+    metric(
+        "hyperport_connections_open",
+        "gauge",
+        "Connections currently open.",
+        &[("", open_connections())],
+    );
+    metric(
+        "hyperport_connections_rejected_total",
+        "counter",
+        "Connections refused with 503 for exceeding the connection limit.",
+        &[("", REJECTED_CONNECTIONS.load(Ordering::Relaxed))],
+    );
+    metric(
+        "hyperport_connections_denied_total",
+        "counter",
+        "Connections closed on accept because their listener's IP filter refused the peer.",
+        &[("", DENIED_CONNECTIONS.load(Ordering::Relaxed))],
+    );
+    metric(
+        "hyperport_accept_errors_total",
+        "counter",
+        "Failed accept(2) calls.",
+        &[("", ACCEPT_ERRORS.load(Ordering::Relaxed))],
+    );
+
+    let classes = ["{class=\"1xx\"}", "{class=\"2xx\"}", "{class=\"3xx\"}", "{class=\"4xx\"}", "{class=\"5xx\"}"];
+    let responses: Vec<(&str, u64)> = classes
+        .iter()
+        .zip(&RESPONSES)
+        .map(|(labels, counter)| (*labels, counter.load(Ordering::Relaxed)))
+        .collect();
+    metric("hyperport_responses_total", "counter", "Responses sent, by status class.", &responses);
+
+    metric(
+        "hyperport_received_bytes_total",
+        "counter",
+        "Bytes read from clients.",
+        &[("", bytes_received())],
+    );
+    metric(
+        "hyperport_sent_bytes_total",
+        "counter",
+        "Bytes written to clients.",
+        &[("", bytes_sent())],
+    );
+
+    metric(
+        "hyperport_proxy_retries_total",
+        "counter",
+        "Proxied requests sent again after an upstream failed or answered 5xx.",
+        &[("", PROXY_RETRIES.load(Ordering::Relaxed))],
+    );
+    metric(
+        "hyperport_proxy_failures_total",
+        "counter",
+        "Proxied requests answered 502 or 504 because no upstream responded.",
+        &[("", PROXY_FAILURES.load(Ordering::Relaxed))],
+    );
+    metric(
+        "hyperport_proxy_circuit_rejections_total",
+        "counter",
+        "Proxied requests refused with 503 because the circuits of their upstreams were open.",
+        &[("", PROXY_CIRCUIT_REJECTIONS.load(Ordering::Relaxed))],
+    );
+
+    metric(
+        "hyperport_rate_limit_requests_total",
+        "counter",
+        "Requests checked against a rate limit, by whether they were let through or refused with 429.",
+        &[
+            ("{result=\"allowed\"}", RATE_LIMIT_ALLOWED.load(Ordering::Relaxed)),
+            ("{result=\"limited\"}", RATE_LIMIT_REJECTED.load(Ordering::Relaxed)),
+        ],
+    );
+
+    let load = |counters: &[AtomicU64; 2], i: usize| counters[i].load(Ordering::Relaxed);
+    metric(
+        "hyperport_forwarded_connections_total",
+        "counter",
+        "Connections accepted by TCP forwarders and sessions opened by UDP ones.",
+        &[
+            ("{protocol=\"tcp\"}", load(&FORWARDED_CONNECTIONS, 0)),
+            ("{protocol=\"udp\"}", load(&FORWARDED_CONNECTIONS, 1)),
+        ],
+    );
+    metric(
+        "hyperport_forwarded_bytes_total",
+        "counter",
+        "Bytes relayed by forwarders, by protocol and direction.",
+        &[
+            ("{protocol=\"tcp\",direction=\"to_target\"}", load(&FORWARDED_BYTES_TO_TARGET, 0)),
+            ("{protocol=\"tcp\",direction=\"from_target\"}", load(&FORWARDED_BYTES_FROM_TARGET, 0)),
+            ("{protocol=\"udp\",direction=\"to_target\"}", load(&FORWARDED_BYTES_TO_TARGET, 1)),
+            ("{protocol=\"udp\",direction=\"from_target\"}", load(&FORWARDED_BYTES_FROM_TARGET, 1)),
+        ],
+    );
+
+    let (user_us, sys_us) = cpu_time();
+    let _ = writeln!(out, "# HELP process_cpu_seconds_total Total user and system CPU time.");
+    let _ = writeln!(out, "# TYPE process_cpu_seconds_total counter");
+    let _ = writeln!(out, "process_cpu_seconds_total {}", (user_us + sys_us) as f64 / 1_000_000.0);
+    out
+}
+
+/// Handler serving [`render_prometheus`], for mounting at e.g. `/metrics`.
+pub struct Metrics;
+
+impl Handler for Metrics {
+    fn call(&self, _request: &Request, writer: &mut ResponseWriter) -> io::Result<()> {
+        let body = render_prometheus();
+        writer.send(
+            StatusCode::Ok,
+            &[("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+            body.as_bytes(),
+        )
+    }
+}
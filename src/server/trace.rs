@@ -0,0 +1,333 @@
+//! Distributed tracing: a span for every connection and every request,
+//! exported to an OpenTelemetry collector over OTLP/HTTP in its JSON
+//! encoding. A request joins the trace named by its W3C `traceparent`
+//! header (https://www.w3.org/TR/trace-context/), and the header is
+//! rewritten to name the request's own span, so that a proxied upstream
+//! continues the same trace.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http::client::Client;
+use crate::http::request::{Request, Version};
+use crate::http::status::StatusCode;
+use crate::util::json;
+use crate::{error, warn};
+
+/// Finished spans that may wait for the exporter. Past this, new spans are
+/// dropped rather than making requests wait on the collector.
+const QUEUE_DEPTH: usize = 4096;
+
+/// Most spans sent in one export request.
+const MAX_BATCH: usize = 512;
+
+/// How long the exporter lets a batch fill once it holds a span.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Limit on each export request, so a stuck collector only delays spans.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `sampled` bit of the trace flags.
+const SAMPLED: u8 = 0x01;
+
+/// Span kinds, as OTLP numbers them.
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+/// Starts spans and queues finished ones for a background thread that
+/// exports them in batches.
+#[derive(Clone)]
+pub(crate) struct Tracer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tx: SyncSender<Span>,
+    keys: RandomState,
+    counter: AtomicU64,
+}
+
+impl Tracer {
+    /// Starts the exporter, which posts to the OTLP/HTTP traces
+    /// `endpoint`, e.g. `http://127.0.0.1:4318/v1/traces`, naming the
+    /// spans' source `service_name`.
+    pub(crate) fn new(endpoint: String, service_name: String) -> Tracer {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        thread::Builder::new()
+            .name("trace-export".to_string())
+            .spawn(move || export(rx, &endpoint, &service_name))
+            .expect("failed to spawn trace export thread");
+        Tracer {
+            inner: Arc::new(Inner {
+                tx,
+                keys: RandomState::new(),
+                counter: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// A span for a connection from `peer`, recorded when it is dropped.
+    pub(crate) fn connection_span(&self, peer: Option<SocketAddr>) -> ConnectionSpan {
+        ConnectionSpan {
+            tracer: self.clone(),
+            trace_id: self.trace_id(),
+            span_id: self.span_id(),
+            start: SystemTime::now(),
+            peer,
+        }
+    }
+
+    /// Starts the span for `request`: a child of the span its valid
+    /// `traceparent` names, or the root of a new trace. The header is
+    /// pointed at the new span. `None` when the caller's trace is not
+    /// sampled, whose spans nobody will collect.
+    pub(crate) fn start_request(&self, request: &mut Request) -> Option<RequestSpan<'_>> {
+        let parent = request.headers.get("traceparent").and_then(parse_traceparent);
+        let flags = parent.map_or(SAMPLED, |(_, _, flags)| flags);
+        if flags & SAMPLED == 0 {
+            return None;
+        }
+        let trace_id = parent.map_or_else(|| self.trace_id(), |(trace_id, _, _)| trace_id);
+        let span_id = self.span_id();
+        request.headers.remove("traceparent");
+        request
+            .headers
+            .append("traceparent", &format!("00-{:032x}-{:016x}-{:02x}", trace_id, span_id, flags));
+        Some(RequestSpan {
+            tracer: self,
+            trace_id,
+            span_id,
+            parent_id: parent.map(|(_, parent_id, _)| parent_id),
+            start: SystemTime::now(),
+        })
+    }
+
+    /// A counter run through the randomly keyed SipHash, as random as IDs
+    /// need to be and never zero, which trace context reserves.
+    fn random(&self) -> u64 {
+        let mut hasher = self.inner.keys.build_hasher();
+        hasher.write_u64(self.inner.counter.fetch_add(1, Ordering::Relaxed));
+        hasher.finish().max(1)
+    }
+
+    fn trace_id(&self) -> u128 {
+        (self.random() as u128) << 64 | self.random() as u128
+    }
+
+    fn span_id(&self) -> u64 {
+        self.random()
+    }
+
+    fn record(&self, span: Span) {
+        match self.inner.tx.try_send(span) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => error!("Trace exporter has stopped"),
+        }
+    }
+}
+
+/// A request's span, between [`Tracer::start_request`] and the response.
+pub(crate) struct RequestSpan<'a> {
+    tracer: &'a Tracer,
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+}
+
+impl RequestSpan<'_> {
+    /// Records the span, once `request` has been answered with `status`.
+    /// `failed` marks a handler error, as a 5xx status does.
+    pub(crate) fn finish(self, request: &Request, status: Option<StatusCode>, failed: bool) {
+        let mut attributes = vec![
+            ("http.request.method", Value::String(request.method.clone())),
+            ("url.path", Value::String(request.path.clone())),
+            ("network.protocol.version", Value::String(protocol_version(request.version).to_string())),
+        ];
+        if let Some(query) = request.query_string() {
+            attributes.push(("url.query", Value::String(query.to_string())));
+        }
+        if let Some(ip) = request.client_ip {
+            attributes.push(("client.address", Value::String(ip.to_string())));
+        }
+        if let Some(agent) = request.headers.get("user-agent") {
+            attributes.push(("user_agent.original", Value::String(agent.to_string())));
+        }
+        if let Some(status) = status {
+            attributes.push(("http.response.status_code", Value::Int(status.as_u16() as i64)));
+        }
+        self.tracer.record(Span {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            parent_id: self.parent_id,
+            name: request.method.clone(),
+            kind: KIND_SERVER,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes,
+            error: failed || status.is_some_and(|status| status.as_u16() >= 500),
+        });
+    }
+}
+
+/// A connection's span, which is recorded when the connection closes and
+/// this is dropped with it. It starts a trace of its own.
+pub(crate) struct ConnectionSpan {
+    tracer: Tracer,
+    trace_id: u128,
+    span_id: u64,
+    start: SystemTime,
+    peer: Option<SocketAddr>,
+}
+
+impl Drop for ConnectionSpan {
+    fn drop(&mut self) {
+        let mut attributes = vec![("network.transport", Value::String("tcp".to_string()))];
+        if let Some(peer) = self.peer {
+            attributes.push(("network.peer.address", Value::String(peer.ip().to_string())));
+            attributes.push(("network.peer.port", Value::Int(peer.port() as i64)));
+        }
+        self.tracer.record(Span {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            parent_id: None,
+            name: "connection".to_string(),
+            kind: KIND_INTERNAL,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes,
+            error: false,
+        });
+    }
+}
+
+/// A finished span, waiting to be exported.
+struct Span {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+enum Value {
+    String(String),
+    Int(i64),
+}
+
+/// The trace ID, parent span ID and trace flags of a `traceparent` value,
+/// or `None` if it is malformed. Versions after 00 may add fields, which
+/// are ignored.
+fn parse_traceparent(value: &str) -> Option<(u128, u64, u8)> {
+    let mut fields = value.trim().split('-');
+    let version = hex(fields.next()?, 2)?;
+    let trace_id = hex(fields.next()?, 32)?;
+    let parent_id = hex(fields.next()?, 16)? as u64;
+    let flags = hex(fields.next()?, 2)? as u8;
+    let invalid = version == 0xff || (version == 0 && fields.next().is_some()) || trace_id == 0 || parent_id == 0;
+    (!invalid).then_some((trace_id, parent_id, flags))
+}
+
+/// Exactly `len` lowercase hex digits.
+fn hex(field: &str, len: usize) -> Option<u128> {
+    let valid = field.len() == len && field.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    valid.then(|| u128::from_str_radix(field, 16).ok()).flatten()
+}
+
+fn protocol_version(version: Version) -> &'static str {
+    match version {
+        Version::Http10 => "1.0",
+        Version::Http11 => "1.1",
+        Version::Http2 => "2",
+    }
+}
+
+/// Exporter thread body. Waits for a span, gives the batch a moment to
+/// fill, and posts it; a failed export loses its spans.
+fn export(rx: Receiver<Span>, endpoint: &str, service_name: &str) {
+    let client = Client::new().timeout(EXPORT_TIMEOUT);
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < MAX_BATCH {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        let body = encode(&batch, service_name);
+        match client.send("POST", endpoint, &[("Content-Type", "application/json")], body.as_bytes()) {
+            Ok(response) if response.status.is_success() => {}
+            Ok(response) => warn!("Trace collector refused {} spans: {}", batch.len(), response.status),
+            Err(e) => warn!("Error exporting traces: {}", e),
+        }
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` in the JSON encoding, where IDs are
+/// hex strings and 64-bit integers are decimal strings.
+fn encode(spans: &[Span], service_name: &str) -> String {
+    let mut out = String::from(r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"#);
+    json::write_string(&mut out, service_name);
+    let _ = write!(
+        out,
+        r#"}}}}]}},"scopeSpans":[{{"scope":{{"name":"hyperport","version":"{}"}},"spans":["#,
+        env!("CARGO_PKG_VERSION")
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, r#"{{"traceId":"{:032x}","spanId":"{:016x}","#, span.trace_id, span.span_id);
+        if let Some(parent_id) = span.parent_id {
+            let _ = write!(out, r#""parentSpanId":"{:016x}","#, parent_id);
+        }
+        out.push_str(r#""name":"#);
+        json::write_string(&mut out, &span.name);
+        let _ = write!(
+            out,
+            r#","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+            span.kind,
+            unix_nanos(span.start),
+            unix_nanos(span.end)
+        );
+        for (j, (key, value)) in span.attributes.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, r#"{{"key":"{}","value":{{"#, key);
+            match value {
+                Value::String(s) => {
+                    out.push_str(r#""stringValue":"#);
+                    json::write_string(&mut out, s);
+                }
+                Value::Int(n) => {
+                    let _ = write!(out, r#""intValue":"{}""#, n);
+                }
+            }
+            out.push_str("}}");
+        }
+        out.push(']');
+        if span.error {
+            out.push_str(r#","status":{"code":2}"#);
+        }
+        out.push('}');
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
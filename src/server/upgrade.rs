@@ -0,0 +1,158 @@
+//! Zero-downtime binary upgrades.
+//!
+//! The running process starts its successor with [`ServerHandle::upgrade`],
+//! passing it duplicates of the listening sockets. The successor picks them
+//! up with [`inherited_listeners`], builds its server on them and calls
+//! [`notify_ready`]. Only then does the old process shut down, finishing the
+//! requests it already has while the successor accepts new ones. The
+//! sockets stay open throughout, so no connection attempt is refused.
+
+use std::env;
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use crate::net::CustomTcpListener;
+use crate::server::ServerHandle;
+
+/// Comma-separated descriptors of the inherited listening sockets.
+const LISTEN_FDS_VAR: &str = "HYPERPORT_LISTEN_FDS";
+
+/// Descriptor the successor writes to once it is ready to accept.
+const READY_FD_VAR: &str = "HYPERPORT_READY_FD";
+
+impl ServerHandle {
+    /// Starts `command` as this server's successor and waits up to
+    /// `timeout` for it to call [`notify_ready`]. On success, follow up with
+    /// [`shutdown`](ServerHandle::shutdown). On failure the successor is
+    /// killed and this server carries on as before.
+    pub fn upgrade(&self, mut command: Command, timeout: Duration) -> io::Result<Child> {
+        if self.context.is_shutting_down() {
+            return Err(io::Error::other("server is shutting down"));
+        }
+
+        // The successor only inherits descriptors without close-on-exec, so
+        // it gets duplicates; the parent's copies close once it is spawned.
+        let listeners = self.context.listeners.lock().unwrap_or_else(|e| e.into_inner());
+        let fds = listeners
+            .iter()
+            .map(|listener| inheritable(listener.as_raw_fd()))
+            .collect::<io::Result<Vec<_>>>()?;
+        drop(listeners);
+        let (ready_read, write_end) = pipe()?;
+        let ready_write = inheritable(write_end.as_raw_fd())?;
+        // Only the successor may hold the write end, or its exit would not
+        // show up as end of file.
+        drop(write_end);
+
+        let list: Vec<String> = fds.iter().map(|fd| fd.as_raw_fd().to_string()).collect();
+        command
+            .env(LISTEN_FDS_VAR, list.join(","))
+            .env(READY_FD_VAR, ready_write.as_raw_fd().to_string());
+        let mut child = command.spawn()?;
+        drop(fds);
+        drop(ready_write);
+
+        match wait_ready(&ready_read, timeout) {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Takes ownership of the listening sockets passed by a predecessor's
+/// [`ServerHandle::upgrade`]. Empty when the process was started normally.
+/// Call this once, early, before spawning threads.
+pub fn inherited_listeners() -> io::Result<Vec<CustomTcpListener>> {
+    let Some(list) = env::var_os(LISTEN_FDS_VAR) else {
+        return Ok(Vec::new());
+    };
+    env::remove_var(LISTEN_FDS_VAR);
+
+    let list = list.to_string_lossy();
+    list.split(',')
+        .filter(|fd| !fd.is_empty())
+        .map(|fd| {
+            let fd: RawFd = fd
+                .parse()
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, format!("invalid {} `{}`", LISTEN_FDS_VAR, list)))?;
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(CustomTcpListener::from_raw_fd(fd))
+        })
+        .collect()
+}
+
+/// Tells the predecessor that this process is ready to accept, so it can
+/// start shutting down. Does nothing when there is no predecessor.
+pub fn notify_ready() -> io::Result<()> {
+    let Some(fd) = env::var_os(READY_FD_VAR) else {
+        return Ok(());
+    };
+    env::remove_var(READY_FD_VAR);
+
+    let fd: RawFd = fd
+        .to_string_lossy()
+        .parse()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, format!("invalid {}", READY_FD_VAR)))?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::write(fd.as_raw_fd(), b"1".as_ptr() as *const libc::c_void, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Duplicates `fd` without close-on-exec, so a spawned process inherits it.
+fn inheritable(fd: RawFd) -> io::Result<OwnedFd> {
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD, 3) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(dup) })
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Waits for the successor's ready byte. End of file means it exited, or
+/// closed the descriptor, without becoming ready.
+fn wait_ready(ready: &OwnedFd, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut pollfd = libc::pollfd {
+            fd: ready.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as i32) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::TimedOut, "successor did not become ready in time"));
+        }
+
+        let mut byte = 0u8;
+        return match unsafe { libc::read(ready.as_raw_fd(), &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => Ok(()),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => Err(io::Error::other("successor exited before becoming ready")),
+        };
+    }
+}
@@ -0,0 +1,117 @@
+//! Enforces request deadlines on pool threads.
+//!
+//! A handler blocked on a dead client, or a client trickling out a body,
+//! would otherwise hold its pool thread indefinitely. The watchdog keeps a
+//! duplicate of each armed socket and shuts it down once its deadline
+//! passes, which makes any read or write blocked on it fail at once.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::net::RawTcpStream;
+
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    /// Keyed by deadline, with a sequence number to keep keys unique.
+    timers: BTreeMap<(Instant, u64), RawTcpStream>,
+    next_id: u64,
+    stopped: bool,
+}
+
+/// Keeps a socket's deadline armed until dropped.
+pub(crate) struct Armed {
+    shared: Arc<Shared>,
+    key: (Instant, u64),
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                timers: BTreeMap::new(),
+                next_id: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || run(&thread_shared))
+            .expect("failed to spawn watchdog thread");
+        Watchdog { shared }
+    }
+
+    /// Shuts `stream` down at `deadline` unless the returned guard is
+    /// dropped first.
+    pub(crate) fn arm(&self, stream: &RawTcpStream, deadline: Instant) -> io::Result<Armed> {
+        let dup = stream.try_clone()?;
+        let mut state = self.shared.lock();
+        let key = (deadline, state.next_id);
+        state.next_id += 1;
+        let earliest = state.timers.keys().next().is_none_or(|first| key < *first);
+        state.timers.insert(key, dup);
+        drop(state);
+
+        if earliest {
+            self.shared.changed.notify_one();
+        }
+        Ok(Armed {
+            shared: Arc::clone(&self.shared),
+            key,
+        })
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        self.shared.lock().timers.remove(&self.key);
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn run(shared: &Shared) {
+    let mut state = shared.lock();
+    while !state.stopped {
+        let now = Instant::now();
+        while let Some(entry) = state.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let _ = entry.remove().shutdown(Shutdown::Both);
+        }
+
+        state = match state.timers.keys().next() {
+            Some(&(deadline, _)) => {
+                let timeout = deadline.saturating_duration_since(now);
+                shared.changed.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0
+            }
+            None => shared.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
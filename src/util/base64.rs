@@ -0,0 +1,50 @@
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_SAFE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes `data` as padded standard base64 (RFC 4648 §4).
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        out.push(STANDARD[(n >> 18) as usize & 63] as char);
+        out.push(STANDARD[(n >> 12) as usize & 63] as char);
+        out.push(if chunk.len() > 1 { STANDARD[(n >> 6) as usize & 63] as char } else { '=' });
+        out.push(if chunk.len() > 2 { STANDARD[n as usize & 63] as char } else { '=' });
+    }
+
+    out
+}
+
+/// Decodes standard base64, with or without its padding. `None` if `text`
+/// holds anything else.
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    decode_with(STANDARD, text)
+}
+
+/// Decodes the URL-safe variant (RFC 4648 §5), with `-` and `_` for `+`
+/// and `/`, as JWTs use it.
+pub fn decode_url(text: &str) -> Option<Vec<u8>> {
+    decode_with(URL_SAFE, text)
+}
+
+fn decode_with(alphabet: &[u8; 64], text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+
+    for chunk in text.chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = alphabet.iter().position(|&a| a == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+
+    Some(out)
+}
@@ -0,0 +1,106 @@
+//! The checksums used by the gzip (CRC-32) and zlib (Adler-32) formats.
+
+/// CRC-32 as used by gzip (RFC 1952 §8), updated incrementally.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut c = !self.crc;
+        for &byte in data {
+            c = CRC_TABLE[((c ^ u32::from(byte)) & 0xff) as usize] ^ (c >> 8);
+        }
+        self.crc = !c;
+    }
+
+    pub fn value(&self) -> u32 {
+        self.crc
+    }
+}
+
+/// Adler-32 as used by zlib (RFC 1950 §9), updated incrementally.
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Adler32::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        // 5552 is the most bytes that can be summed before `b` could
+        // overflow a u32.
+        for block in data.chunks(5552) {
+            for &byte in block {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= 65521;
+            self.b %= 65521;
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        assert_eq!(crc.value(), 0);
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.value(), 0xCBF43926);
+    }
+
+    #[test]
+    fn adler32_known_values() {
+        let mut adler = Adler32::new();
+        assert_eq!(adler.value(), 1);
+        adler.update(b"Wikipedia");
+        assert_eq!(adler.value(), 0x11E60398);
+
+        // Long enough to need the modulo inside the loop.
+        let mut adler = Adler32::new();
+        adler.update(&[0xFF; 100_000]);
+        assert_eq!(adler.value(), 0x149A302C);
+    }
+}
@@ -0,0 +1,821 @@
+//! DEFLATE compression (RFC 1951), with the gzip (RFC 1952) and zlib
+//! (RFC 1950) wrappers.
+//!
+//! Encoders are [`Write`] adapters compressing into an inner writer.
+//! `flush` ends the output so far at a byte boundary (a sync flush), so a
+//! peer can decode everything written up to that point; `finish` ends the
+//! stream and hands back the inner writer.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+use crate::util::checksum::{Adler32, Crc32};
+use crate::util::inflate::{CODE_LENGTH_ORDER, DIST_BASE, DIST_EXTRA, LENGTH_BASE, LENGTH_EXTRA, WINDOW_SIZE};
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+const END_OF_BLOCK: usize = 256;
+
+/// Input gathered before any of it is compressed.
+const INPUT_CHUNK: usize = 64 * 1024;
+
+/// Symbols gathered before a block is emitted; each block gets its own
+/// Huffman codes, fitted to its contents.
+const MAX_TOKENS: usize = 16 * 1024;
+
+/// Largest stored (uncompressed) block.
+const MAX_STORED: usize = 65535;
+
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { len: u16, dist: u16 },
+}
+
+/// How hard each level looks for matches.
+struct Effort {
+    /// Candidates examined per position.
+    chain: usize,
+    /// A match this long is taken without looking further.
+    nice: usize,
+    /// Whether to defer a match by one byte when the next position might
+    /// start a longer one.
+    lazy: bool,
+}
+
+impl Effort {
+    fn for_level(level: u32) -> Effort {
+        let (chain, nice, lazy) = match level {
+            1 => (4, 8, false),
+            2 => (8, 16, false),
+            3 => (16, 32, false),
+            4 => (16, 16, true),
+            5 => (32, 32, true),
+            6 => (128, 128, true),
+            7 => (256, 128, true),
+            8 => (1024, 258, true),
+            _ => (4096, 258, true),
+        };
+        Effort { chain, nice, lazy }
+    }
+}
+
+/// Writes bits least significant first, as DEFLATE packs them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Pads to the next byte boundary with zero bits.
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+/// A canonical Huffman code for writing.
+struct Code {
+    lengths: Vec<u8>,
+    /// Each symbol's code, bit-reversed to go out least significant first.
+    codes: Vec<u16>,
+}
+
+impl Code {
+    fn from_lengths(lengths: Vec<u8>) -> Code {
+        let mut counts = [0u32; 16];
+        for &len in &lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut next = [0u32; 16];
+        let mut code = 0;
+        for bits in 1..16 {
+            code = (code + counts[bits - 1]) << 1;
+            next[bits] = code;
+        }
+
+        let codes = lengths
+            .iter()
+            .map(|&len| {
+                if len == 0 {
+                    return 0;
+                }
+                let code = next[len as usize] as u16;
+                next[len as usize] += 1;
+                code.reverse_bits() >> (16 - len)
+            })
+            .collect();
+        Code { lengths, codes }
+    }
+
+    /// The code minimising the output for symbols occurring `freqs` times,
+    /// with no code longer than `max_bits`.
+    fn optimal(freqs: &[u32], max_bits: u8) -> Code {
+        Code::from_lengths(code_lengths(freqs, max_bits))
+    }
+
+    fn fixed() -> (Code, Code) {
+        let mut literal = vec![8u8; 288];
+        literal[144..256].fill(9);
+        literal[256..280].fill(7);
+        (Code::from_lengths(literal), Code::from_lengths(vec![5; 30]))
+    }
+
+    fn put(&self, out: &mut BitWriter, symbol: usize) {
+        out.write(u32::from(self.codes[symbol]), u32::from(self.lengths[symbol]));
+    }
+
+    /// Bits taken by symbols occurring `freqs` times.
+    fn cost(&self, freqs: &[u32]) -> u64 {
+        freqs.iter().zip(&self.lengths).map(|(&freq, &len)| u64::from(freq) * u64::from(len)).sum()
+    }
+}
+
+fn code_lengths(freqs: &[u32], max_bits: u8) -> Vec<u8> {
+    let mut weights = freqs.to_vec();
+    loop {
+        let lengths = huffman_lengths(&weights);
+        if lengths.iter().all(|&len| len <= max_bits) {
+            return lengths;
+        }
+        // Too deep: flatten the distribution and try again. Halving rounds
+        // up, so no symbol in use drops out.
+        for weight in &mut weights {
+            *weight = weight.div_ceil(2);
+        }
+    }
+}
+
+/// Unrestricted Huffman code lengths for `weights`.
+fn huffman_lengths(weights: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0u8; weights.len()];
+    let used: Vec<usize> = (0..weights.len()).filter(|&symbol| weights[symbol] > 0).collect();
+    if used.len() < 2 {
+        // A lone symbol still needs a complete code: pair it with another.
+        let first = used.first().copied().unwrap_or(0);
+        lengths[first] = 1;
+        lengths[if first == 0 { 1 } else { 0 }] = 1;
+        return lengths;
+    }
+
+    // Leaves are nodes 0..used.len(); each merge appends an internal node,
+    // so every parent comes after its children and the root is last.
+    let mut parent = vec![0usize; 2 * used.len() - 1];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+        used.iter().enumerate().map(|(node, &symbol)| Reverse((u64::from(weights[symbol]), node))).collect();
+    let mut next = used.len();
+    while let (Some(Reverse((a_weight, a))), Some(Reverse((b_weight, b)))) = (heap.pop(), heap.pop()) {
+        parent[a] = next;
+        parent[b] = next;
+        heap.push(Reverse((a_weight + b_weight, next)));
+        next += 1;
+    }
+
+    let mut depth = vec![0u8; next];
+    for node in (0..next - 1).rev() {
+        depth[node] = depth[parent[node]] + 1;
+    }
+    for (node, &symbol) in used.iter().enumerate() {
+        lengths[symbol] = depth[node];
+    }
+    lengths
+}
+
+fn length_code(len: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= len) - 1
+}
+
+fn dist_code(dist: u16) -> usize {
+    DIST_BASE.partition_point(|&base| base <= dist) - 1
+}
+
+/// The code lengths heading a dynamic block, themselves run-length and
+/// Huffman coded.
+struct DynamicHeader {
+    literals: usize,
+    distances: usize,
+    /// Code length symbols with the value of their extra bits.
+    items: Vec<(u8, u8)>,
+    code: Code,
+    /// Code length code lengths sent, in `CODE_LENGTH_ORDER`.
+    sent: usize,
+}
+
+impl DynamicHeader {
+    fn new(literal: &Code, distance: &Code) -> DynamicHeader {
+        let used = |lengths: &[u8]| lengths.iter().rposition(|&len| len != 0).map_or(0, |i| i + 1);
+        let literals = used(&literal.lengths).max(257);
+        let distances = used(&distance.lengths).max(1);
+        let lengths: Vec<u8> =
+            literal.lengths[..literals].iter().chain(&distance.lengths[..distances]).copied().collect();
+
+        let mut items = Vec::new();
+        let mut i = 0;
+        while i < lengths.len() {
+            let len = lengths[i];
+            let run = lengths[i..].iter().take_while(|&&l| l == len).count();
+            if len == 0 && run >= 11 {
+                let n = run.min(138);
+                items.push((18, (n - 11) as u8));
+                i += n;
+            } else if len == 0 && run >= 3 {
+                let n = run.min(10);
+                items.push((17, (n - 3) as u8));
+                i += n;
+            } else if len != 0 && run >= 4 {
+                let n = (run - 1).min(6);
+                items.push((len, 0));
+                items.push((16, (n - 3) as u8));
+                i += 1 + n;
+            } else {
+                items.push((len, 0));
+                i += 1;
+            }
+        }
+
+        let mut freqs = [0u32; 19];
+        for &(symbol, _) in &items {
+            freqs[symbol as usize] += 1;
+        }
+        let code = Code::optimal(&freqs, 7);
+        let sent = CODE_LENGTH_ORDER.iter().rposition(|&symbol| code.lengths[symbol] != 0).map_or(0, |i| i + 1).max(4);
+
+        DynamicHeader {
+            literals,
+            distances,
+            items,
+            code,
+            sent,
+        }
+    }
+
+    fn cost(&self) -> u64 {
+        let items: u64 = self
+            .items
+            .iter()
+            .map(|&(symbol, _)| u64::from(self.code.lengths[symbol as usize]) + u64::from(repeat_bits(symbol)))
+            .sum();
+        14 + 3 * self.sent as u64 + items
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        out.write((self.literals - 257) as u32, 5);
+        out.write((self.distances - 1) as u32, 5);
+        out.write((self.sent - 4) as u32, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..self.sent] {
+            out.write(u32::from(self.code.lengths[symbol]), 3);
+        }
+        for &(symbol, extra) in &self.items {
+            self.code.put(out, symbol as usize);
+            out.write(u32::from(extra), repeat_bits(symbol));
+        }
+    }
+}
+
+/// Extra bits following a code length symbol.
+fn repeat_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+fn write_stored(out: &mut BitWriter, raw: &[u8], last: bool) {
+    let mut rest = raw;
+    loop {
+        let (chunk, tail) = rest.split_at(rest.len().min(MAX_STORED));
+        out.write(u32::from(last && tail.is_empty()), 1);
+        out.write(0, 2);
+        out.align();
+        let len = chunk.len() as u16;
+        out.bytes.extend_from_slice(&len.to_le_bytes());
+        out.bytes.extend_from_slice(&(!len).to_le_bytes());
+        out.bytes.extend_from_slice(chunk);
+        rest = tail;
+        if rest.is_empty() {
+            return;
+        }
+    }
+}
+
+/// Writes `tokens`, which encode `raw`, as whichever block type comes out
+/// smallest.
+fn write_block(out: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let mut literal_freqs = [0u32; 286];
+    let mut distance_freqs = [0u32; 30];
+    let mut extra = 0u64;
+    for &token in tokens {
+        match token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match { len, dist } => {
+                let l = length_code(len);
+                let d = dist_code(dist);
+                literal_freqs[257 + l] += 1;
+                distance_freqs[d] += 1;
+                extra += u64::from(LENGTH_EXTRA[l] + DIST_EXTRA[d]);
+            }
+        }
+    }
+    literal_freqs[END_OF_BLOCK] = 1;
+
+    let literal = Code::optimal(&literal_freqs, 15);
+    let distance = Code::optimal(&distance_freqs, 15);
+    let header = DynamicHeader::new(&literal, &distance);
+    let dynamic = header.cost() + literal.cost(&literal_freqs) + distance.cost(&distance_freqs) + extra;
+
+    let (fixed_literal, fixed_distance) = Code::fixed();
+    let fixed = fixed_literal.cost(&literal_freqs) + fixed_distance.cost(&distance_freqs) + extra;
+
+    // Each stored block costs its header, padding and two length words.
+    let stored = 8 * raw.len() as u64 + 40 * (raw.len() / MAX_STORED + 1) as u64;
+
+    if !raw.is_empty() && stored < fixed.min(dynamic) {
+        write_stored(out, raw, last);
+    } else if fixed <= dynamic {
+        out.write(u32::from(last), 1);
+        out.write(1, 2);
+        write_tokens(out, tokens, &fixed_literal, &fixed_distance);
+    } else {
+        out.write(u32::from(last), 1);
+        out.write(2, 2);
+        header.write(out);
+        write_tokens(out, tokens, &literal, &distance);
+    }
+}
+
+fn write_tokens(out: &mut BitWriter, tokens: &[Token], literal: &Code, distance: &Code) {
+    for &token in tokens {
+        match token {
+            Token::Literal(byte) => literal.put(out, byte as usize),
+            Token::Match { len, dist } => {
+                let l = length_code(len);
+                literal.put(out, 257 + l);
+                out.write(u32::from(len - LENGTH_BASE[l]), u32::from(LENGTH_EXTRA[l]));
+                let d = dist_code(dist);
+                distance.put(out, d);
+                out.write(u32::from(dist - DIST_BASE[d]), u32::from(DIST_EXTRA[d]));
+            }
+        }
+    }
+    literal.put(out, END_OF_BLOCK);
+}
+
+/// Compresses into raw DEFLATE data.
+pub struct Deflate<W: Write> {
+    inner: W,
+    level: u32,
+    effort: Effort,
+    /// The last window of input already compressed, then pending input.
+    data: Vec<u8>,
+    /// Stream offset of `data[0]`.
+    base: usize,
+    /// Stream offset of the next byte to compress.
+    pos: usize,
+    /// Stream offset where the block being gathered starts.
+    block_start: usize,
+    /// For each hash of three bytes, one plus the offset where they were
+    /// last seen; zero if never.
+    head: Vec<usize>,
+    /// For each offset in the window, one plus the previous offset with the
+    /// same hash.
+    prev: Vec<usize>,
+    tokens: Vec<Token>,
+    out: BitWriter,
+}
+
+impl<W: Write> Deflate<W> {
+    /// `level` runs from 0 (no compression) to 9 (smallest output, most
+    /// CPU); 6 is the customary default. Higher values are taken as 9.
+    pub fn new(inner: W, level: u32) -> Self {
+        Deflate {
+            inner,
+            level: level.min(9),
+            effort: Effort::for_level(level),
+            data: Vec::new(),
+            base: 0,
+            pos: 0,
+            block_start: 0,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+            tokens: Vec::new(),
+            out: BitWriter {
+                bytes: Vec::new(),
+                bits: 0,
+                count: 0,
+            },
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Compresses whatever input remains, ends the stream and returns the
+    /// inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress(self.end());
+        self.end_block(true);
+        self.out.align();
+        self.drain()?;
+        Ok(self.inner)
+    }
+
+    fn end(&self) -> usize {
+        self.base + self.data.len()
+    }
+
+    /// Compresses input up to at least offset `limit` into blocks.
+    fn compress(&mut self, limit: usize) {
+        if self.level == 0 {
+            self.pos = limit;
+        }
+        while self.pos < limit {
+            let pos = self.pos;
+            let (len, dist) = self.longest_match(pos);
+            if len < MIN_MATCH {
+                self.tokens.push(Token::Literal(self.data[pos - self.base]));
+                self.insert(pos);
+                self.pos += 1;
+            } else {
+                self.insert(pos);
+                if self.effort.lazy && len < self.effort.nice && self.longest_match(pos + 1).0 > len {
+                    // The next byte starts a longer match; take this one
+                    // as a literal and try again from there.
+                    self.tokens.push(Token::Literal(self.data[pos - self.base]));
+                    self.pos += 1;
+                    continue;
+                }
+                self.tokens.push(Token::Match {
+                    len: len as u16,
+                    dist: dist as u16,
+                });
+                for p in pos + 1..pos + len {
+                    self.insert(p);
+                }
+                self.pos += len;
+            }
+            if self.tokens.len() >= MAX_TOKENS {
+                self.end_block(false);
+            }
+        }
+        self.end_block(false);
+
+        // Keep one window of history for later matches to refer back to.
+        let keep = self.pos.saturating_sub(WINDOW_SIZE);
+        if keep - self.base >= WINDOW_SIZE {
+            self.data.drain(..keep - self.base);
+            self.base = keep;
+        }
+    }
+
+    fn end_block(&mut self, last: bool) {
+        if self.pos == self.block_start && !last {
+            return;
+        }
+        let raw = &self.data[self.block_start - self.base..self.pos - self.base];
+        if self.level == 0 {
+            write_stored(&mut self.out, raw, last);
+        } else {
+            write_block(&mut self.out, &self.tokens, raw, last);
+        }
+        self.tokens.clear();
+        self.block_start = self.pos;
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let at = pos - self.base;
+        let key = u32::from_le_bytes([self.data[at], self.data[at + 1], self.data[at + 2], 0]);
+        (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.end() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = pos + 1;
+    }
+
+    /// The longest earlier occurrence of the bytes at `pos`, as a length
+    /// and distance; a length below `MIN_MATCH` means none worth using.
+    fn longest_match(&self, pos: usize) -> (usize, usize) {
+        let max = (self.end() - pos).min(MAX_MATCH);
+        if max < MIN_MATCH {
+            return (0, 0);
+        }
+
+        let at = pos - self.base;
+        let target = &self.data[at..at + max];
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = self.effort.chain;
+        while candidate > 0 && chain > 0 {
+            let start = candidate - 1;
+            if start >= pos || pos - start > WINDOW_SIZE || start < self.base {
+                break;
+            }
+            let from = start - self.base;
+            if best.0 == 0 || self.data[from + best.0] == target[best.0] {
+                let len = self.data[from..from + max].iter().zip(target).take_while(|(a, b)| a == b).count();
+                if len > best.0 {
+                    best = (len, pos - start);
+                    if len >= self.effort.nice || len == max {
+                        break;
+                    }
+                }
+            }
+            // Slots are reused as the window moves on; a link pointing
+            // forward belongs to a newer offset and ends the chain.
+            let next = self.prev[start % WINDOW_SIZE];
+            if next > start {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        best
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out.bytes)?;
+        self.out.bytes.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Deflate<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        if self.end() - self.pos >= INPUT_CHUNK + MAX_MATCH {
+            // Hold back enough input that matches are not cut short.
+            self.compress(self.end() - MAX_MATCH);
+            self.drain()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress(self.end());
+        // An empty stored block byte-aligns everything written so far.
+        write_stored(&mut self.out, &[], false);
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+/// Compresses into a gzip member.
+pub struct GzipEncoder<W: Write> {
+    deflate: Deflate<W>,
+    crc: Crc32,
+    size: u32,
+    started: bool,
+}
+
+impl<W: Write> GzipEncoder<W> {
+    /// `level` is as for [`Deflate::new`].
+    pub fn new(inner: W, level: u32) -> Self {
+        GzipEncoder {
+            deflate: Deflate::new(inner, level),
+            crc: Crc32::new(),
+            size: 0,
+            started: false,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.deflate.get_mut()
+    }
+
+    /// Ends the member with its CRC and length and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start()?;
+        let mut inner = self.deflate.finish()?;
+        inner.write_all(&self.crc.value().to_le_bytes())?;
+        inner.write_all(&self.size.to_le_bytes())?;
+        Ok(inner)
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        let extra_flags = match self.deflate.level {
+            9 => 2,
+            1 => 4,
+            _ => 0,
+        };
+        // No name or timestamp; operating system 3 is Unix.
+        self.deflate.get_mut().write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, extra_flags, 3])
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.start()?;
+        let n = self.deflate.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.size = self.size.wrapping_add(n as u32);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.start()?;
+        self.deflate.flush()
+    }
+}
+
+/// Compresses into a zlib stream.
+pub struct ZlibEncoder<W: Write> {
+    deflate: Deflate<W>,
+    adler: Adler32,
+    started: bool,
+}
+
+impl<W: Write> ZlibEncoder<W> {
+    /// `level` is as for [`Deflate::new`].
+    pub fn new(inner: W, level: u32) -> Self {
+        ZlibEncoder {
+            deflate: Deflate::new(inner, level),
+            adler: Adler32::new(),
+            started: false,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.deflate.get_mut()
+    }
+
+    /// Ends the stream with its Adler-32 and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start()?;
+        let mut inner = self.deflate.finish()?;
+        inner.write_all(&self.adler.value().to_be_bytes())?;
+        Ok(inner)
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        // A 32 KiB window, and a hint of the level in the second byte,
+        // whose low bits make the pair a multiple of 31.
+        let header: [u8; 2] = match self.deflate.level {
+            0 | 1 => [0x78, 0x01],
+            2..=5 => [0x78, 0x5e],
+            6 => [0x78, 0x9c],
+            _ => [0x78, 0xda],
+        };
+        self.deflate.get_mut().write_all(&header)
+    }
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.start()?;
+        let n = self.deflate.write(buf)?;
+        self.adler.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.start()?;
+        self.deflate.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::util::inflate::{GzipDecoder, Inflate, ZlibDecoder};
+
+    /// Deterministic inputs: empty, tiny, repetitive, incompressible and
+    /// long enough for matches to reach across the whole window.
+    fn samples() -> Vec<Vec<u8>> {
+        let mut noise = Vec::with_capacity(70_000);
+        let mut state = 0x2545F491u32;
+        for _ in 0..70_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            noise.push(state as u8);
+        }
+        let mut mixed = Vec::new();
+        for i in 0..20_000 {
+            mixed.extend_from_slice(format!("line {} of {}\n", i % 977, i % 13).as_bytes());
+        }
+        mixed.extend_from_slice(&noise[..5_000]);
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"hello, hello, hello world\n".to_vec(),
+            vec![0; 100_000],
+            noise,
+            mixed,
+        ]
+    }
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = Deflate::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Inflate::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trips_at_every_level() {
+        for sample in samples() {
+            for level in 0..=9 {
+                let compressed = deflate(&sample, level);
+                assert!(inflate(&compressed) == sample, "level {} lost {} bytes of input", level, sample.len());
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_redundant_input() {
+        let zeros = vec![0; 100_000];
+        assert!(deflate(&zeros, 1).len() < 1_000);
+        assert!(deflate(&zeros, 9).len() < 1_000);
+        // Level 0 stores, at five bytes a block.
+        assert!(deflate(&zeros, 0).len() <= zeros.len() + zeros.len() / 1000 + 10);
+    }
+
+    #[test]
+    fn stores_incompressible_input() {
+        let noise = samples().swap_remove(4);
+        assert!(deflate(&noise, 9).len() <= noise.len() + noise.len() / 1000 + 10);
+    }
+
+    #[test]
+    fn flush_makes_output_so_far_decodable() {
+        let mut encoder = Deflate::new(Vec::new(), 6);
+        encoder.write_all(b"first part, ").unwrap();
+        encoder.flush().unwrap();
+        let mut flushed = encoder.get_mut().clone();
+        assert!(flushed.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+        // Ended there by an empty final block, it is a whole stream.
+        flushed.extend_from_slice(&[0x03, 0x00]);
+        assert_eq!(inflate(&flushed), b"first part, ");
+
+        encoder.write_all(b"second part").unwrap();
+        assert_eq!(inflate(&encoder.finish().unwrap()), b"first part, second part");
+    }
+
+    #[test]
+    fn gzip_round_trips_with_header_and_trailer() {
+        let text = b"hello, hello, hello world\n";
+        let mut encoder = GzipEncoder::new(Vec::new(), 6);
+        encoder.write_all(text).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(gzip[..3], [0x1F, 0x8B, 0x08]);
+        // The trailer holds CRC-32 and length, little-endian.
+        assert_eq!(gzip[gzip.len() - 8..gzip.len() - 4], 0x2B465D87u32.to_le_bytes());
+        assert_eq!(gzip[gzip.len() - 4..], (text.len() as u32).to_le_bytes());
+
+        let mut out = Vec::new();
+        GzipDecoder::new(&gzip[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn zlib_round_trips_with_header_and_trailer() {
+        let text = b"hello, hello, hello world\n";
+        let mut encoder = ZlibEncoder::new(Vec::new(), 6);
+        encoder.write_all(text).unwrap();
+        let zlib = encoder.finish().unwrap();
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        assert_eq!(zlib[zlib.len() - 4..], 0x7DAE0927u32.to_be_bytes());
+
+        let mut out = Vec::new();
+        ZlibDecoder::new([zlib[0], zlib[1]], &zlib[2..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, text);
+    }
+}
@@ -0,0 +1,572 @@
+//! DEFLATE decompression (RFC 1951), with the gzip (RFC 1952) and zlib
+//! (RFC 1950) wrappers.
+//!
+//! Each decoder is a [`Read`] adapter producing output as it is read, so
+//! callers can stop as soon as the output exceeds a limit. Corrupt input
+//! fails with `InvalidData`, truncated input with `UnexpectedEof`.
+
+use std::io::{self, ErrorKind, Read};
+
+use crate::util::checksum::{Adler32, Crc32};
+
+/// Distances reach back at most this far into the output.
+pub(super) const WINDOW_SIZE: usize = 32 * 1024;
+
+pub(super) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+pub(super) const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+pub(super) const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+pub(super) const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order in which code length code lengths are sent in a dynamic block.
+pub(super) const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads bits least significant first, as DEFLATE packs them.
+struct BitReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: vec![0; 8 * 1024].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        if self.pos == self.len {
+            self.len = loop {
+                match self.inner.read(&mut self.buf) {
+                    Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            self.pos = 0;
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    /// The next `n` bits, at most 16.
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            self.bits |= u32::from(self.byte()?) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the rest of the current byte.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    fn read_exact(&mut self, out: &mut [u8]) -> io::Result<()> {
+        self.align();
+        for byte in out {
+            *byte = self.byte()?;
+        }
+        Ok(())
+    }
+}
+
+/// A canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    /// Number of codes of each length.
+    counts: [u16; 16],
+    /// Symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err(invalid("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode<R: Read>(&self, input: &mut BitReader<R>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid Huffman code"))
+    }
+}
+
+enum State {
+    BlockHeader,
+    Stored(usize),
+    Compressed,
+    Done,
+}
+
+enum Symbol {
+    Literal(u8),
+    EndOfBlock,
+    /// Copy this many bytes from this far back.
+    Copy(usize, usize),
+}
+
+/// Decompresses a raw DEFLATE stream.
+pub struct Inflate<R> {
+    input: BitReader<R>,
+    state: State,
+    last_block: bool,
+    /// Literal/length and distance codes of the current compressed block.
+    tables: Option<Box<(Huffman, Huffman)>>,
+    window: Box<[u8]>,
+    /// Total bytes produced.
+    produced: usize,
+    /// A back-reference still being copied: length left and distance.
+    copy: (usize, usize),
+}
+
+impl<R: Read> Inflate<R> {
+    pub fn new(input: R) -> Self {
+        Inflate {
+            input: BitReader::new(input),
+            state: State::BlockHeader,
+            last_block: false,
+            tables: None,
+            window: vec![0; WINDOW_SIZE].into_boxed_slice(),
+            produced: 0,
+            copy: (0, 0),
+        }
+    }
+
+    /// Reads bytes following the end of the compressed stream, such as a
+    /// wrapper's trailer.
+    fn read_trailer(&mut self, out: &mut [u8]) -> io::Result<()> {
+        self.input.read_exact(out)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.window[self.produced % WINDOW_SIZE] = byte;
+        self.produced += 1;
+    }
+
+    fn begin_block(&mut self) -> io::Result<()> {
+        if self.last_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+        self.last_block = self.input.bits(1)? == 1;
+        self.state = match self.input.bits(2)? {
+            0 => {
+                let mut header = [0u8; 4];
+                self.input.read_exact(&mut header)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(invalid("stored block length mismatch"));
+                }
+                State::Stored(len as usize)
+            }
+            1 => {
+                self.tables = Some(Box::new(fixed_tables()?));
+                State::Compressed
+            }
+            2 => {
+                self.tables = Some(Box::new(self.dynamic_tables()?));
+                State::Compressed
+            }
+            _ => return Err(invalid("invalid block type")),
+        };
+        Ok(())
+    }
+
+    fn dynamic_tables(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let literals = self.input.bits(5)? as usize + 257;
+        let distances = self.input.bits(5)? as usize + 1;
+        let code_lengths = self.input.bits(4)? as usize + 4;
+        if literals > 286 || distances > 30 {
+            return Err(invalid("too many codes"));
+        }
+
+        let mut lengths = [0u8; 19];
+        for &index in &CODE_LENGTH_ORDER[..code_lengths] {
+            lengths[index] = self.input.bits(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&lengths)?;
+
+        let mut lengths = vec![0u8; literals + distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = code_length_code.decode(&mut self.input)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths[..i].last().ok_or_else(|| invalid("repeat with no previous length"))?;
+                    (previous, 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid("too many code lengths"));
+            }
+            lengths[i..i + repeat].fill(value);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(invalid("no end-of-block code"));
+        }
+
+        Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+    }
+
+}
+
+/// Decodes the next symbol of a compressed block.
+fn decode_symbol<R: Read>(input: &mut BitReader<R>, tables: &(Huffman, Huffman)) -> io::Result<Symbol> {
+    let symbol = tables.0.decode(input)? as usize;
+    if symbol < 256 {
+        return Ok(Symbol::Literal(symbol as u8));
+    }
+    if symbol == 256 {
+        return Ok(Symbol::EndOfBlock);
+    }
+
+    let index = symbol - 257;
+    if index >= LENGTH_BASE.len() {
+        return Err(invalid("invalid length code"));
+    }
+    let len = LENGTH_BASE[index] as usize + input.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+
+    let index = tables.1.decode(input)? as usize;
+    if index >= DIST_BASE.len() {
+        return Err(invalid("invalid distance code"));
+    }
+    let dist = DIST_BASE[index] as usize + input.bits(u32::from(DIST_EXTRA[index]))? as usize;
+    Ok(Symbol::Copy(len, dist))
+}
+
+fn fixed_tables() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < out.len() {
+            if self.copy.0 > 0 {
+                let (len, dist) = self.copy;
+                let byte = self.window[(self.produced - dist) % WINDOW_SIZE];
+                self.push(byte);
+                out[n] = byte;
+                n += 1;
+                self.copy = (len - 1, dist);
+                continue;
+            }
+
+            match &mut self.state {
+                State::Done => break,
+                State::BlockHeader => self.begin_block()?,
+                State::Stored(0) => self.state = State::BlockHeader,
+                State::Stored(remaining) => {
+                    *remaining -= 1;
+                    let byte = self.input.byte()?;
+                    self.push(byte);
+                    out[n] = byte;
+                    n += 1;
+                }
+                State::Compressed => {
+                    let tables = self.tables.as_deref().expect("compressed block without tables");
+                    match decode_symbol(&mut self.input, tables)? {
+                        Symbol::Literal(byte) => {
+                            self.push(byte);
+                            out[n] = byte;
+                            n += 1;
+                        }
+                        Symbol::EndOfBlock => self.state = State::BlockHeader,
+                        Symbol::Copy(_, dist) if dist > self.produced => {
+                            return Err(invalid("distance too far back"));
+                        }
+                        Symbol::Copy(len, dist) => self.copy = (len, dist),
+                    }
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Decompresses a gzip member, checking its CRC and length.
+pub struct GzipDecoder<R> {
+    inflate: Inflate<R>,
+    crc: Crc32,
+    checked: bool,
+}
+
+impl<R: Read> GzipDecoder<R> {
+    /// Reads the gzip header from `input`.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 10];
+        input.read_exact(&mut header)?;
+        if header[..3] != [0x1f, 0x8b, 8] {
+            return Err(invalid("not a gzip stream"));
+        }
+        let flags = header[3];
+        if flags & 0x04 != 0 {
+            let mut len = [0u8; 2];
+            input.read_exact(&mut len)?;
+            io::copy(&mut (&mut input).take(u16::from_le_bytes(len).into()), &mut io::sink())?;
+        }
+        // Original file name and comment, each zero-terminated.
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                let mut byte = [0u8];
+                loop {
+                    input.read_exact(&mut byte)?;
+                    if byte[0] == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        if flags & 0x02 != 0 {
+            input.read_exact(&mut [0u8; 2])?;
+        }
+
+        Ok(GzipDecoder {
+            inflate: Inflate::new(input),
+            crc: Crc32::new(),
+            checked: false,
+        })
+    }
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inflate.read(out)?;
+        self.crc.update(&out[..n]);
+        if n == 0 && !out.is_empty() && !self.checked {
+            let mut trailer = [0u8; 8];
+            self.inflate.read_trailer(&mut trailer)?;
+            let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+            if crc != self.crc.value() || size != self.inflate.produced as u32 {
+                return Err(invalid("gzip checksum mismatch"));
+            }
+            self.checked = true;
+        }
+        Ok(n)
+    }
+}
+
+/// Decompresses a zlib stream, checking its Adler-32.
+pub struct ZlibDecoder<R> {
+    inflate: Inflate<R>,
+    adler: Adler32,
+    checked: bool,
+}
+
+impl<R: Read> ZlibDecoder<R> {
+    /// Takes the two header bytes, already read from the front of `input`.
+    pub fn new(header: [u8; 2], input: R) -> io::Result<Self> {
+        if !is_zlib_header(header) {
+            return Err(invalid("not a zlib stream"));
+        }
+        if header[1] & 0x20 != 0 {
+            return Err(invalid("zlib preset dictionaries are not supported"));
+        }
+        Ok(ZlibDecoder {
+            inflate: Inflate::new(input),
+            adler: Adler32::new(),
+            checked: false,
+        })
+    }
+}
+
+/// Whether `header` starts a zlib stream rather than raw DEFLATE data,
+/// which some clients send as `Content-Encoding: deflate` instead.
+pub fn is_zlib_header(header: [u8; 2]) -> bool {
+    header[0] & 0x0f == 8 && header[0] >> 4 <= 7 && u16::from_be_bytes(header).is_multiple_of(31)
+}
+
+impl<R: Read> Read for ZlibDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inflate.read(out)?;
+        self.adler.update(&out[..n]);
+        if n == 0 && !out.is_empty() && !self.checked {
+            let mut trailer = [0u8; 4];
+            self.inflate.read_trailer(&mut trailer)?;
+            if u32::from_be_bytes(trailer) != self.adler.value() {
+                return Err(invalid("zlib checksum mismatch"));
+            }
+            self.checked = true;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "hello, hello, hello world\n" compressed by Python's zlib.
+    const TEXT: &[u8] = b"hello, hello, hello world\n";
+    const RAW: &[u8] = &[
+        0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8, 0x40, 0xA2, 0x14, 0xCA, 0xF3, 0x8B, 0x72, 0x52, 0xB8, 0x00,
+    ];
+    const GZIP: &[u8] = &[
+        0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8,
+        0x40, 0xA2, 0x14, 0xCA, 0xF3, 0x8B, 0x72, 0x52, 0xB8, 0x00, 0x87, 0x5D, 0x46, 0x2B, 0x1A, 0x00, 0x00, 0x00,
+    ];
+    const ZLIB: &[u8] = &[
+        0x78, 0x9C, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8, 0x40, 0xA2, 0x14, 0xCA, 0xF3, 0x8B, 0x72, 0x52,
+        0xB8, 0x00, 0x7D, 0xAE, 0x09, 0x27,
+    ];
+
+    /// A dynamic Huffman block: `pangrams()` Huffman coded without matches.
+    const DYNAMIC: &[u8] = &[
+        0x05, 0xC1, 0x89, 0x5A, 0x82, 0x40, 0x14, 0x06, 0xD0, 0x57, 0xF9, 0x9F, 0x80, 0xCF, 0x5C, 0x32,
+        0xB5, 0xAC, 0xAC, 0x6C, 0x31, 0x5B, 0xA9, 0x34, 0xB5, 0x62, 0xB9, 0x30, 0xCC, 0x00, 0x17, 0x06,
+        0x86, 0xED, 0xE9, 0x3B, 0xC7, 0x16, 0x84, 0xDC, 0x44, 0x9E, 0x82, 0xAB, 0xB9, 0x4E, 0x11, 0x70,
+        0x03, 0x69, 0x92, 0xAC, 0x00, 0x57, 0xA4, 0x51, 0x0A, 0x42, 0xEC, 0x74, 0x2D, 0x7C, 0x0E, 0x2D,
+        0xD8, 0x82, 0x90, 0x9B, 0xC8, 0x53, 0x70, 0x35, 0xD7, 0x29, 0x02, 0x6E, 0x20, 0x4D, 0x92, 0x15,
+        0xE0, 0x8A, 0x34, 0x4A, 0x41, 0x88, 0x9D, 0xAE, 0x85, 0xCF, 0xA1, 0x05, 0x5B, 0x10, 0x72, 0x13,
+        0x79, 0x0A, 0xAE, 0xE6, 0x3A, 0x45, 0xC0, 0x0D, 0xA4, 0x49, 0xB2, 0x02, 0x5C, 0x91, 0x46, 0x29,
+        0x08, 0xB1, 0xD3, 0xB5, 0xF0, 0x39, 0xB4, 0xD0, 0x3B, 0xEA, 0x0F, 0x86, 0xA3, 0xE3, 0xF1, 0xC9,
+        0x64, 0x3A, 0x3B, 0x3D, 0x9B, 0x9F, 0x5F, 0x5C, 0x2E, 0xAE, 0xAE, 0x6F, 0x96, 0xB7, 0x77, 0xF7,
+        0x0F, 0xAB, 0xC7, 0xF5, 0xD3, 0xF3, 0xCB, 0xEB, 0xDB, 0xBB, 0xFD, 0xF1, 0xF9, 0xB5, 0xD9, 0x7E,
+        0xEF, 0xF6, 0x87, 0x9F, 0xDF, 0x3F, 0xC7, 0xF5, 0x7C, 0x0A, 0x42, 0x11, 0x49, 0x15, 0x27, 0x29,
+        0x67, 0xB9, 0x2E, 0x4A, 0x53, 0xD5, 0x4D, 0xDB, 0xFD, 0x03,
+    ];
+
+    fn pangrams() -> Vec<u8> {
+        let mut text = b"The quick brown fox jumps over the lazy dog. ".repeat(3);
+        text.extend(b'0'..b'{');
+        text
+    }
+
+    fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Inflate::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        GzipDecoder::new(data)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn unzlib(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ZlibDecoder::new([data[0], data[1]], &data[2..])?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn decodes_fixed_blocks() {
+        assert_eq!(inflate(RAW).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn decodes_stored_blocks() {
+        assert_eq!(inflate(&[0x01, 0x06, 0x00, 0xF9, 0xFF, b's', b't', b'o', b'r', b'e', b'd']).unwrap(), b"stored");
+        // LEN must be the complement of NLEN.
+        let err = inflate(&[0x01, 0x06, 0x00, 0xF8, 0xFF, b's', b't', b'o', b'r', b'e', b'd']).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decodes_dynamic_blocks() {
+        assert_eq!(DYNAMIC[0] >> 1 & 3, 2);
+        assert_eq!(inflate(DYNAMIC).unwrap(), pangrams());
+    }
+
+    #[test]
+    fn decodes_gzip_and_zlib() {
+        assert_eq!(gunzip(GZIP).unwrap(), TEXT);
+        assert!(is_zlib_header([ZLIB[0], ZLIB[1]]));
+        assert!(!is_zlib_header([RAW[0], RAW[1]]));
+        assert_eq!(unzlib(ZLIB).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut gzip = GZIP.to_vec();
+        gzip[28] ^= 1;
+        assert_eq!(gunzip(&gzip).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut gzip = GZIP.to_vec();
+        gzip[32] ^= 1;
+        assert_eq!(gunzip(&gzip).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut zlib = ZLIB.to_vec();
+        *zlib.last_mut().unwrap() ^= 1;
+        assert_eq!(unzlib(&zlib).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert_eq!(inflate(&RAW[..RAW.len() - 2]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(gunzip(&GZIP[..GZIP.len() - 3]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(gunzip(&GZIP[..5]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_reserved_block_type() {
+        assert_eq!(inflate(&[0x07, 0x00]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
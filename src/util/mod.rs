@@ -0,0 +1,17 @@
+//! Small self-contained helpers: encoders, hashing, signature checks and
+//! checksums, compression and decompression, timestamp formatting,
+//! percent-decoding, temporary files, and JSON and TOML subset parsers.
+
+pub mod base64;
+pub mod bcrypt;
+pub mod checksum;
+pub mod deflate;
+pub mod inflate;
+pub mod json;
+pub mod percent;
+pub mod rsa;
+pub mod sha1;
+pub mod sha256;
+pub mod tempfile;
+pub mod time;
+pub mod toml;
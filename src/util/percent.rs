@@ -0,0 +1,71 @@
+//! Percent-encoding and -decoding (RFC 3986 §2.1) of URL components.
+
+/// Escapes every byte of `input` but the unreserved characters, so the
+/// result can stand as a single path segment or query value.
+pub fn encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decodes `%XX` escapes in `input`. Fails on a malformed escape, or when
+/// the result is not UTF-8.
+pub fn decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            out.push(escaped_byte(&bytes[i + 1..])?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Decodes `%XX` escapes in `input`, and `+` as a space when `plus_as_space`
+/// is set, as in query strings and form bodies. Malformed escapes are kept
+/// as they are and invalid UTF-8 is replaced, so decoding never fails.
+pub fn decode_lossy(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match escaped_byte(&bytes[i + 1..]) {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                None => out.push(b'%'),
+            },
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    match String::from_utf8(out) {
+        Ok(s) => s,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+/// The byte encoded by the two hex digits at the front of `rest`.
+fn escaped_byte(rest: &[u8]) -> Option<u8> {
+    let hex = rest.get(..2)?;
+    let digits = std::str::from_utf8(hex).ok()?;
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(digits, 16).ok()
+}
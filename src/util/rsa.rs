@@ -0,0 +1,257 @@
+//! RSA signature verification, RSASSA-PKCS1-v1_5 with SHA-256 (RFC 8017
+//! §8.2.2), on big-endian public key components. Only public operations
+//! are done, so nothing here needs to run in constant time.
+
+use crate::util::sha256::sha256;
+
+/// The DER DigestInfo header preceding a SHA-256 digest in a signature.
+const SHA256_DIGEST_INFO: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0D, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+];
+
+/// Whether `signature` is `message` signed with SHA-256 and the private
+/// key of the public key with modulus `n` and exponent `e`.
+pub fn verify_sha256(n: &[u8], e: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let n = trim(n);
+    let e = trim(e);
+    // The modulus is odd for any real key, which Montgomery reduction
+    // relies on; the signature is exactly as long as the modulus.
+    if n.last().is_none_or(|b| b & 1 == 0) || e.is_empty() || signature.len() != n.len() {
+        return false;
+    }
+    let modulus = Uint::from_be_bytes(n, n.len());
+    let s = Uint::from_be_bytes(signature, n.len());
+    if !s.less_than(&modulus) {
+        return false;
+    }
+    let decrypted = Montgomery::new(modulus).pow(&s, e).to_be_bytes(n.len());
+
+    let digest = sha256(message);
+    let padding = n.len().checked_sub(3 + SHA256_DIGEST_INFO.len() + digest.len());
+    let Some(padding) = padding.filter(|&padding| padding >= 8) else {
+        return false;
+    };
+    let mut expected = Vec::with_capacity(n.len());
+    expected.extend_from_slice(&[0x00, 0x01]);
+    expected.resize(2 + padding, 0xFF);
+    expected.push(0x00);
+    expected.extend_from_slice(SHA256_DIGEST_INFO);
+    expected.extend_from_slice(&digest);
+    decrypted == expected
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    &bytes[zeros..]
+}
+
+/// An unsigned integer of a fixed number of 32-bit limbs, least
+/// significant first.
+#[derive(Clone, Debug)]
+struct Uint(Vec<u32>);
+
+impl Uint {
+    /// `bytes`, big-endian, in enough limbs for `width` bytes.
+    fn from_be_bytes(bytes: &[u8], width: usize) -> Uint {
+        let mut limbs = vec![0u32; width.div_ceil(4)];
+        for (i, &byte) in bytes.iter().rev().enumerate() {
+            limbs[i / 4] |= u32::from(byte) << (8 * (i % 4));
+        }
+        Uint(limbs)
+    }
+
+    fn to_be_bytes(&self, width: usize) -> Vec<u8> {
+        (0..width).rev().map(|i| (self.0[i / 4] >> (8 * (i % 4))) as u8).collect()
+    }
+
+    fn less_than(&self, other: &Uint) -> bool {
+        for (a, b) in self.0.iter().rev().zip(other.0.iter().rev()) {
+            if a != b {
+                return a < b;
+            }
+        }
+        false
+    }
+
+    /// Subtracts `other`, wrapping around on underflow.
+    fn sub_assign(&mut self, other: &Uint) {
+        let mut borrow = false;
+        for (a, &b) in self.0.iter_mut().zip(&other.0) {
+            let (diff, under1) = a.overflowing_sub(b);
+            let (diff, under2) = diff.overflowing_sub(u32::from(borrow));
+            *a = diff;
+            borrow = under1 || under2;
+        }
+    }
+}
+
+/// Arithmetic modulo an odd modulus in Montgomery form, where `x` is held
+/// as `x·R mod n` with `R = 2^(32·limbs)`.
+struct Montgomery {
+    n: Uint,
+    /// `-n⁻¹ mod 2^32`.
+    n_inv: u32,
+    /// `R² mod n`, for converting into Montgomery form.
+    r2: Uint,
+}
+
+impl Montgomery {
+    fn new(n: Uint) -> Montgomery {
+        // Newton's iteration doubles the correct low bits each step.
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(n.0[0].wrapping_mul(inv)));
+        }
+
+        // R² mod n by doubling 1 modulo n, 2·32·limbs times.
+        let limbs = n.0.len();
+        let mut r2 = Uint(vec![0; limbs]);
+        r2.0[0] = 1;
+        for _ in 0..2 * 32 * limbs {
+            let carry = r2.0[limbs - 1] >> 31;
+            for i in (1..limbs).rev() {
+                r2.0[i] = (r2.0[i] << 1) | (r2.0[i - 1] >> 31);
+            }
+            r2.0[0] <<= 1;
+            if carry == 1 || !r2.less_than(&n) {
+                r2.sub_assign(&n);
+            }
+        }
+
+        Montgomery { n, n_inv: inv.wrapping_neg(), r2 }
+    }
+
+    /// `a·b·R⁻¹ mod n`, by coarsely integrated operand scanning.
+    fn mul(&self, a: &Uint, b: &Uint) -> Uint {
+        let s = self.n.0.len();
+        let n = &self.n.0;
+        let mut t = vec![0u32; s + 2];
+        for &bi in &b.0 {
+            let mut carry = 0u64;
+            for (tj, &aj) in t.iter_mut().zip(&a.0) {
+                let sum = u64::from(*tj) + u64::from(aj) * u64::from(bi) + carry;
+                *tj = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[s]) + carry;
+            t[s] = sum as u32;
+            t[s + 1] = (sum >> 32) as u32;
+
+            let m = t[0].wrapping_mul(self.n_inv);
+            let mut carry = (u64::from(t[0]) + u64::from(m) * u64::from(n[0])) >> 32;
+            for j in 1..s {
+                let sum = u64::from(t[j]) + u64::from(m) * u64::from(n[j]) + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[s]) + carry;
+            t[s - 1] = sum as u32;
+            t[s] = t[s + 1] + (sum >> 32) as u32;
+        }
+
+        let overflow = t[s] != 0;
+        t.truncate(s);
+        let mut result = Uint(t);
+        if overflow || !result.less_than(&self.n) {
+            result.sub_assign(&self.n);
+        }
+        result
+    }
+
+    /// `base^exponent mod n`, the exponent big-endian.
+    fn pow(&self, base: &Uint, exponent: &[u8]) -> Uint {
+        let base = self.mul(base, &self.r2);
+        let mut one = Uint(vec![0; self.n.0.len()]);
+        one.0[0] = 1;
+        // 1 in Montgomery form.
+        let mut result = self.mul(&one, &self.r2);
+        for &byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.mul(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.mul(&result, &base);
+                }
+            }
+        }
+        self.mul(&result, &one)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys and signatures from Python's `cryptography`, over this message
+    /// with exponent 65537.
+    const MESSAGE: &[u8] = b"eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJhbGljZSJ9";
+    const E: &[u8] = &[0x01, 0x00, 0x01];
+    const N_2048: &str = "a4dd227cdecf611302b054902afb582bccbf1a0c93335a8165df5f56a5cdd862e406b1e115e86f0e27e376053ab89034\
+        47699a980f9f5ebc7bea2ce447cc5526c173aa2dcd7a29f566df46a3f335220008ff5a82449aaeb4e216946fd0a08b09\
+        731977430c23d328e2a5b059afc98a7424714504874581ba9d127944ad8c5a039a11c5bfdfb020581b0a8f070f149458\
+        7edf20e2fbeceac4e6e1367dadc36384d381812af1ea9403db6b16dec8213c257351cb342a2f576b51c4fee2be5f32a1\
+        3f836c144a6ab6826d18d76ac713d1688fc0bf7a8346654f574cd4c323ecc645e14732864bb602ad02d7a687ddadd8ac\
+        efc1982fb5fdd7e5399117cf1ea4b647";
+    const SIG_2048: &str = "573514789a2a7f655a020f68b2105d9c2a593b01943d254f96e439e83ff46740fdf026503f5ae0187a6d1caeab4dbcd8\
+        d600e85ec816eea6ac69ae856ed9c0a2a079d294d23c474795d296ba14090b22116c8eeded043df3f9b7bb5add79b062\
+        df889f3007a16e37fa65e36ed2c59d0db76acbc71115daa00ee728ce9bb10fa7d66bdd5644b30b4b1fcb99cd3e2b94e9\
+        b983953b06a36eb2b27889fe4d728683071432bf9abf862c06bc69ae52fd20bbbcc93b4a4e04088986dadae62bb4dd2c\
+        b7600edd0ba6c53acdad2a25c2e0459c07c1ede730e81f9e4ad77770102fbafdd1963fb86c68190e966c4c545b8c99fb\
+        f9628a66d5e85731196d4bb158bf9be4";
+
+    const N_3072: &str = "aaebcba9cccbbaf563ba074b8a8aed21847bd08f8a48464b814d2c70d94207b9582f55412def0f0ddb4b55b6e5b3e711\
+        80bc24db543eed6a6fa64eae1112524b964fe142cceca68e4545f76b4606d832f54517786cadb3e8d65e3c6d8f4f71cc\
+        fc52e9a9f494c5249159ecaafdad62d54fe0424a0cf145cd59d5a983505ee94dc2ad8b444aa3b7692b02de80f806b19c\
+        60bab9ce17ccdf0d924b6848b8bb6a6225e9f317970a9f113fa66b2e4086afe4dfeca62cb7dfc7bb1297347d184c213a\
+        7fcc664b14ba785d8c2631dee2cdb4cc38d8ecd3822c7d844030f2b6ac16d9bc7899192eb0e2a1fa429443a4da3a815a\
+        30a256e9ed74d99586cc42378548726206d5f37ede111ef09603b2d4452a3d9e34dc322e72108a099aa82fd4a04f416f\
+        dcf94e176b8baa7e62d5df8915c535a476e4ccc0c1b19366b86319a9a9dbaf3fce1b4e98769032a7767455ae8dde490b\
+        fd3416c5e86daebb70ff0917ea3577056f1fb2680c1d5035f31d8df3db63269858cbd1a5bfb5e451e090528c007ca2a9";
+    const SIG_3072: &str = "9bdf96590387b02a1c5e7a01cc69e23f685c3df5caf9080c51fe2e7b9118c8ec75bc6dad0e0932c0f5f63b22387f003d\
+        f6c1873b3285b6aafa2a9ad5bd962a037cb69baac66b487a232bb4d310ffd9f19ccabb442316a4325e4c31624940c383\
+        3841f528099a1c16438d475305e49801ac54671f94e9695597ab90621373c291aa0c0783ada2b77c8ee2687b1d10492b\
+        5f1acf7c15ae0fef33f1f002216cdc60fd14e77ac640743f5ecf16c95384fa7ee4a0492b3ad5ee316b08bc1437265777\
+        cbbecda54d2943f51d035c66889849f145a9f736c022f861df6138a0b4e09fee4c928c2519a21eb8c5003e325d3b903a\
+        f87a530b166652a716538a1c4090adb2bcb6bb3f360b69113308cd403466cdc00c59927a4277b5d1af4789da5795cf66\
+        492773dda32bdd9cc38d2c600f78ad26244d29aa731526595f36fccdfa97e20191d0b836a09fb3f9623169938bc6736b\
+        11b28d79e3f2f5bfa423ecb648b4069b0ec8c2243fc9a9a5ec54214c239935970fd5ec558744a1586d753f6c3dc8f7a4";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn verifies_known_signatures() {
+        assert!(verify_sha256(&hex(N_2048), E, MESSAGE, &hex(SIG_2048)));
+        assert!(verify_sha256(&hex(N_3072), E, MESSAGE, &hex(SIG_3072)));
+    }
+
+    #[test]
+    fn ignores_leading_zeros_in_key_components() {
+        let n = [&[0][..], &hex(N_2048)].concat();
+        assert!(verify_sha256(&n, &[0, 1, 0, 1], MESSAGE, &hex(SIG_2048)));
+    }
+
+    #[test]
+    fn rejects_altered_messages_and_signatures() {
+        let n = hex(N_2048);
+        let signature = hex(SIG_2048);
+        assert!(!verify_sha256(&n, E, b"eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJtYWxsb3J5In0", &signature));
+
+        let mut flipped = signature.clone();
+        flipped[100] ^= 1;
+        assert!(!verify_sha256(&n, E, MESSAGE, &flipped));
+        assert!(!verify_sha256(&n, E, MESSAGE, &signature[1..]));
+        assert!(!verify_sha256(&n, &[0x03], MESSAGE, &signature));
+        // Another key's signature, and one no smaller than the modulus.
+        assert!(!verify_sha256(&n, E, MESSAGE, &hex(SIG_3072)[..256]));
+        assert!(!verify_sha256(&n, E, MESSAGE, &n));
+    }
+
+    #[test]
+    fn rejects_even_or_empty_key_components() {
+        let mut even = hex(N_2048);
+        *even.last_mut().unwrap() &= 0xFE;
+        assert!(!verify_sha256(&even, E, MESSAGE, &hex(SIG_2048)));
+        assert!(!verify_sha256(&hex(N_2048), &[], MESSAGE, &hex(SIG_2048)));
+    }
+}
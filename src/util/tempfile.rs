@@ -0,0 +1,35 @@
+//! Anonymous temporary files.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::fd::FromRawFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+/// Creates a file in `dir` that has no name, so it disappears once closed,
+/// even if the process dies first. Uses O_TMPFILE, falling back to creating
+/// and immediately unlinking a file on filesystems without it.
+pub fn anonymous(dir: &Path) -> io::Result<File> {
+    let cdir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    let fd = unsafe { libc::open(cdir.as_ptr(), libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC, 0o600) };
+    if fd >= 0 {
+        return Ok(unsafe { File::from_raw_fd(fd) });
+    }
+    let err = io::Error::last_os_error();
+    if !matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR | libc::EINVAL)) {
+        return Err(err);
+    }
+
+    let mut template = dir.join("hyperport-XXXXXX").into_os_string().into_vec();
+    template.push(0);
+    let fd = unsafe { libc::mkostemp(template.as_mut_ptr() as *mut libc::c_char, libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    if unsafe { libc::unlink(template.as_ptr() as *const libc::c_char) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
@@ -0,0 +1,315 @@
+//! A parser for the subset of TOML the configuration file needs: tables,
+//! arrays of tables, and key/value pairs whose values are strings,
+//! integers, booleans or arrays. Dotted keys, floats, dates and inline
+//! tables are rejected.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parses a document into its root table.
+pub fn parse(src: &str) -> Result<Table, Error> {
+    let mut parser = Parser { src, pos: 0, line: 1 };
+    let mut root = Table::new();
+    // The table key/value lines currently go into: None for the root,
+    // otherwise the header name and whether it was an `[[array]]` header.
+    let mut current: Option<(String, bool)> = None;
+
+    loop {
+        parser.skip_blank();
+        let Some(c) = parser.peek() else {
+            return Ok(root);
+        };
+
+        if c == '[' {
+            let array = parser.src[parser.pos..].starts_with("[[");
+            parser.pos += if array { 2 } else { 1 };
+            parser.skip_spaces();
+            let name = parser.key()?;
+            parser.skip_spaces();
+            parser.expect(if array { "]]" } else { "]" })?;
+            parser.end_of_line()?;
+
+            if array {
+                match root.entry(name.clone()).or_insert_with(|| Value::Array(Vec::new())) {
+                    Value::Array(tables) => tables.push(Value::Table(Table::new())),
+                    _ => return Err(parser.error(format!("`{}` is already defined as a table", name))),
+                }
+            } else if root.insert(name.clone(), Value::Table(Table::new())).is_some() {
+                return Err(parser.error(format!("table `{}` is defined twice", name)));
+            }
+            current = Some((name, array));
+            continue;
+        }
+
+        let key = parser.key()?;
+        parser.skip_spaces();
+        parser.expect("=")?;
+        parser.skip_spaces();
+        let value = parser.value()?;
+        parser.end_of_line()?;
+
+        let table = match &current {
+            None => &mut root,
+            Some((name, false)) => match root.get_mut(name) {
+                Some(Value::Table(table)) => table,
+                _ => unreachable!("header inserted a table"),
+            },
+            Some((name, true)) => match root.get_mut(name) {
+                Some(Value::Array(tables)) => match tables.last_mut() {
+                    Some(Value::Table(table)) => table,
+                    _ => unreachable!("header pushed a table"),
+                },
+                _ => unreachable!("header inserted an array"),
+            },
+        };
+        if table.insert(key.clone(), value).is_some() {
+            return Err(parser.error(format!("key `{}` is defined twice", key)));
+        }
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: String) -> Error {
+        Error { line: self.line, message }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", token)))
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(' ' | '\t') = self.peek() {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected `{}` after value", c))),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if self.pos == start {
+                    return Err(self.error("expected a key".to_string()));
+                }
+                if self.peek() == Some('.') {
+                    return Err(self.error("dotted keys are not supported".to_string()));
+                }
+                Ok(self.src[start..self.pos].to_string())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('t') | Some('f') => {
+                if self.expect("true").is_ok() {
+                    Ok(Value::Boolean(true))
+                } else if self.expect("false").is_ok() {
+                    Ok(Value::Boolean(false))
+                } else {
+                    Err(self.error("expected a value".to_string()))
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '+' || c == '-' => self.integer(),
+            Some('{') => Err(self.error("inline tables are not supported".to_string())),
+            _ => Err(self.error("expected a value".to_string())),
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.' | ':') {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        let text = &self.src[start..self.pos];
+        // Underscores may only separate digits.
+        if text.starts_with('_') || text.ends_with('_') || text.contains("__") {
+            return Err(self.error(format!("invalid integer `{}`", text)));
+        }
+        text.replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| self.error(format!("invalid or unsupported number `{}`", text)))
+    }
+
+    fn array(&mut self) -> Result<Value, Error> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected `,` or `]` in array".to_string())),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, Error> {
+        self.expect("'")?;
+        let start = self.pos;
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(self.src[start..self.pos - 1].to_string()),
+                Some('\n') | None => return Err(self.error("unterminated string".to_string())),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, Error> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(out),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode_escape(4)?,
+                        Some('U') => self.unicode_escape(8)?,
+                        _ => return Err(self.error("invalid escape in string".to_string())),
+                    };
+                    out.push(c);
+                }
+                Some('\n') | None => return Err(self.error("unterminated string".to_string())),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self, digits: usize) -> Result<char, Error> {
+        let hex = self.src.get(self.pos..self.pos + digits).unwrap_or("");
+        let c = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == digits)
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("invalid unicode escape".to_string()))?;
+        self.pos += digits;
+        Ok(c)
+    }
+}